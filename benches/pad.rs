@@ -0,0 +1,142 @@
+//! Pad transform benchmarks. Run with `cargo bench --features bench`.
+use std::{
+    collections::HashMap,
+    time::Instant,
+};
+use criterion::{
+    black_box,
+    criterion_group,
+    criterion_main,
+    Criterion,
+};
+use evdev::{
+    AbsoluteAxisCode,
+    EventType,
+    InputEvent,
+    KeyCode,
+    SynchronizationCode,
+};
+use trackjoy::{
+    mapper::{
+        pad::{
+            AxisRange,
+            PadGeometry,
+            PadMapper,
+            PadOutputs,
+            DEFAULT_MAX_SLOTS,
+        },
+        Changes,
+        Tuning,
+    },
+};
+
+/// Roughly a laptop trackpad: 100mm x 60mm at 40 units/mm.
+fn mapper(multitouch: bool) -> PadMapper {
+    let geometry = PadGeometry::new(AxisRange {
+        minimum: 0,
+        maximum: 4000,
+        resolution: 40,
+        fuzz: 8,
+    }, AxisRange {
+        minimum: 0,
+        maximum: 2400,
+        resolution: 40,
+        fuzz: 8,
+    }, false, None, None, None, None, None, None).unwrap();
+    return PadMapper::new(PadOutputs {
+        finger_buttons: [(2, KeyCode::BTN_TL), (3, KeyCode::BTN_TR)].into_iter().collect::<HashMap<_, _>>(),
+        multitouch: multitouch,
+        ..PadOutputs::new(
+            [AbsoluteAxisCode::ABS_X, AbsoluteAxisCode::ABS_Y],
+            [KeyCode::BTN_NORTH, KeyCode::BTN_WEST, KeyCode::BTN_EAST, KeyCode::BTN_SOUTH],
+        )
+    }, geometry, DEFAULT_MAX_SLOTS, 0, vec![]);
+}
+
+fn tuning() -> Tuning {
+    return Tuning {
+        active_low: 0.1,
+        curve: 1.37,
+        ..Tuning::default()
+    };
+}
+
+fn abs(code: AbsoluteAxisCode, value: i32) -> InputEvent {
+    return InputEvent::new(EventType::ABSOLUTE.0, code.0, value);
+}
+
+fn syn() -> InputEvent {
+    return InputEvent::new(EventType::SYNCHRONIZATION.0, SynchronizationCode::SYN_REPORT.0, 0);
+}
+
+/// A frame for each touch moving in a circle around the middle, `step` being the
+/// position along the circle.
+fn frame(out: &mut Vec<InputEvent>, touches: usize, step: usize) {
+    for t in 0 .. touches {
+        let angle = (step as f32 * 0.05) + t as f32;
+        out.push(abs(AbsoluteAxisCode::ABS_MT_SLOT, t as i32));
+        out.push(abs(AbsoluteAxisCode::ABS_MT_POSITION_X, 2000 + (angle.cos() * 900.) as i32));
+        out.push(abs(AbsoluteAxisCode::ABS_MT_POSITION_Y, 1200 + (angle.sin() * 900.) as i32));
+    }
+    out.push(syn());
+}
+
+/// Touch down events for `touches` touches.
+fn touch_down(touches: usize) -> Vec<InputEvent> {
+    let mut out = vec![];
+    for t in 0 .. touches {
+        out.push(abs(AbsoluteAxisCode::ABS_MT_SLOT, t as i32));
+        out.push(abs(AbsoluteAxisCode::ABS_MT_TRACKING_ID, t as i32));
+    }
+    frame(&mut out, touches, 0);
+    return out;
+}
+
+fn bench_frame(c: &mut Criterion) {
+    // Flush math only: one moving touch, one frame per iteration
+    let tuning = tuning();
+    let now = Instant::now();
+    let mut pad = mapper(false);
+    let mut changes = Changes::new();
+    for ev in touch_down(1) {
+        pad.event(ev, &tuning, true, now, &mut changes);
+    }
+    let mut events = vec![];
+    let mut step = 0usize;
+    c.bench_function("pad frame, 1 touch", |b| b.iter(|| {
+        step += 1;
+        events.clear();
+        frame(&mut events, 1, step);
+        changes.clear();
+        for ev in &events {
+            pad.event(*ev, &tuning, true, now, &mut changes);
+        }
+        black_box(&changes);
+    }));
+}
+
+fn bench_pump(c: &mut Criterion) {
+    // End to end: a pre-generated stream of multitouch frames through a fresh mapper,
+    // sending changes the way the source task does
+    let tuning = tuning();
+    let now = Instant::now();
+    let mut events = touch_down(3);
+    for step in 1 .. 1000 {
+        frame(&mut events, 3, step);
+    }
+    c.bench_function("pad event pump, 3 touches, 1000 frames", |b| b.iter(|| {
+        let mut pad = mapper(true);
+        let mut changes = Changes::new();
+        let mut sent = 0usize;
+        for ev in &events {
+            pad.event(*ev, &tuning, true, now, &mut changes);
+            if changes.len() > 0 {
+                sent += black_box(std::mem::take(&mut changes)).len();
+            }
+        }
+        black_box(sent);
+    }));
+}
+
+criterion_group!(benches, bench_frame, bench_pump);
+criterion_main!(benches);
@@ -0,0 +1,134 @@
+//! Arbitrary multitouch event sequences through the pad state machine. Run with
+//! `cargo +nightly fuzz run mt_slots` from the repo root.
+//!
+//! Checks that nothing panics and that once every touch is lifted no buttons are
+//! left pressed and the stick is centered.
+#![no_main]
+use std::{
+    collections::HashMap,
+    time::Instant,
+};
+use arbitrary::Arbitrary;
+use evdev::{
+    AbsoluteAxisCode,
+    EventType,
+    InputEvent,
+    KeyCode,
+    SynchronizationCode,
+};
+use libfuzzer_sys::fuzz_target;
+use trackjoy::{
+    mapper::{
+        pad::{
+            AxisRange,
+            PadGeometry,
+            PadMapper,
+            PadOutputs,
+            DEFAULT_MAX_SLOTS,
+        },
+        Change,
+        Changes,
+        Tuning,
+    },
+};
+
+#[derive(Arbitrary, Debug)]
+enum MtEvent {
+    Slot(i32),
+    TrackingId(i32),
+    PositionX(i32),
+    PositionY(i32),
+    ToolType(i32),
+    Report,
+}
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    multitouch: bool,
+    mirror_horizontal: bool,
+    events: Vec<MtEvent>,
+}
+
+fn abs(code: AbsoluteAxisCode, value: i32) -> InputEvent {
+    return InputEvent::new(EventType::ABSOLUTE.0, code.0, value);
+}
+
+fn syn() -> InputEvent {
+    return InputEvent::new(EventType::SYNCHRONIZATION.0, SynchronizationCode::SYN_REPORT.0, 0);
+}
+
+fuzz_target!(|input: Input| {
+    let geometry = PadGeometry::new(AxisRange {
+        minimum: 0,
+        maximum: 4000,
+        resolution: 40,
+        fuzz: 8,
+    }, AxisRange {
+        minimum: 0,
+        maximum: 2400,
+        resolution: 40,
+        fuzz: 8,
+    }, false, None, None, None, None, None, None).unwrap();
+    let mut mapper = PadMapper::new(PadOutputs {
+        finger_buttons: [(2, KeyCode::BTN_TL), (3, KeyCode::BTN_TR)].into_iter().collect(),
+        multitouch: input.multitouch,
+        mirror_horizontal: input.mirror_horizontal,
+        ..PadOutputs::new(
+            [AbsoluteAxisCode::ABS_X, AbsoluteAxisCode::ABS_Y],
+            [KeyCode::BTN_NORTH, KeyCode::BTN_WEST, KeyCode::BTN_EAST, KeyCode::BTN_SOUTH],
+        )
+    }, geometry, DEFAULT_MAX_SLOTS, 0, vec![]);
+    let tuning = Tuning {
+        active_low: 0.1,
+        curve: 1.37,
+        ..Tuning::default()
+    };
+
+    // Output state as the virtual device would see it
+    let mut buttons = HashMap::new();
+    let mut axes = HashMap::new();
+    let mut changes = Changes::new();
+    let now = Instant::now();
+    let mut apply = |mapper: &mut PadMapper, ev: InputEvent| {
+        mapper.event(ev, &tuning, true, now, &mut changes);
+        for change in changes.drain(..) {
+            match change {
+                Change::Button(k, on) => {
+                    buttons.insert(k, on);
+                },
+                Change::Axis(axis, v) => {
+                    assert!(v.is_finite(), "Axis {:?} got {}", axis, v);
+                    axes.insert(axis, v);
+                },
+            }
+        }
+    };
+    let mut slots = vec![0];
+    for ev in input.events {
+        let ev = match ev {
+            MtEvent::Slot(v) => {
+                slots.push(v);
+                abs(AbsoluteAxisCode::ABS_MT_SLOT, v)
+            },
+            MtEvent::TrackingId(v) => abs(AbsoluteAxisCode::ABS_MT_TRACKING_ID, v),
+            MtEvent::PositionX(v) => abs(AbsoluteAxisCode::ABS_MT_POSITION_X, v),
+            MtEvent::PositionY(v) => abs(AbsoluteAxisCode::ABS_MT_POSITION_Y, v),
+            MtEvent::ToolType(v) => abs(AbsoluteAxisCode::ABS_MT_TOOL_TYPE, v),
+            MtEvent::Report => syn(),
+        };
+        apply(&mut mapper, ev);
+    }
+
+    // Lift every touch
+    for slot in slots {
+        apply(&mut mapper, abs(AbsoluteAxisCode::ABS_MT_SLOT, slot));
+        apply(&mut mapper, abs(AbsoluteAxisCode::ABS_MT_TRACKING_ID, -1));
+    }
+    apply(&mut mapper, syn());
+    for (k, on) in buttons {
+        assert!(!on, "Button {:?} still pressed after all touches lifted", k);
+    }
+    for (axis, v) in axes {
+        assert_eq!(v, 0., "Axis {:?} not centered after all touches lifted", axis);
+    }
+});
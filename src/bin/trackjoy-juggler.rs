@@ -1,16 +1,38 @@
 use std::{
     fs::{
         read_dir,
+        read_to_string,
+    },
+    os::unix::prelude::{
+        AsRawFd,
+        OsStrExt,
     },
-    os::unix::prelude::OsStrExt,
     collections::HashMap,
-    time::Duration,
+    time::{
+        Duration,
+        Instant,
+    },
     path::Path,
 };
 use aargvark::vark;
 use futures::{
     executor::block_on,
 };
+use trackjoy::{
+    grouping::{
+        find_groupings,
+        DevType,
+        FoundDevice,
+        Group,
+        GroupRules,
+    },
+    nodes::{
+        node_type,
+        udev_properties,
+    },
+    parse_config,
+    read_config,
+};
 use loga::{
     ResultContext,
     ea,
@@ -44,50 +66,259 @@ mod re {
     }
 }
 
-#[derive(PartialEq, Eq, Debug, Hash, PartialOrd, Ord)]
-enum DevType {
-    Keys,
-    Pad,
+const DEV_DIR: &'static str = "/dev/input/by-path";
+const INPUT_DIR: &'static str = "/dev/input";
+
+/// Set on launched `trackjoy` processes to find them if the juggler dies.
+const CHILD_ENV: &'static str = "TRACKJOY_JUGGLER_CHILD";
+
+/// What a watch event is for.
+enum Trigger {
+    Devices,
+    Config,
+}
+
+struct GroupProc {
+    proc: Child,
+    /// Virtual device name
+    name: String,
+}
+
+/// Name of the physical device the group is for, from the first pad (or keys if
+/// there are no pads).
+fn group_device_name(group: &Group) -> String {
+    let Some(path) = group.pads.iter().chain(group.keys.iter()).flatten().next() else {
+        return "-".to_string();
+    };
+    match evdev::Device::open(path) {
+        Ok(d) => match d.name() {
+            Some(n) => n.to_string(),
+            None => path.clone(),
+        },
+        Err(_) => path.clone(),
+    }
 }
 
-fn find_groupings(
-    want_keys: usize,
-    want_pads: usize,
-    mut values: Vec<(DevType, String)>,
-) -> Result<Vec<Vec<(DevType, String)>>, loga::Error> {
-    values.sort();
-    let mut groups = vec![];
-    while values.len() > 0 {
-        let mut keys_count = 0usize;
-        let mut pads_count = 0usize;
-        let mut ok_until = 0;
-        for (i, (type_, _)) in values.iter().enumerate() {
-            match type_ {
-                DevType::Keys => {
-                    keys_count += 1;
-                },
-                DevType::Pad => {
-                    pads_count += 1;
-                },
+/// The unique id (serial number, bluetooth address) of the device a node belongs
+/// to, if it has one.
+fn device_uniq(path: &str) -> Option<String> {
+    let node = std::fs::canonicalize(path).ok()?;
+    let uniq = read_to_string(Path::new("/sys/class/input").join(node.file_name()?).join("device/uniq")).ok()?;
+    let uniq = uniq.trim();
+    if uniq.is_empty() {
+        return None;
+    }
+    return Some(uniq.to_string());
+}
+
+/// Find usable devices, one node per physical device: the pad node if there is
+/// one, otherwise the highest numbered keyboard node.
+fn scan_devices(log: &loga::Log, usb_parts_re: &re::UsbPathPartsFromRegex) -> Vec<(DevType, String)> {
+    // Keyed by physical device
+    let mut device_collection = HashMap::new();
+
+    // USB devices, by port
+    let mut have_by_path = false;
+    match read_dir(DEV_DIR) {
+        Ok(devices) => {
+            have_by_path = true;
+            for device in devices {
+                let device = match device {
+                    Ok(d) => d,
+                    Err(e) => {
+                        log.warn_e(e.into(), "Error reading dev tree entry", ea!());
+                        continue;
+                    },
+                };
+                let file_name = match String::from_utf8(device.file_name().as_bytes().to_vec()) {
+                    Ok(f) => f,
+                    Err(e) => {
+                        log.warn_e(
+                            e.into(),
+                            "Couldn't parse device path from utf8",
+                            ea!(device = device.file_name().to_string_lossy()),
+                        );
+                        continue;
+                    },
+                };
+                let parts = match usb_parts_re.parse(&file_name) {
+                    Ok(p) => p,
+                    Err(_) => {
+                        continue;
+                    },
+                };
+                let type_ = if parts.suffix.ends_with("-mouse") {
+                    let attrs =
+                        match std::process::Command::new("udevadm")
+                            .arg("info")
+                            .arg("--attribute-walk")
+                            .arg(device.path())
+                            .output() {
+                            Ok(o) => o,
+                            Err(e) => {
+                                log.warn_e(e.into(), "Error getting sysfs attrs of device", ea!(device = file_name));
+                                continue;
+                            },
+                        };
+                    if TwoWaySearcher::new("DRIVERS==\"hid-multitouch\"".as_bytes())
+                        .search_in(&attrs.stdout)
+                        .is_none() {
+                        continue;
+                    }
+                    DevType::Pad
+                } else if parts.suffix.ends_with("kbd") {
+                    DevType::Keys
+                } else {
+                    continue;
+                };
+                device_collection
+                    .entry(parts.path)
+                    .or_insert_with(Vec::new)
+                    .push(((type_, parts.configuration, parts.interface), device.path().to_string_lossy().to_string()));
             }
-            if keys_count > want_keys || pads_count > want_pads {
-                break;
+        },
+        Err(e) => {
+            log.warn_e(e.into(), "Failed to list devices, falling back to scanning all input device nodes", ea!());
+        },
+    }
+
+    // Bluetooth devices don't get by-path links, find them by their event nodes. If
+    // there are no by-path links at all (containers, unusual setups) use this for
+    // everything.
+    match read_dir(INPUT_DIR) {
+        Ok(nodes) => {
+            for node in nodes {
+                let Ok(node) = node else {
+                    continue;
+                };
+                if !node.file_name().to_string_lossy().starts_with("event") {
+                    continue;
+                }
+                let props = match udev_properties(&node.path()) {
+                    Ok(p) => p,
+                    Err(e) => {
+                        log.warn_e(e, "Error checking device type", ea!());
+                        continue;
+                    },
+                };
+                let bluetooth = props.get("ID_BUS").map(|b| b.as_str()) == Some("bluetooth");
+                if have_by_path && !bluetooth {
+                    continue;
+                }
+                let Some(type_) = node_type(&props) else {
+                    continue;
+                };
+
+                // Identify the physical device by the unique id (bluetooth address, serial
+                // number) or the port without the interface
+                let uniq =
+                    read_to_string(
+                        Path::new("/sys/class/input").join(node.file_name()).join("device/uniq"),
+                    ).unwrap_or_default();
+                let physical = if !uniq.trim().is_empty() {
+                    format!("uniq-{}", uniq.trim())
+                } else if let Some(id_path) = props.get("ID_PATH") {
+                    format!("path-{}", id_path.rsplit_once(':').map(|(p, _)| p).unwrap_or(id_path))
+                } else {
+                    node.path().to_string_lossy().to_string()
+                };
+                device_collection
+                    .entry(physical)
+                    .or_insert_with(Vec::new)
+                    .push(((type_, 0, 0), node.path().to_string_lossy().to_string()));
             }
-            ok_until = i + 1;
+        },
+        Err(e) => {
+            log.warn_e(e.into(), "Failed to list input device nodes", ea!());
+        },
+    }
+    let mut device_list = vec![];
+    for (_, mut v) in device_collection {
+        v.sort();
+        let best = v.pop().unwrap();
+        device_list.push((best.0.0, best.1));
+    }
+    return device_list;
+}
+
+/// Make sure this is the only juggler running, so two don't fight over devices.
+/// The lock is held until the returned file is dropped.
+fn lock_instance() -> Result<std::fs::File, loga::Error> {
+    let path =
+        Path::new(&std::env::var_os("XDG_RUNTIME_DIR").unwrap_or("/tmp".into())).join("trackjoy-juggler.lock");
+    let file =
+        std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&path)
+            .context_with("Error opening lock file", ea!(path = path.to_string_lossy()))?;
+    if unsafe {
+        libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB)
+    } != 0 {
+        return Err(
+            loga::err_with(
+                "Another trackjoy-juggler is already running",
+                ea!(lock = path.to_string_lossy(), err = std::io::Error::last_os_error()),
+            ),
+        );
+    }
+    return Ok(file);
+}
+
+/// Stop `trackjoy` processes left running by a previous juggler that crashed, so
+/// their devices can be grabbed again.
+async fn stop_orphans(log: &loga::Log) {
+    let Ok(procs) = read_dir("/proc") else {
+        return;
+    };
+    let marker = format!("{}=", CHILD_ENV);
+    let mut orphans = vec![];
+    for p in procs {
+        let Ok(p) = p else {
+            continue;
+        };
+        let Ok(pid) = p.file_name().to_string_lossy().parse::<i32>() else {
+            continue;
+        };
+        let Ok(environ) = std::fs::read(p.path().join("environ")) else {
+            continue;
+        };
+        if !environ.split(|b| *b == 0).any(|e| e.starts_with(marker.as_bytes())) {
+            continue;
+        }
+
+        // Programs started by trackjoy (feedback commands, `udevadm`, etc.) inherit the
+        // marker too, only stop trackjoy itself. The binary may have been replaced by an
+        // upgrade since it started.
+        let Ok(exe) = std::fs::read_link(p.path().join("exe")) else {
+            continue;
+        };
+        let exe = exe.to_string_lossy().to_string();
+        let exe = exe.trim_end_matches(" (deleted)");
+        if Path::new(exe).file_name().map(|n| n != "trackjoy").unwrap_or(true) {
+            continue;
         }
-        if ok_until == 0 {
-            return Err(
-                loga::err_with(
-                    "Encountered device type with no config",
-                    ea!(type_ = values.get(0).unwrap().0.dbg_str(), device = values.get(0).unwrap().1),
-                ),
+        log.info("Stopping trackjoy left by a previous juggler", ea!(pid = pid));
+        if unsafe {
+            libc::kill(pid, libc::SIGTERM)
+        } != 0 {
+            log.warn_e(
+                std::io::Error::last_os_error().into(),
+                "Failed to stop orphaned trackjoy",
+                ea!(pid = pid),
             );
+            continue;
+        }
+        orphans.push(p.path());
+    }
+
+    // Wait a bit for them to release their devices
+    for _ in 0 .. 50 {
+        if !orphans.iter().any(|p| p.exists()) {
+            break;
         }
-        let new_working = values.split_off(ok_until);
-        groups.push(values.split_off(0));
-        values = new_working;
+        tokio::time::sleep(Duration::from_millis(100)).await;
     }
-    return Ok(groups);
 }
 
 mod args {
@@ -95,11 +326,21 @@ mod args {
         Aargvark,
         AargvarkJson,
     };
-    use trackjoy::Config;
 
     #[derive(Aargvark)]
     pub struct Args {
-        pub config: AargvarkJson<Config>,
+        /// Changes are picked up automatically: groups are re-evaluated and running
+        /// `trackjoy` processes reload it.
+        pub config: AargvarkJson<serde_json::Value>,
+        /// Passed to `trackjoy`: keep virtual gamepads for this many seconds after
+        /// their devices disappear.
+        pub linger: Option<u64>,
+        /// Wait this many seconds for a group to get all the pads and keys devices in the
+        /// config before launching it with the missing ones marked absent. Without this
+        /// groups are launched with whatever devices are there.
+        pub group_timeout: Option<u64>,
+        /// Reject unknown config fields and codes, also passed to `trackjoy`.
+        pub strict: Option<()>,
     }
 }
 
@@ -113,8 +354,17 @@ async fn main() {
             },
             aargvark::Source::File(f) => f,
         };
+
+        // Absolute so it can be compared with watch event paths
+        let config_source =
+            std::fs::canonicalize(&config_source)
+                .context_with("Error finding config file", ea!(path = config_source.to_string_lossy()))?;
+        let strict = args.strict.is_some();
+        let mut config = parse_config(args.config.value, strict)?;
         let tm = taskmanager::TaskManager::new();
         let log = &loga::new(loga::Level::Info);
+        let _lock = lock_instance()?;
+        stop_orphans(log).await;
         let (event_transmit, mut event_receive) = channel(1);
         tm.critical_task({
             let log = log.clone();
@@ -123,31 +373,61 @@ async fn main() {
             let usb_parts_re = re::UsbPathPartsFromRegex::new();
             async move {
                 let log = &log;
-                let mut procs: HashMap<Vec<(DevType, String)>, Child> = HashMap::new();
+                let mut procs: HashMap<Group, GroupProc> = HashMap::new();
+
+                // Groups missing devices, and when they were first seen
+                let mut incomplete: HashMap<Group, Instant> = HashMap::new();
+                let rescan_transmit = event_transmit.clone();
 
                 // Debounce loop - outer waits forever, ignore first event + subsequent events
                 // until a timeout, then go back to waiting forever
-                const DEV_DIR: &'static str = "/dev/input/by-path";
-                let mut watcher = RecommendedWatcher::new(move |res: Result<Event, notify::Error>| {
-                    block_on(async {
-                        _ = event_transmit.send(res.map(|_| ())).await;
-                    })
+                let mut watcher = RecommendedWatcher::new({
+                    let config_source = config_source.clone();
+                    move |res: Result<Event, notify::Error>| {
+                        block_on(async {
+                            _ = event_transmit.send(res.map(|e| if e.paths.contains(&config_source) {
+                                Trigger::Config
+                            } else {
+                                Trigger::Devices
+                            })).await;
+                        })
+                    }
                 }, notify::Config::default()).log_context(log, "Failed to configure dev node watcher")?;
+                if let Err(e) = watcher.watch(Path::new(DEV_DIR), RecursiveMode::NonRecursive) {
+                    // Not all systems have by-path links
+                    log.warn_e(e.into(), "Error starting watch on by-path device links", ea!());
+                }
+
+                // For bluetooth devices, or everything without by-path links
                 watcher
-                    .watch(Path::new(DEV_DIR), RecursiveMode::NonRecursive)
+                    .watch(Path::new(INPUT_DIR), RecursiveMode::NonRecursive)
                     .log_context(log, "Error starting watch")?;
-                'event_loop: while let Some(Some(_)) = tm.if_alive(event_receive.recv()).await {
+
+                // The config directory rather than the file, since editors often replace the
+                // file
+                watcher
+                    .watch(config_source.parent().unwrap_or(Path::new("/")), RecursiveMode::NonRecursive)
+                    .log_context(log, "Error starting watch on config")?;
+                'event_loop: while let Some(Some(first)) = tm.if_alive(event_receive.recv()).await {
+                    let mut reload = matches!(first, Ok(Trigger::Config));
                     while let Some(timeout_res) =
                         tm.if_alive(tokio::time::timeout(Duration::from_millis(1000), event_receive.recv())).await {
                         match timeout_res {
                             Ok(channel_res) => match channel_res {
                                 Some(event) => {
-                                    if let Err(e) = event {
-                                        log.warn_e(e.into(), "Watch event error", ea!());
-                                        continue;
-                                    } else {
-                                        // Not timeout - not debounced; continue until timeout
-                                        continue;
+                                    match event {
+                                        Err(e) => {
+                                            log.warn_e(e.into(), "Watch event error", ea!());
+                                            continue;
+                                        },
+                                        Ok(trigger) => {
+                                            if let Trigger::Config = trigger {
+                                                reload = true;
+                                            }
+
+                                            // Not timeout - not debounced; continue until timeout
+                                            continue;
+                                        },
                                     }
                                 },
                                 None => {
@@ -158,103 +438,78 @@ async fn main() {
                                 // Timeout elapsed
                             },
                         }
-                        match read_dir(DEV_DIR) {
-                            Ok(devices) => {
-                                // Take highest numbered node from each device (pads, then high numbered
-                                // keyboards). Only use one node per device.
-                                let mut device_collection = HashMap::new();
-                                for device in devices {
-                                    let device = match device {
-                                        Ok(d) => d,
-                                        Err(e) => {
-                                            log.warn_e(e.into(), "Error reading dev tree entry", ea!());
-                                            continue;
-                                        },
-                                    };
-                                    let file_name = match String::from_utf8(device.file_name().as_bytes().to_vec()) {
-                                        Ok(f) => f,
-                                        Err(e) => {
-                                            log.warn_e(
-                                                e.into(),
-                                                "Couldn't parse device path from utf8",
-                                                ea!(device = device.file_name().to_string_lossy()),
-                                            );
-                                            continue;
-                                        },
-                                    };
-                                    let parts = match usb_parts_re.parse(&file_name) {
-                                        Ok(p) => p,
-                                        Err(_) => {
-                                            continue;
-                                        },
-                                    };
-                                    let type_ = if parts.suffix.ends_with("-mouse") {
-                                        let attrs =
-                                            match std::process::Command::new("udevadm")
-                                                .arg("info")
-                                                .arg("--attribute-walk")
-                                                .arg(device.path())
-                                                .output() {
-                                                Ok(o) => o,
-                                                Err(e) => {
-                                                    log.warn_e(
-                                                        e.into(),
-                                                        "Error getting sysfs attrs of device",
-                                                        ea!(device = file_name),
-                                                    );
-                                                    continue;
-                                                },
-                                            };
-                                        if TwoWaySearcher::new("DRIVERS==\"hid-multitouch\"".as_bytes())
-                                            .search_in(&attrs.stdout)
-                                            .is_none() {
-                                            continue;
-                                        }
-                                        DevType::Pad
-                                    } else if parts.suffix.ends_with("kbd") {
-                                        DevType::Keys
-                                    } else {
-                                        continue;
-                                    };
-                                    device_collection
-                                        .entry(parts.path)
-                                        .or_insert_with(Vec::new)
-                                        .push(((type_, parts.configuration, parts.interface), file_name));
-                                }
-                                let mut device_list = vec![];
-                                for (_, mut v) in device_collection {
-                                    v.sort();
-                                    let best = v.pop().unwrap();
-                                    device_list.push((best.0.0, best.1));
-                                }
+                        if reload {
+                            match read_config(&config_source, strict) {
+                                Ok(c) => {
+                                    log.info("Reloaded config", ea!());
+                                    config = c;
+                                },
+                                Err(e) => {
+                                    log.warn_e(e, "Error reloading config, keeping the previous config", ea!());
+                                    reload = false;
+                                },
+                            }
+                        }
+                        let device_list = scan_devices(log, &usb_parts_re);
 
-                                // Group into virtual devices
-                                let mut new_procs = HashMap::new();
-                                let mut pre_new_procs = vec![];
-                                for group in find_groupings(
-                                    args.config.value.keys_mappings.len() as usize,
-                                    args.config.value.pad_mappings.len() as usize,
-                                    device_list.into_iter().collect(),
-                                )? {
-                                    if let Some(proc_group) = procs.remove(&group) {
-                                        new_procs.insert(group, proc_group);
+                        // Group into virtual devices
+                        let found = device_list.into_iter().map(|(type_, path)| FoundDevice {
+                            type_: type_,
+                            uniq: device_uniq(&path),
+                            path: path,
+                        }).collect();
+                        let groupings = find_groupings(&GroupRules::from_config(&config), found)?;
+                        let mut new_procs = HashMap::new();
+                        let mut pre_new_procs = vec![];
+                        let mut new_incomplete = HashMap::new();
+                        for group in groupings {
+                            if let Some(timeout) = args.group_timeout {
+                                if !group.complete() && !procs.contains_key(&group) {
+                                    let since = incomplete.remove(&group).unwrap_or_else(|| {
+                                        // Check again once the timeout passes
+                                        let rescan_transmit = rescan_transmit.clone();
+                                        tokio::spawn(async move {
+                                            tokio::time::sleep(Duration::from_secs(timeout)).await;
+                                            _ = rescan_transmit.send(Ok(Trigger::Devices)).await;
+                                        });
+                                        Instant::now()
+                                    });
+                                    if since.elapsed() < Duration::from_secs(timeout) {
+                                        log.info("Waiting for more devices for group", ea!(group = group.dbg_str()));
+                                        new_incomplete.insert(group, since);
                                         continue;
                                     }
-                                    pre_new_procs.push(group);
                                 }
-                                for (group, mut proc) in procs {
-                                    log.info("Stopping trackjoy", ea!(group = group.dbg_str()));
-                                    match proc.kill().await {
-                                        Ok(_) => { },
-                                        Err(e) => {
-                                            log.warn_e(
-                                                e.into(),
-                                                "Failed to kill child for stale grouping",
-                                                ea!(child = proc.dbg_str()),
-                                            );
-                                            continue;
-                                        },
-                                    };
+                            }
+                            if let Some(proc_group) = procs.remove(&group) {
+                                new_procs.insert(group, proc_group);
+                                continue;
+                            }
+                            pre_new_procs.push(group);
+                        }
+                        for (group, GroupProc { mut proc, .. }) in procs {
+                            log.info("Stopping trackjoy", ea!(group = group.dbg_str()));
+
+                            // Terminate rather than kill so it can linger
+                            let Some(pid) = proc.id() else {
+                                // Already exited
+                                continue;
+                            };
+                            if unsafe {
+                                libc::kill(pid as i32, libc::SIGTERM)
+                            } != 0 {
+                                log.warn_e(
+                                    std::io::Error::last_os_error().into(),
+                                    "Failed to stop child for stale grouping",
+                                    ea!(child = proc.dbg_str()),
+                                );
+                                continue;
+                            }
+
+                            // Don't hold up regrouping while it lingers
+                            tokio::spawn({
+                                let log = log.clone();
+                                async move {
                                     match proc.wait().await {
                                         Ok(_) => { },
                                         Err(e) => {
@@ -263,44 +518,95 @@ async fn main() {
                                                 "Failed to wait for child to stop in stale grouping",
                                                 ea!(child = proc.dbg_str()),
                                             );
-                                            continue;
                                         },
                                     };
                                 }
-                                procs = new_procs;
-                                for group in pre_new_procs {
-                                    log.info("Launching trackjoy", ea!(group = group.dbg_str()));
-                                    let mut c = tokio::process::Command::new("trackjoy");
-                                    c.arg(config_source.as_os_str());
-                                    for (type_, path) in &group {
-                                        match type_ {
-                                            DevType::Keys => {
-                                                c.arg("keys");
-                                            },
-                                            DevType::Pad => {
-                                                c.arg("pad");
-                                            },
-                                        }
-                                        c.arg(path);
-                                    }
-                                    let proc = match c.spawn() {
-                                        Ok(p) => p,
-                                        Err(e) => {
-                                            log.warn_e(
-                                                e.into(),
-                                                "Error starting trackjoy process on dev group",
-                                                ea!(cmd = c.dbg_str()),
-                                            );
-                                            continue;
+                            });
+                        }
+                        procs = new_procs;
+
+                        // Children with unchanged groups reload the config themselves
+                        if reload {
+                            for (group, GroupProc { proc, .. }) in &procs {
+                                let Some(pid) = proc.id() else {
+                                    continue;
+                                };
+                                log.info("Reloading trackjoy config", ea!(group = group.dbg_str()));
+                                if unsafe {
+                                    libc::kill(pid as i32, libc::SIGHUP)
+                                } != 0 {
+                                    log.warn_e(
+                                        std::io::Error::last_os_error().into(),
+                                        "Failed to signal child to reload config",
+                                        ea!(child = proc.dbg_str()),
+                                    );
+                                }
+                            }
+                        }
+                        incomplete = new_incomplete;
+                        for group in pre_new_procs {
+                            // Number groups of the same device so they can be told apart
+                            let device_name = group_device_name(&group);
+                            let mut name;
+                            let mut number = 1;
+                            loop {
+                                name =
+                                    format!(
+                                        "{}: {} #{}",
+                                        config.name.as_deref().unwrap_or("trackjoy"),
+                                        device_name,
+                                        number
+                                    );
+                                if !procs.values().any(|p| p.name == name) {
+                                    break;
+                                }
+                                number += 1;
+                            }
+                            log.info("Launching trackjoy", ea!(group = group.dbg_str(), name = name));
+                            let mut c = tokio::process::Command::new("trackjoy");
+                            c.arg("run");
+                            c.env(CHILD_ENV, "1");
+                            c.arg(config_source.as_os_str());
+
+                            // Devices in mapping order, with missing devices marked absent
+                            for (type_, present, absent) in [
+                                (DevType::Pad, "pad", "absent-pad"),
+                                (DevType::Keys, "keys", "absent-keys"),
+                            ] {
+                                for slot in group.slots(type_) {
+                                    match slot {
+                                        Some(path) => {
+                                            c.arg(present).arg(path);
                                         },
-                                    };
-                                    procs.insert(group, proc);
+                                        None => {
+                                            c.arg(absent);
+                                        },
+                                    }
                                 }
-                            },
-                            Err(e) => {
-                                log.warn_e(e.into(), "Failed to list devices", ea!());
-                            },
-                        };
+                            }
+                            c.arg("--name").arg(&name);
+                            if let Some(linger) = args.linger {
+                                c.arg("--linger").arg(linger.to_string());
+                            }
+                            if strict {
+                                c.arg("--strict");
+                            }
+                            let proc = match c.spawn() {
+                                Ok(p) => p,
+                                Err(e) => {
+                                    log.warn_e(
+                                        e.into(),
+                                        "Error starting trackjoy process on dev group",
+                                        ea!(cmd = c.dbg_str()),
+                                    );
+                                    continue;
+                                },
+                            };
+                            procs.insert(group, GroupProc {
+                                proc: proc,
+                                name: name,
+                            });
+                        }
                         break;
                     }
                 }
@@ -309,7 +615,7 @@ async fn main() {
         });
 
         // Initial scan
-        _ = event_transmit.send(Ok(())).await;
+        _ = event_transmit.send(Ok(Trigger::Devices)).await;
 
         // Wait for shutdown
         tm.join().await?;
@@ -1,142 +1,601 @@
 pub mod trackjoycore;
 
 use std::{
-    sync::{
-        Arc,
-        Mutex,
+    collections::{
+        HashMap,
+        HashSet,
     },
-    collections::HashSet,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
 };
-use aargvark::vark;
+use aargvark::vark_explicit;
 use evdev::{
     uinput::{
+        VirtualDevice,
         VirtualDeviceBuilder,
     },
     AbsInfo,
+    AbsoluteAxisCode,
     AttributeSet,
-    Device,
+    FFEffectCode,
     KeyCode,
     UinputAbsSetup,
 };
 use loga::{
     ea,
+    Log,
     fatal,
     ResultContext,
     DebugDisplay,
 };
-use manual_future::ManualFuture;
+use trackjoy::{
+    grouping::DevType,
+    mapper::{
+        exp_param,
+        pad::{
+            PadSnapshot,
+            DEFAULT_MAX_SLOTS,
+        },
+        Tuning,
+    },
+    nodes::find_node,
+    parse_config,
+    read_config,
+    DeviceRef,
+    OutputDevice,
+    PadPreset,
+};
 use trackjoycore::data::{
     DEST_HALF,
     DEST_MAX,
 };
+use taskmanager::TaskManager;
+use tokio::{
+    signal::unix::{
+        signal,
+        SignalKind,
+    },
+    sync::watch,
+};
 use crate::trackjoycore::{
+    active,
+    battery,
+    calibrate,
+    control,
+    crash,
     pad,
     keys,
+    grab,
+    focus,
+    history::Histories,
+    shadow::PadShadow,
+    stats::Stats,
+    trace::Tracer,
+    identity,
+    import,
+    output::{
+        self,
+        OutputTx,
+    },
+    output_log::OutputLog,
+    probe,
+    profile,
+    selftest,
+    service,
+    sleep,
 };
 
 mod args {
-    use std::path::PathBuf;
+    use std::{
+        os::fd::BorrowedFd,
+        path::PathBuf,
+    };
     use aargvark::{
         Aargvark,
         AargvarkJson,
     };
+    use loga::{
+        ea,
+        ResultContext,
+    };
 
-    #[derive(Aargvark)]
+    #[derive(Clone, Copy)]
     pub enum DeviceType {
-        /// A trackpad, becomes 1 stick and 4 buttons.
         Pad,
-        /// Something with keys, each key is turned into a button. Too many keys will run
-        /// you out of buttons, beware.
         Keys,
     }
 
     #[derive(Aargvark)]
-    pub struct Device {
-        pub device: DeviceType,
-        pub path: PathBuf,
+    pub enum Device {
+        /// A trackpad, becomes 1 stick and 4 buttons.
+        ///
+        /// Devices can be given as event nodes, sysfs device directories, or the start of
+        /// a name in `/dev/input/by-id`; for the latter two the right node is picked.
+        Pad(PathBuf),
+        /// Something with keys, each key is turned into a button. Too many keys will run
+        /// you out of buttons, beware.
+        Keys(PathBuf),
+        /// A pad that isn't connected. Its mapping is skipped so the following pads
+        /// keep theirs.
+        AbsentPad,
+        /// A keys device that isn't connected, like `absent-pad`.
+        AbsentKeys,
+        /// A pad already opened by whatever started trackjoy, by file descriptor number,
+        /// for sandboxes that can't open devices in `/dev/input` (ex: passed in through
+        /// a portal).
+        PadFd(i32),
+        /// A keys device already opened, like `pad-fd`.
+        KeysFd(i32),
+        /// A pad read through its `/dev/hidrawN` node, for pads whose kernel driver
+        /// doesn't report multitouch. Touches are relayed through a virtual pad. Needs
+        /// the `hidraw` feature.
+        PadHidraw(PathBuf),
+    }
+
+    impl Device {
+        /// The path is `/proc/self/fd/N` for devices given as file descriptors.
+        pub fn present(&self) -> Option<(DeviceType, PathBuf)> {
+            match self {
+                Device::Pad(p) => Some((DeviceType::Pad, p.clone())),
+                Device::Keys(p) => Some((DeviceType::Keys, p.clone())),
+                Device::PadFd(fd) => Some((DeviceType::Pad, PathBuf::from(format!("/proc/self/fd/{}", fd)))),
+                Device::PadHidraw(p) => Some((DeviceType::Pad, p.clone())),
+                Device::KeysFd(fd) => Some((DeviceType::Keys, PathBuf::from(format!("/proc/self/fd/{}", fd)))),
+                Device::AbsentPad | Device::AbsentKeys => None,
+            }
+        }
+
+        /// Open the device, for file descriptors a new descriptor for the same device so
+        /// it can be opened again after reloading.
+        pub fn open(&self) -> Result<evdev::Device, loga::Error> {
+            match self {
+                Device::Pad(p) | Device::Keys(p) => {
+                    return Ok(
+                        evdev::Device::open(
+                            p,
+                        ).context_with("Error opening device", ea!(path = p.to_string_lossy()))?,
+                    );
+                },
+                Device::PadFd(fd) | Device::KeysFd(fd) => {
+                    if *fd < 0 {
+                        return Err(loga::err_with("Invalid device file descriptor", ea!(fd = fd)));
+                    }
+                    let fd =
+                        unsafe {
+                            BorrowedFd::borrow_raw(*fd)
+                        }
+                            .try_clone_to_owned()
+                            .context_with("Error duplicating device file descriptor", ea!(fd = fd))?;
+                    return Ok(evdev::Device::from_fd(fd).context("Error opening device from file descriptor")?);
+                },
+                Device::AbsentPad | Device::AbsentKeys => {
+                    return Err(loga::err("Absent devices can't be opened"));
+                },
+                Device::PadHidraw(_) => {
+                    return Err(loga::err("hidraw pads are opened through a virtual pad"));
+                },
+            }
+        }
+
+        /// As given on the command line.
+        pub fn args(&self) -> Vec<String> {
+            match self {
+                Device::Pad(p) => return vec!["pad".to_string(), p.to_string_lossy().to_string()],
+                Device::Keys(p) => return vec!["keys".to_string(), p.to_string_lossy().to_string()],
+                Device::AbsentPad => return vec!["absent-pad".to_string()],
+                Device::AbsentKeys => return vec!["absent-keys".to_string()],
+                Device::PadFd(fd) => return vec!["pad-fd".to_string(), fd.to_string()],
+                Device::KeysFd(fd) => return vec!["keys-fd".to_string(), fd.to_string()],
+                Device::PadHidraw(p) => return vec!["pad-hidraw".to_string(), p.to_string_lossy().to_string()],
+            }
+        }
     }
 
-    /// Creates a single virtual gamepad.
+    /// Creates a single virtual gamepad. Send `SIGHUP` to reload the config.
     #[derive(Aargvark)]
-    pub struct Args {
-        pub config: AargvarkJson<trackjoy::Config>,
+    pub struct RunArgs {
+        pub config: AargvarkJson<serde_json::Value>,
         /// List of touchpad devices (`/dev/input/*-event-mouse`).  Each one will be
         /// converted into new joystick and four buttons on the virtual gamepad.
         pub devices: Vec<Device>,
+        /// If another process has a device grabbed, retry this many times (with
+        /// increasing delays, up to 10s) before giving up. Defaults to 0.
+        pub grab_retries: Option<usize>,
+        /// Don't grab the devices, so they keep working normally alongside the virtual
+        /// gamepad (for overlays or testing).
+        pub no_grab: Option<()>,
+        /// Name of the virtual gamepad, overrides `name` in the config.
+        pub name: Option<String>,
+        /// When stopping (`SIGTERM`, or a device disconnecting) keep the virtual gamepad
+        /// around, centered with nothing pressed, for this many seconds before removing
+        /// it.
+        pub linger: Option<u64>,
+        /// Reject unknown config fields and codes, see `strict` in the config.
+        pub strict: Option<()>,
+        /// Serve a page on `http://127.0.0.1:PORT/` showing touches on the pads, for
+        /// editing the stick shape. Saving writes the config and reloads it. The URL
+        /// with the access token is logged. Requires the `web` feature and a config
+        /// file.
+        pub web: Option<u16>,
+        /// Stream the pad state (touches, stick, pressed buttons) as JSON lines to
+        /// clients of a Unix socket, `trackjoy-NAME-tap.sock` in `$XDG_RUNTIME_DIR`, for
+        /// overlays and visualizers.
+        pub tap: Option<()>,
+        /// Also run this config on the same input without sending its output, logging
+        /// whenever its output differs from the main config's, to try out changes on real
+        /// play. Pads use its stick shaping, `buttons` and `finger_buttons`; keys use
+        /// its whole mapping. It's reloaded along with the main config.
+        pub shadow: Option<PathBuf>,
+        /// Write every event sent to the virtual devices to this file, with timestamps
+        /// and the timestamps of the source events that caused them, for analyzing input
+        /// timing. CSV if the name ends with `.csv`, otherwise JSON lines.
+        pub log_output: Option<PathBuf>,
+    }
+
+    #[derive(Aargvark)]
+    pub enum ImportFormat {
+        /// Steam Input controller config (`.vdf`).
+        Steam,
+        /// AntiMicroX profile (`.amgp`).
+        Antimicrox,
+    }
+
+    /// Convert a keyboard layout from another tool into a config with one keys
+    /// mapping, printed to stdout. Only keyboard bindings are converted.
+    #[derive(Aargvark)]
+    pub struct ImportArgs {
+        pub file: PathBuf,
+        /// The format of the file. Defaults to guessing from the extension (`.vdf` or
+        /// `.amgp`).
+        pub from: Option<ImportFormat>,
+    }
+
+    /// Print the config as it will be used, with defaults, presets and overrides
+    /// applied. Pad presets are left as is, their values are used per pad when it's
+    /// connected (along with presets detected from the pad).
+    #[derive(Aargvark)]
+    pub struct DumpEffectiveConfigArgs {
+        pub config: AargvarkJson<serde_json::Value>,
+        /// As for `run`.
+        pub name: Option<String>,
+        /// As for `run`.
+        pub strict: Option<()>,
+    }
+
+    /// Adjust the stick tuning (`curve`, `dead_inner`, `dead_outer`, `y_smash`) of a
+    /// running instance from an interactive prompt. Changes apply immediately and
+    /// are saved to the top level of the config on exit. While a profile is active
+    /// it replaces these values, until the next change.
+    #[derive(Aargvark)]
+    pub struct TuneArgs {
+        /// The config the instance was started with.
+        pub config: PathBuf,
+        /// As for `run`, if it was passed there.
+        pub name: Option<String>,
+    }
+
+    /// Measure the position extents of a pad while you slide a finger along its
+    /// edges, and save them as its calibration. Press Ctrl-C to finish. Stop
+    /// `trackjoy` first if it has the pad grabbed.
+    #[derive(Aargvark)]
+    pub struct CalibrateArgs {
+        /// The pad, as for `run`.
+        pub device: PathBuf,
+    }
+
+    /// Write a systemd unit running `trackjoy-juggler` with the config, with access to
+    /// input devices and uinput and little else. System units run as a dynamic user
+    /// in the `input` group, so the config must be readable by others and
+    /// `/dev/uinput` writable by the group.
+    #[derive(Aargvark)]
+    pub struct InstallServiceArgs {
+        pub config: PathBuf,
+        /// Run `trackjoy run` on these devices instead of the juggler.
+        pub devices: Vec<Device>,
+        /// Install for the current user (in `~/.config/systemd/user/`) instead of
+        /// system wide. User units can't limit device access and the user needs access
+        /// to the devices already.
+        pub user: Option<()>,
+        /// Name of the unit, defaults to `trackjoy`.
+        pub name: Option<String>,
+        /// Replace the unit if it exists.
+        pub force: Option<()>,
+        /// Enable and start the unit.
+        pub enable: Option<()>,
+    }
+
+    /// Subcommand names, anything else is parsed as the arguments of `run` (the
+    /// command line before there were subcommands).
+    pub const SUBCOMMANDS: &[&str] =
+        &["run", "echo", "import", "dump-effective-config", "tune", "calibrate", "self-test", "install-service"];
+
+    #[derive(Aargvark)]
+    pub enum Args {
+        Run(RunArgs),
+        /// Like `run`, but print what the virtual devices would send (device, code,
+        /// value) instead of creating them, to check mappings where uinput isn't
+        /// available (ex: containers). Media key `passthrough` still needs uinput.
+        Echo(RunArgs),
+        Import(ImportArgs),
+        DumpEffectiveConfig(DumpEffectiveConfigArgs),
+        Tune(TuneArgs),
+        Calibrate(CalibrateArgs),
+        /// Check that everything works: runs `trackjoy` on a fake trackpad (created with
+        /// uinput), touches it, and checks the virtual gamepad's stick and buttons.
+        SelfTest,
+        InstallService(InstallServiceArgs),
     }
 }
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
-    async fn inner() -> Result<(), loga::Error> {
-        let tm = taskmanager::TaskManager::new();
-        let log = loga::new(loga::Level::Info);
+/// Everything about the virtual devices that can't be changed once they're
+/// created. If this is the same after a config reload the devices are kept, so
+/// running games don't see the controller disconnect.
+#[derive(PartialEq, Clone)]
+struct DestSpec {
+    /// Per player
+    names: Vec<String>,
+    /// Per player
+    identities: Vec<Vec<String>>,
+    buttons: HashSet<KeyCode>,
+    axes: HashSet<AbsoluteAxisCode>,
+    keyboard_name: String,
+    /// Buttons routed to the shared keyboard, no keyboard if empty
+    keyboard_buttons: HashSet<KeyCode>,
+    /// Accept rumble from games, see `rumble_dead_zone`
+    rumble: bool,
+}
 
-        // # Get and check args
-        let args: args::Args = vark();
-        let config = args.config.value;
-
-        // Turn into always positive, at 0 curve is 1
-        let curve = 1.37f32.powf(config.curve.unwrap_or(0.));
-        let y_smash = 1.37f32.powf(config.y_smash.unwrap_or(1.));
-        let active_low = config.dead_inner.unwrap_or(0.0);
-        let active_high = 1.0 - config.dead_outer.unwrap_or(0.4);
-        if active_high - active_low < 0. {
-            return Err(loga::err("Dead zones overlap"));
-        }
-
-        // Dest prep
-        let mut dest_completers = vec![];
-        let mut dest_buttons = HashSet::new();
-        let mut dest_axes = vec![];
-
-        // Set up each source device, launch thread waiting for destination setup to
-        // complete
-        let mut pad_buttons_i = 0;
-        let mut keys_buttons_i = 0;
-        for dev in args.devices {
-            let log = log.fork(ea!(device = dev.path.to_string_lossy()));
-            let (dest, dest_completer) = ManualFuture::new();
-            dest_completers.push(dest_completer);
-            let mut source = Device::open(&dev.path).log_context(&log, "Error opening device")?;
-            source.grab().log_context(&log, "Failed to grab device")?;
-            match dev.device {
-                args::DeviceType::Pad => {
-                    let mappings = match config.pad_mappings.get(pad_buttons_i) {
-                        Some(c) => {
-                            pad_buttons_i += 1;
-                            c
-                        },
-                        None => {
-                            return Err(
-                                log.new_err_with(
-                                    "Config doesn't contain enough button mappings for selected pad devices",
-                                    ea!(pad = pad_buttons_i, config_pads = config.pad_mappings.len()),
-                                ),
-                            );
-                        },
-                    };
-                    pad::build(
-                        &tm,
-                        source,
-                        mappings.axes,
-                        mappings.buttons,
-                        dest,
-                        &mut dest_buttons,
-                        &mut dest_axes,
-                        config.multitouch,
-                        config.width,
-                        config.height,
-                        active_high,
-                        active_low,
-                        curve,
-                        y_smash,
-                    )?
-                },
-                args::DeviceType::Keys => keys::build(&tm, source, match config.keys_mappings.get(keys_buttons_i) {
+/// Tasks that depend on the config, replaced on reload.
+struct Session {
+    dest_spec: DestSpec,
+    _tuning: Arc<watch::Sender<Tuning>>,
+    /// Inputs of whether mapping is active that never change
+    _active: Vec<watch::Sender<bool>>,
+    /// Per pad, if requested
+    pad_snapshots: Vec<watch::Receiver<PadSnapshot>>,
+}
+
+async fn start_session(
+    tm: &TaskManager,
+    log: &Log,
+    config: &trackjoy::Config,
+    devices: &[args::Device],
+    grab_retries: usize,
+    no_grab: bool,
+    name: &str,
+    output_tx: &OutputTx,
+    visualize: bool,
+    histories: &Histories,
+    tracer: &Tracer,
+    stats: &Stats,
+    shadow: Option<&trackjoy::Config>,
+    rumble: &watch::Receiver<Vec<f32>>,
+) -> Result<Session, loga::Error> {
+    // Stick shaping, per profile
+    let base_tuning = Tuning::new(config, None)?;
+    let shadow_tuning = match shadow {
+        Some(s) => Some(Tuning::new(s, None).context("Error in shadow config stick shaping")?),
+        None => None,
+    };
+    let mut profiles = vec![];
+    for p in &config.profiles {
+        profiles.push((p.clone(), Tuning::new(config, Some(p))?));
+    }
+    let (tuning, tuning_rx) = watch::channel(base_tuning);
+    let tuning = Arc::new(tuning);
+    let mut _active = vec![];
+    let (profile_active, profile_active_rx) = watch::channel(!config.release_without_profile);
+    let profile_active = if config.release_without_profile {
+        Some(Arc::new(profile_active))
+    } else {
+        // Never changes, just keep alive
+        _active.push(profile_active);
+        None
+    };
+    let profile_cycler =
+        Arc::new(
+            profile::ProfileCycler::new(
+                base_tuning,
+                profiles.clone(),
+                tuning.clone(),
+                output_tx.clone(),
+                profile_active.clone(),
+            ),
+        );
+    if profiles.iter().any(|(p, _)| !p.processes.is_empty()) {
+        profile::build_process_switcher(
+            tm,
+            log,
+            base_tuning,
+            profiles,
+            tuning.clone(),
+            output_tx.clone(),
+            profile_active,
+        );
+    }
+    match control::build(tm, log, name, config, tuning.clone(), histories.clone(), tracer.clone(), stats.clone()) {
+        Ok(_) => { },
+        Err(e) => {
+            log.warn_e(e, "Couldn't start control socket, `trackjoy tune` won't work", ea!());
+        },
+    }
+
+    // Whether mapping is currently on
+    let (focused, focused_rx) = watch::channel(config.focus_window_classes.is_empty());
+    if config.focus_window_classes.is_empty() {
+        // Never changes, just keep alive
+        _active.push(focused);
+    } else {
+        focus::build(tm, log, config.focus_window_classes.clone(), focused)?;
+    }
+    let (awake, awake_rx) = watch::channel(true);
+    if config.release_on_sleep {
+        sleep::build(tm, log, awake)?;
+    } else {
+        _active.push(awake);
+    }
+    let active_rx = active::all(tm, vec![focused_rx, awake_rx, profile_active_rx]);
+
+    // Modifier and recenter keys held on keyboards, for pads
+    let (modifiers, modifiers_rx) = watch::channel(HashSet::new());
+    let modifiers = Arc::new(modifiers);
+
+    // Battery levels, for wireless devices
+    battery::build(
+        tm,
+        log,
+        devices.iter().filter_map(|d| d.present()).map(|(_, p)| p).collect(),
+        config.battery_warn_percent(),
+    );
+
+    // Dest prep
+    let mut dest_buttons = HashSet::new();
+    let mut keyboard_buttons = HashSet::new();
+    let mut dest_axes = vec![];
+    let player_count = config.players.len().max(1);
+    let mut player_identities = vec![vec![]; player_count];
+
+    // Buttons mapped by each source, to catch unintended sharing
+    let mut claimed_buttons: HashMap<(usize, KeyCode), DeviceRef> = HashMap::new();
+
+    // Set up each source device
+    let mut pad_buttons_i = 0;
+    let mut pad_snapshots = vec![];
+    let mut keys_buttons_i = 0;
+    for dev in devices {
+        let (device_type, path) = match dev {
+            args::Device::AbsentPad => {
+                pad_buttons_i += 1;
+                continue;
+            },
+            args::Device::AbsentKeys => {
+                keys_buttons_i += 1;
+                continue;
+            },
+            dev => dev.present().unwrap(),
+        };
+        let log = log.fork(ea!(device = path.to_string_lossy()));
+        let player = config.players.iter().position(|p| match device_type {
+            args::DeviceType::Pad => p.pads.contains(&pad_buttons_i),
+            args::DeviceType::Keys => p.keys.contains(&keys_buttons_i),
+        }).unwrap_or(0);
+        let mut source = match dev {
+            #[cfg(feature = "hidraw")]
+            args::Device::PadHidraw(p) => {
+                trackjoycore::hidraw::build(tm, &log, p, !no_grab).await.log_context(&log, "Error opening hidraw pad")?
+            },
+            #[cfg(not(feature = "hidraw"))]
+            args::Device::PadHidraw(_) => {
+                return Err(
+                    loga::err("This build doesn't include the hidraw backend, rebuild with `--features hidraw`"),
+                );
+            },
+            dev => dev.open().log_context(&log, "Error opening device")?,
+        };
+        probe::report(&log, &source);
+        player_identities[player].push(identity::source_identity(&source));
+        if !no_grab {
+            grab::grab(&log, &mut source, &path, grab_retries).await?;
+        }
+        let mut source_buttons = HashSet::new();
+        let mut split_buttons = HashSet::new();
+        let mut split_player = None;
+        match device_type {
+            args::DeviceType::Pad => {
+                let mappings = match config.pad_mappings.get(pad_buttons_i) {
+                    Some(c) => {
+                        pad_buttons_i += 1;
+                        c
+                    },
+                    None => {
+                        return Err(
+                            log.new_err_with(
+                                "Config doesn't contain enough button mappings for selected pad devices",
+                                ea!(pad = pad_buttons_i, config_pads = config.pad_mappings.len()),
+                            ),
+                        );
+                    },
+                };
+                let preset = match mappings.preset {
+                    Some(p) => Some(p),
+                    None => {
+                        let id = source.input_id();
+                        let detected = PadPreset::detect(id.vendor(), id.product());
+                        if let Some(p) = detected {
+                            log.info("Detected pad model, using preset", ea!(preset = p.dbg_str()));
+                        }
+                        detected
+                    },
+                };
+                let preset = preset.map(|p| p.values());
+                pad::build(
+                    tm,
+                    &log,
+                    source,
+                    DeviceRef::Pad(pad_buttons_i - 1),
+                    mappings.axes,
+                    mappings.buttons,
+                    mappings.finger_buttons.clone(),
+                    output_tx.clone(),
+                    player,
+                    player_count,
+                    &mut source_buttons,
+                    &mut dest_axes,
+                    config.multitouch,
+                    config.stick_weighting.unwrap_or(trackjoy::StickWeighting::Mean),
+                    mappings.mirror_horizontal.unwrap_or(config.handedness == Some(trackjoy::Handedness::Left)),
+                    mappings.orientation,
+                    mappings.fuzz,
+                    mappings.resolution_x,
+                    mappings.resolution_y,
+                    mappings.max_slots.unwrap_or(DEFAULT_MAX_SLOTS),
+                    mappings.hover_axes,
+                    mappings.stick_region,
+                    mappings.click_buttons.clone(),
+                    mappings.force_buttons.clone(),
+                    mappings.selector.clone(),
+                    mappings.zone_feedback.clone(),
+                    mappings.profile_swipe.clone(),
+                    mappings.player_cycle_fingers,
+                    mappings.tilt.clone(),
+                    mappings.ignore_events.clone(),
+                    config.width.or(preset.as_ref().map(|p| p.width)),
+                    config.height.or(preset.as_ref().map(|p| p.height)),
+                    tuning_rx.clone(),
+                    exp_param(preset.as_ref().map(|p| p.y_smash).unwrap_or(1.)),
+                    !no_grab,
+                    active_rx.clone(),
+                    if visualize {
+                        let (snapshot, snapshot_rx) = watch::channel(PadSnapshot::default());
+                        pad_snapshots.push(snapshot_rx);
+                        Some(snapshot)
+                    } else {
+                        None
+                    },
+                    histories.clone(),
+                    tracer.clone(),
+                    mappings.modifier_buttons.clone(),
+                    modifiers_rx.clone(),
+                    mappings.recenter_key,
+                    config.rumble_dead_zone.map(|r| (r, rumble.clone())),
+                    profile_cycler.clone(),
+                    config.stats.then(|| stats.clone()),
+                    shadow.zip(shadow_tuning).map(|(s, tuning)| {
+                        let m = s.pad_mappings.get(pad_buttons_i - 1);
+                        PadShadow {
+                            tuning: tuning,
+                            button_codes: m.map(|m| m.buttons).unwrap_or(mappings.buttons),
+                            finger_buttons: m.map(|m| m.finger_buttons.clone()).unwrap_or_default(),
+                        }
+                    }),
+                )?
+            },
+            args::DeviceType::Keys => {
+                let mappings = match config.keys_mappings.get(keys_buttons_i) {
                     Some(c) => {
                         keys_buttons_i += 1;
-                        c.clone()
+                        c.with_preset()
                     },
                     None => {
                         return Err(
@@ -146,43 +605,634 @@ async fn main() {
                             ),
                         );
                     },
-                }, dest, &mut dest_buttons)?,
+                };
+                if let Some(split) = &mappings.split {
+                    if split.player >= player_count {
+                        return Err(
+                            log.new_err_with(
+                                "Keys mapping split is for a player that doesn't exist",
+                                ea!(player = split.player, players = player_count),
+                            ),
+                        );
+                    }
+                    if split.player != player {
+                        player_identities[split.player].push(identity::source_identity(&source));
+                    }
+                    split_player = Some(split.player);
+                }
+                keys::build(
+                    tm,
+                    &log,
+                    source,
+                    DeviceRef::Keys(keys_buttons_i - 1),
+                    mappings,
+                    output_tx.clone(),
+                    player,
+                    player_count,
+                    config.player_cycle_key,
+                    &mut source_buttons,
+                    &mut split_buttons,
+                    &mut dest_axes,
+                    !no_grab,
+                    active_rx.clone(),
+                    histories.clone(),
+                    tracer.clone(),
+                    modifiers.clone(),
+                    config.pad_mappings.iter().filter_map(|m| m.recenter_key).collect(),
+                    config.stats.then(|| stats.clone()),
+                    shadow.and_then(|s| s.keys_mappings.get(keys_buttons_i - 1)).map(|m| m.with_preset()),
+                )?
+            },
+        }
+        let source_ref = match device_type {
+            args::DeviceType::Pad => DeviceRef::Pad(pad_buttons_i - 1),
+            args::DeviceType::Keys => DeviceRef::Keys(keys_buttons_i - 1),
+        };
+        let routes = match source_ref {
+            DeviceRef::Pad(i) => &config.pad_mappings[i].outputs,
+            DeviceRef::Keys(i) | DeviceRef::KeysSplit(i) => &config.keys_mappings[i].outputs,
+        };
+        let mut claims = vec![(source_ref, player, source_buttons)];
+        if let (DeviceRef::Keys(i), Some(split_player)) = (source_ref, split_player) {
+            claims.push((DeviceRef::KeysSplit(i), split_player, split_buttons));
+        }
+        for (source_ref, player, source_buttons) in claims {
+            for k in source_buttons {
+                match routes.get(&k).cloned().unwrap_or(OutputDevice::Gamepad) {
+                    OutputDevice::Gamepad => { },
+                    OutputDevice::Keyboard => {
+                        // Shared by all players, no conflicts to check
+                        keyboard_buttons.insert(k);
+                        continue;
+                    },
+                }
+                if let Some(other) = claimed_buttons.get(&(player, k)) {
+                    if !config.allow_shared_buttons && !config.button_policies.contains_key(&k) {
+                        return Err(
+                            log.new_err_with(
+                                "Button mapped by multiple devices of the same player, set `allow_shared_buttons` if this is intended",
+                                ea!(button = k.dbg_str(), other_device = other.dbg_str()),
+                            ),
+                        );
+                    }
+                }
+                claimed_buttons.insert((player, k), source_ref);
+                dest_buttons.insert(k);
             }
         }
+    }
+
+    // One virtual device per player. Each one gets all the buttons and axes since keys
+    // devices can move between players.
+    let mut names = vec![];
+    for player in 0 .. player_count {
+        names.push(if player_count > 1 {
+            format!("{} {}", name, player + 1)
+        } else {
+            name.to_string()
+        });
+    }
+    for (player, identities) in player_identities.iter_mut().enumerate() {
+        if player > 0 {
+            identities.push(format!("player {}", player + 1));
+        }
+    }
+    return Ok(Session {
+        dest_spec: DestSpec {
+            names: names,
+            identities: player_identities,
+            buttons: dest_buttons,
+            axes: dest_axes.into_iter().collect(),
+            keyboard_name: format!("{} keyboard", name),
+            keyboard_buttons: keyboard_buttons,
+            rumble: config.rumble_dead_zone.is_some(),
+        },
+        _tuning: tuning,
+        _active: _active,
+        pad_snapshots: pad_snapshots,
+    });
+}
+
+/// The event node to use for a device argument, see `find_node`.
+fn resolve_node(log: &loga::Log, path: PathBuf, type_: DevType) -> Result<PathBuf, loga::Error> {
+    let node = find_node(&path, type_)?;
+    if node != path {
+        log.info("Using event node for device", ea!(device = path.to_string_lossy(), node = node.to_string_lossy()));
+    }
+    return Ok(node);
+}
+
+/// Fill in everything that's left to defaults or comes from elsewhere.
+fn effective_config(mut config: trackjoy::Config, name_override: Option<String>) -> trackjoy::Config {
+    config.name = Some(name_override.unwrap_or(config.name().to_string()));
+    let handedness = config.handedness.unwrap_or(trackjoy::Handedness::Right);
+    config.handedness = Some(handedness);
+    for m in &mut config.pad_mappings {
+        m.mirror_horizontal = Some(m.mirror_horizontal.unwrap_or(handedness == trackjoy::Handedness::Left));
+        m.max_slots = Some(m.max_slots.unwrap_or(DEFAULT_MAX_SLOTS));
+        if let Some(r) = &mut m.stick_region {
+            r.left = Some(r.left.unwrap_or(0.));
+            r.top = Some(r.top.unwrap_or(0.));
+            r.right = Some(r.right.unwrap_or(1.));
+            r.bottom = Some(r.bottom.unwrap_or(1.));
+        }
+        if let Some(c) = &mut m.click_buttons {
+            c.zone_height = Some(c.zone_height.unwrap_or(trackjoy::DEFAULT_CLICK_ZONE_HEIGHT));
+        }
+        if let Some(f) = &mut m.force_buttons {
+            f.hysteresis = Some(f.hysteresis.unwrap_or(trackjoy::DEFAULT_FORCE_HYSTERESIS));
+        }
+        if let Some(s) = &mut m.selector {
+            s.center = Some(s.center.unwrap_or(trackjoy::DEFAULT_SELECTOR_CENTER));
+        }
+        if let Some(s) = &mut m.profile_swipe {
+            s.fingers = Some(s.fingers.unwrap_or(trackjoy::DEFAULT_SWIPE_FINGERS));
+            s.distance = Some(s.distance.unwrap_or(trackjoy::DEFAULT_SWIPE_DISTANCE));
+        }
+        if let Some(t) = &mut m.tilt {
+            t.dead_inner = Some(t.dead_inner.unwrap_or(trackjoy::DEFAULT_TILT_DEAD_INNER));
+            t.dead_outer = Some(t.dead_outer.unwrap_or(trackjoy::DEFAULT_TILT_DEAD_OUTER));
+            t.curve = Some(t.curve.unwrap_or(trackjoy::DEFAULT_CURVE));
+            t.range = Some(t.range.unwrap_or(trackjoy::DEFAULT_TILT_RANGE));
+        }
+    }
+    config.keys_mappings = config.keys_mappings.iter().map(|m| {
+        let mut m = m.with_preset();
+        m.media_keys = Some(m.media_keys.unwrap_or(trackjoy::MediaKeysPolicy::Map));
+        m
+    }).collect();
+
+    // Stick shaping as `Tuning` sees it, profiles with the top level values they
+    // inherit
+    let mut profiles = std::mem::take(&mut config.profiles);
+    for p in &mut profiles {
+        let s = config.shaping(Some(p));
+        p.curve = Some(s.curve);
+        p.dead_inner = Some(s.dead_inner);
+        p.dead_outer = Some(s.dead_outer);
+        p.y_smash = s.y_smash;
+        p.release_mode = Some(s.release_mode);
+        p.flick = s.flick;
+    }
+    config.profiles = profiles;
+    let s = config.shaping(None);
+    config.curve = Some(s.curve);
+    config.dead_inner = Some(s.dead_inner);
+    config.dead_outer = Some(s.dead_outer);
+    config.release_mode = Some(s.release_mode);
+    config.flick = s.flick;
+    config.battery_warn_percent = Some(config.battery_warn_percent());
+
+    // Output policies
+    for r in config.button_repeat.values_mut().chain(config.axis_repeat.values_mut()) {
+        r.delay_ms = Some(r.delay_ms.unwrap_or(trackjoy::DEFAULT_REPEAT_DELAY_MS));
+        r.interval_ms = Some(r.interval_ms.unwrap_or(trackjoy::DEFAULT_REPEAT_INTERVAL_MS));
+    }
+    for n in config.axis_notches.values_mut() {
+        n.hysteresis = Some(n.hysteresis.unwrap_or(trackjoy::DEFAULT_NOTCH_HYSTERESIS));
+    }
+    if let Some(o) = &mut config.press_order {
+        o.release_order = Some(o.release_order.unwrap_or(trackjoy::DEFAULT_RELEASE_ORDER));
+        o.delay_ms = Some(o.delay_ms.unwrap_or(trackjoy::DEFAULT_PRESS_ORDER_DELAY_MS));
+    }
+    if let Some(e) = &mut config.emergency_release {
+        e.suspend = Some(e.suspend.unwrap_or(trackjoy::DEFAULT_EMERGENCY_SUSPEND));
+    }
+    if let Some(r) = &mut config.rumble_dead_zone {
+        r.threshold = Some(r.threshold.unwrap_or(trackjoy::DEFAULT_RUMBLE_THRESHOLD));
+    }
+    config.aliases.clear();
+    config.variables.clear();
+    return config;
+}
+
+/// Which sources send which buttons somewhere other than the gamepad.
+fn output_routes(config: &trackjoy::Config) -> HashMap<DeviceRef, HashMap<KeyCode, OutputDevice>> {
+    let mut out = HashMap::new();
+    for (i, m) in config.pad_mappings.iter().enumerate() {
+        out.insert(DeviceRef::Pad(i), m.outputs.clone());
+    }
+    for (i, m) in config.keys_mappings.iter().enumerate() {
+        out.insert(DeviceRef::Keys(i), m.outputs.clone());
+        if m.split.is_some() {
+            out.insert(DeviceRef::KeysSplit(i), m.outputs.clone());
+        }
+    }
+    return out;
+}
+
+/// With `echo` nothing is created, the events are printed instead.
+fn build_player_dest(spec: &DestSpec, player: usize) -> Result<VirtualDevice, loga::Error> {
+    let mut keys = AttributeSet::<KeyCode>::new();
+    for button in &spec.buttons {
+        keys.insert(*button);
+    }
+    let dest_axis_setup = AbsInfo::new(DEST_HALF, 0, DEST_MAX, 20, 0, 1);
+    let mut dest =
+        VirtualDeviceBuilder::new()
+            .context("Error creating virtual device builder")?
+            .name(&spec.names[player])
+            .input_id(identity::dest_input_id(&spec.identities[player]));
+    for axis in &spec.axes {
+        dest =
+            dest
+                .with_absolute_axis(&UinputAbsSetup::new(*axis, dest_axis_setup))
+                .context_with("Error adding axis to virtual device", ea!(axis = axis.dbg_str()))?;
+    }
+    let mut dest = dest.with_keys(&keys).context("Error adding keys to virtual device")?;
+    if spec.rumble {
+        dest =
+            dest
+                .with_ff(&AttributeSet::from_iter([FFEffectCode::FF_RUMBLE]))
+                .context("Error adding rumble to virtual device")?
+                .with_ff_effects_max(output::RUMBLE_EFFECTS as u32);
+    }
+    let mut dest = dest.build().context("Unable to create virtual joystick device")?;
+    for path in dest.enumerate_dev_nodes_blocking().context("Error listing virtual device dev nodes")? {
+        let path = path.context("Error getting virtual device node path")?;
+        println!("Virtual device created at: {}", path.display());
+    }
+    return Ok(dest);
+}
+
+fn build_keyboard_dest(spec: &DestSpec) -> Result<VirtualDevice, loga::Error> {
+    let mut keys = AttributeSet::<KeyCode>::new();
+    for button in &spec.keyboard_buttons {
+        keys.insert(*button);
+    }
+    let mut keyboard =
+        VirtualDeviceBuilder::new()
+            .context("Error creating virtual keyboard builder")?
+            .name(&spec.keyboard_name)
+            .with_keys(&keys)
+            .context("Error adding keys to virtual keyboard")?
+            .build()
+            .context("Unable to create virtual keyboard")?;
+    for path in keyboard.enumerate_dev_nodes_blocking().context("Error listing virtual keyboard dev nodes")? {
+        let path = path.context("Error getting virtual keyboard node path")?;
+        println!("Virtual keyboard created at: {}", path.display());
+    }
+    return Ok(keyboard);
+}
+
+/// With `echo` nothing is created, the events are printed instead. Each device can
+/// be created again by the output if it's lost.
+fn build_dests(spec: &DestSpec, echo: bool) -> Result<output::Dests, loga::Error> {
+    if echo {
+        return Ok(output::Dests {
+            players: spec.names.iter().map(|_| output::DestDevice::Echo).collect(),
+            keyboard: (!spec.keyboard_buttons.is_empty()).then_some(output::DestDevice::Echo),
+            rumble: false,
+        });
+    }
+    let spec = Arc::new(spec.clone());
+    let mut dests = vec![];
+    for player in 0 .. spec.names.len() {
+        let rebuild: output::Rebuild = Arc::new({
+            let spec = spec.clone();
+            move || build_player_dest(&spec, player)
+        });
+        dests.push(output::DestDevice::Uinput(rebuild()?, rebuild));
+    }
+    let keyboard = if spec.keyboard_buttons.is_empty() {
+        None
+    } else {
+        let rebuild: output::Rebuild = Arc::new({
+            let spec = spec.clone();
+            move || build_keyboard_dest(&spec)
+        });
+        Some(output::DestDevice::Uinput(rebuild()?, rebuild))
+    };
+    return Ok(output::Dests {
+        players: dests,
+        keyboard: keyboard,
+        rumble: spec.rumble,
+    });
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    async fn inner() -> Result<(), loga::Error> {
+        let tm = taskmanager::TaskManager::new();
+        let log = loga::new(loga::Level::Info);
+        crash::install_hook();
+
+        // # Get and check args
+        let mut raw_args = std::env::args();
+        let command = raw_args.next();
+        let mut raw_args = raw_args.collect::<Vec<_>>();
+        match raw_args.first() {
+            Some(a) if args::SUBCOMMANDS.contains(&a.as_str()) || a.starts_with("-") => { },
+            None => { },
+            Some(_) => {
+                raw_args.insert(0, "run".to_string());
+            },
+        }
+        let (args, echo) = match vark_explicit::<args::Args>(command, raw_args) {
+            args::Args::Run(a) => (a, false),
+            args::Args::Echo(a) => (a, true),
+            args::Args::Import(a) => {
+                let format = match a.from {
+                    Some(args::ImportFormat::Steam) => import::Format::Steam,
+                    Some(args::ImportFormat::Antimicrox) => import::Format::AntiMicroX,
+                    None => match a.file.extension().and_then(|e| e.to_str()) {
+                        Some(e) if e.eq_ignore_ascii_case("vdf") => import::Format::Steam,
+                        Some(e) if e.eq_ignore_ascii_case("amgp") => import::Format::AntiMicroX,
+                        _ => {
+                            return Err(
+                                loga::err_with(
+                                    "Can't tell the import format from the file extension, pass `--from`",
+                                    ea!(path = a.file.to_string_lossy()),
+                                ),
+                            );
+                        },
+                    },
+                };
+                let config = import::import(&log, format, &a.file)?;
+                println!("{}", serde_json::to_string_pretty(&config).context("Error serializing imported config")?);
+                return Ok(());
+            },
+            args::Args::DumpEffectiveConfig(a) => {
+                let config = effective_config(parse_config(a.config.value, a.strict.is_some())?, a.name);
+                println!("{}", serde_json::to_string_pretty(&config).context("Error serializing config")?);
+                return Ok(());
+            },
+            args::Args::Tune(a) => {
+                let name = match a.name {
+                    Some(n) => n,
+                    None => read_config(&a.config, false)?.name().to_string(),
+                };
+                control::tune(&a.config, &name)?;
+                return Ok(());
+            },
+            args::Args::Calibrate(a) => {
+                let node = resolve_node(&log, a.device, DevType::Pad)?;
+                calibrate::run(&log, &node).await?;
+                return Ok(());
+            },
+            args::Args::SelfTest => {
+                selftest::run(&log).await?;
+                return Ok(());
+            },
+            args::Args::InstallService(a) => {
+                service::install(&log, &a.config, service::ServiceOptions {
+                    name: a.name.unwrap_or("trackjoy".to_string()),
+                    user: a.user.is_some(),
+                    run_args: if a.devices.is_empty() {
+                        None
+                    } else {
+                        Some(a.devices.iter().flat_map(|d| d.args()).collect())
+                    },
+                    hidraw: a.devices.iter().any(|d| matches!(d, args::Device::PadHidraw(_))),
+                    force: a.force.is_some(),
+                    enable: a.enable.is_some(),
+                })?;
+                return Ok(());
+            },
+        };
+        let config_path = match args.config.source {
+            aargvark::Source::Stdin => None,
+            aargvark::Source::File(f) => Some(f),
+        };
+        let strict = args.strict.is_some();
+        let mut config = parse_config(args.config.value, strict)?;
+        let shadow_path = args.shadow;
+        let mut shadow = match &shadow_path {
+            Some(path) => Some(read_config(path, strict).context("Error reading shadow config")?),
+            None => None,
+        };
+        let devices = args.devices.into_iter().map(|d| match d {
+            args::Device::Pad(p) => Ok(args::Device::Pad(resolve_node(&log, p, DevType::Pad)?)),
+            args::Device::Keys(p) => Ok(args::Device::Keys(resolve_node(&log, p, DevType::Keys)?)),
+            d => Ok(d),
+        }).collect::<Result<Vec<_>, loga::Error>>()?;
+        let grab_retries = args.grab_retries.unwrap_or(0);
+        let no_grab = args.no_grab.is_some();
+        let name_override = args.name;
+        let linger = args.linger;
+        let tap = args.tap.is_some();
+        let web = match args.web {
+            Some(port) => {
+                if !cfg!(feature = "web") {
+                    return Err(loga::err("This build doesn't include the web UI, rebuild with `--features web`"));
+                }
+                let Some(path) = &config_path else {
+                    return Err(
+                        loga::err("The web UI saves to the config file, it can't be used with a config from stdin"),
+                    );
+                };
+                Some((port, path.clone()))
+            },
+            None => None,
+        };
+
+        // Sources send output changes to a single writer, which owns the virtual devices
+        let output_log = match args.log_output {
+            Some(path) => Some(OutputLog::open(&log, &path)?),
+            None => None,
+        };
+        let (output_tx, output_rx) = output::channel();
+        let (rumble, rumble_rx) = watch::channel(vec![]);
+        let tracer = Tracer::default();
+        output::build(&tm, &log, output_rx, linger.map(Duration::from_secs), output_log, rumble, tracer.clone());
 
-        // Set up dest
-        {
-            let mut dest =
-                VirtualDeviceBuilder::new().context("Error creating virtual device builder")?.name("Trackpad JS");
-            let dest_axis_setup = AbsInfo::new(DEST_HALF, 0, DEST_MAX, 20, 0, 1);
-            for axis in dest_axes {
-                dest =
-                    dest
-                        .with_absolute_axis(&UinputAbsSetup::new(axis, dest_axis_setup))
-                        .context_with("Error adding axis to virtual device", ea!(axis = axis.dbg_str()))?;
+        // Stop cleanly on signals so the virtual devices can linger
+        let mut terminate = signal(SignalKind::terminate()).context("Error setting up terminate signal handler")?;
+        let mut interrupt = signal(SignalKind::interrupt()).context("Error setting up interrupt signal handler")?;
+        tm.critical_task::<_, loga::Error>({
+            let tm = tm.clone();
+            async move {
+                let received = tm.if_alive(async {
+                    tokio::select!{
+                        _ = terminate.recv() => {
+                        },
+                        _ = interrupt.recv() => {
+                        }
+                    }
+                }).await;
+                if received.is_some() {
+                    tm.terminate();
+                }
+                return Ok(());
             }
-            let mut keys = AttributeSet::<KeyCode>::new();
-            for button in dest_buttons {
-                keys.insert(button);
+        });
+
+        // Dump recent events on request (SIGUSR1)
+        let histories = Histories::default();
+        let mut dump_events =
+            signal(SignalKind::user_defined1()).context("Error setting up event dump signal handler")?;
+        tm.critical_task::<_, loga::Error>({
+            let tm = tm.clone();
+            let log = log.clone();
+            let histories = histories.clone();
+            async move {
+                while let Some(Some(_)) = tm.if_alive(dump_events.recv()).await {
+                    match histories.write() {
+                        Ok(path) => {
+                            log.info("Wrote recent events", ea!(path = path.to_string_lossy()));
+                        },
+                        Err(e) => {
+                            log.warn_e(e, "Error dumping recent events", ea!());
+                        },
+                    }
+                }
+                return Ok(());
             }
-            let mut dest =
-                dest
-                    .with_keys(&keys)
-                    .context("Error adding keys to virtual device")?
-                    .build()
-                    .context("Unable to create virtual joystick device")?;
-            for path in dest.enumerate_dev_nodes_blocking().context("Error listing virtual device dev nodes")? {
-                let path = path.context("Error getting virtual device node path")?;
-                println!("Virtual device created at: {}", path.display());
+        });
+
+        // Usage stats, written on exit
+        let stats = Stats::default();
+
+        // Run, restarting everything but the virtual devices when the config is reloaded
+        // (SIGHUP)
+        let mut reload = signal(SignalKind::hangup()).context("Error setting up reload signal handler")?;
+        tm.critical_task::<_, loga::Error>({
+            let tm = tm.clone();
+            let log = log.clone();
+            let stats = stats.clone();
+            async move {
+                let mut dest_spec: Option<DestSpec> = None;
+
+                // The config (and shadow config) of the last session that started, to go back
+                // to if a reloaded one fails. Unset at startup, where failing is fatal.
+                let mut last_good: Option<(trackjoy::Config, Option<trackjoy::Config>)> = None;
+                loop {
+                    let session_tm = TaskManager::new();
+                    let name = name_override.as_deref().unwrap_or(config.name());
+                    let started = async {
+                        let session =
+                            start_session(
+                                &session_tm,
+                                &log,
+                                &config,
+                                &devices,
+                                grab_retries,
+                                no_grab,
+                                name,
+                                &output_tx,
+                                web.is_some() || tap,
+                                &histories,
+                                &tracer,
+                                &stats,
+                                shadow.as_ref(),
+                                &rumble_rx,
+                            ).await?;
+                        if tap {
+                            trackjoycore::tap::build(&session_tm, &log, name, session.pad_snapshots.clone())?;
+                        }
+                        #[cfg(feature = "web")]
+                        if let Some((port, path)) = &web {
+                            trackjoycore::web::build(
+                                &session_tm,
+                                &log,
+                                *port,
+                                path.clone(),
+                                strict,
+                                session.pad_snapshots.clone(),
+                            ).await?;
+                        }
+                        let dests = if dest_spec.as_ref() == Some(&session.dest_spec) {
+                            None
+                        } else {
+                            Some(build_dests(&session.dest_spec, echo)?)
+                        };
+                        return Ok::<_, loga::Error>((session, dests));
+                    }.await;
+                    let (session, dests) = match started {
+                        Ok(s) => s,
+                        Err(e) => {
+                            // Stop whatever of the session started and release what it sent
+                            session_tm.terminate();
+                            _ = session_tm.join().await;
+                            _ = output_tx.send(output::Message::Reset);
+                            match last_good.take() {
+                                Some((good_config, good_shadow)) => {
+                                    log.warn_e(
+                                        e,
+                                        "Error starting with the reloaded config, restarting with the previous config",
+                                        ea!(),
+                                    );
+                                    config = good_config;
+                                    shadow = good_shadow;
+                                    continue;
+                                },
+                                None => {
+                                    return Err(e);
+                                },
+                            }
+                        },
+                    };
+                    last_good = Some((config.clone(), shadow.clone()));
+                    dest_spec = Some(session.dest_spec);
+                    _ = output_tx.send(output::Message::Configure(dests, output::Arbitration {
+                        axis_mixes: config.axis_mixes.clone(),
+                        button_policies: config.button_policies.clone(),
+                        axis_policies: config.axis_policies.clone(),
+                        routes: output_routes(&config),
+                        button_repeat: config.button_repeat.clone(),
+                        axis_repeat: config.axis_repeat.clone(),
+                        axis_notches: config.axis_notches.clone(),
+                        press_order: config.press_order.clone(),
+                        axis_snapshots: config.axis_snapshots.clone(),
+                        dead_man: config.dead_man,
+                        emergency_release: config.emergency_release.clone(),
+                        profile_axes: config.profiles.iter().map(|p| p.axes.clone()).collect(),
+                    }));
+                    let reloaded = tm.if_alive(session_tm.if_alive(reload.recv())).await;
+                    session_tm.terminate();
+                    session_tm.join().await.context("Error in critical task")?;
+                    match reloaded {
+                        Some(Some(Some(()))) => { },
+                        _ => {
+                            break;
+                        },
+                    }
+
+                    // Old sources are stopped, release everything they held
+                    _ = output_tx.send(output::Message::Reset);
+                    match &config_path {
+                        Some(path) => match read_config(path, strict) {
+                            Ok(c) => {
+                                log.info("Reloaded config", ea!());
+                                config = c;
+                            },
+                            Err(e) => {
+                                log.warn_e(e, "Error reloading config, restarting with the previous config", ea!());
+                            },
+                        },
+                        None => {
+                            log.warn(
+                                "Config was read from stdin and can't be reloaded, restarting with the same config",
+                                ea!(),
+                            );
+                        },
+                    }
+                    if let Some(path) = &shadow_path {
+                        match read_config(path, strict) {
+                            Ok(c) => {
+                                shadow = Some(c);
+                            },
+                            Err(e) => {
+                                log.warn_e(e, "Error reloading shadow config, keeping the previous one", ea!());
+                            },
+                        }
+                    }
+                }
+                return Ok(());
             }
-            let dest = Arc::new(Mutex::new(dest));
-            for completer in dest_completers {
-                completer.complete(dest.clone()).await;
+        });
+        let res = tm.join().await.context("Error in critical task");
+        if !stats.is_empty() {
+            match stats.write() {
+                Ok(paths) => {
+                    log.info("Wrote usage stats", ea!(path = paths[0].to_string_lossy()));
+                },
+                Err(e) => {
+                    log.warn_e(e, "Error writing usage stats", ea!());
+                },
             }
         }
-
-        // Run
-        tm.join().await.context("Error in critical task")?;
+        res?;
         return Ok(());
     }
 
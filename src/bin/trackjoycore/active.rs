@@ -0,0 +1,85 @@
+use std::io;
+use evdev::{
+    EventStream,
+    InputEvent,
+};
+use futures::future::select_all;
+use loga::ResultContext;
+use taskmanager::TaskManager;
+use tokio::sync::watch;
+
+pub enum SourceEvent {
+    Event(InputEvent),
+    /// Mapping was turned on or off, ex: due to window focus changes.
+    Active(bool),
+}
+
+/// Wait for the next source event or a change in whether mapping is active.
+pub async fn next(source: &mut EventStream, active: &mut watch::Receiver<bool>) -> Result<SourceEvent, io::Error> {
+    tokio::select!{
+        ev = source.next_event() => {
+            return Ok(SourceEvent::Event(ev?));
+        },
+        changed = active.changed() => {
+            if changed.is_err() {
+                // Sender gone, nothing will change any more
+                return Ok(SourceEvent::Event(source.next_event().await?));
+            }
+            return Ok(SourceEvent::Active(*active.borrow_and_update()));
+        }
+    }
+}
+
+/// Grab the source while mapping is active and release it otherwise, so it works
+/// normally when not in use. Does nothing if grabbing is disabled.
+pub fn set_grab(source: &mut EventStream, grab: bool, active: bool) -> Result<(), loga::Error> {
+    if !grab {
+        return Ok(());
+    }
+    if active {
+        source.device_mut().grab().context("Failed to grab device")?;
+    } else {
+        source.device_mut().ungrab().context("Failed to release device grab")?;
+    }
+    return Ok(());
+}
+
+/// Mapping is active only while all of the inputs are, ex: the window is focused and
+/// the system isn't going to sleep.
+pub fn all(tm: &TaskManager, mut inputs: Vec<watch::Receiver<bool>>) -> watch::Receiver<bool> {
+    let (active, active_rx) = watch::channel(inputs.iter_mut().all(|i| *i.borrow_and_update()));
+    tm.critical_task::<_, loga::Error>({
+        let tm = tm.clone();
+        async move {
+            loop {
+                if inputs.is_empty() {
+                    // Nothing will change any more
+                    break;
+                }
+                let (changed, i, _) =
+                    match tm.if_alive(select_all(inputs.iter_mut().map(|i| Box::pin(i.changed())))).await {
+                        Some(x) => x,
+                        None => {
+                            break;
+                        },
+                    };
+                if changed.is_err() {
+                    // Sender gone, stays at its last value
+                    let input = inputs.remove(i);
+                    if !*input.borrow() {
+                        active.send_replace(false);
+                        break;
+                    }
+                }
+                let new = inputs.iter_mut().all(|i| *i.borrow_and_update());
+                active.send_if_modified(|a| {
+                    let changed = *a != new;
+                    *a = new;
+                    changed
+                });
+            }
+            return Ok(());
+        }
+    });
+    return active_rx;
+}
@@ -0,0 +1,75 @@
+use std::{
+    fs::{
+        read_dir,
+        read_to_string,
+    },
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::Duration,
+};
+use loga::ea;
+use taskmanager::TaskManager;
+
+/// Battery charge in percent for an input device, from the power supply the
+/// kernel attaches to the device's HID parent (Bluetooth and some wireless
+/// devices). `None` if there is no battery.
+fn read_capacity(dev_path: &Path) -> Option<u8> {
+    let event = dev_path.canonicalize().ok()?.file_name()?.to_os_string();
+    let supplies = read_dir(Path::new("/sys/class/input").join(event).join("device/device/power_supply")).ok()?;
+    for supply in supplies {
+        let Ok(supply) = supply else {
+            continue;
+        };
+        if let Ok(capacity) = read_to_string(supply.path().join("capacity")) {
+            if let Ok(capacity) = capacity.trim().parse() {
+                return Some(capacity);
+            }
+        }
+    }
+    return None;
+}
+
+/// Periodically log the battery level of devices that have one, with a warning
+/// when it's at or below `warn_percent`.
+pub fn build(tm: &TaskManager, log: &loga::Log, devices: Vec<PathBuf>, warn_percent: u8) {
+    let devices = devices.into_iter().filter(|d| read_capacity(d).is_some()).collect::<Vec<_>>();
+    if devices.is_empty() {
+        return;
+    }
+    tm.critical_task::<_, loga::Error>({
+        let tm = tm.clone();
+        let log = log.clone();
+        async move {
+            let mut last = vec![None; devices.len()];
+            loop {
+                for (i, device) in devices.iter().enumerate() {
+                    let capacity = read_capacity(device);
+                    if capacity == last[i] {
+                        continue;
+                    }
+                    last[i] = capacity;
+                    let Some(capacity) = capacity else {
+                        continue;
+                    };
+                    if capacity <= warn_percent {
+                        log.warn(
+                            "Device battery low",
+                            ea!(device = device.to_string_lossy(), percent = capacity),
+                        );
+                    } else {
+                        log.info("Device battery", ea!(device = device.to_string_lossy(), percent = capacity));
+                    }
+                }
+                match tm.if_alive(tokio::time::sleep(Duration::from_secs(60))).await {
+                    Some(_) => { },
+                    None => {
+                        break;
+                    },
+                }
+            }
+            return Ok(());
+        }
+    });
+}
@@ -0,0 +1,78 @@
+//! `trackjoy calibrate`: measures the position extents of a pad while the user
+//! traces its edges, and saves them as the pad's calibration.
+use std::path::Path;
+use evdev::{
+    AbsoluteAxisCode,
+    EventSummary,
+};
+use loga::{
+    ea,
+    DebugDisplay,
+    ResultContext,
+};
+use tokio::signal::unix::{
+    signal,
+    SignalKind,
+};
+use trackjoy::calibration;
+
+/// Widen the `[min, max]` extents to include the value.
+fn extend(extents: &mut Option<[i32; 2]>, v: i32) {
+    *extents = Some(match *extents {
+        Some([min, max]) => [min.min(v), max.max(v)],
+        None => [v, v],
+    });
+}
+
+/// Read the pad until interrupted, then save the extents seen. The rest of any
+/// saved calibration (center offset, rotation) is kept.
+pub async fn run(log: &loga::Log, path: &Path) -> Result<(), loga::Error> {
+    let source = evdev::Device::open(path).context_with("Error opening device", ea!(path = path.to_string_lossy()))?;
+    let id = source.input_id();
+    let key = calibration::device_key(id.vendor(), id.product(), source.unique_name());
+    let mut source = source.into_event_stream().context("Couldn't make input device async")?;
+    let mut interrupt = signal(SignalKind::interrupt()).context("Error setting up interrupt signal handler")?;
+    eprintln!("Slide a finger along all the edges of the pad, then press Ctrl-C to save");
+    let mut x = None;
+    let mut y = None;
+    loop {
+        let ev = tokio::select!{
+            ev = source.next_event() => ev.context("Error reading pad events")?,
+            _ = interrupt.recv() => {
+                break;
+            }
+        };
+        match ev.destructure() {
+            EventSummary::AbsoluteAxis(_, AbsoluteAxisCode::ABS_MT_POSITION_X | AbsoluteAxisCode::ABS_X, v) => {
+                extend(&mut x, v);
+            },
+            EventSummary::AbsoluteAxis(_, AbsoluteAxisCode::ABS_MT_POSITION_Y | AbsoluteAxisCode::ABS_Y, v) => {
+                extend(&mut y, v);
+            },
+            _ => { },
+        }
+    }
+    let (Some(x), Some(y)) = (x, y) else {
+        return Err(loga::err_with("No touches seen, nothing saved", ea!(device = key)));
+    };
+    if x[0] == x[1] || y[0] == y[1] {
+        return Err(
+            loga::err_with(
+                "Touches didn't cover the pad, nothing saved",
+                ea!(device = key, x = x.dbg_str(), y = y.dbg_str()),
+            ),
+        );
+    }
+    let mut saved = match calibration::load(&key) {
+        Ok(c) => c.unwrap_or_default(),
+        Err(e) => {
+            log.warn_e(e, "Error loading saved pad calibration, replacing it", ea!(device = key));
+            Default::default()
+        },
+    };
+    saved.x = Some(x);
+    saved.y = Some(y);
+    calibration::save(&key, &saved)?;
+    log.info("Saved pad calibration", ea!(device = key, x = x.dbg_str(), y = y.dbg_str()));
+    return Ok(());
+}
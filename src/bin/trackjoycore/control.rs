@@ -0,0 +1,427 @@
+//! Control socket for adjusting a running instance, and `trackjoy tune` which uses
+//! it. Requests and responses are one JSON object per line.
+use std::{
+    io::{
+        BufRead,
+        BufReader,
+        Write,
+    },
+    os::unix::net::UnixStream,
+    path::{
+        Path,
+        PathBuf,
+    },
+    sync::Arc,
+    time::Duration,
+};
+use loga::{
+    ea,
+    ResultContext,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use taskmanager::TaskManager;
+use tokio::{
+    io::{
+        AsyncBufReadExt,
+        AsyncWriteExt,
+    },
+    net::UnixListener,
+    sync::watch,
+};
+use trackjoy::{
+    mapper::Tuning,
+    Config,
+};
+use super::{
+    history::Histories,
+    stats::Stats,
+    trace::{
+        TraceTarget,
+        Tracer,
+    },
+};
+
+/// How long a trace lasts if the request doesn't say.
+const DEFAULT_TRACE_SECONDS: f32 = 10.;
+
+/// Longer traces are rejected.
+const MAX_TRACE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Stick shaping values as written in the config (top level).
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct TuningValues {
+    pub dead_inner: Option<f32>,
+    pub dead_outer: Option<f32>,
+    pub curve: Option<f32>,
+    pub y_smash: Option<f32>,
+}
+
+impl TuningValues {
+    fn from_config(config: &Config) -> TuningValues {
+        return TuningValues {
+            dead_inner: config.dead_inner,
+            dead_outer: config.dead_outer,
+            curve: config.curve,
+            y_smash: config.y_smash,
+        };
+    }
+
+    fn apply(&self, config: &mut Config) {
+        config.dead_inner = self.dead_inner;
+        config.dead_outer = self.dead_outer;
+        config.curve = self.curve;
+        config.y_smash = self.y_smash;
+    }
+
+    fn field(&mut self, name: &str) -> Option<&mut Option<f32>> {
+        match name {
+            "dead_inner" => return Some(&mut self.dead_inner),
+            "dead_outer" => return Some(&mut self.dead_outer),
+            "curve" => return Some(&mut self.curve),
+            "y_smash" => return Some(&mut self.y_smash),
+            _ => return None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Request {
+    GetTuning,
+    /// Use these values until the next config reload. Unset values use the defaults.
+    SetTuning(TuningValues),
+    /// The recent raw events from each source, like `SIGUSR1`.
+    GetEvents,
+    /// Write the usage statistics (see `stats` in the config) now.
+    WriteStats,
+    /// Log how an input is mapped for a while: a key name like `KEY_A`, or `touch`
+    /// for pad touches.
+    Trace {
+        input: String,
+        seconds: Option<f32>,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Response {
+    Tuning(TuningValues),
+    Events(String),
+    /// The files written
+    StatsWritten(Vec<String>),
+    /// Tracing started, for this many seconds
+    Tracing(f32),
+    Error(String),
+}
+
+/// A socket of the instance with this virtual gamepad name, `suffix` distinguishing
+/// sockets for different purposes.
+pub fn runtime_socket_path(name: &str, suffix: &str) -> PathBuf {
+    let name = name.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' {
+        c
+    } else {
+        '_'
+    }).collect::<String>();
+    return Path::new(&std::env::var_os("XDG_RUNTIME_DIR").unwrap_or("/tmp".into()))
+        .join(format!("trackjoy-{}{}.sock", name, suffix));
+}
+
+/// The control socket of the instance with this virtual gamepad name.
+pub fn socket_path(name: &str) -> PathBuf {
+    return runtime_socket_path(name, "");
+}
+
+fn parse_trace_target(input: &str) -> Option<TraceTarget> {
+    if input == "touch" {
+        return Some(TraceTarget::Touch);
+    }
+    return serde_json::from_value(serde_json::Value::String(input.to_string())).ok().map(TraceTarget::Key);
+}
+
+async fn handle(
+    log: &loga::Log,
+    conn: tokio::net::UnixStream,
+    config: &mut Config,
+    tuning: &watch::Sender<Tuning>,
+    histories: &Histories,
+    tracer: &Tracer,
+    stats: &Stats,
+) -> Result<(), loga::Error> {
+    let (read, mut write) = conn.into_split();
+    let mut lines = tokio::io::BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await.context("Error reading control request")? {
+        let resp = match serde_json::from_str::<Request>(&line) {
+            Err(e) => Response::Error(format!("Invalid request: {}", e)),
+            Ok(Request::GetTuning) => Response::Tuning(TuningValues::from_config(config)),
+            Ok(Request::GetEvents) => Response::Events(histories.dump()),
+            Ok(Request::WriteStats) => match stats.write() {
+                Ok(paths) => Response::StatsWritten(
+                    paths.into_iter().map(|p| p.to_string_lossy().to_string()).collect(),
+                ),
+                Err(e) => Response::Error(e.to_string()),
+            },
+            Ok(Request::Trace { input, seconds }) => {
+                let seconds = seconds.unwrap_or(DEFAULT_TRACE_SECONDS);
+                let duration = Duration::try_from_secs_f32(seconds).ok().filter(|d| !d.is_zero() && *d <= MAX_TRACE);
+                match (parse_trace_target(&input), duration) {
+                    (_, None) => Response::Error(
+                        format!(
+                            "Invalid trace duration {}, expected more than 0 and at most {} seconds",
+                            seconds,
+                            MAX_TRACE.as_secs()
+                        ),
+                    ),
+                    (Some(target), Some(duration)) => {
+                        tracer.start(log, target, duration);
+                        Response::Tracing(seconds)
+                    },
+                    (None, _) => Response::Error(
+                        format!("Unknown input `{}`, expected a key like KEY_A or `touch`", input),
+                    ),
+                }
+            },
+            Ok(Request::SetTuning(values)) => {
+                let mut new_config = config.clone();
+                values.apply(&mut new_config);
+                match Tuning::new(&new_config, None) {
+                    Ok(t) => {
+                        tuning.send_replace(t);
+                        *config = new_config;
+                        Response::Tuning(values)
+                    },
+                    Err(e) => Response::Error(e.to_string()),
+                }
+            },
+        };
+        let mut out = serde_json::to_vec(&resp).unwrap();
+        out.push(b'\n');
+        write.write_all(&out).await.context("Error sending control response")?;
+    }
+    return Ok(());
+}
+
+/// Listen for control connections. Tuning changes are applied to the top level
+/// values; a profile switch replaces them until the next change.
+pub fn build(
+    tm: &TaskManager,
+    log: &loga::Log,
+    name: &str,
+    config: &Config,
+    tuning: Arc<watch::Sender<Tuning>>,
+    histories: Histories,
+    tracer: Tracer,
+    stats: Stats,
+) -> Result<(), loga::Error> {
+    let path = socket_path(name);
+
+    // Left over if a previous instance didn't stop cleanly
+    _ = std::fs::remove_file(&path);
+    let listener =
+        UnixListener::bind(&path).context_with("Error creating control socket", ea!(path = path.to_string_lossy()))?;
+    let mut config = config.clone();
+    tm.critical_task::<_, loga::Error>({
+        let tm = tm.clone();
+        let log = log.clone();
+        async move {
+            loop {
+                let conn = match tm.if_alive(listener.accept()).await {
+                    Some(Ok((c, _))) => c,
+                    Some(Err(e)) => {
+                        log.warn_e(e.into(), "Error accepting control connection", ea!());
+                        continue;
+                    },
+                    None => {
+                        break;
+                    },
+                };
+
+                // One client at a time
+                match tm.if_alive(handle(&log, conn, &mut config, &tuning, &histories, &tracer, &stats)).await {
+                    Some(Err(e)) => {
+                        log.warn_e(e, "Error in control connection", ea!());
+                    },
+                    _ => { },
+                }
+            }
+            _ = std::fs::remove_file(&path);
+            return Ok(());
+        }
+    });
+    return Ok(());
+}
+
+struct Client {
+    read: BufReader<UnixStream>,
+    write: UnixStream,
+}
+
+impl Client {
+    fn request(&mut self, req: &Request) -> Result<Response, loga::Error> {
+        let mut out = serde_json::to_vec(req).unwrap();
+        out.push(b'\n');
+        self.write.write_all(&out).context("Error sending request to trackjoy")?;
+        let mut line = String::new();
+        if self.read.read_line(&mut line).context("Error reading response from trackjoy")? == 0 {
+            return Err(loga::err("trackjoy closed the connection (stopped or reloaded config)"));
+        }
+        return Ok(serde_json::from_str(&line).context("Invalid response from trackjoy")?);
+    }
+}
+
+const TUNE_HELP: &str =
+    "Commands:
+  show                    Show the current values
+  FIELD VALUE             Set dead_inner, dead_outer, curve or y_smash, ex: `curve 1.5`
+  FIELD default           Unset a value, using the default
+  trace INPUT [SECONDS]   Log how an input (ex: KEY_A, or touch) is mapped, default 10s
+  save                    Save the values to the config
+  quit                    Save if changed and exit
+  discard                 Exit without saving";
+
+fn show(values: &TuningValues) {
+    let fmt = |v: Option<f32>| v.map(|v| v.to_string()).unwrap_or("default".to_string());
+    println!("dead_inner {}", fmt(values.dead_inner));
+    println!("dead_outer {}", fmt(values.dead_outer));
+    println!("curve {}", fmt(values.curve));
+    println!("y_smash {}", fmt(values.y_smash));
+}
+
+/// Write the values to the top level of the config file, leaving everything else
+/// as is.
+fn save(config_path: &Path, values: &TuningValues) -> Result<(), loga::Error> {
+    let ctx = ea!(path = config_path.to_string_lossy());
+    let mut config =
+        serde_json::from_slice::<serde_json::Value>(
+            &std::fs::read(config_path).context_with("Error reading config", ctx.clone())?,
+        ).context_with("Error parsing config", ctx.clone())?;
+    let serde_json::Value::Object(o) = &mut config else {
+        return Err(loga::err_with("Config isn't a JSON object", ctx));
+    };
+    for (k, v) in [
+        ("dead_inner", values.dead_inner),
+        ("dead_outer", values.dead_outer),
+        ("curve", values.curve),
+        ("y_smash", values.y_smash),
+    ] {
+        match v {
+            Some(v) => {
+                o.insert(k.to_string(), serde_json::json!(v));
+            },
+            None => {
+                // Plain `remove` moves the last key into its place
+                o.shift_remove(k);
+            },
+        }
+    }
+    std::fs::write(config_path, serde_json::to_string_pretty(&config).unwrap() + "\n").context_with(
+        "Error writing config",
+        ctx,
+    )?;
+    return Ok(());
+}
+
+/// Interactive prompt adjusting the tuning of a running instance.
+pub fn tune(config_path: &Path, name: &str) -> Result<(), loga::Error> {
+    let path = socket_path(name);
+    let conn =
+        UnixStream::connect(
+            &path,
+        ).context_with("Error connecting to trackjoy, is it running?", ea!(path = path.to_string_lossy()))?;
+    let mut client = Client {
+        read: BufReader::new(conn.try_clone().context("Error setting up connection")?),
+        write: conn,
+    };
+    let mut values = match client.request(&Request::GetTuning)? {
+        Response::Tuning(v) => v,
+        Response::Error(e) => return Err(loga::err_with("Error getting tuning", ea!(err = e))),
+        Response::Events(_) | Response::StatsWritten(_) | Response::Tracing(_) => {
+            return Err(loga::err("Unexpected response from trackjoy"));
+        },
+    };
+    let mut saved = values;
+    show(&values);
+    println!("Type `help` for commands");
+    let mut stdin = std::io::stdin().lock();
+    loop {
+        print!("> ");
+        _ = std::io::stdout().flush();
+        let mut line = String::new();
+        if stdin.read_line(&mut line).context("Error reading input")? == 0 {
+            println!();
+            break;
+        }
+        let parts = line.split_whitespace().collect::<Vec<_>>();
+        match parts.as_slice() {
+            [] => { },
+            ["help"] => println!("{}", TUNE_HELP),
+            ["show"] => show(&values),
+            ["save"] => {
+                save(config_path, &values)?;
+                saved = values;
+                println!("Saved");
+            },
+            ["quit"] | ["exit"] => {
+                break;
+            },
+            ["discard"] => {
+                return Ok(());
+            },
+            ["trace", input] | ["trace", input, _] => {
+                let seconds = match parts.get(2) {
+                    Some(v) => match v.parse::<f32>() {
+                        Ok(v) => Some(v),
+                        Err(_) => {
+                            println!("Invalid number `{}`", v);
+                            continue;
+                        },
+                    },
+                    None => None,
+                };
+                match client.request(&Request::Trace {
+                    input: input.to_string(),
+                    seconds: seconds,
+                })? {
+                    Response::Tracing(s) => println!("Tracing {} for {}s, see the trackjoy log", input, s),
+                    Response::Error(e) => println!("Not started: {}", e),
+                    Response::Tuning(_) | Response::Events(_) | Response::StatsWritten(_) => {
+                        println!("Unexpected response from trackjoy");
+                    },
+                }
+            },
+            [field, value] => {
+                let mut new_values = values;
+                let Some(dest) = new_values.field(field) else {
+                    println!("Unknown field `{}`, type `help` for commands", field);
+                    continue;
+                };
+                if *value == "default" {
+                    *dest = None;
+                } else {
+                    match value.parse::<f32>() {
+                        Ok(v) => *dest = Some(v),
+                        Err(_) => {
+                            println!("Invalid number `{}`", value);
+                            continue;
+                        },
+                    }
+                }
+                match client.request(&Request::SetTuning(new_values))? {
+                    Response::Tuning(v) => values = v,
+                    Response::Error(e) => println!("Not applied: {}", e),
+                    Response::Events(_) | Response::StatsWritten(_) | Response::Tracing(_) => {
+                        println!("Unexpected response from trackjoy");
+                    },
+                }
+            },
+            _ => println!("Unknown command, type `help` for commands"),
+        }
+    }
+    if values != saved {
+        save(config_path, &values)?;
+        println!("Saved to {}", config_path.to_string_lossy());
+    }
+    return Ok(());
+}
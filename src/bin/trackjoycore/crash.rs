@@ -0,0 +1,58 @@
+//! Crash reports, written to `crashes` in the state directory. Panics write the
+//! message and backtrace; mappers that panic add their state and recent events.
+use std::{
+    io::Write as _,
+    path::PathBuf,
+    sync::Mutex,
+    time::SystemTime,
+};
+use loga::{
+    ea,
+    ResultContext,
+};
+use trackjoy::calibration::state_dir;
+
+/// The report written by the last panic, for adding details.
+static LAST_REPORT: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+fn write_report(text: &str) -> Result<PathBuf, loga::Error> {
+    let dir = state_dir()?.join("crashes");
+    std::fs::create_dir_all(
+        &dir,
+    ).context_with("Error creating crash report directory", ea!(path = dir.to_string_lossy()))?;
+    let time = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let path = dir.join(format!("{}-{}.txt", time, std::process::id()));
+    std::fs::write(&path, text).context_with("Error writing crash report", ea!(path = path.to_string_lossy()))?;
+    return Ok(path);
+}
+
+/// Write a report for each panic, in addition to the usual message.
+pub fn install_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        default_hook(info);
+        let text = format!("{}\n\nBacktrace:\n{}\n", info, std::backtrace::Backtrace::force_capture());
+        match write_report(&text) {
+            Ok(path) => {
+                eprintln!("Wrote crash report to {}", path.to_string_lossy());
+                *LAST_REPORT.lock().unwrap_or_else(|e| e.into_inner()) = Some(path);
+            },
+            Err(e) => {
+                eprintln!("Couldn't write crash report: {}", e);
+            },
+        }
+    }));
+}
+
+/// Add details about the state when it panicked to the last report.
+pub fn add_details(log: &loga::Log, source: &str, details: &str) {
+    let Some(path) = LAST_REPORT.lock().unwrap_or_else(|e| e.into_inner()).clone() else {
+        return;
+    };
+    let res = std::fs::OpenOptions::new().append(true).open(&path).and_then(|mut f| {
+        write!(f, "\n# {}\n\n{}", source, details)
+    });
+    if let Err(e) = res {
+        log.warn_e(e.into(), "Error adding details to crash report", ea!(path = path.to_string_lossy()));
+    }
+}
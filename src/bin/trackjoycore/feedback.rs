@@ -0,0 +1,130 @@
+//! Feedback for touches sliding into corner zones (see `zone_feedback`) and profile
+//! swipes.
+use std::{
+    fs::{
+        File,
+        OpenOptions,
+    },
+    os::fd::AsRawFd,
+};
+use evdev::{
+    Device,
+    FFEffect,
+    FFEffectCode,
+    FFEffectData,
+    FFEffectKind,
+    FFReplay,
+    FFTrigger,
+};
+use loga::{
+    ea,
+    ResultContext,
+};
+use trackjoy::ZoneFeedbackConfig;
+
+/// `KDMKTONE` from `linux/kd.h`
+const KDMKTONE: u64 = 0x4B30;
+
+/// The PC speaker timer frequency, the tone is set as a divisor of this.
+const PIT_HZ: u32 = 1193180;
+
+pub enum Feedback {
+    Command(Vec<String>),
+    Rumble(FFEffect),
+    Beep {
+        console: File,
+        ms: u16,
+        hz: u32,
+    },
+}
+
+impl Feedback {
+    /// Prepare the feedback; for rumble this uploads the effect to the pad.
+    pub fn new(source: &mut Device, config: ZoneFeedbackConfig) -> Result<Feedback, loga::Error> {
+        match config {
+            ZoneFeedbackConfig::Command(command) => {
+                if command.is_empty() {
+                    return Err(loga::err("Feedback command is empty"));
+                }
+                return Ok(Feedback::Command(command));
+            },
+            ZoneFeedbackConfig::Rumble { ms } => {
+                if !source.supported_ff().map(|f| f.contains(FFEffectCode::FF_RUMBLE)).unwrap_or(false) {
+                    return Err(loga::err("Pad doesn't support rumble"));
+                }
+                let effect = source.upload_ff_effect(FFEffectData {
+                    direction: 0,
+                    trigger: FFTrigger::default(),
+                    replay: FFReplay {
+                        length: ms.unwrap_or(30),
+                        delay: 0,
+                    },
+                    kind: FFEffectKind::Rumble {
+                        strong_magnitude: u16::MAX,
+                        weak_magnitude: u16::MAX,
+                    },
+                }).context("Error uploading rumble effect to pad")?;
+                return Ok(Feedback::Rumble(effect));
+            },
+            ZoneFeedbackConfig::Beep { ms, hz } => {
+                let console =
+                    OpenOptions::new()
+                        .write(true)
+                        .open("/dev/console")
+                        .context("Error opening /dev/console for beeping")?;
+                return Ok(Feedback::Beep {
+                    console: console,
+                    ms: ms.unwrap_or(30),
+                    hz: hz.unwrap_or(880).max(20),
+                });
+            },
+        }
+    }
+
+    /// Play once. This doesn't wait for the feedback to finish.
+    fn play(&mut self, log: &loga::Log, env: (&str, String)) {
+        match self {
+            Feedback::Command(command) => {
+                match tokio::process::Command::new(&command[0]).args(&command[1..]).env(env.0, env.1).spawn() {
+                    Ok(_) => { },
+                    Err(e) => {
+                        log.warn_e(e.into(), "Error running feedback command", ea!(command = command.join(" ")));
+                    },
+                }
+            },
+            Feedback::Rumble(effect) => {
+                if let Err(e) = effect.play(1) {
+                    log.warn_e(e.into(), "Error playing feedback rumble", ea!());
+                }
+            },
+            Feedback::Beep { console, ms, hz } => {
+                // Duration in ms in the high bits, divisor in the low
+                let arg = ((*ms as u64) << 16) | (PIT_HZ / *hz) as u64;
+                if unsafe {
+                    libc::ioctl(console.as_raw_fd(), KDMKTONE as _, arg)
+                } < 0 {
+                    log.warn_e(std::io::Error::last_os_error().into(), "Error beeping for feedback", ea!());
+                }
+            },
+        }
+    }
+
+    /// A touch entered the corner zone for button `zone`.
+    pub fn trigger(&mut self, log: &loga::Log, zone: usize) {
+        self.play(log, ("TRACKJOY_ZONE", zone.to_string()));
+    }
+
+    /// How many times to play `profile` for switching to the profile at `index` (0
+    /// for the top level settings).
+    pub fn profile_repeats(&self, index: usize) -> usize {
+        match self {
+            Feedback::Command(_) => return 1,
+            _ => return index + 1,
+        }
+    }
+
+    /// One repeat of the confirmation for switching to the profile at `index`.
+    pub fn profile(&mut self, log: &loga::Log, index: usize) {
+        self.play(log, ("TRACKJOY_PROFILE", index.to_string()));
+    }
+}
@@ -0,0 +1,109 @@
+use std::process::Stdio;
+use loga::{
+    ea,
+    ResultContext,
+};
+use taskmanager::TaskManager;
+use tokio::{
+    io::{
+        AsyncBufReadExt,
+        BufReader,
+    },
+    process::Command,
+    sync::watch,
+};
+
+/// Get the `WM_CLASS` (instance and class names) of an X11 window.
+async fn get_window_classes(window: &str) -> Result<Vec<String>, loga::Error> {
+    let out =
+        Command::new("xprop")
+            .arg("-id")
+            .arg(window)
+            .arg("WM_CLASS")
+            .output()
+            .await
+            .context("Error running xprop")?;
+    if !out.status.success() {
+        return Err(
+            loga::err_with("xprop exited with error", ea!(output = String::from_utf8_lossy(&out.stderr))),
+        );
+    }
+
+    // `WM_CLASS(STRING) = "instance", "Class"` - the names are the quoted parts
+    return Ok(
+        String::from_utf8_lossy(&out.stdout)
+            .split('"')
+            .skip(1)
+            .step_by(2)
+            .map(|s| s.to_string())
+            .collect(),
+    );
+}
+
+/// Watch the X11 focused window and mark mapping active only while the focused
+/// window has one of the `window_classes`.
+pub fn build(
+    tm: &TaskManager,
+    log: &loga::Log,
+    window_classes: Vec<String>,
+    active: watch::Sender<bool>,
+) -> Result<(), loga::Error> {
+    let mut spy =
+        Command::new("xprop")
+            .arg("-root")
+            .arg("-spy")
+            .arg("_NET_ACTIVE_WINDOW")
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .context("Error starting xprop to watch window focus")?;
+    let mut lines = BufReader::new(spy.stdout.take().unwrap()).lines();
+    tm.critical_task::<_, loga::Error>({
+        let tm = tm.clone();
+        let log = log.clone();
+        async move {
+            let _spy = spy;
+            loop {
+                let line = match tm.if_alive(lines.next_line()).await {
+                    Some(x) => x,
+                    None => {
+                        break;
+                    },
+                }.context("Error reading xprop output")?;
+                let line = match line {
+                    Some(l) => l,
+                    None => {
+                        return Err(loga::err("xprop focus watcher exited unexpectedly"));
+                    },
+                };
+
+                // `_NET_ACTIVE_WINDOW(WINDOW): window id # 0x3a00007`, `0x0` when nothing is
+                // focused
+                let window = match line.rsplit_once("# ") {
+                    Some((_, w)) => w.trim().to_string(),
+                    None => {
+                        continue;
+                    },
+                };
+                let matched = if window == "0x0" {
+                    false
+                } else {
+                    match get_window_classes(&window).await {
+                        Ok(classes) => classes.iter().any(|c| window_classes.contains(c)),
+                        Err(e) => {
+                            log.warn_e(e, "Error getting focused window class", ea!(window = window));
+                            false
+                        },
+                    }
+                };
+                active.send_if_modified(|a| {
+                    let changed = *a != matched;
+                    *a = matched;
+                    changed
+                });
+            }
+            return Ok(());
+        }
+    });
+    return Ok(());
+}
@@ -0,0 +1,85 @@
+use std::{
+    fs::{
+        read_dir,
+        read_link,
+        read_to_string,
+        canonicalize,
+    },
+    io::ErrorKind,
+    path::Path,
+    time::Duration,
+};
+use evdev::Device;
+use loga::{
+    ea,
+    ResultContext,
+};
+
+/// Find processes with the device open, as `(pid, command name)`. This can't tell
+/// which one holds the grab, but the culprit is usually obvious from the names.
+fn find_holders(path: &Path) -> Vec<(String, String)> {
+    let mut out = vec![];
+    let Ok(path) = canonicalize(path) else {
+        return out;
+    };
+    let Ok(procs) = read_dir("/proc") else {
+        return out;
+    };
+    for proc in procs {
+        let Ok(proc) = proc else {
+            continue;
+        };
+        let pid = proc.file_name().to_string_lossy().to_string();
+        if !pid.chars().all(|c| c.is_ascii_digit()) || pid == std::process::id().to_string() {
+            continue;
+        }
+
+        // Fails for processes of other users without privileges, skip those
+        let Ok(fds) = read_dir(proc.path().join("fd")) else {
+            continue;
+        };
+        for fd in fds {
+            let Ok(fd) = fd else {
+                continue;
+            };
+            if read_link(fd.path()).ok().as_deref() != Some(path.as_path()) {
+                continue;
+            }
+            let comm = read_to_string(proc.path().join("comm")).unwrap_or_default().trim().to_string();
+            out.push((pid, comm));
+            break;
+        }
+    }
+    return out;
+}
+
+/// Grab the device, retrying with backoff up to `retries` times if something else
+/// holds it.
+pub async fn grab(log: &loga::Log, source: &mut Device, path: &Path, retries: usize) -> Result<(), loga::Error> {
+    let mut delay = Duration::from_millis(500);
+    let mut attempt = 0usize;
+    loop {
+        let e = match source.grab() {
+            Ok(_) => return Ok(()),
+            Err(e) => e,
+        };
+        if e.kind() != ErrorKind::ResourceBusy {
+            return Err(e).log_context(log, "Failed to grab device");
+        }
+
+        // Only processes visible to this user can be listed
+        let holders =
+            find_holders(path)
+                .into_iter()
+                .map(|(pid, comm)| format!("{} ({})", comm, pid))
+                .collect::<Vec<_>>()
+                .join(", ");
+        if attempt >= retries {
+            return Err(e).log_context_with(log, "Failed to grab device, another process has it grabbed", ea!(open_by = holders));
+        }
+        log.warn("Device is grabbed by another process, retrying", ea!(open_by = holders, delay = delay.as_millis()));
+        tokio::time::sleep(delay).await;
+        delay = (delay * 2).min(Duration::from_secs(10));
+        attempt += 1;
+    }
+}
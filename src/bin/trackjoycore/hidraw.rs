@@ -0,0 +1,1101 @@
+//! Fallback for pads whose kernel driver doesn't report multitouch properly (some
+//! cheap USB touchpads only get a mouse): reads HID touch reports from
+//! `/dev/hidrawN` directly and replays them on a virtual multitouch pad, which is
+//! then mapped like any other pad.
+use std::{
+    collections::HashMap,
+    io::Read,
+    os::unix::fs::OpenOptionsExt,
+    path::{
+        Path,
+        PathBuf,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use evdev::{
+    uinput::{
+        VirtualDevice,
+        VirtualDeviceBuilder,
+    },
+    AbsInfo,
+    AbsoluteAxisCode,
+    AttributeSet,
+    BusType,
+    Device,
+    EventType,
+    InputEvent,
+    InputId,
+    KeyCode,
+    PropType,
+    UinputAbsSetup,
+};
+use loga::{
+    ea,
+    ResultContext,
+};
+use taskmanager::TaskManager;
+use tokio::io::unix::AsyncFd;
+
+/// Slots on the virtual pad, more contacts than this are dropped.
+const MAX_CONTACTS: usize = 10;
+const TRACKING_ID_MAX: i32 = 65535;
+
+/// How long to wait for the virtual pad's node to appear and become readable.
+const START_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest report read at once.
+const REPORT_MAX: usize = 4096;
+
+// Usages, page in the upper 16 bits
+const USAGE_X: u32 = 0x0001_0030;
+const USAGE_Y: u32 = 0x0001_0031;
+const USAGE_BUTTON_1: u32 = 0x0009_0001;
+const USAGE_FINGER: u32 = 0x000d_0022;
+const USAGE_TIP_SWITCH: u32 = 0x000d_0042;
+const USAGE_CONFIDENCE: u32 = 0x000d_0047;
+const USAGE_CONTACT_ID: u32 = 0x000d_0051;
+const USAGE_CONTACT_COUNT: u32 = 0x000d_0054;
+
+// Units
+const UNIT_CENTIMETER: u32 = 0x11;
+const UNIT_INCH: u32 = 0x13;
+
+#[derive(Clone, Copy, Default)]
+struct Globals {
+    usage_page: u32,
+    logical_min: i32,
+    /// Raw, its sign depends on the minimum
+    logical_max: (u32, usize),
+    physical_min: i32,
+    physical_max: (u32, usize),
+    unit_exponent: i32,
+    unit: u32,
+    report_size: usize,
+    report_count: usize,
+    report_id: u8,
+}
+
+impl Globals {
+    /// Maximums are only negative if the minimum is too, otherwise descriptors with
+    /// ex: 1 byte 0xFF mean 255.
+    fn max(&self, raw: (u32, usize), min: i32) -> i32 {
+        if min < 0 {
+            return sign_extend(raw.0, raw.1 * 8);
+        } else {
+            return raw.0 as i32;
+        }
+    }
+
+    fn logical(&self) -> (i32, i32) {
+        return (self.logical_min, self.max(self.logical_max, self.logical_min));
+    }
+
+    /// Units per mm, or 0 if the descriptor doesn't say.
+    fn resolution(&self) -> i32 {
+        let (min, max) = self.logical();
+        let (pmin, pmax) = match (self.physical_min, self.max(self.physical_max, self.physical_min)) {
+            (0, 0) => (min, max),
+            p => p,
+        };
+        let mm = match self.unit {
+            UNIT_CENTIMETER => 10.,
+            UNIT_INCH => 25.4,
+            _ => return 0,
+        };
+        let size = (pmax - pmin) as f64 * 10f64.powi(self.unit_exponent) * mm;
+        if size <= 0. {
+            return 0;
+        }
+        return ((max - min) as f64 / size).round() as i32;
+    }
+}
+
+fn sign_extend(value: u32, bits: usize) -> i32 {
+    if bits == 0 || bits >= 32 {
+        return value as i32;
+    }
+    let shift = 32 - bits as u32;
+    return ((value << shift) as i32) >> shift;
+}
+
+#[derive(Clone, Copy)]
+struct Field {
+    bit: usize,
+    size: usize,
+    signed: bool,
+}
+
+impl Field {
+    fn read(&self, report: &[u8]) -> Option<i32> {
+        if self.size == 0 || self.size > 32 || self.bit + self.size > report.len() * 8 {
+            return None;
+        }
+        let mut value = 0u32;
+        for i in 0 .. self.size {
+            let bit = self.bit + i;
+            if (report[bit / 8] >> (bit % 8)) & 1 != 0 {
+                value |= 1 << i;
+            }
+        }
+        if self.signed {
+            return Some(sign_extend(value, self.size));
+        } else {
+            return Some(value as i32);
+        }
+    }
+}
+
+#[derive(Default)]
+struct Finger {
+    /// The finger collection in the descriptor
+    collection: usize,
+    tip: Option<Field>,
+    confidence: Option<Field>,
+    id: Option<Field>,
+    x: Option<Field>,
+    y: Option<Field>,
+}
+
+#[derive(Default)]
+struct TouchReport {
+    id: u8,
+    fingers: Vec<Finger>,
+    contact_count: Option<Field>,
+    button: Option<Field>,
+}
+
+#[derive(Clone, Copy)]
+struct Axis {
+    min: i32,
+    max: i32,
+    resolution: i32,
+}
+
+struct Layout {
+    report_ids: bool,
+    reports: Vec<TouchReport>,
+    x: Axis,
+    y: Axis,
+}
+
+/// Find the touch contacts in the input reports. Only what's needed for pads is
+/// understood, everything else is skipped over.
+fn parse_descriptor(desc: &[u8]) -> Result<Layout, loga::Error> {
+    let mut globals = Globals::default();
+    let mut global_stack = vec![];
+    let mut usages: Vec<u32> = vec![];
+    let mut usage_min = None;
+    let mut usage_max = None;
+
+    // Finger collection of each open collection, if it's in one
+    let mut collections: Vec<Option<usize>> = vec![];
+    let mut finger_collections = 0usize;
+
+    // Bit position in each input report
+    let mut offsets: HashMap<u8, usize> = HashMap::new();
+    let mut reports: Vec<TouchReport> = vec![];
+    let mut report_ids = false;
+    let mut x = None;
+    let mut y = None;
+    let mut i = 0;
+    while i < desc.len() {
+        let prefix = desc[i];
+        if prefix == 0xfe {
+            // Long item, nothing standard uses them
+            let size = desc.get(i + 1).copied().unwrap_or(0) as usize;
+            i += 3 + size;
+            continue;
+        }
+        let size = match prefix & 3 {
+            3 => 4,
+            s => s as usize,
+        };
+        let Some(data) = desc.get(i + 1 .. i + 1 + size) else {
+            return Err(loga::err_with("HID report descriptor is truncated", ea!(offset = i)));
+        };
+        i += 1 + size;
+        let mut udata = 0u32;
+        for (j, b) in data.iter().enumerate() {
+            udata |= (*b as u32) << (8 * j);
+        }
+        let sdata = sign_extend(udata, size * 8);
+        let usage = if size == 4 {
+            udata
+        } else {
+            globals.usage_page << 16 | udata
+        };
+        match ((prefix >> 2) & 3, prefix >> 4) {
+            // Main: input
+            (0, 8) => {
+                let constant = udata & 1 != 0;
+                let variable = udata & 2 != 0;
+                let offset = offsets.entry(globals.report_id).or_insert(0);
+                if !constant && variable {
+                    let (logical_min, _) = globals.logical();
+                    for k in 0 .. globals.report_count {
+                        let usage = match (usages.get(k).or(usages.last()), usage_min, usage_max) {
+                            (Some(u), _, _) => *u,
+                            (None, Some(min), Some(max)) => (min + k as u32).min(max),
+                            _ => continue,
+                        };
+                        let field = Field {
+                            bit: *offset + k * globals.report_size,
+                            size: globals.report_size,
+                            signed: logical_min < 0,
+                        };
+                        let report = match reports.iter().position(|r| r.id == globals.report_id) {
+                            Some(r) => &mut reports[r],
+                            None => {
+                                reports.push(TouchReport {
+                                    id: globals.report_id,
+                                    ..Default::default()
+                                });
+                                reports.last_mut().unwrap()
+                            },
+                        };
+                        match usage {
+                            USAGE_CONTACT_COUNT => {
+                                report.contact_count = Some(field);
+                                continue;
+                            },
+                            USAGE_BUTTON_1 => {
+                                report.button = Some(field);
+                                continue;
+                            },
+                            _ => { },
+                        }
+
+                        // Contact fields, outside of finger collections it's a mouse report
+                        let Some(collection) = collections.iter().rev().find_map(|c| *c) else {
+                            continue;
+                        };
+                        let finger = match report.fingers.iter().position(|f| f.collection == collection) {
+                            Some(f) => &mut report.fingers[f],
+                            None => {
+                                report.fingers.push(Finger {
+                                    collection: collection,
+                                    ..Default::default()
+                                });
+                                report.fingers.last_mut().unwrap()
+                            },
+                        };
+                        let axis = || {
+                            let (min, max) = globals.logical();
+                            return Axis {
+                                min: min,
+                                max: max,
+                                resolution: globals.resolution(),
+                            };
+                        };
+                        match usage {
+                            USAGE_TIP_SWITCH => finger.tip = Some(field),
+                            USAGE_CONFIDENCE => finger.confidence = Some(field),
+                            USAGE_CONTACT_ID => finger.id = Some(field),
+                            USAGE_X => {
+                                finger.x = Some(field);
+                                x.get_or_insert_with(axis);
+                            },
+                            USAGE_Y => {
+                                finger.y = Some(field);
+                                y.get_or_insert_with(axis);
+                            },
+                            _ => { },
+                        }
+                    }
+                }
+                *offset += globals.report_size * globals.report_count;
+            },
+            // Main: collection
+            (0, 10) => {
+                let usage = usages.first().copied().or(usage_min);
+                if usage == Some(USAGE_FINGER) {
+                    collections.push(Some(finger_collections));
+                    finger_collections += 1;
+                } else {
+                    collections.push(None);
+                }
+            },
+            // Main: end collection
+            (0, 12) => {
+                collections.pop();
+            },
+            // Global
+            (1, 0) => globals.usage_page = udata,
+            (1, 1) => globals.logical_min = sdata,
+            (1, 2) => globals.logical_max = (udata, size),
+            (1, 3) => globals.physical_min = sdata,
+            (1, 4) => globals.physical_max = (udata, size),
+            (1, 5) => {
+                // Usually a 4 bit signed value
+                globals.unit_exponent = if udata <= 15 {
+                    sign_extend(udata, 4)
+                } else {
+                    sdata
+                };
+            },
+            (1, 6) => globals.unit = udata,
+            (1, 7) => globals.report_size = udata as usize,
+            (1, 8) => {
+                globals.report_id = udata as u8;
+                report_ids = true;
+            },
+            (1, 9) => globals.report_count = udata as usize,
+            (1, 10) => global_stack.push(globals),
+            (1, 11) => {
+                globals = global_stack.pop().unwrap_or(globals);
+            },
+            // Local
+            (2, 0) => usages.push(usage),
+            (2, 1) => usage_min = Some(usage),
+            (2, 2) => usage_max = Some(usage),
+            _ => { },
+        }
+
+        // Locals only apply to the next main item
+        if (prefix >> 2) & 3 == 0 {
+            usages.clear();
+            usage_min = None;
+            usage_max = None;
+        }
+    }
+    reports.retain_mut(|r| {
+        r.fingers.retain(|f| f.x.is_some() && f.y.is_some());
+        return !r.fingers.is_empty();
+    });
+    let (Some(x), Some(y)) = (x, y) else {
+        return Err(loga::err("HID report descriptor has no touch contacts"));
+    };
+    if reports.is_empty() {
+        return Err(loga::err("HID report descriptor has no input reports with touch contacts"));
+    }
+    return Ok(Layout {
+        report_ids: report_ids,
+        reports: reports,
+        x: x,
+        y: y,
+    });
+}
+
+struct Contact {
+    id: i32,
+    x: i32,
+    y: i32,
+}
+
+/// Turns touch reports into multitouch events.
+struct Bridge {
+    layout: Layout,
+    /// Contacts so far in the current frame, which may span several reports
+    pending: Vec<Contact>,
+    expected: usize,
+    received: usize,
+    /// Contact id and tracking id of each slot
+    slots: [Option<(i32, i32)>; MAX_CONTACTS],
+    next_tracking: i32,
+    button: bool,
+}
+
+fn abs(code: AbsoluteAxisCode, value: i32) -> InputEvent {
+    return InputEvent::new(EventType::ABSOLUTE.0, code.0, value);
+}
+
+fn key(code: KeyCode, on: bool) -> InputEvent {
+    return InputEvent::new(EventType::KEY.0, code.0, on as i32);
+}
+
+impl Bridge {
+    fn new(layout: Layout) -> Self {
+        return Self {
+            layout: layout,
+            pending: vec![],
+            expected: 0,
+            received: 0,
+            slots: [None; MAX_CONTACTS],
+            next_tracking: 0,
+            button: false,
+        };
+    }
+
+    /// Events to send for the report, empty until a frame is complete.
+    fn handle(&mut self, data: &[u8]) -> Vec<InputEvent> {
+        let (id, body) = match (self.layout.report_ids, data.split_first()) {
+            (true, Some((id, body))) => (*id, body),
+            (true, None) => return vec![],
+            (false, _) => (0, data),
+        };
+        let Some(report) = self.layout.reports.iter().find(|r| r.id == id) else {
+            return vec![];
+        };
+
+        // A nonzero count starts a frame, zero continues one (hybrid reporting)
+        match report.contact_count.and_then(|f| f.read(body)) {
+            Some(count) if count > 0 => {
+                self.pending.clear();
+                self.expected = count as usize;
+                self.received = 0;
+            },
+            Some(_) => { },
+            None => {
+                self.pending.clear();
+                self.expected = report.fingers.len();
+                self.received = 0;
+            },
+        }
+        for (i, finger) in report.fingers.iter().enumerate() {
+            if self.received >= self.expected {
+                break;
+            }
+            self.received += 1;
+            if finger.tip.and_then(|f| f.read(body)).unwrap_or(1) == 0 {
+                continue;
+            }
+            if finger.confidence.and_then(|f| f.read(body)).unwrap_or(1) == 0 {
+                // Palm
+                continue;
+            }
+            let (Some(x), Some(y)) =
+                (finger.x.and_then(|f| f.read(body)), finger.y.and_then(|f| f.read(body))) else {
+                    continue;
+                };
+            self.pending.push(Contact {
+                id: finger.id.and_then(|f| f.read(body)).unwrap_or(i as i32),
+                x: x,
+                y: y,
+            });
+        }
+        if let Some(button) = report.button.and_then(|f| f.read(body)) {
+            self.button = button != 0;
+        }
+        if self.received < self.expected {
+            return vec![];
+        }
+        return self.frame();
+    }
+
+    fn frame(&mut self) -> Vec<InputEvent> {
+        let contacts = std::mem::take(&mut self.pending);
+        let mut events = vec![];
+
+        // Lifted
+        for (slot, state) in self.slots.iter_mut().enumerate() {
+            let Some((id, _)) = *state else {
+                continue;
+            };
+            if contacts.iter().any(|c| c.id == id) {
+                continue;
+            }
+            events.push(abs(AbsoluteAxisCode::ABS_MT_SLOT, slot as i32));
+            events.push(abs(AbsoluteAxisCode::ABS_MT_TRACKING_ID, -1));
+            *state = None;
+        }
+
+        // Down and moved
+        let mut first = true;
+        for contact in &contacts {
+            let slot = match self.slots.iter().position(|s| matches!(s, Some((id, _)) if *id == contact.id)) {
+                Some(s) => s,
+                None => {
+                    let Some(s) = self.slots.iter().position(|s| s.is_none()) else {
+                        continue;
+                    };
+                    self.next_tracking = (self.next_tracking + 1) % TRACKING_ID_MAX;
+                    self.slots[s] = Some((contact.id, self.next_tracking));
+                    events.push(abs(AbsoluteAxisCode::ABS_MT_SLOT, s as i32));
+                    events.push(abs(AbsoluteAxisCode::ABS_MT_TRACKING_ID, self.next_tracking));
+                    s
+                },
+            };
+            events.push(abs(AbsoluteAxisCode::ABS_MT_SLOT, slot as i32));
+            events.push(abs(AbsoluteAxisCode::ABS_MT_POSITION_X, contact.x));
+            events.push(abs(AbsoluteAxisCode::ABS_MT_POSITION_Y, contact.y));
+            if first {
+                events.push(abs(AbsoluteAxisCode::ABS_X, contact.x));
+                events.push(abs(AbsoluteAxisCode::ABS_Y, contact.y));
+                first = false;
+            }
+        }
+
+        // Single touch state
+        let count = self.slots.iter().filter(|s| s.is_some()).count();
+        events.push(key(KeyCode::BTN_TOUCH, count > 0));
+        events.push(key(KeyCode::BTN_TOOL_FINGER, count == 1));
+        events.push(key(KeyCode::BTN_TOOL_DOUBLETAP, count == 2));
+        events.push(key(KeyCode::BTN_TOOL_TRIPLETAP, count == 3));
+        events.push(key(KeyCode::BTN_TOOL_QUADTAP, count == 4));
+        events.push(key(KeyCode::BTN_TOOL_QUINTTAP, count >= 5));
+        events.push(key(KeyCode::BTN_LEFT, self.button));
+        return events;
+    }
+}
+
+/// Name and id from the HID device's uevent.
+fn hid_identity(sys: &Path) -> (String, InputId) {
+    let mut name = "hidraw pad".to_string();
+    let mut id = InputId::new(BusType::BUS_USB, 0, 0, 0);
+    let Ok(uevent) = std::fs::read_to_string(sys.join("uevent")) else {
+        return (name, id);
+    };
+    for line in uevent.lines() {
+        if let Some(n) = line.strip_prefix("HID_NAME=") {
+            name = n.to_string();
+        } else if let Some(i) = line.strip_prefix("HID_ID=") {
+            // ex: 0003:0000046D:0000C52B
+            let parts = i.split(':').filter_map(|p| u32::from_str_radix(p, 16).ok()).collect::<Vec<_>>();
+            if let [bus, vendor, product] = parts[..] {
+                id = InputId::new(BusType(bus as u16), vendor as u16, product as u16, 0);
+            }
+        }
+    }
+    return (name, id);
+}
+
+fn virtual_pad(name: &str, id: InputId, layout: &Layout) -> Result<VirtualDevice, loga::Error> {
+    let mut keys = AttributeSet::<KeyCode>::new();
+    for k in [
+        KeyCode::BTN_LEFT,
+        KeyCode::BTN_TOUCH,
+        KeyCode::BTN_TOOL_FINGER,
+        KeyCode::BTN_TOOL_DOUBLETAP,
+        KeyCode::BTN_TOOL_TRIPLETAP,
+        KeyCode::BTN_TOOL_QUADTAP,
+        KeyCode::BTN_TOOL_QUINTTAP,
+    ] {
+        keys.insert(k);
+    }
+    let mut props = AttributeSet::<PropType>::new();
+    props.insert(PropType::POINTER);
+    let mut pad =
+        VirtualDeviceBuilder::new()
+            .context("Error creating hidraw pad builder")?
+            .name(&format!("{} (trackjoy hidraw)", name))
+            .input_id(id);
+    for (axis, info) in [
+        (AbsoluteAxisCode::ABS_X, layout.x),
+        (AbsoluteAxisCode::ABS_Y, layout.y),
+        (AbsoluteAxisCode::ABS_MT_POSITION_X, layout.x),
+        (AbsoluteAxisCode::ABS_MT_POSITION_Y, layout.y),
+        (AbsoluteAxisCode::ABS_MT_SLOT, Axis {
+            min: 0,
+            max: MAX_CONTACTS as i32 - 1,
+            resolution: 0,
+        }),
+        (AbsoluteAxisCode::ABS_MT_TRACKING_ID, Axis {
+            min: 0,
+            max: TRACKING_ID_MAX,
+            resolution: 0,
+        }),
+    ] {
+        pad =
+            pad
+                .with_absolute_axis(
+                    &UinputAbsSetup::new(axis, AbsInfo::new(0, info.min, info.max, 0, 0, info.resolution)),
+                )
+                .context("Error adding axis to hidraw pad")?;
+    }
+    return Ok(
+        pad
+            .with_keys(&keys)
+            .context("Error adding keys to hidraw pad")?
+            .with_properties(&props)
+            .context("Error adding properties to hidraw pad")?
+            .build()
+            .context("Error creating hidraw pad, is uinput accessible?")?,
+    );
+}
+
+/// Open the virtual pad's node once udev has set it up.
+async fn open_node(pad: &mut VirtualDevice) -> Result<(PathBuf, Device), loga::Error> {
+    let start = Instant::now();
+    loop {
+        let mut last_error = None;
+        for path in pad.enumerate_dev_nodes_blocking().context("Error listing hidraw pad dev nodes")? {
+            let path = path.context("Error getting hidraw pad node path")?;
+            match Device::open(&path) {
+                Ok(d) => return Ok((path, d)),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        if start.elapsed() > START_TIMEOUT {
+            match last_error {
+                Some(e) => return Err(e).context("Error opening hidraw pad node"),
+                None => return Err(loga::err("hidraw pad node didn't appear")),
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+/// Grab the kernel's own input nodes for the HID device (usually a mouse), which
+/// would otherwise keep moving the pointer alongside the relayed touches.
+fn grab_kernel_nodes(log: &loga::Log, sys: &Path) -> Vec<Device> {
+    let mut grabbed = vec![];
+    let Ok(inputs) = std::fs::read_dir(sys.join("input")) else {
+        return grabbed;
+    };
+    for input in inputs.flatten() {
+        let Ok(nodes) = std::fs::read_dir(input.path()) else {
+            continue;
+        };
+        for node in nodes.flatten() {
+            let name = node.file_name();
+            if !name.to_string_lossy().starts_with("event") {
+                continue;
+            }
+            let path = Path::new("/dev/input").join(&name);
+            let mut device = match Device::open(&path) {
+                Ok(d) => d,
+                Err(e) => {
+                    log.warn_e(
+                        e.into(),
+                        "Error opening kernel node of hidraw device, it won't be grabbed",
+                        ea!(path = path.to_string_lossy()),
+                    );
+                    continue;
+                },
+            };
+            match device.grab() {
+                Ok(_) => {
+                    log.info("Grabbed kernel node of hidraw device", ea!(path = path.to_string_lossy()));
+                    grabbed.push(device);
+                },
+                Err(e) => {
+                    log.warn_e(
+                        e.into(),
+                        "Error grabbing kernel node of hidraw device",
+                        ea!(path = path.to_string_lossy()),
+                    );
+                },
+            }
+        }
+    }
+    return grabbed;
+}
+
+/// Start relaying touch reports from the hidraw node to a virtual pad, returning
+/// the opened virtual pad to use as the source. If `grab` is set the kernel's
+/// nodes for the same device are grabbed while relaying. The relay stops with the
+/// task manager.
+pub async fn build(tm: &TaskManager, log: &loga::Log, path: &Path, grab: bool) -> Result<Device, loga::Error> {
+    let path =
+        std::fs::canonicalize(path).context_with("Error finding hidraw node", ea!(path = path.to_string_lossy()))?;
+    let Some(node) = path.file_name() else {
+        return Err(loga::err_with("Not a hidraw node", ea!(path = path.to_string_lossy())));
+    };
+    let sys = Path::new("/sys/class/hidraw").join(node).join("device");
+    let desc =
+        std::fs::read(
+            sys.join("report_descriptor"),
+        ).context_with("Error reading HID report descriptor", ea!(path = sys.to_string_lossy()))?;
+    let layout = parse_descriptor(&desc).context_with("Couldn't use HID device as a pad", ea!(
+        // Usually in mouse mode until switched by `hid-multitouch`
+        hint = "Only devices sending touch reports work, ones that only send mouse reports need a kernel driver"
+    ))?;
+    log.info(
+        "hidraw pad layout",
+        ea!(
+            reports = layout.reports.len(),
+            contacts = layout.reports.iter().map(|r| r.fingers.len()).max().unwrap_or(0),
+            x = format!("{}..{} res {}", layout.x.min, layout.x.max, layout.x.resolution),
+            y = format!("{}..{} res {}", layout.y.min, layout.y.max, layout.y.resolution)
+        ),
+    );
+    let (name, id) = hid_identity(&sys);
+    let mut pad = virtual_pad(&name, id, &layout)?;
+    let (pad_path, source) = open_node(&mut pad).await?;
+    log.info("Relaying hidraw touches through virtual pad", ea!(node = pad_path.to_string_lossy()));
+    let hidraw =
+        std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(&path)
+            .context_with("Error opening hidraw node", ea!(path = path.to_string_lossy()))?;
+    let hidraw = AsyncFd::new(hidraw).context("Couldn't make hidraw node async")?;
+    let kernel_nodes = if grab {
+        grab_kernel_nodes(log, &sys)
+    } else {
+        vec![]
+    };
+    tm.critical_task::<_, loga::Error>({
+        let tm = tm.clone();
+        let log = log.clone();
+        async move {
+            // Held until the relay stops
+            let _kernel_nodes = kernel_nodes;
+            let mut bridge = Bridge::new(layout);
+            let mut buf = [0u8; REPORT_MAX];
+            loop {
+                let mut ready = match tm.if_alive(hidraw.readable()).await {
+                    Some(r) => r.context("Error waiting for hidraw report")?,
+                    None => {
+                        break;
+                    },
+                };
+                let len = match ready.try_io(|f| {
+                    let mut file = f.get_ref();
+                    return file.read(&mut buf);
+                }) {
+                    Ok(r) => r.context("Error reading hidraw report")?,
+                    Err(_) => {
+                        continue;
+                    },
+                };
+                if len == 0 {
+                    return Err(loga::err("hidraw device disconnected"));
+                }
+                let events = bridge.handle(&buf[..len]);
+                if events.is_empty() {
+                    continue;
+                }
+                match pad.emit(&events) {
+                    Ok(_) => { },
+                    Err(e) => {
+                        log.warn_e(e.into(), "Error relaying hidraw touches", ea!());
+                    },
+                }
+            }
+            return Ok(());
+        }
+    });
+    return Ok(source);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Contact collection from the Windows Precision Touchpad sample descriptor
+    const FINGER: &[u8] = &[
+        0x05, 0x0d, // Usage page (digitizers)
+        0x09, 0x22, // Usage (finger)
+        0xa1, 0x02, // Collection (logical)
+        0x15, 0x00, // Logical min (0)
+        0x25, 0x01, // Logical max (1)
+        0x09, 0x47, // Usage (confidence)
+        0x09, 0x42, // Usage (tip switch)
+        0x95, 0x02, // Report count (2)
+        0x75, 0x01, // Report size (1)
+        0x81, 0x02, // Input (data, var, abs)
+        0x95, 0x01, // Report count (1)
+        0x75, 0x02, // Report size (2)
+        0x25, 0x02, // Logical max (2)
+        0x09, 0x51, // Usage (contact id)
+        0x81, 0x02, // Input (data, var, abs)
+        0x75, 0x01, // Report size (1)
+        0x95, 0x04, // Report count (4)
+        0x81, 0x03, // Input (const)
+        0x05, 0x01, // Usage page (generic desktop)
+        0x15, 0x00, // Logical min (0)
+        0x26, 0xff, 0x0f, // Logical max (4095)
+        0x75, 0x10, // Report size (16)
+        0x55, 0x0e, // Unit exponent (-2)
+        0x65, 0x11, // Unit (cm)
+        0x09, 0x30, // Usage (x)
+        0x35, 0x00, // Physical min (0)
+        0x46, 0xb5, 0x04, // Physical max (1205)
+        0x95, 0x01, // Report count (1)
+        0x81, 0x02, // Input (data, var, abs)
+        0x46, 0x8a, 0x03, // Physical max (906)
+        0x09, 0x31, // Usage (y)
+        0x81, 0x02, // Input (data, var, abs)
+        0xc0, // End collection
+    ];
+
+    /// A mouse collection (report 2) followed by the touchpad collection (report 1)
+    /// with two contacts per report, so more fingers need hybrid reporting.
+    fn descriptor() -> Vec<u8> {
+        let mut out = vec![
+            0x05, 0x01, // Usage page (generic desktop)
+            0x09, 0x02, // Usage (mouse)
+            0xa1, 0x01, // Collection (application)
+            0x85, 0x02, // Report id (2)
+            0x09, 0x01, // Usage (pointer)
+            0xa1, 0x00, // Collection (physical)
+            0x05, 0x09, // Usage page (button)
+            0x19, 0x01, // Usage min (1)
+            0x29, 0x02, // Usage max (2)
+            0x15, 0x00, // Logical min (0)
+            0x25, 0x01, // Logical max (1)
+            0x95, 0x02, // Report count (2)
+            0x75, 0x01, // Report size (1)
+            0x81, 0x02, // Input (data, var, abs)
+            0x95, 0x01, // Report count (1)
+            0x75, 0x06, // Report size (6)
+            0x81, 0x03, // Input (const)
+            0x05, 0x01, // Usage page (generic desktop)
+            0x09, 0x30, // Usage (x)
+            0x09, 0x31, // Usage (y)
+            0x15, 0x81, // Logical min (-127)
+            0x25, 0x7f, // Logical max (127)
+            0x75, 0x08, // Report size (8)
+            0x95, 0x02, // Report count (2)
+            0x81, 0x06, // Input (data, var, rel)
+            0xc0, // End collection
+            0xc0, // End collection
+            0x05, 0x0d, // Usage page (digitizers)
+            0x09, 0x05, // Usage (touch pad)
+            0xa1, 0x01, // Collection (application)
+            0x85, 0x01, // Report id (1)
+        ];
+        out.extend(FINGER);
+        out.extend(FINGER);
+        out.extend([
+            0x55, 0x0c, // Unit exponent (-4)
+            0x66, 0x01, 0x10, // Unit (seconds)
+            0x47, 0xff, 0xff, 0x00, 0x00, // Physical max (65535)
+            0x27, 0xff, 0xff, 0x00, 0x00, // Logical max (65535)
+            0x75, 0x10, // Report size (16)
+            0x95, 0x01, // Report count (1)
+            0x05, 0x0d, // Usage page (digitizers)
+            0x09, 0x56, // Usage (scan time)
+            0x81, 0x02, // Input (data, var, abs)
+            0x09, 0x54, // Usage (contact count)
+            0x25, 0x7f, // Logical max (127)
+            0x95, 0x01, // Report count (1)
+            0x75, 0x08, // Report size (8)
+            0x81, 0x02, // Input (data, var, abs)
+            0x05, 0x09, // Usage page (button)
+            0x09, 0x01, // Usage (button 1)
+            0x25, 0x01, // Logical max (1)
+            0x75, 0x01, // Report size (1)
+            0x95, 0x01, // Report count (1)
+            0x81, 0x02, // Input (data, var, abs)
+            0x95, 0x07, // Report count (7)
+            0x81, 0x03, // Input (const)
+            0xc0, // End collection
+        ]);
+        return out;
+    }
+
+    struct Touch {
+        confidence: bool,
+        tip: bool,
+        id: u8,
+        x: u16,
+        y: u16,
+    }
+
+    fn touch(id: u8, x: u16, y: u16) -> Touch {
+        return Touch {
+            confidence: true,
+            tip: true,
+            id: id,
+            x: x,
+            y: y,
+        };
+    }
+
+    /// A touchpad report, unused contact entries are zeroed.
+    fn report(touches: &[Touch], count: u8, button: bool) -> Vec<u8> {
+        let mut out = vec![1];
+        for i in 0 .. 2 {
+            match touches.get(i) {
+                Some(t) => {
+                    out.push(t.confidence as u8 | ((t.tip as u8) << 1) | (t.id << 2));
+                    out.extend(t.x.to_le_bytes());
+                    out.extend(t.y.to_le_bytes());
+                },
+                None => out.extend([0; 5]),
+            }
+        }
+        out.extend([0, 0]);
+        out.push(count);
+        out.push(button as u8);
+        return out;
+    }
+
+    fn bridge() -> Bridge {
+        return Bridge::new(parse_descriptor(&descriptor()).unwrap());
+    }
+
+    fn pack(events: &[InputEvent]) -> Vec<(u16, u16, i32)> {
+        return events.iter().map(|e| (e.event_type().0, e.code(), e.value())).collect();
+    }
+
+    /// Touch and tool key events for the number of contacts down.
+    fn touch_keys(count: usize, button: bool) -> Vec<InputEvent> {
+        return vec![
+            key(KeyCode::BTN_TOUCH, count > 0),
+            key(KeyCode::BTN_TOOL_FINGER, count == 1),
+            key(KeyCode::BTN_TOOL_DOUBLETAP, count == 2),
+            key(KeyCode::BTN_TOOL_TRIPLETAP, count == 3),
+            key(KeyCode::BTN_TOOL_QUADTAP, count == 4),
+            key(KeyCode::BTN_TOOL_QUINTTAP, count >= 5),
+            key(KeyCode::BTN_LEFT, button),
+        ];
+    }
+
+    #[test]
+    fn descriptor_layout() {
+        let layout = parse_descriptor(&descriptor()).unwrap();
+        assert!(layout.report_ids);
+        assert_eq!(layout.reports.len(), 1);
+        let report = &layout.reports[0];
+        assert_eq!(report.id, 1);
+        assert_eq!(report.fingers.len(), 2);
+        assert_eq!(report.contact_count.map(|f| (f.bit, f.size)), Some((96, 8)));
+        assert_eq!(report.button.map(|f| (f.bit, f.size)), Some((104, 1)));
+        let second = &report.fingers[1];
+        assert_eq!(second.confidence.map(|f| (f.bit, f.size)), Some((40, 1)));
+        assert_eq!(second.tip.map(|f| (f.bit, f.size)), Some((41, 1)));
+        assert_eq!(second.id.map(|f| (f.bit, f.size)), Some((42, 2)));
+        assert_eq!(second.x.map(|f| (f.bit, f.size)), Some((48, 16)));
+        assert_eq!(second.y.map(|f| (f.bit, f.size)), Some((64, 16)));
+
+        // 4095 over 120.5mm and 90.6mm
+        assert_eq!((layout.x.min, layout.x.max, layout.x.resolution), (0, 4095, 34));
+        assert_eq!((layout.y.min, layout.y.max, layout.y.resolution), (0, 4095, 45));
+    }
+
+    #[test]
+    fn descriptor_without_touches() {
+        let desc = descriptor();
+
+        // Just the mouse collection
+        assert!(parse_descriptor(&desc[.. 52]).is_err());
+    }
+
+    #[test]
+    fn field_read() {
+        let field = |signed| Field {
+            bit: 4,
+            size: 12,
+            signed: signed,
+        };
+        assert_eq!(field(false).read(&[0xf0, 0xff]), Some(4095));
+        assert_eq!(field(true).read(&[0xf0, 0xff]), Some(-1));
+        assert_eq!(field(true).read(&[0x00, 0x80]), Some(-2048));
+        assert_eq!(field(true).read(&[0xf0, 0x7f]), Some(2047));
+        assert_eq!(field(false).read(&[0xf0]), None);
+    }
+
+    #[test]
+    fn mouse_reports_ignored() {
+        let mut bridge = bridge();
+        assert!(bridge.handle(&[2, 1, 5, 5]).is_empty());
+    }
+
+    #[test]
+    fn hybrid_frame() {
+        let mut bridge = bridge();
+
+        // Three contacts split over two reports, only the first has the count
+        assert!(bridge.handle(&report(&[touch(0, 100, 200), touch(1, 300, 400)], 3, false)).is_empty());
+        let events = bridge.handle(&report(&[touch(2, 500, 600)], 0, false));
+        let mut expected = vec![
+            abs(AbsoluteAxisCode::ABS_MT_SLOT, 0),
+            abs(AbsoluteAxisCode::ABS_MT_TRACKING_ID, 1),
+            abs(AbsoluteAxisCode::ABS_MT_SLOT, 0),
+            abs(AbsoluteAxisCode::ABS_MT_POSITION_X, 100),
+            abs(AbsoluteAxisCode::ABS_MT_POSITION_Y, 200),
+            abs(AbsoluteAxisCode::ABS_X, 100),
+            abs(AbsoluteAxisCode::ABS_Y, 200),
+            abs(AbsoluteAxisCode::ABS_MT_SLOT, 1),
+            abs(AbsoluteAxisCode::ABS_MT_TRACKING_ID, 2),
+            abs(AbsoluteAxisCode::ABS_MT_SLOT, 1),
+            abs(AbsoluteAxisCode::ABS_MT_POSITION_X, 300),
+            abs(AbsoluteAxisCode::ABS_MT_POSITION_Y, 400),
+            abs(AbsoluteAxisCode::ABS_MT_SLOT, 2),
+            abs(AbsoluteAxisCode::ABS_MT_TRACKING_ID, 3),
+            abs(AbsoluteAxisCode::ABS_MT_SLOT, 2),
+            abs(AbsoluteAxisCode::ABS_MT_POSITION_X, 500),
+            abs(AbsoluteAxisCode::ABS_MT_POSITION_Y, 600),
+        ];
+        expected.extend(touch_keys(3, false));
+        assert_eq!(pack(&events), pack(&expected));
+
+        // Moving keeps the slots
+        assert!(bridge.handle(&report(&[touch(0, 110, 200), touch(1, 300, 400)], 3, false)).is_empty());
+        let events = bridge.handle(&report(&[touch(2, 500, 600)], 0, true));
+        let mut expected = vec![
+            abs(AbsoluteAxisCode::ABS_MT_SLOT, 0),
+            abs(AbsoluteAxisCode::ABS_MT_POSITION_X, 110),
+            abs(AbsoluteAxisCode::ABS_MT_POSITION_Y, 200),
+            abs(AbsoluteAxisCode::ABS_X, 110),
+            abs(AbsoluteAxisCode::ABS_Y, 200),
+            abs(AbsoluteAxisCode::ABS_MT_SLOT, 1),
+            abs(AbsoluteAxisCode::ABS_MT_POSITION_X, 300),
+            abs(AbsoluteAxisCode::ABS_MT_POSITION_Y, 400),
+            abs(AbsoluteAxisCode::ABS_MT_SLOT, 2),
+            abs(AbsoluteAxisCode::ABS_MT_POSITION_X, 500),
+            abs(AbsoluteAxisCode::ABS_MT_POSITION_Y, 600),
+        ];
+        expected.extend(touch_keys(3, true));
+        assert_eq!(pack(&events), pack(&expected));
+    }
+
+    #[test]
+    fn zero_count_lift() {
+        let mut bridge = bridge();
+        assert!(!bridge.handle(&report(&[touch(0, 100, 200), touch(1, 300, 400)], 2, false)).is_empty());
+
+        // No contacts at all after a complete frame lifts everything
+        let events = bridge.handle(&report(&[], 0, false));
+        let mut expected = vec![
+            abs(AbsoluteAxisCode::ABS_MT_SLOT, 0),
+            abs(AbsoluteAxisCode::ABS_MT_TRACKING_ID, -1),
+            abs(AbsoluteAxisCode::ABS_MT_SLOT, 1),
+            abs(AbsoluteAxisCode::ABS_MT_TRACKING_ID, -1),
+        ];
+        expected.extend(touch_keys(0, false));
+        assert_eq!(pack(&events), pack(&expected));
+    }
+
+    #[test]
+    fn tip_off_lift() {
+        let mut bridge = bridge();
+        assert!(!bridge.handle(&report(&[touch(0, 100, 200), touch(1, 300, 400)], 2, false)).is_empty());
+        let mut lifted = touch(1, 300, 400);
+        lifted.tip = false;
+        let events = bridge.handle(&report(&[touch(0, 100, 200), lifted], 2, false));
+        let mut expected = vec![
+            abs(AbsoluteAxisCode::ABS_MT_SLOT, 1),
+            abs(AbsoluteAxisCode::ABS_MT_TRACKING_ID, -1),
+            abs(AbsoluteAxisCode::ABS_MT_SLOT, 0),
+            abs(AbsoluteAxisCode::ABS_MT_POSITION_X, 100),
+            abs(AbsoluteAxisCode::ABS_MT_POSITION_Y, 200),
+            abs(AbsoluteAxisCode::ABS_X, 100),
+            abs(AbsoluteAxisCode::ABS_Y, 200),
+        ];
+        expected.extend(touch_keys(1, false));
+        assert_eq!(pack(&events), pack(&expected));
+    }
+
+    #[test]
+    fn palm_ignored() {
+        let mut bridge = bridge();
+        let mut palm = touch(1, 300, 400);
+        palm.confidence = false;
+        let events = bridge.handle(&report(&[touch(0, 100, 200), palm], 2, false));
+        let mut expected = vec![
+            abs(AbsoluteAxisCode::ABS_MT_SLOT, 0),
+            abs(AbsoluteAxisCode::ABS_MT_TRACKING_ID, 1),
+            abs(AbsoluteAxisCode::ABS_MT_SLOT, 0),
+            abs(AbsoluteAxisCode::ABS_MT_POSITION_X, 100),
+            abs(AbsoluteAxisCode::ABS_MT_POSITION_Y, 200),
+            abs(AbsoluteAxisCode::ABS_X, 100),
+            abs(AbsoluteAxisCode::ABS_Y, 200),
+        ];
+        expected.extend(touch_keys(1, false));
+        assert_eq!(pack(&events), pack(&expected));
+
+        // Once it's confident it's a new contact
+        let events = bridge.handle(&report(&[touch(0, 100, 200), touch(1, 300, 400)], 2, false));
+        let mut expected = vec![
+            abs(AbsoluteAxisCode::ABS_MT_SLOT, 0),
+            abs(AbsoluteAxisCode::ABS_MT_POSITION_X, 100),
+            abs(AbsoluteAxisCode::ABS_MT_POSITION_Y, 200),
+            abs(AbsoluteAxisCode::ABS_X, 100),
+            abs(AbsoluteAxisCode::ABS_Y, 200),
+            abs(AbsoluteAxisCode::ABS_MT_SLOT, 1),
+            abs(AbsoluteAxisCode::ABS_MT_TRACKING_ID, 2),
+            abs(AbsoluteAxisCode::ABS_MT_SLOT, 1),
+            abs(AbsoluteAxisCode::ABS_MT_POSITION_X, 300),
+            abs(AbsoluteAxisCode::ABS_MT_POSITION_Y, 400),
+        ];
+        expected.extend(touch_keys(2, false));
+        assert_eq!(pack(&events), pack(&expected));
+    }
+}
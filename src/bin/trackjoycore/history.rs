@@ -0,0 +1,111 @@
+//! The last raw events from each source, for figuring out what happened after the
+//! fact (stuck buttons, crashes). Dumped with `SIGUSR1` or the control socket.
+use std::{
+    collections::{
+        HashMap,
+        VecDeque,
+    },
+    fmt::Write as _,
+    path::PathBuf,
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::{
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+use evdev::InputEvent;
+use loga::{
+    ea,
+    ResultContext,
+};
+use trackjoy::{
+    calibration::state_dir,
+    DeviceRef,
+};
+
+/// Events kept per source.
+const EVENT_HISTORY: usize = 256;
+
+/// Local time with milliseconds.
+fn format_time(t: SystemTime) -> String {
+    let d = t.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = d.as_secs() as libc::time_t;
+    let mut tm: libc::tm = unsafe {
+        std::mem::zeroed()
+    };
+    unsafe {
+        libc::localtime_r(&secs, &mut tm);
+    }
+    return format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}",
+        tm.tm_year + 1900,
+        tm.tm_mon + 1,
+        tm.tm_mday,
+        tm.tm_hour,
+        tm.tm_min,
+        tm.tm_sec,
+        d.subsec_millis()
+    );
+}
+
+/// Shared by all sources, survives config reloads.
+#[derive(Clone, Default)]
+pub struct Histories(Arc<Mutex<HashMap<DeviceRef, VecDeque<InputEvent>>>>);
+
+impl Histories {
+    pub fn push(&self, source: DeviceRef, ev: InputEvent) {
+        let mut sources = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let events = sources.entry(source).or_insert_with(|| VecDeque::with_capacity(EVENT_HISTORY));
+        if events.len() == EVENT_HISTORY {
+            events.pop_front();
+        }
+        events.push_back(ev);
+    }
+
+    fn dump_events(out: &mut String, events: &VecDeque<InputEvent>) {
+        for ev in events {
+            _ = writeln!(out, "{}  {:?}", format_time(ev.timestamp()), ev.destructure());
+        }
+    }
+
+    /// One source's events, oldest first.
+    pub fn dump_source(&self, source: DeviceRef) -> String {
+        let mut out = String::new();
+        if let Some(events) = self.0.lock().unwrap_or_else(|e| e.into_inner()).get(&source) {
+            Self::dump_events(&mut out, events);
+        }
+        return out;
+    }
+
+    /// All sources' events, oldest first.
+    pub fn dump(&self) -> String {
+        let sources = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let mut sources = sources.iter().collect::<Vec<_>>();
+        sources.sort_by_key(|(s, _)| format!("{:?}", s));
+        let mut out = String::new();
+        for (source, events) in sources {
+            _ = writeln!(out, "# {:?}\n", source);
+            Self::dump_events(&mut out, events);
+            out.push('\n');
+        }
+        return out;
+    }
+
+    /// Write the dump to the state directory, returning the path.
+    pub fn write(&self) -> Result<PathBuf, loga::Error> {
+        let dir = state_dir()?.join("events");
+        std::fs::create_dir_all(
+            &dir,
+        ).context_with("Error creating event dump directory", ea!(path = dir.to_string_lossy()))?;
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let path = dir.join(format!("{}-{}.txt", time, std::process::id()));
+        std::fs::write(
+            &path,
+            self.dump(),
+        ).context_with("Error writing event dump", ea!(path = path.to_string_lossy()))?;
+        return Ok(path);
+    }
+}
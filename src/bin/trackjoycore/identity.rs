@@ -0,0 +1,41 @@
+use evdev::{
+    BusType,
+    Device,
+    InputId,
+};
+
+/// Arbitrary, shared by all trackjoy virtual devices.
+const DEST_VENDOR: u16 = 0x7472;
+
+/// Something that identifies a source device across restarts and reconnects: the
+/// unique id (serial number, bluetooth address) if it has one, otherwise the
+/// physical (port) path, otherwise just the model.
+pub fn source_identity(source: &Device) -> String {
+    let id = source.input_id();
+    let model = format!("{:04x}:{:04x}:{}", id.vendor(), id.product(), source.name().unwrap_or(""));
+    if let Some(uniq) = source.unique_name().filter(|u| !u.is_empty()) {
+        return format!("{}/{}", model, uniq);
+    }
+    if let Some(phys) = source.physical_path().filter(|p| !p.is_empty()) {
+        return format!("{}/{}", model, phys);
+    }
+    return model;
+}
+
+/// FNV-1a, unlike the std hashers the output is fixed across builds.
+fn hash(data: &[u8]) -> u64 {
+    let mut out = 0xcbf29ce484222325u64;
+    for b in data {
+        out ^= *b as u64;
+        out = out.wrapping_mul(0x100000001b3);
+    }
+    return out;
+}
+
+/// Uinput can't set a `uniq`, so the identity of the sources goes into the product
+/// and version. SDL and Steam key controller ordering and settings on these so they
+/// stick across restarts as long as the same devices are used.
+pub fn dest_input_id(source_identities: &[String]) -> InputId {
+    let hash = hash(source_identities.join("\n").as_bytes());
+    return InputId::new(BusType::BUS_USB, DEST_VENDOR, (hash >> 16) as u16, hash as u16);
+}
@@ -0,0 +1,483 @@
+//! Converting keyboard-to-gamepad layouts from other tools into a keys mapping.
+//! These map gamepad inputs to keys, so they're inverted here: each key that a
+//! gamepad input produced becomes a key that produces that gamepad input.
+use std::{
+    collections::HashMap,
+    path::Path,
+};
+use evdev::{
+    AbsoluteAxisCode,
+    KeyCode,
+};
+use loga::{
+    ea,
+    ResultContext,
+};
+use trackjoy::{
+    KeysConfig,
+    KeysStickConfig,
+};
+
+/// Key from a name like `W`, `SPACE` or `LEFT_SHIFT`.
+fn key_from_name(name: &str) -> Option<KeyCode> {
+    let name = name.trim().to_uppercase();
+    let evdev_name = match name.as_str() {
+        "RETURN" => "ENTER",
+        "ESCAPE" => "ESC",
+        "LEFT_CONTROL" => "LEFTCTRL",
+        "RIGHT_CONTROL" => "RIGHTCTRL",
+        "LEFT_SHIFT" => "LEFTSHIFT",
+        "RIGHT_SHIFT" => "RIGHTSHIFT",
+        "LEFT_ALT" => "LEFTALT",
+        "RIGHT_ALT" => "RIGHTALT",
+        "UP_ARROW" => "UP",
+        "DOWN_ARROW" => "DOWN",
+        "LEFT_ARROW" => "LEFT",
+        "RIGHT_ARROW" => "RIGHT",
+        "PERIOD" => "DOT",
+        "PAGE_UP" => "PAGEUP",
+        "PAGE_DOWN" => "PAGEDOWN",
+        n => n,
+    };
+    return serde_json::from_value(serde_json::Value::String(format!("KEY_{}", evdev_name))).ok();
+}
+
+/// Key from an X11 keysym, as used by AntiMicroX.
+fn key_from_keysym(keysym: u32) -> Option<KeyCode> {
+    match keysym {
+        0x20 => return Some(KeyCode::KEY_SPACE),
+        0x30 ..= 0x39 => {
+            let digit = char::from_u32(keysym).unwrap();
+            return key_from_name(&digit.to_string());
+        },
+        0x41 ..= 0x5a | 0x61 ..= 0x7a => {
+            let letter = char::from_u32(keysym).unwrap();
+            return key_from_name(&letter.to_string());
+        },
+        0xff08 => return Some(KeyCode::KEY_BACKSPACE),
+        0xff09 => return Some(KeyCode::KEY_TAB),
+        0xff0d => return Some(KeyCode::KEY_ENTER),
+        0xff1b => return Some(KeyCode::KEY_ESC),
+        0xff51 => return Some(KeyCode::KEY_LEFT),
+        0xff52 => return Some(KeyCode::KEY_UP),
+        0xff53 => return Some(KeyCode::KEY_RIGHT),
+        0xff54 => return Some(KeyCode::KEY_DOWN),
+        0xffbe ..= 0xffc9 => return key_from_name(&format!("F{}", keysym - 0xffbe + 1)),
+        0xffe1 => return Some(KeyCode::KEY_LEFTSHIFT),
+        0xffe2 => return Some(KeyCode::KEY_RIGHTSHIFT),
+        0xffe3 => return Some(KeyCode::KEY_LEFTCTRL),
+        0xffe4 => return Some(KeyCode::KEY_RIGHTCTRL),
+        0xffe9 => return Some(KeyCode::KEY_LEFTALT),
+        0xffea => return Some(KeyCode::KEY_RIGHTALT),
+        _ => return None,
+    }
+}
+
+/// The mapping being built.
+struct Import<'a> {
+    log: &'a loga::Log,
+    buttons: HashMap<KeyCode, KeyCode>,
+    stick: Option<KeysStickConfig>,
+}
+
+impl<'a> Import<'a> {
+    fn button(&mut self, key: KeyCode, dest: KeyCode) {
+        if let Some(old) = self.buttons.insert(key, dest) {
+            if old != dest {
+                self.log.warn(
+                    "Key used for multiple gamepad inputs, keeping the last",
+                    ea!(key = format!("{:?}", key), button = format!("{:?}", dest)),
+                );
+            }
+        }
+    }
+
+    /// Only one stick can be made from keys, the first one found is used.
+    fn stick(&mut self, axes: [AbsoluteAxisCode; 2], key: KeyCode, direction: [f32; 2]) {
+        let stick = self.stick.get_or_insert_with(|| KeysStickConfig {
+            axes: axes,
+            keys: HashMap::new(),
+            attack: None,
+            release: None,
+        });
+        if stick.axes != axes {
+            self.log.warn("Only one stick can be mapped from keys, skipping", ea!(key = format!("{:?}", key)));
+            return;
+        }
+        stick.keys.insert(key, direction);
+    }
+
+    fn finish(self) -> serde_json::Value {
+        let keys = KeysConfig {
+            preset: None,
+            stick: self.stick,
+            scancodes: HashMap::new(),
+            repeat_turbo: false,
+            nudges: vec![],
+            media_keys: None,
+            ignore_events: vec![],
+            outputs: HashMap::new(),
+            modifiers: vec![],
+            uniq: None,
+            split: None,
+            layers: vec![],
+            buttons: self.buttons,
+        };
+        return serde_json::json!({
+            "pad_mappings": [],
+            "keys_mappings": [keys],
+        });
+    }
+}
+
+// # Steam Input
+
+/// A node in a Valve KeyValues (VDF) file.
+enum Vdf {
+    Str(String),
+    Obj(Vec<(String, Vdf)>),
+}
+
+impl Vdf {
+    fn get(&self, key: &str) -> Option<&Vdf> {
+        match self {
+            Vdf::Obj(children) => return children.iter().find(|(k, _)| k.eq_ignore_ascii_case(key)).map(|(_, v)| v),
+            Vdf::Str(_) => return None,
+        }
+    }
+
+    fn children(&self) -> &[(String, Vdf)] {
+        match self {
+            Vdf::Obj(children) => return children,
+            Vdf::Str(_) => return &[],
+        }
+    }
+
+    fn str(&self) -> Option<&str> {
+        match self {
+            Vdf::Str(s) => return Some(s),
+            Vdf::Obj(_) => return None,
+        }
+    }
+}
+
+fn parse_vdf(text: &str) -> Result<Vdf, loga::Error> {
+    fn token(chars: &mut std::iter::Peekable<std::str::Chars>) -> Option<String> {
+        loop {
+            match chars.peek() {
+                Some(c) if c.is_whitespace() => {
+                    chars.next();
+                },
+                Some('/') => {
+                    // Comment
+                    while chars.next().map(|c| c != '\n').unwrap_or(false) { }
+                },
+                _ => break,
+            }
+        }
+        let first = chars.next()?;
+        match first {
+            '{' | '}' => return Some(first.to_string()),
+            '"' => {
+                let mut out = String::from("\"");
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => out.extend(chars.next()),
+                        c => out.push(c),
+                    }
+                }
+                return Some(out);
+            },
+            c => {
+                let mut out = format!("\"{}", c);
+                while let Some(c) = chars.peek() {
+                    if c.is_whitespace() || *c == '{' || *c == '}' || *c == '"' {
+                        break;
+                    }
+                    out.push(*c);
+                    chars.next();
+                }
+                return Some(out);
+            },
+        }
+    }
+
+    fn obj(chars: &mut std::iter::Peekable<std::str::Chars>, top: bool) -> Result<Vec<(String, Vdf)>, loga::Error> {
+        let mut out = vec![];
+        loop {
+            let key = match token(chars) {
+                Some(t) if t == "}" && !top => return Ok(out),
+                Some(t) if t.starts_with('"') => t[1..].to_string(),
+                None if top => return Ok(out),
+                t => {
+                    return Err(
+                        loga::err_with("Unexpected token in VDF, expected key", ea!(token = t.unwrap_or_default())),
+                    );
+                },
+            };
+            let value = match token(chars) {
+                Some(t) if t == "{" => Vdf::Obj(obj(chars, false)?),
+                Some(t) if t.starts_with('"') => Vdf::Str(t[1..].to_string()),
+                t => {
+                    return Err(
+                        loga::err_with(
+                            "Unexpected token in VDF, expected value",
+                            ea!(key = key, token = t.unwrap_or_default()),
+                        ),
+                    );
+                },
+            };
+            out.push((key, value));
+        }
+    }
+
+    return Ok(Vdf::Obj(obj(&mut text.chars().peekable(), true)?));
+}
+
+fn import_steam(log: &loga::Log, text: &str) -> Result<serde_json::Value, loga::Error> {
+    let root = parse_vdf(text)?;
+    let mappings = root.get("controller_mappings").context("Missing `controller_mappings` in Steam config")?;
+    let mut out = Import {
+        log: log,
+        buttons: HashMap::new(),
+        stick: None,
+    };
+
+    // What physical control each group is active for
+    let mut group_sources = HashMap::new();
+    for (key, preset) in mappings.children() {
+        if key != "preset" {
+            continue;
+        }
+        let Some(bindings) = preset.get("group_source_bindings") else {
+            continue;
+        };
+        for (group, binding) in bindings.children() {
+            let Some(binding) = binding.str() else {
+                continue;
+            };
+            let mut parts = binding.split_whitespace();
+            let source = parts.next().unwrap_or_default();
+            if parts.next() != Some("active") {
+                continue;
+            }
+            group_sources.entry(group.clone()).or_insert(source.to_string());
+        }
+    }
+    for (key, group) in mappings.children() {
+        if key != "group" {
+            continue;
+        }
+        let id = group.get("id").and_then(|i| i.str()).unwrap_or_default();
+        let Some(source) = group_sources.get(id) else {
+            // Not used in the active preset
+            continue;
+        };
+        let Some(inputs) = group.get("inputs") else {
+            continue;
+        };
+        for (input, input_config) in inputs.children() {
+            let input = input.to_lowercase();
+            let Some(activators) = input_config.get("activators") else {
+                continue;
+            };
+            let Some(bindings) = activators.get("Full_Press").and_then(|a| a.get("bindings")) else {
+                continue;
+            };
+            for (_, binding) in bindings.children() {
+                let Some(binding) = binding.str() else {
+                    continue;
+                };
+
+                // Ex: `key_press SPACE, Jump`
+                let binding = binding.split(',').next().unwrap_or_default();
+                let Some(key_name) = binding.strip_prefix("key_press ") else {
+                    log.warn("Skipping non-key binding", ea!(input = input, binding = binding));
+                    continue;
+                };
+                let Some(key) = key_from_name(key_name) else {
+                    log.warn("Unknown key in binding, skipping", ea!(input = input, key = key_name));
+                    continue;
+                };
+                let stick_axes = match source.as_str() {
+                    "joystick" | "left_trackpad" => Some([AbsoluteAxisCode::ABS_X, AbsoluteAxisCode::ABS_Y]),
+                    "right_joystick" | "right_trackpad" => Some([AbsoluteAxisCode::ABS_RX, AbsoluteAxisCode::ABS_RY]),
+                    _ => None,
+                };
+                let direction = match input.as_str() {
+                    "dpad_north" => Some([0., -1.]),
+                    "dpad_south" => Some([0., 1.]),
+                    "dpad_west" => Some([-1., 0.]),
+                    "dpad_east" => Some([1., 0.]),
+                    _ => None,
+                };
+                if let (Some(axes), Some(direction)) = (stick_axes, direction) {
+                    out.stick(axes, key, direction);
+                    continue;
+                }
+                let dest = match (source.as_str(), input.as_str()) {
+                    (_, "button_a") => KeyCode::BTN_SOUTH,
+                    (_, "button_b") => KeyCode::BTN_EAST,
+                    (_, "button_x") => KeyCode::BTN_WEST,
+                    (_, "button_y") => KeyCode::BTN_NORTH,
+                    (_, "dpad_north") => KeyCode::BTN_DPAD_UP,
+                    (_, "dpad_south") => KeyCode::BTN_DPAD_DOWN,
+                    (_, "dpad_west") => KeyCode::BTN_DPAD_LEFT,
+                    (_, "dpad_east") => KeyCode::BTN_DPAD_RIGHT,
+                    (_, "left_bumper") => KeyCode::BTN_TL,
+                    (_, "right_bumper") => KeyCode::BTN_TR,
+                    (_, "button_escape") => KeyCode::BTN_START,
+                    (_, "button_menu") => KeyCode::BTN_SELECT,
+                    ("left_trigger", _) => KeyCode::BTN_TL2,
+                    ("right_trigger", _) => KeyCode::BTN_TR2,
+                    ("joystick", "click") => KeyCode::BTN_THUMBL,
+                    ("right_joystick", "click") => KeyCode::BTN_THUMBR,
+                    _ => {
+                        log.warn("Unsupported Steam input, skipping", ea!(source = source, input = input));
+                        continue;
+                    },
+                };
+                out.button(key, dest);
+            }
+        }
+    }
+    return Ok(out.finish());
+}
+
+// # AntiMicroX
+
+/// Keys in the keyboard slots of a profile element.
+fn antimicrox_keys(log: &loga::Log, node: roxmltree::Node) -> Vec<KeyCode> {
+    let mut keys = vec![];
+    for slot in node.descendants().filter(|n| n.has_tag_name("slot")) {
+        let mode = slot.children().find(|n| n.has_tag_name("mode")).and_then(|n| n.text()).unwrap_or_default();
+        if mode != "keyboard" {
+            continue;
+        }
+        let Some(code) = slot.children().find(|n| n.has_tag_name("code")).and_then(|n| n.text()) else {
+            continue;
+        };
+        let keysym = match u32::from_str_radix(code.trim_start_matches("0x"), 16) {
+            Ok(k) => k,
+            Err(_) => {
+                continue;
+            },
+        };
+        match key_from_keysym(keysym) {
+            Some(k) => keys.push(k),
+            None => {
+                log.warn("Unsupported key code in AntiMicroX profile, skipping", ea!(code = code));
+            },
+        }
+    }
+    return keys;
+}
+
+fn antimicrox_index(node: &roxmltree::Node) -> usize {
+    return node.attribute("index").and_then(|i| i.parse::<usize>().ok()).unwrap_or(0);
+}
+
+fn import_antimicrox(log: &loga::Log, text: &str) -> Result<serde_json::Value, loga::Error> {
+    let doc = roxmltree::Document::parse(text).context("Error parsing AntiMicroX profile XML")?;
+    let mut out = Import {
+        log: log,
+        buttons: HashMap::new(),
+        stick: None,
+    };
+
+    // Only the first set, the others are alternate layers
+    let Some(set) = doc.descendants().find(|n| n.has_tag_name("set")) else {
+        return Err(loga::err("No button sets in AntiMicroX profile"));
+    };
+    for node in set.children().filter(|n| n.is_element()) {
+        match node.tag_name().name() {
+            "button" => {
+                // SDL game controller button order, 1 based
+                let dest = match antimicrox_index(&node) {
+                    1 => KeyCode::BTN_SOUTH,
+                    2 => KeyCode::BTN_EAST,
+                    3 => KeyCode::BTN_WEST,
+                    4 => KeyCode::BTN_NORTH,
+                    5 => KeyCode::BTN_SELECT,
+                    6 => KeyCode::BTN_MODE,
+                    7 => KeyCode::BTN_START,
+                    8 => KeyCode::BTN_THUMBL,
+                    9 => KeyCode::BTN_THUMBR,
+                    10 => KeyCode::BTN_TL,
+                    11 => KeyCode::BTN_TR,
+                    12 => KeyCode::BTN_DPAD_UP,
+                    13 => KeyCode::BTN_DPAD_DOWN,
+                    14 => KeyCode::BTN_DPAD_LEFT,
+                    15 => KeyCode::BTN_DPAD_RIGHT,
+                    i => {
+                        log.warn("Unsupported AntiMicroX button, skipping", ea!(index = i));
+                        continue;
+                    },
+                };
+                for key in antimicrox_keys(log, node) {
+                    out.button(key, dest);
+                }
+            },
+            "trigger" => {
+                // SDL axis index, 1 based
+                let dest = match antimicrox_index(&node) {
+                    5 => KeyCode::BTN_TL2,
+                    6 => KeyCode::BTN_TR2,
+                    i => {
+                        log.warn("Unsupported AntiMicroX trigger, skipping", ea!(index = i));
+                        continue;
+                    },
+                };
+                for key in antimicrox_keys(log, node) {
+                    out.button(key, dest);
+                }
+            },
+            "stick" => {
+                let axes = match antimicrox_index(&node) {
+                    1 => [AbsoluteAxisCode::ABS_X, AbsoluteAxisCode::ABS_Y],
+                    2 => [AbsoluteAxisCode::ABS_RX, AbsoluteAxisCode::ABS_RY],
+                    i => {
+                        log.warn("Unsupported AntiMicroX stick, skipping", ea!(index = i));
+                        continue;
+                    },
+                };
+                for dir in node.children().filter(|n| n.has_tag_name("stickbutton")) {
+                    // Clockwise from up
+                    let direction = match antimicrox_index(&dir) {
+                        1 => [0., -1.],
+                        2 => [1., -1.],
+                        3 => [1., 0.],
+                        4 => [1., 1.],
+                        5 => [0., 1.],
+                        6 => [-1., 1.],
+                        7 => [-1., 0.],
+                        8 => [-1., -1.],
+                        _ => continue,
+                    };
+                    for key in antimicrox_keys(log, dir) {
+                        out.stick(axes, key, direction);
+                    }
+                }
+            },
+            _ => { },
+        }
+    }
+    return Ok(out.finish());
+}
+
+#[derive(Clone, Copy)]
+pub enum Format {
+    Steam,
+    AntiMicroX,
+}
+
+/// Convert a layout file to a config with a single keys mapping.
+pub fn import(log: &loga::Log, format: Format, path: &Path) -> Result<serde_json::Value, loga::Error> {
+    let text =
+        std::fs::read_to_string(path).context_with("Error reading file to import", ea!(path = path.to_string_lossy()))?;
+    match format {
+        Format::Steam => return import_steam(log, &text),
+        Format::AntiMicroX => return import_antimicrox(log, &text),
+    }
+}
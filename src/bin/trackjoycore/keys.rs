@@ -1,86 +1,363 @@
 use std::{
-    collections::{
-        HashMap,
-        HashSet,
-    },
-    sync::{
-        Arc,
-        Mutex,
-    },
+    collections::HashSet,
+    sync::Arc,
+    time::Instant,
 };
 use evdev::{
-    SynchronizationCode,
-    InputEvent,
-    EventType,
+    uinput::VirtualDeviceBuilder,
     Device,
-    uinput::VirtualDevice,
     KeyCode,
+    AbsoluteAxisCode,
+    AttributeSet,
+    EventSummary,
+    EventType,
+    InputEvent,
+    SynchronizationCode,
 };
 use loga::{
+    ea,
     ResultContext,
 };
-use manual_future::ManualFuture;
 use taskmanager::TaskManager;
+use tokio::sync::watch;
+use trackjoy::{
+    mapper::{
+        keys::{
+            KeysMapper,
+            KeysStep,
+            MEDIA_KEYS,
+        },
+        Changes,
+    },
+    DeviceRef,
+    EventCode,
+    KeysConfig,
+    KeysSplitConfig,
+    MediaKeysPolicy,
+};
+use super::{
+    active::{
+        self,
+        set_grab,
+        SourceEvent,
+    },
+    history::Histories,
+    output::{
+        Contribution,
+        Message,
+        OutputTx,
+    },
+    shadow::ShadowDiff,
+    stats::Stats,
+    trace::Tracer,
+};
+
+/// The mapping for the `split` part of a keys mapping, sharing the rest of the
+/// settings.
+fn split_config(config: &KeysConfig, split: KeysSplitConfig) -> KeysConfig {
+    return KeysConfig {
+        preset: None,
+        stick: split.stick,
+        scancodes: split.scancodes,
+        repeat_turbo: config.repeat_turbo,
+        nudges: split.nudges,
+        media_keys: match config.media_keys {
+            // Passed through by the main mapping
+            Some(MediaKeysPolicy::Passthrough) => Some(MediaKeysPolicy::Ignore),
+            p => p,
+        },
+        ignore_events: config.ignore_events.clone(),
+        outputs: config.outputs.clone(),
+        modifiers: vec![],
+        uniq: None,
+        split: None,
+        layers: vec![],
+        buttons: split.buttons,
+    };
+}
 
+/// `split_buttons` gets the buttons of the `split` part of the mapping, if any.
 pub fn build(
     tm: &TaskManager,
+    log: &loga::Log,
     source: Device,
-    button_codes: HashMap<KeyCode, KeyCode>,
-    dest: ManualFuture<Arc<Mutex<VirtualDevice>>>,
+    source_ref: DeviceRef,
+    mut config: KeysConfig,
+    output: OutputTx,
+    player: usize,
+    player_count: usize,
+    player_cycle_key: Option<KeyCode>,
     dest_buttons: &mut HashSet<KeyCode>,
+    split_buttons: &mut HashSet<KeyCode>,
+    dest_axes: &mut Vec<AbsoluteAxisCode>,
+    grab: bool,
+    mut active: watch::Receiver<bool>,
+    history: Histories,
+    tracer: Tracer,
+    modifiers: Arc<watch::Sender<HashSet<KeyCode>>>,
+    recenter_keys: Vec<KeyCode>,
+    stats: Option<Stats>,
+    shadow: Option<KeysConfig>,
 ) -> Result<(), loga::Error> {
-    let mut buttons = HashMap::new();
-    let mut last_buttons = HashMap::new();
-    for (_, dest_code) in &button_codes {
-        dest_buttons.insert(*dest_code);
-        buttons.insert(*dest_code, false);
-        last_buttons.insert(*dest_code, false);
+    let split = config.split.take().map(|s| (s.player, split_config(&config, s)));
+
+    // Allocate buttons/axes
+    for (config, dest_buttons) in [Some((&config, dest_buttons)), split.as_ref().map(|(_, c)| (c, split_buttons))]
+        .into_iter()
+        .flatten() {
+        for dest_code in config
+            .buttons
+            .values()
+            .chain(config.scancodes.values())
+            .chain(config.layers.iter().flat_map(|l| l.buttons.values())) {
+            dest_buttons.insert(*dest_code);
+        }
+        if let Some(stick) = &config.stick {
+            for axis in stick.axes {
+                if !dest_axes.contains(&axis) {
+                    dest_axes.push(axis);
+                }
+            }
+        }
+        for nudge in &config.nudges {
+            if !dest_axes.contains(&nudge.axis) {
+                dest_axes.push(nudge.axis);
+            }
+        }
+    }
+
+    // Auxiliary keyboard for media keys
+    let mut media_dest = None;
+    if config.media_keys == Some(MediaKeysPolicy::Passthrough) {
+        let mut keys = AttributeSet::<KeyCode>::new();
+        if let Some(supported) = source.supported_keys() {
+            for k in MEDIA_KEYS {
+                if supported.contains(*k) {
+                    keys.insert(*k);
+                }
+            }
+        }
+        if keys.iter().next().is_some() {
+            media_dest =
+                Some(
+                    VirtualDeviceBuilder::new()
+                        .context("Error creating media key keyboard builder")?
+                        .name(&format!("{} media keys", source.name().unwrap_or("trackjoy")))
+                        .with_keys(&keys)
+                        .context("Error adding keys to media key keyboard")?
+                        .build()
+                        .context("Unable to create media key keyboard")?,
+                );
+        }
     }
 
+    // Keys already held at startup
+    let ignore_events = config.ignore_events.clone();
+    let modifier_keys = config.modifiers.iter().chain(&recenter_keys).copied().collect::<HashSet<_>>();
+    let held = source.get_key_state().context("Error getting initial key state")?;
+    let held = held.iter().filter(|k| !ignore_events.contains(&EventCode::Key(*k))).collect::<Vec<_>>();
+    let mut shadow = match shadow {
+        Some(c) => Some((
+            KeysMapper::new(c, held.iter().copied(), player, player_count, player_cycle_key)
+                .context("Error setting up shadow keys mapping")?,
+            ShadowDiff::default(),
+        )),
+        None => None,
+    };
+    let mut split = match split {
+        Some((split_player, c)) => Some((
+            KeysMapper::new(c, held.iter().copied(), split_player, player_count, None)
+                .context("Error setting up split keys mapping")?,
+            Changes::new(),
+        )),
+        None => None,
+    };
+    let split_ref = match source_ref {
+        DeviceRef::Keys(i) => DeviceRef::KeysSplit(i),
+        r => r,
+    };
+    let mut mapper = KeysMapper::new(config, held, player, player_count, player_cycle_key)?;
+
     // Read and write events
     let mut source = source.into_event_stream().context("Couldn't make input device async")?;
     tm.critical_task::<_, loga::Error>({
         let tm = tm.clone();
+        let log = log.clone();
         async move {
-            let dest = dest.await;
+            if !*active.borrow_and_update() {
+                set_grab(&mut source, grab, false)?;
+            }
+            let mut changes = Changes::new();
+            let mut shadow_changes = Changes::new();
+
+            // A traced key was pressed or released this frame
+            let mut traced = false;
             loop {
-                let ev = match tm.if_alive(source.next_event()).await {
-                    Some(x) => x,
-                    None => {
-                        break;
+                let now = Instant::now();
+                let deadline =
+                    [
+                        mapper.deadline(now),
+                        split.as_ref().and_then(|(m, _)| m.deadline(now)),
+                        shadow.as_ref().and_then(|(m, _)| m.deadline(now)),
+                    ]
+                        .into_iter()
+                        .flatten()
+                        .min();
+                let ev = match deadline {
+                    None => match tm.if_alive(active::next(&mut source, &mut active)).await {
+                        Some(x) => x,
+                        None => {
+                            break;
+                        },
                     },
-                }?;
-                match ev.destructure() {
-                    evdev::EventSummary::Synchronization(_, t, _) => {
-                        if t == SynchronizationCode::SYN_REPORT {
-                            let mut dest_events = vec![];
-                            for (k, on) in &buttons {
-                                let last_on = last_buttons[k];
-                                if *on && !last_on {
-                                    dest_events.push(InputEvent::new(EventType::KEY.0, k.0, 1));
-                                } else if !on && last_on {
-                                    dest_events.push(InputEvent::new(EventType::KEY.0, k.0, 0));
+                    Some(at) => match tm.if_alive(
+                        tokio::time::timeout_at(at.into(), active::next(&mut source, &mut active)),
+                    ).await {
+                        Some(Ok(x)) => x,
+                        Some(Err(_)) => {
+                            // Stick ramping
+                            mapper.tick(*active.borrow(), Instant::now(), &mut changes);
+                            if let Some((split_mapper, split_changes)) = &mut split {
+                                split_mapper.tick(*active.borrow(), Instant::now(), split_changes);
+                                if split_changes.len() > 0 && output.send(Message::Contribution(Contribution {
+                                    source: split_ref,
+                                    player: split_mapper.player(),
+                                    changes: std::mem::take(split_changes),
+                                    time: None,
+                                })).is_err() {
+                                    // Output stopped, shutting down
+                                    break;
                                 }
                             }
-                            last_buttons = buttons.clone();
-                            if dest_events.len() > 0 {
-                                dest
-                                    .lock()
-                                    .unwrap()
-                                    .emit(&dest_events)
-                                    .context("Failed to send events to virtual device")?;
+                            if let Some((shadow_mapper, diff)) = &mut shadow {
+                                shadow_mapper.tick(*active.borrow(), Instant::now(), &mut shadow_changes);
+                                diff.shadow(&std::mem::take(&mut shadow_changes));
+                                diff.real(&changes);
+                                diff.compare(&log, source_ref);
                             }
-                        }
+                            if changes.len() > 0 && output.send(Message::Contribution(Contribution {
+                                source: source_ref,
+                                player: mapper.player(),
+                                changes: std::mem::take(&mut changes),
+                                time: None,
+                            })).is_err() {
+                                // Output stopped, shutting down
+                                break;
+                            }
+                            continue;
+                        },
+                        None => {
+                            break;
+                        },
                     },
-                    evdev::EventSummary::Key(_, t, v) => {
-                        match button_codes.get(&t) {
-                            Some(c) => {
-                                buttons.insert(*c, v != 0);
+                }?;
+                let send;
+                let time;
+                match ev {
+                    SourceEvent::Event(ev) => {
+                        time = Some(ev.timestamp());
+                        history.push(source_ref, ev);
+                        if ignore_events.iter().any(|c| c.matches(&ev)) {
+                            continue;
+                        }
+                        match ev.destructure() {
+                            EventSummary::Key(_, k, v) if v != 2 && modifier_keys.contains(&k) => {
+                                modifiers.send_if_modified(|m| if v == 1 {
+                                    m.insert(k)
+                                } else {
+                                    m.remove(&k)
+                                });
+                            },
+                            _ => { },
+                        }
+                        if let EventSummary::Key(_, k, v) = ev.destructure() {
+                            if tracer.key(k) {
+                                log.info(
+                                    "Trace: key event",
+                                    ea!(key = format!("{:?}", k), value = v, mapping = mapper.explain(k)),
+                                );
+                                traced = true;
+                            }
+                        }
+                        if let Some((shadow_mapper, diff)) = &mut shadow {
+                            shadow_mapper.event(ev, *active.borrow(), Instant::now(), &mut shadow_changes);
+                            diff.shadow(&std::mem::take(&mut shadow_changes));
+                        }
+                        if let Some((split_mapper, split_changes)) = &mut split {
+                            // Media keys are passed through by the main mapping, changes are sent below
+                            split_mapper.event(ev, *active.borrow(), Instant::now(), split_changes);
+                        }
+                        match mapper.event(ev, *active.borrow(), Instant::now(), &mut changes) {
+                            KeysStep::None => {
+                                send = false;
                             },
-                            None => (),
+                            KeysStep::Passthrough(k, v) => {
+                                // When not grabbed they already go to the system
+                                if let (Some(dest), true) = (&mut media_dest, grab && *active.borrow()) {
+                                    dest
+                                        .emit(&[InputEvent::new(EventType::KEY.0, k.0, v)])
+                                        .context("Failed to send media key to virtual keyboard")?;
+                                }
+                                send = false;
+                            },
+                            KeysStep::Send => {
+                                send = true;
+                            },
+                        }
+                        if traced && ev.event_type() == EventType::SYNCHRONIZATION &&
+                            ev.code() == SynchronizationCode::SYN_REPORT.0 {
+                            tracer.changes(&log, mapper.player(), &changes);
+                            traced = false;
+                        }
+                    },
+                    SourceEvent::Active(active) => {
+                        time = None;
+                        set_grab(&mut source, grab, active)?;
+                        if !active {
+                            // Keys keep being tracked but nothing is sent until reactivated
+                            mapper.release(&mut changes);
+                            if let Some((split_mapper, split_changes)) = &mut split {
+                                split_mapper.release(split_changes);
+                            }
+                            if let Some((shadow_mapper, diff)) = &mut shadow {
+                                shadow_mapper.release(&mut shadow_changes);
+                                diff.shadow(&std::mem::take(&mut shadow_changes));
+                            }
                         }
+                        send = changes.len() > 0;
                     },
-                    _ => { },
+                }
+                if let (true, Some(stats)) = (send, &stats) {
+                    stats.presses(source_ref, &changes);
+                }
+                if let Some((split_mapper, split_changes)) = &mut split {
+                    if split_changes.len() > 0 {
+                        if let Some(stats) = &stats {
+                            stats.presses(split_ref, split_changes);
+                        }
+                        if output.send(Message::Contribution(Contribution {
+                            source: split_ref,
+                            player: split_mapper.player(),
+                            changes: std::mem::take(split_changes),
+                            time: time,
+                        })).is_err() {
+                            // Output stopped, shutting down
+                            break;
+                        }
+                    }
+                }
+                if let Some((_, diff)) = &mut shadow {
+                    diff.real(&changes);
+                    diff.compare(&log, source_ref);
+                }
+                if send && output.send(Message::Contribution(Contribution {
+                    source: source_ref,
+                    player: mapper.player(),
+                    changes: std::mem::take(&mut changes),
+                    time: time,
+                })).is_err() {
+                    // Output stopped, shutting down
+                    break;
                 }
             }
             return Ok(());
@@ -1,3 +1,29 @@
+pub mod active;
+pub mod battery;
+pub mod calibrate;
+pub mod control;
+pub mod crash;
 pub mod data;
+pub mod feedback;
+pub mod focus;
+pub mod grab;
+pub mod history;
+#[cfg(feature = "hidraw")]
+pub mod hidraw;
+pub mod identity;
+pub mod import;
 pub mod keys;
+pub mod output;
+pub mod output_log;
 pub mod pad;
+pub mod probe;
+pub mod profile;
+pub mod selftest;
+pub mod service;
+pub mod shadow;
+pub mod sleep;
+pub mod stats;
+pub mod tap;
+pub mod trace;
+#[cfg(feature = "web")]
+pub mod web;
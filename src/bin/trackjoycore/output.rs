@@ -0,0 +1,1480 @@
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+        VecDeque,
+    },
+    io::ErrorKind,
+    os::fd::{
+        AsRawFd,
+        RawFd,
+    },
+    sync::{
+        Arc,
+        Mutex,
+        MutexGuard,
+    },
+    time::{
+        Duration,
+        SystemTime,
+    },
+};
+use evdev::{
+    uinput::VirtualDevice,
+    AbsoluteAxisCode,
+    AbsoluteAxisEvent,
+    EventSummary,
+    EventType,
+    FFEffectKind,
+    InputEvent,
+    KeyCode,
+    UInputCode,
+};
+use futures::future::select_all;
+use loga::{
+    ea,
+    ResultContext,
+};
+use taskmanager::TaskManager;
+use tokio::{
+    io::unix::AsyncFd,
+    sync::{
+        watch,
+        Notify,
+    },
+    time::Instant,
+};
+use trackjoy::{
+    mapper::{
+        Change,
+        Changes,
+    },
+    AxisMixConfig,
+    AxisAdjustConfig,
+    AxisPolicy,
+    AxisSnapshotConfig,
+    ButtonPolicy,
+    DeviceRef,
+    EmergencyReleaseConfig,
+    NotchConfig,
+    OutputDevice,
+    PressOrderConfig,
+    ReleaseOrder,
+    RepeatConfig,
+    DEFAULT_EMERGENCY_SUSPEND,
+    DEFAULT_NOTCH_HYSTERESIS,
+    DEFAULT_PRESS_ORDER_DELAY_MS,
+    DEFAULT_RELEASE_ORDER,
+    DEFAULT_REPEAT_DELAY_MS,
+    DEFAULT_REPEAT_INTERVAL_MS,
+};
+use super::{
+    data::{
+        DEST_HALF,
+        DEST_MAX,
+    },
+    output_log::{
+        code_name,
+        OutputLog,
+    },
+    trace::Tracer,
+};
+
+/// Output changes from a source, sent at the end of each source event frame.
+pub struct Contribution {
+    pub source: DeviceRef,
+    /// The player (virtual device) the source is controlling. If this changes,
+    /// everything the source holds moves to the new player.
+    pub player: usize,
+    pub changes: Changes,
+    /// The kernel timestamp of the source event that ended the frame, none for changes
+    /// made over time (ex: stick ramps) or on deactivation.
+    pub time: Option<SystemTime>,
+}
+
+pub enum Message {
+    Contribution(Contribution),
+    /// The sources were stopped; forget and release everything they held.
+    Reset,
+    /// Sources are set up. Replaces the virtual devices if new ones are provided and
+    /// sets how outputs are combined.
+    Configure(Option<Dests>, Arbitration),
+    /// The active profile changed: 0 for the top level settings, otherwise the profile
+    /// index plus 1. Back to 0 on `Reset`.
+    Profile(usize),
+}
+
+/// The virtual devices written to.
+pub struct Dests {
+    /// Gamepad per player
+    pub players: Vec<DestDevice>,
+    /// The gamepads were created with rumble support, see `RUMBLE_EFFECTS`
+    pub rumble: bool,
+    /// Shared by all players, for buttons routed to `keyboard`
+    pub keyboard: Option<DestDevice>,
+}
+
+/// Creates the virtual device again if it stops working, ex: it was removed by
+/// reloading the uinput module.
+pub type Rebuild = Arc<dyn Fn() -> Result<VirtualDevice, loga::Error> + Send + Sync>;
+
+/// Where the events for a virtual device go.
+pub enum DestDevice {
+    Uinput(VirtualDevice, Rebuild),
+    /// Printed to stdout instead, for `trackjoy echo`.
+    Echo,
+}
+
+impl DestDevice {
+    fn emit(&mut self, name: &str, events: &[InputEvent]) -> std::io::Result<()> {
+        match self {
+            DestDevice::Uinput(device, _) => {
+                return device.emit(events);
+            },
+            DestDevice::Echo => {
+                for ev in events {
+                    if ev.event_type() == EventType::SYNCHRONIZATION {
+                        continue;
+                    }
+                    println!("{} {} {}", name, code_name(ev), ev.value());
+                }
+                return Ok(());
+            },
+        }
+    }
+}
+
+/// Contributions arriving within this long of the first one are written together,
+/// so simultaneous changes from several sources reach the game in one report.
+const BATCH_WINDOW: Duration = Duration::from_millis(1);
+
+/// How often smoothed mix inputs are updated while they catch up.
+const SMOOTH_TICK: Duration = Duration::from_millis(8);
+
+/// Smoothed mix inputs this close to the source value are snapped to it.
+const SMOOTH_EPSILON: f32 = 0.001;
+
+/// Rumble effects a game can upload to a virtual gamepad at once. Effect ids are
+/// below this, higher force feedback codes are settings like `FF_GAIN`.
+pub const RUMBLE_EFFECTS: u16 = 16;
+
+/// Past this many waiting messages, older contributions are merged into newer ones
+/// from the same source, dropping the changes that were overwritten. This keeps a
+/// stalled output (ex: a blocked virtual device write) from building up seconds of
+/// lag.
+const QUEUE_LIMIT: usize = 256;
+
+/// How often to warn about dropped changes while they're being dropped.
+const DROPPED_WARN_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Virtual device writes interrupted by a signal (`EINTR`) are retried right away
+/// this many times, then held like writes that would block (`EAGAIN`).
+const EMIT_RETRIES: usize = 3;
+
+/// Wait before retrying held virtual device writes.
+const EMIT_RETRY_DELAY: Duration = Duration::from_millis(1);
+
+struct QueueState {
+    messages: VecDeque<Message>,
+    senders: usize,
+    receiver: bool,
+    /// Changes dropped since last checked
+    dropped: u64,
+}
+
+struct Queue {
+    state: Mutex<QueueState>,
+    notify: Notify,
+}
+
+impl Queue {
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        return self.state.lock().unwrap_or_else(|e| e.into_inner());
+    }
+}
+
+/// Sends messages to the output task, see `channel`.
+pub struct OutputTx(Arc<Queue>);
+
+pub struct OutputRx(Arc<Queue>);
+
+/// The queue to the output task. Like an unbounded channel, but limited by
+/// merging contributions, see `QUEUE_LIMIT`.
+pub fn channel() -> (OutputTx, OutputRx) {
+    let queue = Arc::new(Queue {
+        state: Mutex::new(QueueState {
+            messages: VecDeque::new(),
+            senders: 1,
+            receiver: true,
+            dropped: 0,
+        }),
+        notify: Notify::new(),
+    });
+    return (OutputTx(queue.clone()), OutputRx(queue));
+}
+
+/// Merge contribution `old` into the following `new` from the same source, keeping
+/// only the last change of each button and axis. Returns the number of changes
+/// dropped.
+fn merge_contribution(old: Contribution, new: &mut Contribution) -> u64 {
+    let mut changes = old.changes;
+    changes.extend(new.changes.drain(..));
+    let mut dropped = 0;
+    for (i, change) in changes.iter().enumerate() {
+        let overwritten = changes[i + 1..].iter().any(|later| match (change, later) {
+            (Change::Button(a, _), Change::Button(b, _)) => a == b,
+            (Change::Axis(a, _), Change::Axis(b, _)) => a == b,
+            _ => false,
+        });
+        if overwritten {
+            dropped += 1;
+        } else {
+            new.changes.push(*change);
+        }
+    }
+    return dropped;
+}
+
+impl OutputTx {
+    /// Fails if the output task stopped.
+    pub fn send(&self, message: Message) -> Result<(), Message> {
+        let mut guard = self.0.lock();
+        let state = &mut *guard;
+        if !state.receiver {
+            return Err(message);
+        }
+        state.messages.push_back(message);
+        if state.messages.len() > QUEUE_LIMIT {
+            // Merge the oldest contribution that has a newer one from the same source and
+            // player (so player changes are still seen), without moving changes past other
+            // messages
+            let messages = &mut state.messages;
+            let mut merge = None;
+            for (i, m) in messages.iter().enumerate() {
+                let Message::Contribution(old) = m else {
+                    continue;
+                };
+                let next = messages.iter().skip(i + 1).map_while(|m| match m {
+                    Message::Contribution(new) => Some(new),
+                    _ => None,
+                }).position(|new| new.source == old.source && new.player == old.player);
+                if let Some(next) = next {
+                    merge = Some((i, i + next));
+                    break;
+                }
+            }
+            if let Some((i, next)) = merge {
+                // After removing `i`, the newer contribution is at `next`
+                if let Some(Message::Contribution(old)) = messages.remove(i) {
+                    if let Some(Message::Contribution(new)) = messages.get_mut(next) {
+                        state.dropped += merge_contribution(old, new);
+                    }
+                }
+            }
+        }
+        drop(guard);
+        self.0.notify.notify_one();
+        return Ok(());
+    }
+}
+
+impl Clone for OutputTx {
+    fn clone(&self) -> Self {
+        self.0.lock().senders += 1;
+        return OutputTx(self.0.clone());
+    }
+}
+
+impl Drop for OutputTx {
+    fn drop(&mut self) {
+        self.0.lock().senders -= 1;
+        self.0.notify.notify_one();
+    }
+}
+
+impl OutputRx {
+    /// The next message, `None` once there are no more senders.
+    pub async fn recv(&mut self) -> Option<Message> {
+        loop {
+            {
+                let mut state = self.0.lock();
+                if let Some(message) = state.messages.pop_front() {
+                    return Some(message);
+                }
+                if state.senders == 0 {
+                    return None;
+                }
+            }
+            self.0.notify.notified().await;
+        }
+    }
+
+    /// Changes dropped since the last call.
+    fn take_dropped(&mut self) -> u64 {
+        return std::mem::take(&mut self.0.lock().dropped);
+    }
+}
+
+impl Drop for OutputRx {
+    fn drop(&mut self) {
+        self.0.lock().receiver = false;
+    }
+}
+
+struct SourceState {
+    player: usize,
+    buttons: HashMap<KeyCode, bool>,
+    axes: HashMap<AbsoluteAxisCode, f32>,
+}
+
+/// How outputs are combined, from the config.
+pub struct Arbitration {
+    pub axis_mixes: Vec<AxisMixConfig>,
+    pub button_policies: HashMap<KeyCode, ButtonPolicy>,
+    pub axis_policies: HashMap<AbsoluteAxisCode, AxisPolicy>,
+    /// Buttons going somewhere other than the gamepad, per source
+    pub routes: HashMap<DeviceRef, HashMap<KeyCode, OutputDevice>>,
+    pub button_repeat: HashMap<KeyCode, RepeatConfig>,
+    pub axis_repeat: HashMap<AbsoluteAxisCode, RepeatConfig>,
+    pub axis_notches: HashMap<AbsoluteAxisCode, NotchConfig>,
+    pub press_order: Option<PressOrderConfig>,
+    pub axis_snapshots: Vec<AxisSnapshotConfig>,
+    pub dead_man: Option<KeyCode>,
+    pub emergency_release: Option<EmergencyReleaseConfig>,
+    /// Per profile, in config order
+    pub profile_axes: Vec<HashMap<AbsoluteAxisCode, AxisAdjustConfig>>,
+}
+
+struct Rules {
+    mixes: HashMap<AbsoluteAxisCode, HashMap<DeviceRef, f32>>,
+    /// Filter time constants of mix inputs with smoothing
+    smoothing: HashMap<(AbsoluteAxisCode, DeviceRef), Duration>,
+    button_policies: HashMap<KeyCode, ButtonPolicy>,
+    axis_policies: HashMap<AbsoluteAxisCode, AxisPolicy>,
+    routes: HashMap<DeviceRef, HashMap<KeyCode, OutputDevice>>,
+    button_repeat: HashMap<KeyCode, RepeatConfig>,
+    axis_repeat: HashMap<AbsoluteAxisCode, RepeatConfig>,
+    axis_notches: HashMap<AbsoluteAxisCode, NotchConfig>,
+    press_order: Option<PressOrderConfig>,
+    axis_snapshots: Vec<AxisSnapshotConfig>,
+    dead_man: Option<KeyCode>,
+    emergency_release: Option<EmergencyReleaseConfig>,
+    profile_axes: Vec<HashMap<AbsoluteAxisCode, AxisAdjustConfig>>,
+    /// See `Message::Profile`
+    profile: usize,
+}
+
+impl Rules {
+    fn new(arbitration: Arbitration) -> Rules {
+        let mut out = Rules {
+            mixes: HashMap::new(),
+            smoothing: HashMap::new(),
+            button_policies: arbitration.button_policies,
+            axis_policies: arbitration.axis_policies,
+            routes: arbitration.routes,
+            button_repeat: arbitration.button_repeat,
+            axis_repeat: arbitration.axis_repeat,
+            axis_notches: arbitration.axis_notches,
+            press_order: arbitration.press_order,
+            axis_snapshots: arbitration.axis_snapshots,
+            dead_man: arbitration.dead_man,
+            emergency_release: arbitration.emergency_release,
+            profile_axes: arbitration.profile_axes,
+            profile: 0,
+        };
+        for mix in arbitration.axis_mixes {
+            for input in &mix.inputs {
+                match input.smoothing_ms {
+                    Some(ms) if ms > 0 => {
+                        out.smoothing.insert((mix.axis, input.source), Duration::from_millis(ms));
+                    },
+                    _ => { },
+                }
+            }
+            out.mixes.insert(mix.axis, mix.inputs.into_iter().map(|i| (i.source, i.weight)).collect());
+        }
+        return out;
+    }
+
+    fn weight(&self, axis: &AbsoluteAxisCode, source: &DeviceRef) -> f32 {
+        match self.mixes.get(axis) {
+            Some(weights) => return weights.get(source).cloned().unwrap_or(0.),
+            None => return 1.,
+        }
+    }
+
+    fn route(&self, source: &DeviceRef, button: &KeyCode) -> OutputDevice {
+        return self
+            .routes
+            .get(source)
+            .and_then(|r| r.get(button))
+            .cloned()
+            .unwrap_or(OutputDevice::Gamepad);
+    }
+}
+
+/// The source that most recently changed each output.
+struct Writers {
+    buttons: HashMap<KeyCode, DeviceRef>,
+    axes: HashMap<AbsoluteAxisCode, DeviceRef>,
+}
+
+/// A virtual device written to.
+struct Dest {
+    device: DestDevice,
+    /// For logs, ex: `player0`
+    name: String,
+    log: loga::Log,
+    output_log: Option<OutputLog>,
+    tracer: Tracer,
+    /// Kernel timestamp of the newest source event whose changes haven't been written
+    /// yet, for the output log
+    source_time: Option<SystemTime>,
+    /// The device, nonblocking, if it supports rumble
+    rumble_fd: Option<AsyncFd<RawFd>>,
+    /// Events that couldn't be sent yet, the newest per code, sent before the next
+    /// events
+    unsent: Vec<InputEvent>,
+    /// When to try sending `unsent` again
+    next_retry: Option<Instant>,
+    /// Writes retried and held events replaced by newer ones, for the logs
+    retries: u64,
+    dropped: u64,
+    /// The device was recreated since this was last cleared, so it's back at its
+    /// initial state
+    rebuilt: bool,
+}
+
+impl Dest {
+    /// With `rumble` the device is watched for rumble events.
+    fn new(
+        device: DestDevice,
+        name: String,
+        log: &loga::Log,
+        output_log: &Option<OutputLog>,
+        tracer: &Tracer,
+        rumble: bool,
+    ) -> Result<Dest, loga::Error> {
+        let rumble_fd = match (&device, rumble) {
+            (DestDevice::Uinput(device, _), true) => Some(rumble_fd(device)?),
+            _ => None,
+        };
+        return Ok(Dest {
+            device: device,
+            name: name,
+            log: log.clone(),
+            output_log: output_log.clone(),
+            tracer: tracer.clone(),
+            source_time: None,
+            rumble_fd: rumble_fd,
+            unsent: vec![],
+            next_retry: None,
+            retries: 0,
+            dropped: 0,
+            rebuilt: false,
+        });
+    }
+
+    /// Replace the device after a write failed.
+    fn rebuild(&mut self, err: std::io::Error) -> Result<(), loga::Error> {
+        let DestDevice::Uinput(_, rebuild) = &self.device else {
+            return Err(err).context_with("Failed to send events to virtual device", ea!(device = self.name));
+        };
+        let rebuild = rebuild.clone();
+        self.log.warn_e(err.into(), "Failed to send events to virtual device, recreating it", ea!(device = self.name));
+
+        // Stop watching the old device before it's closed, so nothing reusing its fd
+        // number is affected
+        let rumble = self.rumble_fd.take().is_some();
+        let device = rebuild().context_with("Error recreating virtual device", ea!(device = self.name))?;
+        if rumble {
+            self.rumble_fd = Some(rumble_fd(&device)?);
+        }
+        self.device = DestDevice::Uinput(device, rebuild);
+        self.rebuilt = true;
+        return Ok(());
+    }
+
+    /// Keep an event to send later. Only the newest value per code is kept, so a
+    /// release isn't dropped while its press was sent.
+    fn hold(&mut self, ev: InputEvent) {
+        if let Some(i) = self.unsent.iter().position(|u| u.event_type() == ev.event_type() && u.code() == ev.code()) {
+            self.unsent.remove(i);
+            self.dropped += 1;
+        }
+        self.unsent.push(ev);
+    }
+
+    /// Send events, along with any held from earlier. If the device is busy the
+    /// events are held and sent with the next call, which the output task makes at
+    /// `next_retry` if nothing else is written first.
+    fn emit(&mut self, events: &[InputEvent]) -> Result<(), loga::Error> {
+        let mut attempt = 0;
+        let mut rebuilt = false;
+        let mut events = events;
+        let combined;
+        let resending = !self.unsent.is_empty();
+        if resending {
+            for ev in events {
+                self.hold(*ev);
+            }
+            combined = std::mem::take(&mut self.unsent);
+            events = &combined;
+        }
+        self.next_retry = None;
+        if events.is_empty() {
+            return Ok(());
+        }
+        loop {
+            match self.device.emit(&self.name, events) {
+                Ok(_) => {
+                    if resending {
+                        self.log.info(
+                            "Virtual device accepting events again",
+                            ea!(device = self.name, retries = self.retries, dropped = self.dropped),
+                        );
+                    }
+                    break;
+                },
+                Err(e) if e.kind() == ErrorKind::Interrupted && attempt < EMIT_RETRIES => {
+                    self.retries += 1;
+                    attempt += 1;
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => {
+                    self.retries += 1;
+                    if !resending {
+                        self.log.warn(
+                            "Virtual device is busy, holding events until it accepts them",
+                            ea!(device = self.name, err = e, retries = self.retries, dropped = self.dropped),
+                        );
+                    }
+                    for ev in events {
+                        self.hold(*ev);
+                    }
+                    self.next_retry = Some(Instant::now() + EMIT_RETRY_DELAY);
+                    return Ok(());
+                },
+                Err(e) => {
+                    if rebuilt {
+                        return Err(e).context_with("Failed to send events to virtual device", ea!(device = self.name));
+                    }
+                    self.rebuild(e)?;
+                    rebuilt = true;
+                },
+            }
+        }
+        let source_time = self.source_time.take();
+        if let Some(output_log) = &self.output_log {
+            output_log.write(&self.name, events, source_time);
+        }
+        self.tracer.sent(&self.log, &self.name, events);
+        return Ok(());
+    }
+}
+
+struct PlayerState {
+    dest: Dest,
+    last_buttons: HashMap<KeyCode, bool>,
+    last_axes: HashMap<AbsoluteAxisCode, i32>,
+    /// When each held repeating output was first held
+    button_held_since: HashMap<KeyCode, Instant>,
+    axis_held_since: HashMap<AbsoluteAxisCode, Instant>,
+    /// When a repeating output next changes
+    next_repeat: Option<Instant>,
+    /// Current position of notched axes
+    axis_notch: HashMap<AbsoluteAxisCode, usize>,
+    /// Button changes waiting for the `press_order` delay
+    queued: VecDeque<InputEvent>,
+    /// When the next queued button change can be sent
+    next_queued: Option<Instant>,
+    /// Smoothed mix inputs, and when they were last updated
+    smoothed: HashMap<(DeviceRef, AbsoluteAxisCode), (f32, Instant)>,
+    /// When smoothed mix inputs next need updating
+    next_smooth: Option<Instant>,
+    /// Stored axis positions, by the axes of the snapshot
+    snapshots: HashMap<Vec<AbsoluteAxisCode>, Vec<f32>>,
+    /// Snapshot store buttons held at the last write, to store once per press
+    snapshot_store_held: HashSet<KeyCode>,
+    /// Uploaded rumble effects: strength (0-1) and length (zero for until stopped)
+    rumble_effects: HashMap<i16, (f32, Duration)>,
+    /// Playing rumble effects and when they end
+    rumble_playing: HashMap<i16, Option<Instant>>,
+    /// When a playing rumble effect next ends
+    next_rumble_end: Option<Instant>,
+}
+
+/// What woke the output task.
+enum Wake {
+    Message(Message),
+    /// A player's gamepad has rumble events from the game
+    Rumble(usize),
+}
+
+struct KeyboardState {
+    dest: Dest,
+    last_buttons: HashMap<KeyCode, bool>,
+}
+
+/// Buffers reused between writes.
+struct Scratch {
+    buttons: HashMap<KeyCode, bool>,
+    axes: HashMap<AbsoluteAxisCode, f32>,
+    events: Vec<InputEvent>,
+}
+
+/// Whether a repeating output is on after being held this long, and how long until
+/// that changes.
+fn repeat_phase(config: &RepeatConfig, held: Duration) -> (bool, Duration) {
+    let delay = Duration::from_millis(config.delay_ms.unwrap_or(DEFAULT_REPEAT_DELAY_MS));
+    let interval = Duration::from_millis(config.interval_ms.unwrap_or(DEFAULT_REPEAT_INTERVAL_MS).max(2));
+    if held < delay {
+        return (true, delay - held);
+    }
+    let phase = Duration::from_nanos(((held - delay).as_nanos() % interval.as_nanos()) as u64);
+    let half = interval / 2;
+    if phase < half {
+        return (false, half - phase);
+    } else {
+        return (true, interval - phase);
+    }
+}
+
+/// The notch for an axis value (unit space), staying at `current` unless the value
+/// is far enough past the middle between notches.
+fn notch(config: &NotchConfig, current: Option<usize>, v: f32) -> usize {
+    let last = config.notches.max(2) - 1;
+    let pos = (v.clamp(-1., 1.) + 1.) / 2. * last as f32;
+    if let Some(current) = current {
+        if (pos - current as f32).abs() <= 0.5 + config.hysteresis.unwrap_or(DEFAULT_NOTCH_HYSTERESIS) {
+            return current;
+        }
+    }
+    return (pos.round() as usize).min(last);
+}
+
+/// Sort key for a button change: releases first, then by the configured order.
+fn press_order_key(config: &PressOrderConfig, ev: &InputEvent) -> (bool, i64) {
+    let rank =
+        config.order.iter().position(|k| k.0 == ev.code()).unwrap_or(config.order.len()) as i64 * 0x10000 +
+            ev.code() as i64;
+    let pressed = ev.value() != 0;
+    if !pressed && config.release_order.unwrap_or(DEFAULT_RELEASE_ORDER) == ReleaseOrder::Reverse {
+        return (false, -rank);
+    }
+    return (pressed, rank);
+}
+
+/// Send the next button change waiting for the `press_order` delay, if any.
+fn write_queued(state: &mut PlayerState, rules: &Rules, now: Instant) -> Result<(), loga::Error> {
+    let Some(ev) = state.queued.pop_front() else {
+        state.next_queued = None;
+        return Ok(());
+    };
+    state.dest.emit(&[ev])?;
+    let delay = rules.press_order.as_ref().and_then(|o| o.delay_ms).unwrap_or(DEFAULT_PRESS_ORDER_DELAY_MS);
+    state.next_queued = Some(now + Duration::from_millis(delay));
+    return Ok(());
+}
+
+/// A source's axis value after its mix input smoothing, if any.
+fn smooth(
+    state: &mut PlayerState,
+    rules: &Rules,
+    source: DeviceRef,
+    axis: AbsoluteAxisCode,
+    v: f32,
+    now: Instant,
+) -> f32 {
+    let Some(time_constant) = rules.smoothing.get(&(axis, source)) else {
+        return v;
+    };
+    let smoothed = state.smoothed.entry((source, axis)).or_insert((v, now));
+    let dt = now.saturating_duration_since(smoothed.1).as_secs_f32();
+    smoothed.0 += (v - smoothed.0) * (1. - (-dt / time_constant.as_secs_f32()).exp());
+    smoothed.1 = now;
+    if (v - smoothed.0).abs() <= SMOOTH_EPSILON {
+        smoothed.0 = v;
+    } else {
+        // Keep updating until it catches up
+        let next = now + SMOOTH_TICK;
+        state.next_smooth = Some(state.next_smooth.map(|n| n.min(next)).unwrap_or(next));
+    }
+    return smoothed.0;
+}
+
+/// Make the gamepad nonblocking and watch it for rumble events.
+fn rumble_fd(dest: &VirtualDevice) -> Result<AsyncFd<RawFd>, loga::Error> {
+    let fd = dest.as_raw_fd();
+    let flags = unsafe {
+        libc::fcntl(fd, libc::F_GETFL)
+    };
+    if flags < 0 || unsafe {
+        libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK)
+    } < 0 {
+        return Err(
+            loga::err_with(
+                "Error making virtual device nonblocking",
+                ea!(err = std::io::Error::last_os_error()),
+            ),
+        );
+    }
+    return Ok(AsyncFd::new(fd).context("Error watching virtual device for rumble")?);
+}
+
+/// Wait for a player's gamepad to have rumble events, never if there are none with
+/// rumble.
+async fn rumble_ready(players: &[PlayerState]) -> usize {
+    let waits = players.iter().enumerate().filter_map(|(i, p)| {
+        let fd = p.dest.rumble_fd.as_ref()?;
+        return Some(Box::pin(async move {
+            _ = fd.readable().await;
+            i
+        }));
+    }).collect::<Vec<_>>();
+    if waits.is_empty() {
+        return std::future::pending().await;
+    }
+    return select_all(waits).await.0;
+}
+
+/// Wait for the next message or rumble events, `None` if the senders are gone.
+async fn next_wake(messages: &mut OutputRx, players: &[PlayerState]) -> Option<Wake> {
+    tokio::select!{
+        m = messages.recv() => {
+            return m.map(Wake::Message);
+        },
+        player = rumble_ready(players) => {
+            return Some(Wake::Rumble(player));
+        }
+    }
+}
+
+/// Handle the game uploading, erasing and playing rumble effects on a gamepad.
+async fn read_rumble(state: &mut PlayerState, now: Instant) -> Result<(), loga::Error> {
+    let (Some(fd), DestDevice::Uinput(device, _)) = (&state.dest.rumble_fd, &mut state.dest.device) else {
+        return Ok(());
+    };
+    let mut ready = fd.readable().await.context("Error waiting for virtual device events")?;
+    loop {
+        let events = match device.fetch_events() {
+            Ok(e) => e.collect::<Vec<_>>(),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                ready.clear_ready();
+                break;
+            },
+            Err(e) => {
+                return Err(e).context("Error reading virtual device events");
+            },
+        };
+        for ev in events {
+            match ev.destructure() {
+                EventSummary::UInput(ev, UInputCode::UI_FF_UPLOAD, _) => {
+                    let mut upload = device.process_ff_upload(ev).context("Error receiving rumble effect")?;
+                    let effect = upload.effect();
+                    let strength = match effect.kind {
+                        FFEffectKind::Rumble { strong_magnitude, weak_magnitude } => {
+                            strong_magnitude.max(weak_magnitude) as f32 / u16::MAX as f32
+                        },
+                        _ => 0.,
+                    };
+                    state
+                        .rumble_effects
+                        .insert(upload.effect_id(), (strength, Duration::from_millis(effect.replay.length as u64)));
+                    upload.set_retval(0);
+                },
+                EventSummary::UInput(ev, UInputCode::UI_FF_ERASE, _) => {
+                    let mut erase = device.process_ff_erase(ev).context("Error erasing rumble effect")?;
+                    let id = erase.effect_id() as i16;
+                    state.rumble_effects.remove(&id);
+                    state.rumble_playing.remove(&id);
+                    erase.set_retval(0);
+                },
+                _ if ev.event_type() == EventType::FORCEFEEDBACK && ev.code() < RUMBLE_EFFECTS => {
+                    let id = ev.code() as i16;
+                    match state.rumble_effects.get(&id) {
+                        Some((_, length)) if ev.value() > 0 => {
+                            state.rumble_playing.insert(id, if length.is_zero() {
+                                None
+                            } else {
+                                Some(now + *length * ev.value() as u32)
+                            });
+                        },
+                        _ => {
+                            state.rumble_playing.remove(&id);
+                        },
+                    }
+                },
+                _ => { },
+            }
+        }
+    }
+    return Ok(());
+}
+
+/// Drop ended rumble effects and send the strength of what's playing on each
+/// gamepad.
+fn publish_rumble(players: &mut [PlayerState], rumble: &watch::Sender<Vec<f32>>, now: Instant) {
+    let strengths = players.iter_mut().map(|state| {
+        state.rumble_playing.retain(|_, end| end.map(|e| e > now).unwrap_or(true));
+        state.next_rumble_end = state.rumble_playing.values().flatten().min().cloned();
+        return state
+            .rumble_playing
+            .keys()
+            .filter_map(|id| state.rumble_effects.get(id))
+            .map(|(strength, _)| *strength)
+            .fold(0., f32::max);
+    }).collect::<Vec<_>>();
+    rumble.send_if_modified(|r| {
+        if *r == strengths {
+            return false;
+        }
+        *r = strengths;
+        return true;
+    });
+}
+
+/// Unit space (-1 .. 1) to virtual device axis value.
+fn dest_value(v: f32) -> i32 {
+    return ((v * DEST_HALF as f32 + DEST_HALF as f32) as i32).clamp(0, DEST_MAX);
+}
+
+/// Combine what all the sources of a player hold according to the output policies
+/// and send the differences from the last write.
+fn write(
+    players: &mut [PlayerState],
+    sources: &HashMap<DeviceRef, SourceState>,
+    rules: &Rules,
+    writers: &Writers,
+    scratch: &mut Scratch,
+    player: usize,
+    now: Instant,
+) -> Result<(), loga::Error> {
+    let Some(state) = players.get_mut(player) else {
+        // Virtual devices not created yet, will be written once they are
+        return Ok(());
+    };
+    let buttons = &mut scratch.buttons;
+    let axes = &mut scratch.axes;
+    buttons.clear();
+    axes.clear();
+    state.next_smooth = None;
+    for (source_ref, source) in sources {
+        if source.player != player {
+            continue;
+        }
+        for (k, on) in &source.buttons {
+            if rules.route(source_ref, k) != OutputDevice::Gamepad {
+                continue;
+            }
+            *buttons.entry(*k).or_insert(false) |= *on;
+        }
+        for (axis, v) in &source.axes {
+            let v = smooth(state, rules, *source_ref, *axis, *v, now);
+            *axes.entry(*axis).or_insert(0f32) += v * rules.weight(axis, source_ref);
+        }
+    }
+    for (k, policy) in &rules.button_policies {
+        match policy {
+            ButtonPolicy::Any => { },
+            ButtonPolicy::LastWriter => {
+                let on = writers.buttons.get(k).and_then(|w| sources.get(w)).and_then(|s| if s.player == player {
+                    s.buttons.get(k).cloned()
+                } else {
+                    None
+                });
+                buttons.insert(*k, on.unwrap_or(false));
+            },
+        }
+    }
+    for (axis, policy) in &rules.axis_policies {
+        match policy {
+            AxisPolicy::Sum => { },
+            AxisPolicy::LastWriter => {
+                let v = writers.axes.get(axis).and_then(|w| sources.get(w).map(|s| (w, s))).and_then(|(w, s)| {
+                    if s.player == player {
+                        s.axes.get(axis).map(|v| smooth(state, rules, *w, *axis, *v, now) * rules.weight(axis, w))
+                    } else {
+                        None
+                    }
+                });
+                axes.insert(*axis, v.unwrap_or(0.));
+            },
+        }
+    }
+
+    // Release everything unless the dead man button is held
+    if let Some(k) = rules.dead_man {
+        if !buttons.remove(&k).unwrap_or(false) {
+            buttons.clear();
+            axes.clear();
+        }
+    }
+
+    // Store and recall axis positions
+    for snapshot in &rules.axis_snapshots {
+        let store = buttons.remove(&snapshot.store).unwrap_or(false);
+        let recall = buttons.remove(&snapshot.recall).unwrap_or(false);
+        if store && state.snapshot_store_held.insert(snapshot.store) {
+            state
+                .snapshots
+                .insert(
+                    snapshot.axes.clone(),
+                    snapshot.axes.iter().map(|a| axes.get(a).cloned().unwrap_or(0.)).collect(),
+                );
+        } else if !store {
+            state.snapshot_store_held.remove(&snapshot.store);
+        }
+        if recall {
+            if let Some(values) = state.snapshots.get(&snapshot.axes) {
+                for (axis, v) in snapshot.axes.iter().zip(values) {
+                    axes.insert(*axis, *v);
+                }
+            }
+        }
+    }
+
+    // Snap notched axes
+    for (axis, config) in &rules.axis_notches {
+        let v = axes.get(axis).cloned().unwrap_or(0.);
+        let i = notch(config, state.axis_notch.get(axis).cloned(), v);
+        state.axis_notch.insert(*axis, i);
+        axes.insert(*axis, i as f32 / (config.notches.max(2) - 1) as f32 * 2. - 1.);
+    }
+
+    // Profile adjustments
+    if let Some(adjust) = rules.profile.checked_sub(1).and_then(|i| rules.profile_axes.get(i)) {
+        for (axis, adjust) in adjust {
+            let Some(v) = axes.get_mut(axis) else {
+                continue;
+            };
+            if adjust.invert {
+                *v = -*v;
+            }
+            *v = v.max(adjust.min.unwrap_or(-1.)).min(adjust.max.unwrap_or(1.));
+        }
+    }
+
+    // Pulse held repeating outputs
+    state.next_repeat = None;
+    for (k, repeat) in &rules.button_repeat {
+        if !buttons.get(k).cloned().unwrap_or(false) {
+            state.button_held_since.remove(k);
+            continue;
+        }
+        let since = *state.button_held_since.entry(*k).or_insert(now);
+        let (on, next) = repeat_phase(repeat, now - since);
+        buttons.insert(*k, on);
+        state.next_repeat = Some(state.next_repeat.map(|n| n.min(now + next)).unwrap_or(now + next));
+    }
+    for (axis, repeat) in &rules.axis_repeat {
+        if dest_value(axes.get(axis).cloned().unwrap_or(0.)) == DEST_HALF {
+            state.axis_held_since.remove(axis);
+            continue;
+        }
+        let since = *state.axis_held_since.entry(*axis).or_insert(now);
+        let (on, next) = repeat_phase(repeat, now - since);
+        if !on {
+            axes.insert(*axis, 0.);
+        }
+        state.next_repeat = Some(state.next_repeat.map(|n| n.min(now + next)).unwrap_or(now + next));
+    }
+    let dest_events = &mut scratch.events;
+    dest_events.clear();
+
+    // Anything no longer held by a source on this player is released
+    for (k, last_on) in &mut state.last_buttons {
+        let on = buttons.get(k).cloned().unwrap_or(false);
+        if on != *last_on {
+            dest_events.push(InputEvent::new(EventType::KEY.0, k.0, on as i32));
+            *last_on = on;
+        }
+    }
+    for (k, on) in buttons.iter() {
+        if !state.last_buttons.contains_key(k) {
+            if *on {
+                dest_events.push(InputEvent::new(EventType::KEY.0, k.0, 1));
+            }
+            state.last_buttons.insert(*k, *on);
+        }
+    }
+
+    // Order simultaneous button changes, spacing them out if there's a delay
+    let mut write_next_queued = false;
+    if let Some(order) = &rules.press_order {
+        dest_events.sort_by_key(|e| press_order_key(order, e));
+        if order.delay_ms.unwrap_or(DEFAULT_PRESS_ORDER_DELAY_MS) > 0 && dest_events.len() > 0 {
+            state.queued.extend(dest_events.drain(..));
+            write_next_queued = state.next_queued.is_none();
+        }
+    }
+    for (axis, last_v) in &mut state.last_axes {
+        let v = dest_value(axes.get(axis).cloned().unwrap_or(0.));
+        if v != *last_v {
+            dest_events.push(*AbsoluteAxisEvent::new(*axis, v));
+            *last_v = v;
+        }
+    }
+    for (axis, v) in axes.iter() {
+        if !state.last_axes.contains_key(axis) {
+            let v = dest_value(*v);
+            if v != DEST_HALF {
+                dest_events.push(*AbsoluteAxisEvent::new(*axis, v));
+            }
+            state.last_axes.insert(*axis, v);
+        }
+    }
+    if dest_events.len() > 0 {
+        state.dest.emit(dest_events.as_slice())?;
+    }
+    if write_next_queued {
+        write_queued(state, rules, now)?;
+    }
+    if std::mem::take(&mut state.dest.rebuilt) {
+        // New device, send everything again
+        state.last_buttons.clear();
+        state.last_axes.clear();
+        state.rumble_effects.clear();
+        state.rumble_playing.clear();
+        return write(players, sources, rules, writers, scratch, player, now);
+    }
+    return Ok(());
+}
+
+/// Send the differences in buttons routed to the keyboard from all sources,
+/// regardless of player.
+fn write_keyboard(
+    keyboard: &mut Option<KeyboardState>,
+    sources: &HashMap<DeviceRef, SourceState>,
+    rules: &Rules,
+    scratch: &mut Scratch,
+) -> Result<(), loga::Error> {
+    let Some(state) = keyboard else {
+        return Ok(());
+    };
+    let buttons = &mut scratch.buttons;
+    buttons.clear();
+    for (source_ref, source) in sources {
+        for (k, on) in &source.buttons {
+            if rules.route(source_ref, k) != OutputDevice::Keyboard {
+                continue;
+            }
+            *buttons.entry(*k).or_insert(false) |= *on;
+        }
+    }
+    if let Some(k) = rules.dead_man {
+        if !sources.values().any(|s| s.buttons.get(&k).cloned().unwrap_or(false)) {
+            buttons.clear();
+        }
+    }
+    let dest_events = &mut scratch.events;
+    dest_events.clear();
+    for (k, last_on) in &mut state.last_buttons {
+        let on = buttons.get(k).cloned().unwrap_or(false);
+        if on != *last_on {
+            dest_events.push(InputEvent::new(EventType::KEY.0, k.0, on as i32));
+            *last_on = on;
+        }
+    }
+    for (k, on) in buttons.iter() {
+        if !state.last_buttons.contains_key(k) {
+            if *on {
+                dest_events.push(InputEvent::new(EventType::KEY.0, k.0, 1));
+            }
+            state.last_buttons.insert(*k, *on);
+        }
+    }
+    if dest_events.len() > 0 {
+        state.dest.emit(dest_events.as_slice())?;
+    }
+    if std::mem::take(&mut state.dest.rebuilt) {
+        // New device, send everything again
+        state.last_buttons.clear();
+        return write_keyboard(keyboard, sources, rules, scratch);
+    }
+    return Ok(());
+}
+
+/// Write the players with batched changes, ending the batch window.
+fn flush_batch(
+    players: &mut [PlayerState],
+    keyboard: &mut Option<KeyboardState>,
+    sources: &HashMap<DeviceRef, SourceState>,
+    rules: &Rules,
+    writers: &Writers,
+    scratch: &mut Scratch,
+    dirty: &mut HashSet<usize>,
+    now: Instant,
+) -> Result<(), loga::Error> {
+    for player in dirty.drain() {
+        write(players, sources, rules, writers, scratch, player, now)?;
+    }
+    write_keyboard(keyboard, sources, rules, scratch)?;
+
+    // Later writes (repeats, queued presses) aren't for these source events
+    for state in players {
+        state.dest.source_time = None;
+    }
+    if let Some(state) = keyboard {
+        state.dest.source_time = None;
+    }
+    return Ok(());
+}
+
+/// Start the task that owns the virtual devices and merges source contributions
+/// into them. Nothing is written until the virtual devices are provided.
+///
+/// With `linger` the virtual devices are kept (neutral) for that long after
+/// shutdown starts, so games don't see the controller disconnect during brief
+/// interruptions.
+///
+/// With `output_log` every event sent is also written there. `rumble` gets the
+/// strength (0-1) of the game's rumble per player, if the gamepads support it.
+/// Events for outputs changed by a traced input are logged with `tracer`.
+pub fn build(
+    tm: &TaskManager,
+    log: &loga::Log,
+    mut messages: OutputRx,
+    linger: Option<Duration>,
+    output_log: Option<OutputLog>,
+    rumble: watch::Sender<Vec<f32>>,
+    tracer: Tracer,
+) {
+    tm.critical_task::<_, loga::Error>({
+        let tm = tm.clone();
+        let log = log.clone();
+        async move {
+            let mut dropped_total = 0u64;
+            let mut dropped_warned: Option<Instant> = None;
+            let mut rules = Rules::new(Arbitration {
+                axis_mixes: vec![],
+                button_policies: HashMap::new(),
+                axis_policies: HashMap::new(),
+                routes: HashMap::new(),
+                button_repeat: HashMap::new(),
+                axis_repeat: HashMap::new(),
+                axis_notches: HashMap::new(),
+                press_order: None,
+                axis_snapshots: vec![],
+                dead_man: None,
+                emergency_release: None,
+                profile_axes: vec![],
+            });
+            let mut sources = HashMap::new();
+            let mut writers = Writers {
+                buttons: HashMap::new(),
+                axes: HashMap::new(),
+            };
+            let mut players: Vec<PlayerState> = vec![];
+            let mut keyboard: Option<KeyboardState> = None;
+
+            // After an emergency release, sources are ignored until this time
+            let mut suspended_until: Option<Instant> = None;
+            let mut scratch = Scratch {
+                buttons: HashMap::new(),
+                axes: HashMap::new(),
+                events: vec![],
+            };
+
+            // Players with changes not written yet, and when they must be written by
+            let mut dirty = HashSet::new();
+            let mut deadline = None;
+
+            // Buttons changed in the current batch
+            let mut batch_buttons = HashSet::new();
+            loop {
+                // Repeating outputs, spaced out button changes, rumble ending
+                let timer =
+                    players
+                        .iter()
+                        .flat_map(
+                            |p| [p.next_repeat, p.next_queued, p.next_smooth, p.next_rumble_end, p.dest.next_retry],
+                        )
+                        .chain(keyboard.iter().map(|k| k.dest.next_retry))
+                        .flatten()
+                        .min();
+                let wake = match (deadline, timer) {
+                    (Some(a), Some(b)) => Some(a.min(b)),
+                    (a, b) => a.or(b),
+                };
+                let message = match wake {
+                    None => match tm.if_alive(next_wake(&mut messages, &players)).await {
+                        Some(Some(x)) => x,
+                        _ => {
+                            break;
+                        },
+                    },
+                    Some(at) => match tm.if_alive(
+                        tokio::time::timeout_at(at, next_wake(&mut messages, &players)),
+                    ).await {
+                        Some(Ok(Some(x))) => x,
+                        Some(Err(_)) => {
+                            let now = Instant::now();
+                            if deadline.map(|d| d <= now).unwrap_or(false) {
+                                // Batch window over
+                                flush_batch(
+                                    &mut players,
+                                    &mut keyboard,
+                                    &sources,
+                                    &rules,
+                                    &writers,
+                                    &mut scratch,
+                                    &mut dirty,
+                                    now,
+                                )?;
+                                batch_buttons.clear();
+                                deadline = None;
+                            }
+
+                            // Repeating outputs changing, smoothed inputs catching up
+                            for player in 0 .. players.len() {
+                                let state = &players[player];
+                                if [state.next_repeat, state.next_smooth].into_iter().flatten().any(|r| r <= now) {
+                                    write(&mut players, &sources, &rules, &writers, &mut scratch, player, now)?;
+                                }
+                            }
+
+                            // Spaced out button changes
+                            for state in &mut players {
+                                if state.next_queued.map(|r| r <= now).unwrap_or(false) {
+                                    write_queued(state, &rules, now)?;
+                                }
+                            }
+
+                            // Held writes to busy virtual devices
+                            for player in 0 .. players.len() {
+                                let dest = &mut players[player].dest;
+                                if dest.next_retry.map(|r| r <= now).unwrap_or(false) {
+                                    dest.emit(&[])?;
+                                    if dest.rebuilt {
+                                        write(&mut players, &sources, &rules, &writers, &mut scratch, player, now)?;
+                                    }
+                                }
+                            }
+                            if let Some(state) = &mut keyboard {
+                                if state.dest.next_retry.map(|r| r <= now).unwrap_or(false) {
+                                    state.dest.emit(&[])?;
+                                    if state.dest.rebuilt {
+                                        write_keyboard(&mut keyboard, &sources, &rules, &mut scratch)?;
+                                    }
+                                }
+                            }
+
+                            // Rumble ending
+                            if players.iter().any(|p| p.next_rumble_end.map(|r| r <= now).unwrap_or(false)) {
+                                publish_rumble(&mut players, &rumble, now);
+                            }
+                            continue;
+                        },
+                        _ => {
+                            break;
+                        },
+                    },
+                };
+                let dropped = messages.take_dropped();
+                if dropped > 0 {
+                    dropped_total += dropped;
+                    let now = Instant::now();
+                    if dropped_warned.map(|w| now - w >= DROPPED_WARN_INTERVAL).unwrap_or(true) {
+                        log.warn(
+                            "Output is falling behind, dropped overwritten source changes",
+                            ea!(dropped_total = dropped_total),
+                        );
+                        dropped_warned = Some(now);
+                    }
+                }
+                let message = match message {
+                    Wake::Message(m) => m,
+                    Wake::Rumble(player) => {
+                        let now = Instant::now();
+                        read_rumble(&mut players[player], now).await?;
+                        publish_rumble(&mut players, &rumble, now);
+                        continue;
+                    },
+                };
+                let contribution = match message {
+                    Message::Contribution(c) => c,
+                    Message::Reset => {
+                        sources.clear();
+                        rules.profile = 0;
+                        writers.buttons.clear();
+                        writers.axes.clear();
+                        dirty.clear();
+                        batch_buttons.clear();
+                        deadline = None;
+                        for player in 0 .. players.len() {
+                            write(&mut players, &sources, &rules, &writers, &mut scratch, player, Instant::now())?;
+                        }
+                        write_keyboard(&mut keyboard, &sources, &rules, &mut scratch)?;
+                        continue;
+                    },
+                    Message::Configure(dests, arbitration) => {
+                        let profile = rules.profile;
+                        rules = Rules::new(arbitration);
+                        rules.profile = profile;
+                        if let Some(dests) = dests {
+                            players = vec![];
+                            for (player, dest) in dests.players.into_iter().enumerate() {
+                                let dest =
+                                    Dest::new(
+                                        dest,
+                                        format!("player{}", player),
+                                        &log,
+                                        &output_log,
+                                        &tracer,
+                                        dests.rumble,
+                                    )?;
+                                players.push(PlayerState {
+                                    dest: dest,
+                                    last_buttons: HashMap::new(),
+                                    last_axes: HashMap::new(),
+                                    button_held_since: HashMap::new(),
+                                    axis_held_since: HashMap::new(),
+                                    next_repeat: None,
+                                    axis_notch: HashMap::new(),
+                                    queued: VecDeque::new(),
+                                    next_queued: None,
+                                    smoothed: HashMap::new(),
+                                    next_smooth: None,
+                                    snapshots: HashMap::new(),
+                                    snapshot_store_held: HashSet::new(),
+                                    rumble_effects: HashMap::new(),
+                                    rumble_playing: HashMap::new(),
+                                    next_rumble_end: None,
+                                });
+                            }
+                            publish_rumble(&mut players, &rumble, Instant::now());
+                            keyboard = match dests.keyboard {
+                                Some(dest) => Some(KeyboardState {
+                                    dest: Dest::new(dest, "keyboard".to_string(), &log, &output_log, &tracer, false)?,
+                                    last_buttons: HashMap::new(),
+                                }),
+                                None => None,
+                            };
+                        }
+                        dirty.clear();
+                        batch_buttons.clear();
+                        deadline = None;
+                        for player in 0 .. players.len() {
+                            write(&mut players, &sources, &rules, &writers, &mut scratch, player, Instant::now())?;
+                        }
+                        write_keyboard(&mut keyboard, &sources, &rules, &mut scratch)?;
+                        continue;
+                    },
+                    Message::Profile(profile) => {
+                        if profile == rules.profile {
+                            continue;
+                        }
+                        rules.profile = profile;
+                        for player in 0 .. players.len() {
+                            write(&mut players, &sources, &rules, &writers, &mut scratch, player, Instant::now())?;
+                        }
+                        continue;
+                    },
+                };
+                if let Some(until) = suspended_until {
+                    if Instant::now() < until {
+                        continue;
+                    }
+                    log.info("Emergency release over, mapping again", ea!());
+                    suspended_until = None;
+                }
+
+                // A button changing back within the batch window would never be seen (ex: a quick
+                // tap), write what's batched first
+                let flips = contribution.changes.iter().any(|change| match change {
+                    Change::Button(k, on) => batch_buttons.contains(k) &&
+                        sources
+                            .get(&contribution.source)
+                            .and_then(|s| s.buttons.get(k))
+                            .map(|old| old != on)
+                            .unwrap_or(false),
+                    Change::Axis(..) => false,
+                });
+                if flips {
+                    flush_batch(
+                        &mut players,
+                        &mut keyboard,
+                        &sources,
+                        &rules,
+                        &writers,
+                        &mut scratch,
+                        &mut dirty,
+                        Instant::now(),
+                    )?;
+                    batch_buttons.clear();
+                    deadline = None;
+                }
+                let source = sources.entry(contribution.source).or_insert_with(|| SourceState {
+                    player: contribution.player,
+                    buttons: HashMap::new(),
+                    axes: HashMap::new(),
+                });
+                let old_player = source.player;
+                source.player = contribution.player;
+                for change in contribution.changes {
+                    match change {
+                        Change::Button(k, on) => {
+                            source.buttons.insert(k, on);
+                            writers.buttons.insert(k, contribution.source);
+                            batch_buttons.insert(k);
+                        },
+                        Change::Axis(axis, v) => {
+                            source.axes.insert(axis, v);
+                            writers.axes.insert(axis, contribution.source);
+                        },
+                    }
+                }
+                if let Some(time) = contribution.time {
+                    if let Some(state) = players.get_mut(contribution.player) {
+                        state.dest.source_time = Some(time);
+                    }
+                    if let Some(state) = &mut keyboard {
+                        state.dest.source_time = Some(time);
+                    }
+                }
+                dirty.insert(contribution.player);
+                dirty.insert(old_player);
+                if deadline.is_none() {
+                    deadline = Some(Instant::now() + BATCH_WINDOW);
+                }
+
+                // Emergency release, right away rather than after the batch window
+                if let Some(emergency) = &rules.emergency_release {
+                    if !emergency.buttons.is_empty() &&
+                        emergency
+                            .buttons
+                            .iter()
+                            .all(|k| sources.values().any(|s| s.buttons.get(k).cloned().unwrap_or(false))) {
+                        let suspend = Duration::from_secs_f32(emergency.suspend.unwrap_or(DEFAULT_EMERGENCY_SUSPEND));
+                        log.warn("Emergency release, ignoring sources", ea!(seconds = suspend.as_secs_f32()));
+                        let now = Instant::now();
+                        suspended_until = Some(now + suspend);
+                        sources.clear();
+                        writers.buttons.clear();
+                        writers.axes.clear();
+                        dirty.clear();
+                        batch_buttons.clear();
+                        deadline = None;
+                        for state in &mut players {
+                            state.queued.clear();
+                            state.next_queued = None;
+                        }
+                        for player in 0 .. players.len() {
+                            write(&mut players, &sources, &rules, &writers, &mut scratch, player, now)?;
+                        }
+                        write_keyboard(&mut keyboard, &sources, &rules, &mut scratch)?;
+                    }
+                }
+            }
+            if let Some(linger) = linger {
+                sources.clear();
+                for player in 0 .. players.len() {
+                    write(&mut players, &sources, &rules, &writers, &mut scratch, player, Instant::now())?;
+                }
+                write_keyboard(&mut keyboard, &sources, &rules, &mut scratch)?;
+                tokio::time::sleep(linger).await;
+            }
+            return Ok(());
+        }
+    });
+}
@@ -0,0 +1,135 @@
+//! Emitted event log (`run --log-output`): every event sent to the virtual
+//! devices, with timestamps, for analyzing input timing in other tools. Along with
+//! when each event was sent, it has the kernel timestamp of the source event that
+//! caused it, so the latency and the spacing of the source frames can be checked.
+use std::{
+    fs::File,
+    io::{
+        BufWriter,
+        Write,
+    },
+    path::Path,
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::{
+        Instant,
+        SystemTime,
+    },
+};
+use evdev::{
+    EventSummary,
+    InputEvent,
+};
+use loga::{
+    ea,
+    ResultContext,
+};
+
+enum Format {
+    Csv,
+    Ndjson,
+}
+
+struct OutputLogState {
+    log: loga::Log,
+    format: Format,
+    file: Option<BufWriter<File>>,
+    start: Instant,
+    /// The same moment by the clock source event timestamps use
+    start_system: SystemTime,
+}
+
+/// Shared by the virtual devices.
+#[derive(Clone)]
+pub struct OutputLog(Arc<Mutex<OutputLogState>>);
+
+impl OutputLog {
+    /// CSV if the path ends with `.csv`, otherwise one JSON object per line.
+    pub fn open(log: &loga::Log, path: &Path) -> Result<OutputLog, loga::Error> {
+        let format = match path.extension().and_then(|e| e.to_str()) {
+            Some(e) if e.eq_ignore_ascii_case("csv") => Format::Csv,
+            _ => Format::Ndjson,
+        };
+        let mut file =
+            BufWriter::new(
+                File::create(
+                    path,
+                ).context_with("Error creating output log", ea!(path = path.to_string_lossy()))?,
+            );
+        match format {
+            Format::Csv => {
+                writeln!(file, "time,source_time,device,code,value").context("Error writing output log header")?;
+            },
+            Format::Ndjson => { },
+        }
+        return Ok(OutputLog(Arc::new(Mutex::new(OutputLogState {
+            log: log.clone(),
+            format: format,
+            file: Some(file),
+            start: Instant::now(),
+            start_system: SystemTime::now(),
+        }))));
+    }
+
+    /// Log events sent to a virtual device together. `time` is seconds since the log
+    /// was opened, and `source_time` the kernel timestamp of the source event they're
+    /// for on the same scale, if they're for one. If writing fails a warning is logged
+    /// and nothing more is written.
+    pub fn write(&self, device: &str, events: &[InputEvent], source_time: Option<SystemTime>) {
+        let mut state = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let state = &mut *state;
+        let Some(file) = &mut state.file else {
+            return;
+        };
+        let time = state.start.elapsed().as_secs_f64();
+        let source_time = source_time.map(|t| match t.duration_since(state.start_system) {
+            Ok(d) => d.as_secs_f64(),
+            Err(e) => -e.duration().as_secs_f64(),
+        });
+        if let Err(e) = write_events(file, &state.format, time, source_time, device, events) {
+            state.log.warn_e(e.into(), "Error writing output log, stopping it", ea!());
+            state.file = None;
+        }
+    }
+}
+
+/// The event's code as shown in logs, ex: `BTN_SOUTH`.
+pub fn code_name(ev: &InputEvent) -> String {
+    match ev.destructure() {
+        EventSummary::Key(_, k, _) => return format!("{:?}", k),
+        EventSummary::AbsoluteAxis(_, a, _) => return format!("{:?}", a),
+        _ => return format!("{}:{}", ev.event_type().0, ev.code()),
+    }
+}
+
+fn write_events(
+    file: &mut BufWriter<File>,
+    format: &Format,
+    time: f64,
+    source_time: Option<f64>,
+    device: &str,
+    events: &[InputEvent],
+) -> std::io::Result<()> {
+    for ev in events {
+        let code = code_name(ev);
+        match format {
+            Format::Csv => {
+                let source_time = source_time.map(|t| format!("{:.6}", t)).unwrap_or_default();
+                writeln!(file, "{:.6},{},{},{},{}", time, source_time, device, code, ev.value())?;
+            },
+            Format::Ndjson => {
+                serde_json::to_writer(&mut *file, &serde_json::json!({
+                    "time": time,
+                    "source_time": source_time,
+                    "device": device,
+                    "code": code,
+                    "value": ev.value(),
+                }))?;
+                writeln!(file)?;
+            },
+        }
+    }
+    return file.flush();
+}
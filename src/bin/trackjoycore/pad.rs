@@ -1,262 +1,752 @@
 use std::{
-    sync::{
-        Mutex,
-        Arc,
+    collections::{
+        HashSet,
+        HashMap,
+    },
+    os::fd::AsRawFd,
+    panic::AssertUnwindSafe,
+    sync::Arc,
+    time::{
+        Duration,
+        Instant,
     },
-    collections::HashSet,
 };
 use evdev::{
     Device,
-    uinput::VirtualDevice,
+    EventSummary,
+    EventType,
     KeyCode,
     AbsoluteAxisCode,
-    AbsoluteAxisEvent,
-    InputEvent,
-    EventType,
-    SynchronizationCode,
+    PropType,
 };
 use glam::Vec2;
+use tokio::sync::watch;
 use loga::{
+    ea,
+    DebugDisplay,
     ResultContext,
 };
-use manual_future::ManualFuture;
 use taskmanager::TaskManager;
-use crate::trackjoycore::data::DEST_MAX;
-use super::data::DEST_HALF;
+use trackjoy::{
+    calibration,
+    mapper::{
+        pad::{
+            AxisRange,
+            ClickZones,
+            ForceLevels,
+            InitialTouch,
+            PadGeometry,
+            PadMapper,
+            PadOutputs,
+            PadSnapshot,
+            PadWarning,
+            ProfileSwipe,
+            Region,
+            Selector,
+            Tilt,
+            MT_TOOL_PALM,
+        },
+        exp_param,
+        Changes,
+        Tuning,
+    },
+    DeviceRef,
+    EventCode,
+    PadClickConfig,
+    PadForceConfig,
+    PadOrientation,
+    PadModifierButtons,
+    PadRegionConfig,
+    PadSelectorConfig,
+    PadTiltConfig,
+    ProfileSwipeConfig,
+    RumbleDeadZoneConfig,
+    StickWeighting,
+    ZoneFeedbackConfig,
+    DEFAULT_CLICK_ZONE_HEIGHT,
+    DEFAULT_CURVE,
+    DEFAULT_FORCE_HYSTERESIS,
+    DEFAULT_RUMBLE_THRESHOLD,
+    DEFAULT_SELECTOR_CENTER,
+    DEFAULT_SWIPE_DISTANCE,
+    DEFAULT_SWIPE_FINGERS,
+    DEFAULT_TILT_DEAD_INNER,
+    DEFAULT_TILT_DEAD_OUTER,
+    DEFAULT_TILT_RANGE,
+};
+use super::{
+    crash,
+    feedback::Feedback,
+    history::Histories,
+    profile::ProfileCycler,
+    shadow::{
+        PadShadow,
+        ShadowDiff,
+    },
+    stats::Stats,
+    trace::Tracer,
+    output::{
+        Contribution,
+        Message,
+        OutputTx,
+    },
+    active::{
+        self,
+        set_grab,
+        SourceEvent,
+    },
+};
+
+/// Time between repeats of the profile swipe confirmation.
+const PROFILE_FEEDBACK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Current value of a multitouch axis in every slot (`EVIOCGMTSLOTS`), which the
+/// evdev crate doesn't expose.
+fn get_mt_slots(source: &Device, code: AbsoluteAxisCode, slots: usize) -> Result<Vec<i32>, loga::Error> {
+    // Struct `input_mt_request_layout`: code then one value per slot
+    let mut buf = vec![0i32; slots + 1];
+    buf[0] = code.0 as i32;
+    let size = (buf.len() * std::mem::size_of::<i32>()) as u64;
+
+    // _IOC(_IOC_READ, 'E', 0x0a, size)
+    let request = (2u64 << 30) | (size << 16) | ((b'E' as u64) << 8) | 0x0a;
+    if unsafe {
+        libc::ioctl(source.as_raw_fd(), request as _, buf.as_mut_ptr())
+    } < 0 {
+        return Err(std::io::Error::last_os_error()).context_with(
+            "Error getting trackpad multitouch slot state",
+            ea!(axis = code.dbg_str()),
+        );
+    }
+    buf.remove(0);
+    return Ok(buf);
+}
 
-const BUTTON_COUNT: usize = 4;
+/// Warn if the pad sends this many events without any multitouch data.
+const STARVATION_EVENTS: usize = 100;
+
+/// Suggestion for when the pad doesn't send multitouch data.
+const WRONG_NODE_HINT: &str =
+    "This is probably the wrong event device for the pad (ex: the relative mouse node), try the one ending in \
+     `-event-mouse` in `/dev/input/by-id/`";
+
+/// Update the state for visualizers, if any are watching.
+fn publish_snapshot(mapper: &PadMapper, snapshot: &Option<watch::Sender<PadSnapshot>>) {
+    let Some(snapshot) = snapshot else {
+        return;
+    };
+    let new = mapper.snapshot();
+    snapshot.send_if_modified(|s| {
+        if *s == new {
+            return false;
+        }
+        *s = new;
+        return true;
+    });
+}
+
+/// The position axis ranges (x, y) the pad reports.
+fn position_ranges(source: &Device) -> Result<[AxisRange; 2], loga::Error> {
+    let axes = source.get_abs_state().context("Error getting trackpad absolute state")?;
+    let axis_range = |i: usize, name: &str| -> Result<AxisRange, loga::Error> {
+        let info = axes.get(i).ok_or_else(|| loga::err_with("Failed to get trackpad axis info", ea!(axis = name)))?;
+        return Ok(AxisRange {
+            minimum: info.minimum,
+            maximum: info.maximum,
+            resolution: info.resolution,
+            fuzz: info.fuzz,
+        });
+    };
+    return Ok([axis_range(0, "x")?, axis_range(1, "y")?]);
+}
+
+/// The tuning with the inner dead zone widened while the game rumbles, see
+/// `rumble_dead_zone` in the config.
+fn rumble_tuning(
+    tuning: Tuning,
+    rumble: &Option<(RumbleDeadZoneConfig, watch::Receiver<Vec<f32>>)>,
+    player: usize,
+) -> Tuning {
+    let Some((config, strengths)) = rumble else {
+        return tuning;
+    };
+    let strength = strengths.borrow().get(player).cloned().unwrap_or(0.);
+    if strength <= 0. || strength < config.threshold.unwrap_or(DEFAULT_RUMBLE_THRESHOLD) {
+        return tuning;
+    }
+    let mut out = tuning;
+    out.active_low = out.active_low.max(config.dead_inner).min(out.active_high);
+    return out;
+}
+
+/// Run a mapper step, adding the mapper state and recent events to the crash report
+/// if it panics.
+fn guard<T>(
+    log: &loga::Log,
+    source_ref: DeviceRef,
+    mapper: &mut PadMapper,
+    history: &Histories,
+    f: impl FnOnce(&mut PadMapper) -> T,
+) -> T {
+    match std::panic::catch_unwind(AssertUnwindSafe(|| f(mapper))) {
+        Ok(x) => return x,
+        Err(e) => {
+            crash::add_details(
+                log,
+                &format!("Pad {:?}", source_ref),
+                &format!("{}\nRecent events:\n{}", mapper.dump(), history.dump_source(source_ref)),
+            );
+            std::panic::resume_unwind(e);
+        },
+    }
+}
 
 pub fn build(
     tm: &TaskManager,
-    source: Device,
+    log: &loga::Log,
+    mut source: Device,
+    source_ref: DeviceRef,
     axis_codes: [AbsoluteAxisCode; 2],
     button_codes: [KeyCode; 4],
-    dest: ManualFuture<Arc<Mutex<VirtualDevice>>>,
+    finger_buttons: HashMap<usize, KeyCode>,
+    output: OutputTx,
+    mut player: usize,
+    player_count: usize,
     dest_buttons: &mut HashSet<KeyCode>,
     dest_axes: &mut Vec<AbsoluteAxisCode>,
     multitouch: bool,
+    stick_weighting: StickWeighting,
+    mirror_horizontal: bool,
+    orientation: Option<PadOrientation>,
+    fuzz: Option<i32>,
+    resolution_x: Option<i32>,
+    resolution_y: Option<i32>,
+    max_slots: usize,
+    hover_axes: Option<[AbsoluteAxisCode; 2]>,
+    stick_region: Option<PadRegionConfig>,
+    click_buttons: Option<PadClickConfig>,
+    force_buttons: Option<PadForceConfig>,
+    selector: Option<PadSelectorConfig>,
+    zone_feedback: Option<ZoneFeedbackConfig>,
+    profile_swipe: Option<ProfileSwipeConfig>,
+    player_cycle_fingers: Option<usize>,
+    tilt: Option<PadTiltConfig>,
+    ignore_events: Vec<EventCode>,
     cm_x_radius: Option<f32>,
     cm_y_radius: Option<f32>,
-    active_high: f32,
-    active_low: f32,
-    curve: f32,
-    y_smash: f32,
+    tuning: watch::Receiver<Tuning>,
+    default_y_smash: f32,
+    grab: bool,
+    mut active: watch::Receiver<bool>,
+    snapshot: Option<watch::Sender<PadSnapshot>>,
+    history: Histories,
+    tracer: Tracer,
+    modifier_buttons: Vec<PadModifierButtons>,
+    mut modifiers: watch::Receiver<HashSet<KeyCode>>,
+    recenter_key: Option<KeyCode>,
+    rumble: Option<(RumbleDeadZoneConfig, watch::Receiver<Vec<f32>>)>,
+    profiles: Arc<ProfileCycler>,
+    stats: Option<Stats>,
+    shadow: Option<PadShadow>,
 ) -> Result<(), loga::Error> {
     // Allocate buttons/axes
     for c in &button_codes {
         dest_buttons.insert(*c);
     }
-    dest_axes.extend_from_slice(&axis_codes);
+    for c in finger_buttons.values() {
+        dest_buttons.insert(*c);
+    }
+    for c in modifier_buttons.iter().flat_map(|m| m.buttons.iter()) {
+        dest_buttons.insert(*c);
+    }
+    let click_zones = match click_buttons {
+        Some(c) if source.properties().contains(PropType::BUTTONPAD) => Some(ClickZones {
+            left: c.left,
+            right: c.right,
+            other: c.other,
+            zone_height: c.zone_height.unwrap_or(DEFAULT_CLICK_ZONE_HEIGHT),
+        }),
+        _ => None,
+    };
+    for c in click_zones.iter().flat_map(|z| [z.left, z.right, z.other]).flatten() {
+        dest_buttons.insert(c);
+    }
+    let stick_region = stick_region.map(|r| Region {
+        min: Vec2::new(r.left.unwrap_or(0.), r.top.unwrap_or(0.)),
+        max: Vec2::new(r.right.unwrap_or(1.), r.bottom.unwrap_or(1.)),
+    });
+    if let Some(r) = stick_region {
+        if !r.min.cmplt(r.max).all() {
+            return Err(loga::err("Pad stick region is empty, `left` and `top` must be less than `right` and `bottom`"));
+        }
+    }
+    let selector = selector.map(|s| Selector {
+        buttons: s.buttons,
+        center: s.center.unwrap_or(DEFAULT_SELECTOR_CENTER),
+    });
+    for c in selector.iter().flat_map(|s| s.buttons.iter()) {
+        dest_buttons.insert(*c);
+    }
+    for axis in axis_codes.iter().chain(hover_axes.iter().flatten()).chain(tilt.iter().flat_map(|t| t.axes.iter())) {
+        if !dest_axes.contains(axis) {
+            dest_axes.push(*axis);
+        }
+    }
+
+    if !source.supported_absolute_axes().map(|a| a.contains(AbsoluteAxisCode::ABS_MT_POSITION_X)).unwrap_or(false) {
+        log.warn("Pad doesn't report multitouch positions, the stick won't move", ea!(hint = WRONG_NODE_HINT));
+    }
 
     // Prep spatial info
     let source_axes = source.get_abs_state().context("Error getting trackpad absolute state")?;
-    let source_x_axis = source_axes.get(0).ok_or_else(|| loga::err("Failed to get trackpad x axis info"))?;
-    let source_y_axis = source_axes.get(1).ok_or_else(|| loga::err("Failed to get trackpad y axis state"))?;
-    let source_max = Vec2::new(source_x_axis.maximum as f32, source_y_axis.maximum as f32);
-    let source_min = Vec2::new(source_x_axis.minimum as f32, source_y_axis.minimum as f32);
-    let resolution = Vec2::new(source_x_axis.resolution as f32, source_y_axis.resolution as f32);
-    let phys_size = (source_max - source_min) / resolution / 10.;
-    let source_range_half = (source_max - source_min) / 2.;
-    let source_middle = source_min + source_range_half;
-    let mut unit_divisor;
-    if phys_size.x > phys_size.y {
-        unit_divisor = Vec2::new(source_range_half.y * resolution.x / resolution.y, source_range_half.y);
-    } else {
-        unit_divisor = Vec2::new(source_range_half.x, source_range_half.x * resolution.y / resolution.x);
-    }
-    if let Some(x_radius) = cm_x_radius {
-        unit_divisor.x = x_radius * 10. * resolution.x;
+
+    // Saved calibration
+    let id = source.input_id();
+    let calibration_key = calibration::device_key(id.vendor(), id.product(), source.unique_name());
+    let calibration = match calibration::load(&calibration_key) {
+        Ok(c) => c,
+        Err(e) => {
+            log.warn_e(e, "Error loading saved pad calibration, ignoring", ea!(device = calibration_key));
+            None
+        },
+    };
+    if calibration.is_some() {
+        log.info("Using saved pad calibration", ea!(device = calibration_key));
     }
-    if let Some(y_radius) = cm_y_radius {
-        unit_divisor.y = y_radius * 10. * resolution.x;
+    let direct = source.properties().contains(PropType::DIRECT);
+    let make_geometry = move |[mut x_range, mut y_range]: [AxisRange; 2]| -> Result<PadGeometry, loga::Error> {
+        if let Some(calibration) = &calibration {
+            if let Some([min, max]) = calibration.x {
+                x_range.minimum = min;
+                x_range.maximum = max;
+            }
+            if let Some([min, max]) = calibration.y {
+                y_range.minimum = min;
+                y_range.maximum = max;
+            }
+        }
+        let mut geometry =
+            PadGeometry::new(
+                x_range,
+                y_range,
+                direct,
+                orientation,
+                fuzz,
+                resolution_x,
+                resolution_y,
+                cm_x_radius,
+                cm_y_radius,
+            )?;
+        if let Some(calibration) = &calibration {
+            geometry.calibrate(Vec2::from(calibration.center_offset), calibration.rotation);
+        }
+        return Ok(geometry);
+    };
+    let mut ranges = position_ranges(&source)?;
+    let geometry = make_geometry(ranges)?;
+
+    // Pick up touches that are already down at startup, rather than waiting for them
+    // to be lifted and put down again.
+    let mut initial_slot = 0usize;
+    let mut initial_touches = vec![];
+    if source.supported_absolute_axes().map(|a| a.contains(AbsoluteAxisCode::ABS_MT_SLOT)).unwrap_or(false) {
+        let slot_info = &source_axes[AbsoluteAxisCode::ABS_MT_SLOT.0 as usize];
+        let slots = (slot_info.maximum + 1).max(1) as usize;
+        let tracking_ids = get_mt_slots(&source, AbsoluteAxisCode::ABS_MT_TRACKING_ID, slots)?;
+        let xs = get_mt_slots(&source, AbsoluteAxisCode::ABS_MT_POSITION_X, slots)?;
+        let ys = get_mt_slots(&source, AbsoluteAxisCode::ABS_MT_POSITION_Y, slots)?;
+        let tool_types = get_mt_slots(&source, AbsoluteAxisCode::ABS_MT_TOOL_TYPE, slots)?;
+        let distances = if source
+            .supported_absolute_axes()
+            .map(|a| a.contains(AbsoluteAxisCode::ABS_MT_DISTANCE))
+            .unwrap_or(false) {
+            get_mt_slots(&source, AbsoluteAxisCode::ABS_MT_DISTANCE, slots)?
+        } else {
+            vec![0; slots]
+        };
+        initial_slot = slot_info.value.max(0) as usize;
+        initial_touches = (0 .. slots).map(|i| InitialTouch {
+            tracking_id: tracking_ids[i],
+            pos: Vec2::new(xs[i] as f32, ys[i] as f32),
+            palm: tool_types[i] == MT_TOOL_PALM,
+            hover: distances[i] > 0,
+        }).collect();
     }
-    let dest_half = Vec2::new(DEST_HALF as f32, DEST_HALF as f32);
+    let tilt = match tilt {
+        Some(t) => {
+            let supported = source.supported_absolute_axes();
+            let tilt_range = |code: AbsoluteAxisCode| -> Option<f32> {
+                if !supported.map(|a| a.contains(code)).unwrap_or(false) {
+                    return None;
+                }
+                let info = source_axes.get(code.0 as usize)?;
+                let full = info.minimum.abs().max(info.maximum.abs()) as f32 * t.range.unwrap_or(DEFAULT_TILT_RANGE);
+                if full <= 0. {
+                    return None;
+                }
+                return Some(full);
+            };
+            match (tilt_range(AbsoluteAxisCode::ABS_TILT_X), tilt_range(AbsoluteAxisCode::ABS_TILT_Y)) {
+                (Some(x), Some(y)) => {
+                    let out = Tilt {
+                        axis_codes: t.axes,
+                        full: Vec2::new(x, y),
+                        active_low: t.dead_inner.unwrap_or(DEFAULT_TILT_DEAD_INNER),
+                        active_high: 1.0 - t.dead_outer.unwrap_or(DEFAULT_TILT_DEAD_OUTER),
+                        curve: exp_param(t.curve.unwrap_or(DEFAULT_CURVE)),
+                    };
+                    if out.active_high - out.active_low < 0. {
+                        return Err(loga::err("Tilt dead zones overlap"));
+                    }
+                    Some(out)
+                },
+                _ => {
+                    log.warn("Pad doesn't report pen tilt, ignoring tilt mapping", ea!());
+                    None
+                },
+            }
+        },
+        None => None,
+    };
+    let force = match force_buttons {
+        Some(f) => {
+            let supported = source.supported_absolute_axes();
+            match source_axes.get(AbsoluteAxisCode::ABS_PRESSURE.0 as usize) {
+                Some(info) if supported.map(|a| a.contains(AbsoluteAxisCode::ABS_PRESSURE)).unwrap_or(false) &&
+                    info.maximum > info.minimum => {
+                    let range = (info.maximum - info.minimum) as f32;
+                    let mut levels =
+                        f
+                            .levels
+                            .iter()
+                            .map(|l| (info.minimum + (l.pressure * range).round() as i32, l.button))
+                            .collect::<Vec<_>>();
+                    levels.sort_by_key(|l| l.0);
+                    for (_, c) in &levels {
+                        dest_buttons.insert(*c);
+                    }
+                    Some(ForceLevels {
+                        levels: levels,
+                        hysteresis: (f.hysteresis.unwrap_or(DEFAULT_FORCE_HYSTERESIS) * range).round() as i32,
+                        cumulative: f.cumulative,
+                    })
+                },
+                _ => {
+                    log.warn("Pad doesn't report pressure, ignoring force buttons", ea!());
+                    None
+                },
+            }
+        },
+        None => None,
+    };
+    let profile_swipe_outputs = profile_swipe.as_ref().map(|s| ProfileSwipe {
+        fingers: s.fingers.unwrap_or(DEFAULT_SWIPE_FINGERS),
+        distance: s.distance.unwrap_or(DEFAULT_SWIPE_DISTANCE),
+    });
+    let mut shadow = shadow.map(|s| {
+        let mapper = PadMapper::new(PadOutputs {
+            axis_codes: axis_codes,
+            button_codes: s.button_codes,
+            finger_buttons: s.finger_buttons,
+            multitouch: multitouch,
+            stick_weighting: stick_weighting,
+            mirror_horizontal: mirror_horizontal,
+            default_y_smash: default_y_smash,
+            hover_axes: hover_axes,
+            stick_region: stick_region,
+            click_zones: click_zones,
+            force: force.clone(),
+            selector: selector.clone(),
+            profile_swipe: profile_swipe_outputs,
+            player_cycle_fingers: player_cycle_fingers,
+            tilt: tilt,
+        }, geometry, max_slots, initial_slot, initial_touches.clone());
+        (mapper, s.tuning, ShadowDiff::default())
+    });
+    let mut mapper = PadMapper::new(PadOutputs {
+        axis_codes: axis_codes,
+        button_codes: button_codes,
+        finger_buttons: finger_buttons,
+        multitouch: multitouch,
+        stick_weighting: stick_weighting,
+        mirror_horizontal: mirror_horizontal,
+        default_y_smash: default_y_smash,
+        hover_axes: hover_axes,
+        stick_region: stick_region,
+        click_zones: click_zones,
+        force: force,
+        selector: selector,
+        profile_swipe: profile_swipe_outputs,
+        player_cycle_fingers: player_cycle_fingers,
+        tilt: tilt,
+    }, geometry, max_slots, initial_slot, initial_touches);
+
+    let mut swipe_feedback = match profile_swipe.and_then(|s| s.feedback).or_else(|| zone_feedback.clone()) {
+        Some(c) => match Feedback::new(&mut source, c) {
+            Ok(f) => Some(f),
+            Err(e) => {
+                log.warn_e(e, "Error setting up profile swipe feedback, disabling", ea!());
+                None
+            },
+        },
+        None => None,
+    };
+    let mut feedback = match zone_feedback {
+        Some(c) => match Feedback::new(&mut source, c) {
+            Ok(f) => Some(f),
+            Err(e) => {
+                log.warn_e(e, "Error setting up zone feedback, disabling", ea!());
+                None
+            },
+        },
+        None => None,
+    };
 
     // Read and write events
     let mut source = source.into_event_stream().context("Couldn't make input device async")?;
     tm.critical_task::<_, loga::Error>({
         let tm = tm.clone();
+        let log = log.clone();
         async move {
-            enum TouchBake {
-                Indeterminate,
-                Axis,
-                Button(usize),
+            if !*active.borrow_and_update() {
+                set_grab(&mut source, grab, false)?;
             }
+            let mut changes = Changes::new();
 
-            struct TouchState {
-                enabled: bool,
-                pos: Vec2,
-                baked: TouchBake,
-            }
-
-            struct State {
-                slot: usize,
-                last_axis: [i32; 2],
-                last_buttons: [bool; 4],
-                touch_states: Vec<TouchState>,
-                dest: Arc<Mutex<VirtualDevice>>,
-            }
+            // Events other than multitouch data before any multitouch data
+            let mut starved_events = Some(0usize);
 
-            let mut state = State {
-                slot: 0usize,
-                last_axis: [0i32; 2],
-                last_buttons: [false; 4],
-                touch_states: vec![TouchState {
-                    enabled: false,
-                    pos: source_middle,
-                    baked: TouchBake::Indeterminate,
-                }],
-                dest: dest.await,
-            };
+            // Profile swipe confirmation: the profile position, repeats left, and when to
+            // play the next one
+            let mut profile_feedback: Option<(usize, usize, Instant)> = None;
+            let mut recenter_held = false;
+            let mut shadow_changes = Changes::new();
             loop {
-                let ev = match tm.if_alive(source.next_event()).await {
-                    Some(x) => x,
-                    None => {
-                        break;
+                let now = Instant::now();
+                let deadline =
+                    [
+                        mapper.deadline(&rumble_tuning(*tuning.borrow(), &rumble, player), now),
+                        shadow.as_ref().and_then(|(m, t, _)| m.deadline(t, now)),
+                        profile_feedback.map(|(_, _, at)| at),
+                    ]
+                        .into_iter()
+                        .flatten()
+                        .min();
+                let ev = match deadline {
+                    None => match tm.if_alive(active::next(&mut source, &mut active)).await {
+                        Some(x) => x,
+                        None => {
+                            break;
+                        },
                     },
-                }?;
-                match ev.destructure() {
-                    evdev::EventSummary::Synchronization(_, t, _) => {
-                        if t == SynchronizationCode::SYN_REPORT {
-                            let mut axis_sum = Vec2::ZERO;
-                            let mut axis_sum_count = 0usize;
-                            let mut buttons = [false; BUTTON_COUNT];
-                            for (state_i, state) in state.touch_states.iter_mut().enumerate() {
-                                if !state.enabled {
-                                    continue;
-                                }
-                                if state_i > 0 && !multitouch {
-                                    continue;
-                                }
-
-                                // narrowest axis is -1 .. 1 for full span of trackpad; -1 is up; trans axis may
-                                // be over or under 1 depending on resolution ratio ratio
-                                let mut unitspace_vec = (state.pos - source_middle) / unit_divisor;
-
-                                // y-space compressed downward (towards 1) with low numbers of y_smash
-                                unitspace_vec.y = ((unitspace_vec.y / 2. + 0.52).clamp(0., 1.1).powf(y_smash) - 0.52) * 2.;
-                                match state.baked {
-                                    TouchBake::Indeterminate => {
-                                        if unitspace_vec.length() <= 1. {
-                                            state.baked = TouchBake::Axis;
-                                            axis_sum += unitspace_vec;
-                                            axis_sum_count += 1;
-                                        } else {
-                                            let button_i = match (unitspace_vec.x >= 0., unitspace_vec.y >= 0.) {
-                                                (true, true) => 0,
-                                                (false, true) => 1,
-                                                (true, false) => 2,
-                                                (false, false) => 3,
-                                            };
-                                            buttons[button_i] = true;
-                                            state.baked = TouchBake::Button(button_i);
-                                        }
-                                    },
-                                    TouchBake::Axis => {
-                                        axis_sum += unitspace_vec;
-                                        axis_sum_count += 1;
-                                    },
-                                    TouchBake::Button(button_i) => {
-                                        buttons[button_i] = true;
-                                    },
+                    Some(at) => match tm.if_alive(
+                        tokio::time::timeout_at(at.into(), active::next(&mut source, &mut active)),
+                    ).await {
+                        Some(Ok(x)) => x,
+                        Some(Err(_)) => {
+                            let now = Instant::now();
+                            if let (Some((index, repeats, at)), Some(feedback)) =
+                                (&mut profile_feedback, &mut swipe_feedback) {
+                                if now >= *at {
+                                    feedback.profile(&log, *index);
+                                    *repeats -= 1;
+                                    *at = now + PROFILE_FEEDBACK_INTERVAL;
+                                    if *repeats == 0 {
+                                        profile_feedback = None;
+                                    }
                                 }
                             }
-                            let mut dest_events = vec![];
 
-                            // Prepare events for axis change
-                            let axis = if axis_sum_count > 0 {
-                                // Average of axis touches, unit vec (-1 .. 1 both axes)
-                                let mut unitspace_vec = axis_sum / (axis_sum_count as f32);
-                                let dist = unitspace_vec.length();
-                                if dist < active_low {
-                                    // Center dead space
-                                    unitspace_vec = Vec2::ZERO;
-                                } else {
-                                    if dist >= active_high {
-                                        // Outer dead space (set length to 1)
-                                        unitspace_vec /= dist;
-                                    } else {
-                                        // Scale linearly between dead spaces
-                                        let activespace_dist = (dist - active_low) / (active_high - active_low);
-                                        unitspace_vec *= activespace_dist / dist;
+                            // Outputs changing over time, ex: stick returning to center
+                            let tuning = rumble_tuning(*tuning.borrow(), &rumble, player);
+                            let active = *active.borrow();
+                            guard(&log, source_ref, &mut mapper, &history, |m| {
+                                m.tick(&tuning, active, Instant::now(), &mut changes)
+                            });
+                            if let Some((shadow_mapper, shadow_tuning, diff)) = &mut shadow {
+                                shadow_mapper.tick(shadow_tuning, active, Instant::now(), &mut shadow_changes);
+                                diff.shadow(&std::mem::take(&mut shadow_changes));
+                                diff.real(&changes);
+                                diff.compare(&log, source_ref);
+                            }
+                            publish_snapshot(&mapper, &snapshot);
+                            if changes.len() > 0 && output.send(Message::Contribution(Contribution {
+                                source: source_ref,
+                                player: player,
+                                changes: std::mem::take(&mut changes),
+                                time: None,
+                            })).is_err() {
+                                // Output stopped, shutting down
+                                break;
+                            }
+                            continue;
+                        },
+                        None => {
+                            break;
+                        },
+                    },
+                }?;
+                let time;
 
-                                        // Apply a curve
-                                        unitspace_vec = unitspace_vec * (activespace_dist.powf(curve) / activespace_dist);
+                // Moved to the next player, send even if nothing changed so held outputs move too
+                let mut cycled = false;
+                match ev {
+                    SourceEvent::Event(ev) => {
+                        time = Some(ev.timestamp());
+                        history.push(source_ref, ev);
+                        if ignore_events.iter().any(|c| c.matches(&ev)) {
+                            continue;
+                        }
+                        if let Some(count) = &mut starved_events {
+                            match ev.destructure() {
+                                EventSummary::AbsoluteAxis(_, code, _) if code.0 >= AbsoluteAxisCode::ABS_MT_SLOT.0 => {
+                                    starved_events = None;
+                                },
+                                EventSummary::Synchronization(..) => { },
+                                _ => {
+                                    *count += 1;
+                                    if *count == STARVATION_EVENTS {
+                                        log.warn(
+                                            "Pad is sending events but no multitouch data, the stick won't move",
+                                            ea!(hint = WRONG_NODE_HINT),
+                                        );
                                     }
-                                }
-                                let out = unitspace_vec * dest_half + dest_half;
-                                [(out.x as i32).clamp(0, DEST_MAX), (out.y as i32).clamp(0, DEST_MAX)]
-                            } else {
-                                [dest_half.x as i32, dest_half.y as i32]
-                            };
-                            if axis != state.last_axis {
-                                dest_events.push(*AbsoluteAxisEvent::new(axis_codes[0], axis[0]));
-                                dest_events.push(*AbsoluteAxisEvent::new(axis_codes[1], axis[1]));
+                                },
                             }
-                            state.last_axis = axis;
-
-                            // Prepare events for button changes
-                            for i in 0 .. BUTTON_COUNT {
-                                let on = buttons[i];
-                                if on && !state.last_buttons[i] {
-                                    dest_events.push(InputEvent::new(EventType::KEY.0, button_codes[i].0, 1));
-                                } else if !on && state.last_buttons[i] {
-                                    dest_events.push(InputEvent::new(EventType::KEY.0, button_codes[i].0, 0));
+                        }
+                        // Modifier and recenter keys take effect at the next report
+                        if ev.event_type() == EventType::SYNCHRONIZATION && modifiers.has_changed().unwrap_or(false) {
+                            let held = modifiers.borrow_and_update();
+                            if !modifier_buttons.is_empty() {
+                                let codes =
+                                    modifier_buttons
+                                        .iter()
+                                        .find(|m| m.modifiers.iter().all(|k| held.contains(k)))
+                                        .map(|m| m.buttons)
+                                        .unwrap_or(button_codes);
+                                mapper.set_button_codes(codes, &mut changes);
+                            }
+                            if let Some(k) = recenter_key {
+                                let down = held.contains(&k);
+                                if down && !recenter_held {
+                                    let offset = mapper.recenter();
+                                    if let Some((shadow_mapper, _, _)) = &mut shadow {
+                                        shadow_mapper.recenter();
+                                    }
+                                    log.info(
+                                        "Moved pad stick center",
+                                        ea!(offset_mm = format!("{:.1}, {:.1}", offset.x, offset.y)),
+                                    );
                                 }
-                                state.last_buttons[i] = on;
+                                recenter_held = down;
                             }
+                        }
+                        let tuning = rumble_tuning(*tuning.borrow(), &rumble, player);
+                        let active = *active.borrow();
+                        match guard(&log, source_ref, &mut mapper, &history, |m| {
+                            m.event(ev, &tuning, active, Instant::now(), &mut changes)
+                        }) {
+                            Some(PadWarning::SlotOutOfRange(slot)) => {
+                                log.warn(
+                                    "Pad selected an out of range multitouch slot, ignoring its touches",
+                                    ea!(slot = slot, max_slots = max_slots),
+                                );
+                            },
+                            None => { },
+                        }
+                        if let Some((shadow_mapper, shadow_tuning, diff)) = &mut shadow {
+                            shadow_mapper.event(ev, shadow_tuning, active, Instant::now(), &mut shadow_changes);
+                            diff.shadow(&std::mem::take(&mut shadow_changes));
+                        }
 
-                            // Send
-                            if dest_events.len() > 0 {
-                                state
-                                    .dest
-                                    .lock()
-                                    .unwrap()
-                                    .emit(&dest_events)
-                                    .context("Failed to send events to virtual device")?;
+                        if ev.event_type() == EventType::SYNCHRONIZATION {
+                            if tracer.touch() && (changes.len() > 0 || !mapper.snapshot().touches.is_empty()) {
+                                log.info("Trace: pad frame", ea!(state = mapper.dump()));
+                                tracer.changes(&log, player, &changes);
+                            }
+                            publish_snapshot(&mapper, &snapshot);
+                            if let Some(stats) = &stats {
+                                stats.touches(source_ref, &mapper.snapshot());
+                            }
+                            if mapper.take_player_cycle() && active && player_count > 1 {
+                                player = (player + 1) % player_count;
+                                cycled = true;
+                                log.info(
+                                    "Moved pad to the next player",
+                                    ea!(pad = source_ref.dbg_str(), player = player),
+                                );
+                            }
+                            match (mapper.take_zone_entered(), &mut feedback) {
+                                (Some(zone), Some(feedback)) if active => {
+                                    feedback.trigger(&log, zone);
+                                },
+                                _ => { },
+                            }
+                            match mapper.take_swipe() {
+                                Some(step) if active => {
+                                    let index = profiles.step(&log, step);
+                                    if let Some(feedback) = &swipe_feedback {
+                                        profile_feedback =
+                                            Some((index, feedback.profile_repeats(index), Instant::now()));
+                                    }
+                                },
+                                _ => { },
                             }
                         }
                     },
-                    evdev::EventSummary::AbsoluteAxis(_, type_, value) => match type_ {
-                        AbsoluteAxisCode::ABS_MT_SLOT => {
-                            state.slot = value as usize;
-                            while state.touch_states.len() < state.slot + 1 {
-                                state.touch_states.push(TouchState {
-                                    enabled: false,
-                                    pos: source_middle,
-                                    baked: TouchBake::Indeterminate,
-                                });
-                            }
-                        },
-                        AbsoluteAxisCode::ABS_MT_POSITION_X => {
-                            state.touch_states[state.slot].pos.x = value as f32;
-                        },
-                        AbsoluteAxisCode::ABS_MT_POSITION_Y => {
-                            state.touch_states[state.slot].pos.y = value as f32;
-                        },
-                        AbsoluteAxisCode::ABS_MT_TRACKING_ID => {
-                            let enabled = value != -1;
-                            state.touch_states[state.slot].enabled = enabled;
-                            if !enabled {
-                                if let TouchBake::Button(i) = state.touch_states[state.slot].baked {
-                                    // Sometimes evdev doesn't send release events for slots so they get stuck. Make
-                                    // another press + release reset the button as an intuitive workaround/fix...
-                                    for s in &mut state.touch_states {
-                                        if s.enabled && match s.baked {
-                                            TouchBake::Button(j) if i == j => true,
-                                            _ => false,
-                                        } {
-                                            s.enabled = false;
-                                            s.baked = TouchBake::Indeterminate;
+                    SourceEvent::Active(active) => {
+                        time = None;
+                        set_grab(&mut source, grab, active)?;
+                        if active {
+                            // Some pads report different ranges after resuming or reconnecting
+                            let new_ranges = position_ranges(source.device())?;
+                            if new_ranges != ranges {
+                                log.info(
+                                    "Pad ranges changed, updating",
+                                    ea!(old = ranges.dbg_str(), new = new_ranges.dbg_str()),
+                                );
+                                match make_geometry(new_ranges) {
+                                    Ok(geometry) => {
+                                        mapper.set_geometry(geometry);
+                                        if let Some((shadow_mapper, _, _)) = &mut shadow {
+                                            shadow_mapper.set_geometry(geometry);
                                         }
-                                    }
+                                    },
+                                    Err(e) => {
+                                        log.warn_e(e, "Error updating pad geometry, keeping the old one", ea!());
+                                    },
                                 }
-                                state.touch_states[state.slot].baked = TouchBake::Indeterminate;
+                                ranges = new_ranges;
                             }
-                        },
-                        _ => (),
+                        } else {
+                            // Release everything; touches keep being tracked but nothing is sent until
+                            // reactivated
+                            guard(&log, source_ref, &mut mapper, &history, |m| m.release(&mut changes));
+                            if let Some((shadow_mapper, _, diff)) = &mut shadow {
+                                shadow_mapper.release(&mut shadow_changes);
+                                diff.shadow(&std::mem::take(&mut shadow_changes));
+                            }
+                            publish_snapshot(&mapper, &snapshot);
+                        }
                     },
-                    _ => { },
+                }
+
+                // Send
+                if let Some(stats) = &stats {
+                    stats.presses(source_ref, &changes);
+                }
+                if let Some((_, _, diff)) = &mut shadow {
+                    diff.real(&changes);
+                    diff.compare(&log, source_ref);
+                }
+                if (changes.len() > 0 || cycled) && output.send(Message::Contribution(Contribution {
+                    source: source_ref,
+                    player: player,
+                    changes: std::mem::take(&mut changes),
+                    time: time,
+                })).is_err() {
+                    // Output stopped, shutting down
+                    break;
                 }
             }
             return Ok(());
@@ -0,0 +1,104 @@
+//! Startup capability report of each source, so logs from user reports show what
+//! the device claims to support.
+use evdev::{
+    AbsoluteAxisCode,
+    Device,
+};
+use loga::{
+    ea,
+    DebugDisplay,
+};
+
+/// Slot counts above this are likely bogus, real pads report 2 to 10.
+const MAX_PLAUSIBLE_SLOTS: i32 = 64;
+
+/// Axes whose resolution is used to size the pad.
+const POSITION_AXES: &[AbsoluteAxisCode] = &[
+    AbsoluteAxisCode::ABS_X,
+    AbsoluteAxisCode::ABS_Y,
+    AbsoluteAxisCode::ABS_MT_POSITION_X,
+    AbsoluteAxisCode::ABS_MT_POSITION_Y,
+];
+
+/// Log the axis ranges, resolutions, slot count and keys of a source, and warn
+/// about values that will probably cause problems.
+pub fn report(log: &loga::Log, source: &Device) {
+    let id = source.input_id();
+    log.info(
+        "Source device",
+        ea!(
+            name = source.name().unwrap_or(""),
+            vendor = format!("{:04x}", id.vendor()),
+            product = format!("{:04x}", id.product()),
+            version = format!("{:04x}", id.version()),
+            bus = id.bus_type().dbg_str(),
+            properties = source.properties().iter().map(|p| p.dbg_str()).collect::<Vec<_>>().join(" ")
+        ),
+    );
+
+    // Axes
+    let supported = source.supported_absolute_axes();
+    match (supported, source.get_abs_state()) {
+        (Some(supported), Ok(states)) => {
+            for code in supported.iter() {
+                let Some(info) = states.get(code.0 as usize) else {
+                    continue;
+                };
+                log.info(
+                    "Source axis",
+                    ea!(
+                        axis = code.dbg_str(),
+                        minimum = info.minimum,
+                        maximum = info.maximum,
+                        resolution = info.resolution,
+                        fuzz = info.fuzz,
+                        flat = info.flat
+                    ),
+                );
+                if code == AbsoluteAxisCode::ABS_MT_SLOT {
+                    let slots = info.maximum + 1;
+                    log.info("Source multitouch slots", ea!(slots = slots));
+                    if slots < 1 || slots > MAX_PLAUSIBLE_SLOTS {
+                        log.warn("Source reports an implausible number of touch slots", ea!(slots = slots));
+                    }
+                    continue;
+                }
+                if info.maximum <= info.minimum {
+                    log.warn(
+                        "Source axis has an empty range",
+                        ea!(axis = code.dbg_str(), minimum = info.minimum, maximum = info.maximum),
+                    );
+                    continue;
+                }
+                if POSITION_AXES.contains(&code) && info.resolution <= 0 {
+                    log.warn(
+                        "Source position axis has no resolution, physical size will be guessed",
+                        ea!(axis = code.dbg_str()),
+                    );
+                }
+                if info.fuzz > (info.maximum - info.minimum) / 10 {
+                    log.warn(
+                        "Source axis fuzz is large for its range",
+                        ea!(axis = code.dbg_str(), fuzz = info.fuzz, range = info.maximum - info.minimum),
+                    );
+                }
+            }
+        },
+        (Some(_), Err(e)) => {
+            log.warn_e(e.into(), "Error reading source axis info", ea!());
+        },
+        (None, _) => {
+            log.info("Source has no absolute axes", ea!());
+        },
+    }
+
+    // Keys
+    match source.supported_keys() {
+        Some(keys) => {
+            log.info("Source keys", ea!(keys = keys.iter().map(|k| k.dbg_str()).collect::<Vec<_>>().join(" ")));
+        },
+        None => {
+            log.info("Source has no keys", ea!());
+        },
+    }
+}
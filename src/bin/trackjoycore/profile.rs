@@ -0,0 +1,151 @@
+use std::{
+    collections::HashSet,
+    fs::{
+        read_dir,
+        read_link,
+        read_to_string,
+    },
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::Duration,
+};
+use loga::ea;
+use taskmanager::TaskManager;
+use tokio::sync::watch;
+use trackjoy::{
+    mapper::Tuning,
+    ProfileConfig,
+};
+use super::output::{
+    Message,
+    OutputTx,
+};
+
+/// Names of running processes - both the short kernel name (`comm`, truncated to
+/// 15 characters) and the executable file name where readable.
+fn running_processes() -> HashSet<String> {
+    let mut out = HashSet::new();
+    let Ok(procs) = read_dir("/proc") else {
+        return out;
+    };
+    for proc in procs {
+        let Ok(proc) = proc else {
+            continue;
+        };
+        if !proc.file_name().to_string_lossy().chars().all(|c| c.is_ascii_digit()) {
+            continue;
+        }
+        if let Ok(comm) = read_to_string(proc.path().join("comm")) {
+            out.insert(comm.trim().to_string());
+        }
+        if let Ok(exe) = read_link(proc.path().join("exe")) {
+            if let Some(name) = exe.file_name() {
+                out.insert(name.to_string_lossy().to_string());
+            }
+        }
+    }
+    return out;
+}
+
+/// Periodically scan running processes and switch to the first profile with a
+/// matching process, or back to the base tuning if none match. `profile_active`
+/// is set to whether a profile is active, see `release_without_profile`.
+pub fn build_process_switcher(
+    tm: &TaskManager,
+    log: &loga::Log,
+    base: Tuning,
+    profiles: Vec<(ProfileConfig, Tuning)>,
+    tuning: Arc<watch::Sender<Tuning>>,
+    output: OutputTx,
+    profile_active: Option<Arc<watch::Sender<bool>>>,
+) {
+    tm.critical_task::<_, loga::Error>({
+        let tm = tm.clone();
+        let log = log.clone();
+        async move {
+            let mut current: Option<String> = None;
+            loop {
+                let running = running_processes();
+                let found_i = profiles.iter().position(|(p, _)| p.processes.iter().any(|name| running.contains(name)));
+                let found = found_i.map(|i| &profiles[i]);
+                let found_name = found.map(|(p, _)| p.name.clone());
+                if found_name != current {
+                    log.info(
+                        "Switching profile",
+                        ea!(profile = found_name.as_ref().map(|n| n.as_str()).unwrap_or("-")),
+                    );
+                    tuning.send_replace(found.map(|(_, t)| *t).unwrap_or(base));
+                    _ = output.send(Message::Profile(found_i.map(|i| i + 1).unwrap_or(0)));
+                    if let Some(profile_active) = &profile_active {
+                        profile_active.send_replace(found_i.is_some());
+                    }
+                    current = found_name;
+                }
+                match tm.if_alive(tokio::time::sleep(Duration::from_secs(2))).await {
+                    Some(_) => { },
+                    None => {
+                        break;
+                    },
+                }
+            }
+            return Ok(());
+        }
+    });
+}
+
+/// Steps through the profiles on request (ex: pad swipes), with the top level
+/// settings first.
+pub struct ProfileCycler {
+    base: Tuning,
+    profiles: Vec<(ProfileConfig, Tuning)>,
+    /// 0 for the top level settings, otherwise the profile index plus 1
+    current: Mutex<usize>,
+    tuning: Arc<watch::Sender<Tuning>>,
+    output: OutputTx,
+    profile_active: Option<Arc<watch::Sender<bool>>>,
+}
+
+impl ProfileCycler {
+    pub fn new(
+        base: Tuning,
+        profiles: Vec<(ProfileConfig, Tuning)>,
+        tuning: Arc<watch::Sender<Tuning>>,
+        output: OutputTx,
+        profile_active: Option<Arc<watch::Sender<bool>>>,
+    ) -> ProfileCycler {
+        return ProfileCycler {
+            base: base,
+            profiles: profiles,
+            current: Mutex::new(0),
+            tuning: tuning,
+            output: output,
+            profile_active: profile_active,
+        };
+    }
+
+    /// Switch `step` positions forward (negative for back), wrapping around. Returns
+    /// the new position.
+    pub fn step(&self, log: &loga::Log, step: i32) -> usize {
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        let count = self.profiles.len() as i32 + 1;
+        *current = (*current as i32 + step).rem_euclid(count) as usize;
+        match *current {
+            0 => {
+                log.info("Switching profile", ea!(profile = "-"));
+                self.tuning.send_replace(self.base);
+            },
+            i => {
+                let (p, t) = &self.profiles[i - 1];
+                log.info("Switching profile", ea!(profile = p.name));
+                self.tuning.send_replace(*t);
+            },
+        }
+        _ = self.output.send(Message::Profile(*current));
+        if let Some(profile_active) = &self.profile_active {
+            profile_active.send_replace(*current != 0);
+        }
+        return *current;
+    }
+}
@@ -0,0 +1,290 @@
+//! `trackjoy self-test`: runs `trackjoy` on a fake trackpad, touches it, and checks
+//! the virtual gamepad does what it should.
+use std::{
+    process::Stdio,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use evdev::{
+    uinput::{
+        VirtualDevice,
+        VirtualDeviceBuilder,
+    },
+    AbsInfo,
+    AbsoluteAxisCode,
+    AttributeSet,
+    Device,
+    EventType,
+    InputEvent,
+    KeyCode,
+    PropType,
+    UinputAbsSetup,
+};
+use loga::{
+    ea,
+    ResultContext,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    process::Command,
+};
+use super::data::{
+    DEST_HALF,
+    DEST_MAX,
+};
+
+const PAD_NAME: &str = "trackjoy self-test pad";
+const GAMEPAD_NAME: &str = "trackjoy self-test";
+
+/// Fake pad surface size, 100x80mm at 10 units per mm. The stick circle spans the
+/// height.
+const PAD_WIDTH: i32 = 1000;
+const PAD_HEIGHT: i32 = 800;
+const PAD_RESOLUTION: i32 = 10;
+
+/// How long to wait for the virtual gamepad to appear.
+const START_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for touches to reach the virtual gamepad.
+const SETTLE: Duration = Duration::from_millis(150);
+
+/// Allowed difference from the expected axis values, in gamepad units.
+const AXIS_TOLERANCE: i32 = DEST_MAX / 10;
+
+const BUTTONS: [KeyCode; 4] = [KeyCode::BTN_SOUTH, KeyCode::BTN_EAST, KeyCode::BTN_NORTH, KeyCode::BTN_WEST];
+
+fn config() -> serde_json::Value {
+    return serde_json::json!({
+        "name": GAMEPAD_NAME,
+        "dead_inner": 0,
+        "dead_outer": 0.2,
+        "y_smash": 0,
+        "pad_mappings": [{
+            "axes": ["ABS_X", "ABS_Y"],
+            "buttons": BUTTONS,
+        }],
+    });
+}
+
+fn fake_pad() -> Result<VirtualDevice, loga::Error> {
+    let mut keys = AttributeSet::<KeyCode>::new();
+    keys.insert(KeyCode::BTN_LEFT);
+    keys.insert(KeyCode::BTN_TOUCH);
+    keys.insert(KeyCode::BTN_TOOL_FINGER);
+    let mut props = AttributeSet::<PropType>::new();
+    props.insert(PropType::POINTER);
+    let mut pad = VirtualDeviceBuilder::new().context("Error creating fake pad builder")?.name(PAD_NAME);
+    for (axis, max, resolution) in [
+        (AbsoluteAxisCode::ABS_X, PAD_WIDTH, PAD_RESOLUTION),
+        (AbsoluteAxisCode::ABS_Y, PAD_HEIGHT, PAD_RESOLUTION),
+        (AbsoluteAxisCode::ABS_MT_SLOT, 4, 0),
+        (AbsoluteAxisCode::ABS_MT_TRACKING_ID, 65535, 0),
+        (AbsoluteAxisCode::ABS_MT_POSITION_X, PAD_WIDTH, PAD_RESOLUTION),
+        (AbsoluteAxisCode::ABS_MT_POSITION_Y, PAD_HEIGHT, PAD_RESOLUTION),
+    ] {
+        pad =
+            pad
+                .with_absolute_axis(&UinputAbsSetup::new(axis, AbsInfo::new(0, 0, max, 0, 0, resolution)))
+                .context("Error adding axis to fake pad")?;
+    }
+    return Ok(
+        pad
+            .with_keys(&keys)
+            .context("Error adding keys to fake pad")?
+            .with_properties(&props)
+            .context("Error adding properties to fake pad")?
+            .build()
+            .context("Error creating fake pad, is uinput accessible?")?,
+    );
+}
+
+fn abs(code: AbsoluteAxisCode, value: i32) -> InputEvent {
+    return InputEvent::new(EventType::ABSOLUTE.0, code.0, value);
+}
+
+fn key(code: KeyCode, on: bool) -> InputEvent {
+    return InputEvent::new(EventType::KEY.0, code.0, on as i32);
+}
+
+/// Touch at a unit space position (the stick circle is the unit circle, y down).
+fn touch(pad: &mut VirtualDevice, x: f32, y: f32) -> Result<(), loga::Error> {
+    let x = PAD_WIDTH / 2 + (x * (PAD_HEIGHT / 2) as f32) as i32;
+    let y = PAD_HEIGHT / 2 + (y * (PAD_HEIGHT / 2) as f32) as i32;
+    pad.emit(&[
+        abs(AbsoluteAxisCode::ABS_MT_SLOT, 0),
+        abs(AbsoluteAxisCode::ABS_MT_TRACKING_ID, 1),
+        abs(AbsoluteAxisCode::ABS_MT_POSITION_X, x),
+        abs(AbsoluteAxisCode::ABS_MT_POSITION_Y, y),
+        key(KeyCode::BTN_TOUCH, true),
+        key(KeyCode::BTN_TOOL_FINGER, true),
+        abs(AbsoluteAxisCode::ABS_X, x),
+        abs(AbsoluteAxisCode::ABS_Y, y),
+    ]).context("Error sending fake touch")?;
+    return Ok(());
+}
+
+fn lift(pad: &mut VirtualDevice) -> Result<(), loga::Error> {
+    pad.emit(&[
+        abs(AbsoluteAxisCode::ABS_MT_SLOT, 0),
+        abs(AbsoluteAxisCode::ABS_MT_TRACKING_ID, -1),
+        key(KeyCode::BTN_TOUCH, false),
+        key(KeyCode::BTN_TOOL_FINGER, false),
+    ]).context("Error sending fake touch release")?;
+    return Ok(());
+}
+
+/// Find the virtual gamepad once `trackjoy` has created it.
+async fn find_gamepad() -> Result<Device, loga::Error> {
+    let start = Instant::now();
+    loop {
+        for (_, dev) in evdev::enumerate() {
+            if dev.name() == Some(GAMEPAD_NAME) {
+                return Ok(dev);
+            }
+        }
+        if start.elapsed() > START_TIMEOUT {
+            return Err(loga::err("trackjoy didn't create the virtual gamepad"));
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+struct Expect {
+    desc: &'static str,
+    /// Unit space
+    stick: [f32; 2],
+    button: Option<KeyCode>,
+}
+
+/// Compare the gamepad state, printing the result.
+fn check(gamepad: &Device, expect: &Expect) -> Result<bool, loga::Error> {
+    let axes = gamepad.get_abs_state().context("Error reading virtual gamepad axes")?;
+    let keys = gamepad.get_key_state().context("Error reading virtual gamepad buttons")?;
+    let mut problems = vec![];
+    for (axis, want) in [(AbsoluteAxisCode::ABS_X, expect.stick[0]), (AbsoluteAxisCode::ABS_Y, expect.stick[1])] {
+        let want = DEST_HALF + (want * DEST_HALF as f32) as i32;
+        let got = axes[axis.0 as usize].value;
+        if (got - want).abs() > AXIS_TOLERANCE {
+            problems.push(format!("{:?} is {}, expected about {}", axis, got, want));
+        }
+    }
+    for b in BUTTONS {
+        let want = expect.button == Some(b);
+        if keys.contains(b) != want {
+            problems.push(format!("{:?} is {}", b, if want {
+                "released, expected pressed"
+            } else {
+                "pressed, expected released"
+            }));
+        }
+    }
+    if problems.is_empty() {
+        println!("ok    {}", expect.desc);
+        return Ok(true);
+    } else {
+        println!("FAIL  {}: {}", expect.desc, problems.join(", "));
+        return Ok(false);
+    }
+}
+
+/// Returns an error if anything doesn't work.
+pub async fn run(log: &loga::Log) -> Result<(), loga::Error> {
+    let mut pad = fake_pad()?;
+    let mut pad_path = None;
+    let start = Instant::now();
+    while pad_path.is_none() {
+        for path in pad.enumerate_dev_nodes_blocking().context("Error listing fake pad dev nodes")? {
+            pad_path = Some(path.context("Error getting fake pad node path")?);
+        }
+        if pad_path.is_none() {
+            if start.elapsed() > START_TIMEOUT {
+                return Err(loga::err("Fake pad node didn't appear"));
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+    let pad_path = pad_path.unwrap();
+    println!("ok    Created fake pad at {}", pad_path.display());
+
+    // Run the real thing on it
+    let exe = std::env::current_exe().context("Error finding trackjoy executable")?;
+    let mut trackjoy =
+        Command::new(exe)
+            .arg("run")
+            .arg("-")
+            .arg("pad")
+            .arg(&pad_path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .context("Error starting trackjoy")?;
+    let mut stdin = trackjoy.stdin.take().unwrap();
+    stdin.write_all(config().to_string().as_bytes()).await.context("Error sending config to trackjoy")?;
+    drop(stdin);
+    let gamepad = match tokio::time::timeout(START_TIMEOUT, find_gamepad()).await {
+        Ok(g) => g?,
+        Err(_) => {
+            return Err(loga::err("trackjoy didn't create the virtual gamepad"));
+        },
+    };
+    println!("ok    trackjoy created the virtual gamepad");
+    tokio::time::sleep(SETTLE).await;
+
+    // Scripted touches
+    let mut failed = 0usize;
+    let steps: [(Option<[f32; 2]>, Expect); 6] = [
+        (None, Expect {
+            desc: "Centered with no touches",
+            stick: [0., 0.],
+            button: None,
+        }),
+        (Some([0.9, 0.]), Expect {
+            desc: "Touch on the right moves the stick right",
+            stick: [1., 0.],
+            button: None,
+        }),
+        (Some([0., -0.9]), Expect {
+            desc: "Sliding up moves the stick up",
+            stick: [0., -1.],
+            button: None,
+        }),
+        (None, Expect {
+            desc: "Lifting centers the stick",
+            stick: [0., 0.],
+            button: None,
+        }),
+        (Some([1.2, 0.9]), Expect {
+            desc: "Touch in the bottom right corner presses the first button",
+            stick: [0., 0.],
+            button: Some(BUTTONS[0]),
+        }),
+        (None, Expect {
+            desc: "Lifting releases the button",
+            stick: [0., 0.],
+            button: None,
+        }),
+    ];
+    for (touch_at, expect) in &steps {
+        match touch_at {
+            Some([x, y]) => touch(&mut pad, *x, *y)?,
+            None => lift(&mut pad)?,
+        }
+        tokio::time::sleep(SETTLE).await;
+        if !check(&gamepad, expect)? {
+            failed += 1;
+        }
+    }
+    if let Ok(Some(status)) = trackjoy.try_wait() {
+        return Err(loga::err_with("trackjoy stopped during the test", ea!(status = status)));
+    }
+    _ = trackjoy.start_kill();
+    _ = trackjoy.wait().await;
+    if failed > 0 {
+        return Err(loga::err_with("Self-test failed", ea!(failed = failed, total = steps.len())));
+    }
+    log.info("Self-test passed", ea!());
+    return Ok(());
+}
@@ -0,0 +1,175 @@
+//! `trackjoy install-service`: writes a systemd unit with the device access
+//! trackjoy needs and not much else.
+use std::{
+    os::unix::fs::MetadataExt,
+    path::{
+        Path,
+        PathBuf,
+    },
+    process::Command,
+};
+use loga::{
+    ea,
+    ResultContext,
+};
+
+pub struct ServiceOptions {
+    /// Unit name without `.service`
+    pub name: String,
+    /// A user unit instead of a system one
+    pub user: bool,
+    /// Arguments for `trackjoy run`, or `None` to run `trackjoy-juggler`
+    pub run_args: Option<Vec<String>>,
+    /// Some of the devices are `pad-hidraw`
+    pub hidraw: bool,
+    pub force: bool,
+    pub enable: bool,
+}
+
+/// Quote an `ExecStart` argument, escaping systemd specifiers and variables too.
+fn quote(arg: &str) -> String {
+    let escaped = arg.replace('\\', "\\\\").replace('"', "\\\"").replace('%', "%%").replace('$', "$$");
+    return format!("\"{}\"", escaped);
+}
+
+fn unit(exe_dir: &Path, exec: &[String], user: bool, reload: bool, hidraw: bool) -> String {
+    let mut out = String::new();
+    out.push_str("[Unit]\n");
+    out.push_str("Description=Trackpads as gamepads (trackjoy)\n");
+    out.push_str("\n[Service]\n");
+    out.push_str(&format!("ExecStart={}\n", exec.iter().map(|a| quote(a)).collect::<Vec<_>>().join(" ")));
+    if reload {
+        out.push_str("ExecReload=kill -HUP $MAINPID\n");
+    }
+    out.push_str("Restart=on-failure\n");
+    let path = format!("PATH={}:/usr/local/bin:/usr/bin:/bin", exe_dir.display());
+    out.push_str(&format!("Environment={}\n", quote(&path)));
+    out.push_str("NoNewPrivileges=yes\n");
+    if !user {
+        // Devices only, through the input group. `/dev/uinput` must be writable by the
+        // group too, which usually takes a udev rule.
+        out.push_str("DynamicUser=yes\n");
+        out.push_str("SupplementaryGroups=input\n");
+        out.push_str("DevicePolicy=closed\n");
+        out.push_str("DeviceAllow=char-input rw\n");
+        out.push_str("DeviceAllow=/dev/uinput rw\n");
+        if hidraw {
+            // Also needs a udev rule to make the node readable by the group
+            out.push_str("DeviceAllow=char-hidraw rw\n");
+        }
+
+        // Stats, calibration and crash reports; sockets
+        out.push_str("StateDirectory=trackjoy\n");
+        out.push_str("Environment=XDG_STATE_HOME=%S\n");
+        out.push_str("RuntimeDirectory=trackjoy\n");
+        out.push_str("Environment=XDG_RUNTIME_DIR=%t/trackjoy\n");
+
+        // Everything else
+        out.push_str("ProtectSystem=strict\n");
+        out.push_str("ProtectHome=read-only\n");
+        out.push_str("PrivateTmp=yes\n");
+        out.push_str("ProtectKernelTunables=yes\n");
+        out.push_str("ProtectKernelModules=yes\n");
+        out.push_str("ProtectControlGroups=yes\n");
+        out.push_str("RestrictAddressFamilies=AF_UNIX AF_NETLINK AF_INET AF_INET6\n");
+        out.push_str("LockPersonality=yes\n");
+        out.push_str("MemoryDenyWriteExecute=yes\n");
+    }
+    out.push_str("\n[Install]\n");
+    if user {
+        out.push_str("WantedBy=default.target\n");
+    } else {
+        out.push_str("WantedBy=multi-user.target\n");
+    }
+    return out;
+}
+
+fn systemctl(user: bool, args: &[&str]) -> Result<(), loga::Error> {
+    let mut command = Command::new("systemctl");
+    if user {
+        command.arg("--user");
+    }
+    let status = command.args(args).status().context("Error running systemctl")?;
+    if !status.success() {
+        return Err(loga::err_with("systemctl failed", ea!(args = args.join(" "), status = status)));
+    }
+    return Ok(());
+}
+
+/// Write the unit for the config, and enable and start it if requested.
+pub fn install(log: &loga::Log, config: &Path, options: ServiceOptions) -> Result<(), loga::Error> {
+    let config =
+        std::fs::canonicalize(config).context_with("Error finding config file", ea!(path = config.to_string_lossy()))?;
+    let exe = std::env::current_exe().context("Error finding trackjoy executable")?;
+    let exe_dir = exe.parent().map(|p| p.to_path_buf()).unwrap_or_default();
+    let (exec, reload) = match &options.run_args {
+        Some(run_args) => {
+            let mut exec = vec![exe.to_string_lossy().to_string(), "run".to_string()];
+            exec.push(config.to_string_lossy().to_string());
+            exec.extend(run_args.iter().cloned());
+            (exec, true)
+        },
+        None => {
+            // Picks up config changes by itself
+            let juggler = exe_dir.join("trackjoy-juggler");
+            (vec![juggler.to_string_lossy().to_string(), config.to_string_lossy().to_string()], false)
+        },
+    };
+
+    // Common problems
+    if !options.user {
+        match std::fs::metadata("/dev/uinput") {
+            Ok(m) => {
+                if m.mode() & 0o020 == 0 {
+                    log.warn(
+                        "/dev/uinput isn't writable by its group, the service won't be able to create the gamepad",
+                        ea!(hint = "Add a udev rule like `KERNEL==\"uinput\", GROUP=\"input\", MODE=\"0660\"`"),
+                    );
+                }
+            },
+            Err(e) => {
+                log.warn_e(e.into(), "Couldn't check /dev/uinput, is the uinput module loaded?", ea!());
+            },
+        }
+        if config.starts_with("/home") || config.starts_with("/root") {
+            log.warn(
+                "The config is in a home directory, which the service's dynamic user usually can't read",
+                ea!(path = config.to_string_lossy(), hint = "Move it somewhere like /etc/trackjoy/"),
+            );
+        }
+    }
+
+    // Write
+    let dir = if options.user {
+        let home =
+            std::env::var_os("HOME").ok_or_else(|| loga::err("HOME isn't set, can't find the user unit directory"))?;
+        let config_home =
+            std::env::var_os("XDG_CONFIG_HOME")
+                .filter(|d| !d.is_empty())
+                .map(PathBuf::from)
+                .unwrap_or(PathBuf::from(home).join(".config"));
+        config_home.join("systemd/user")
+    } else {
+        PathBuf::from("/etc/systemd/system")
+    };
+    let path = dir.join(format!("{}.service", options.name));
+    if path.exists() && !options.force {
+        return Err(
+            loga::err_with("The unit already exists, pass --force to replace it", ea!(path = path.to_string_lossy())),
+        );
+    }
+    std::fs::create_dir_all(&dir).context_with("Error creating unit directory", ea!(path = dir.to_string_lossy()))?;
+    std::fs::write(
+        &path,
+        unit(&exe_dir, &exec, options.user, reload, options.hidraw),
+    ).context_with("Error writing unit", ea!(path = path.to_string_lossy()))?;
+    println!("Wrote {}", path.display());
+
+    // Enable
+    if options.enable {
+        systemctl(options.user, &["daemon-reload"])?;
+        systemctl(options.user, &["enable", "--now", &format!("{}.service", options.name)])?;
+        println!("Enabled and started {}.service", options.name);
+    }
+    return Ok(());
+}
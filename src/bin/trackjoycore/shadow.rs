@@ -0,0 +1,115 @@
+//! Shadow mapping (`run --shadow`): a second config run on the same events whose
+//! output is only compared with the real output, for trying out changes.
+use std::collections::{
+    HashMap,
+    HashSet,
+};
+use evdev::{
+    AbsoluteAxisCode,
+    KeyCode,
+};
+use loga::{
+    ea,
+    DebugDisplay,
+};
+use trackjoy::{
+    mapper::{
+        Change,
+        Changes,
+        Tuning,
+    },
+    DeviceRef,
+};
+
+/// Axis values closer than this count as the same.
+const AXIS_TOLERANCE: f32 = 0.05;
+
+/// The shadow config's settings for a pad. The pad's surface settings (size,
+/// orientation, zones) are shared with the real mapping.
+pub struct PadShadow {
+    pub tuning: Tuning,
+    pub button_codes: [KeyCode; 4],
+    pub finger_buttons: HashMap<usize, KeyCode>,
+}
+
+#[derive(Default)]
+struct OutputState {
+    buttons: HashSet<KeyCode>,
+    axes: HashMap<AbsoluteAxisCode, f32>,
+}
+
+impl OutputState {
+    fn apply(&mut self, changes: &Changes) {
+        for change in changes {
+            match change {
+                Change::Button(code, true) => {
+                    self.buttons.insert(*code);
+                },
+                Change::Button(code, false) => {
+                    self.buttons.remove(code);
+                },
+                Change::Axis(code, value) => {
+                    self.axes.insert(*code, *value);
+                },
+            }
+        }
+    }
+}
+
+/// Tracks the real and shadow outputs of a source and logs when they start or stop
+/// differing.
+#[derive(Default)]
+pub struct ShadowDiff {
+    real: OutputState,
+    shadow: OutputState,
+    /// The last logged difference, empty if they matched
+    last: String,
+}
+
+impl ShadowDiff {
+    pub fn real(&mut self, changes: &Changes) {
+        self.real.apply(changes);
+    }
+
+    pub fn shadow(&mut self, changes: &Changes) {
+        self.shadow.apply(changes);
+    }
+
+    fn describe(&self) -> String {
+        let mut out = vec![];
+        let mut buttons = self.real.buttons.symmetric_difference(&self.shadow.buttons).collect::<Vec<_>>();
+        buttons.sort_by_key(|c| c.0);
+        for code in buttons {
+            out.push(format!("{:?} {}", code, if self.real.buttons.contains(code) {
+                "pressed, not in shadow"
+            } else {
+                "pressed only in shadow"
+            }));
+        }
+        let mut axes = self.real.axes.keys().chain(self.shadow.axes.keys()).copied().collect::<Vec<_>>();
+        axes.sort_by_key(|c| c.0);
+        axes.dedup();
+        for code in axes {
+            let real = self.real.axes.get(&code).copied().unwrap_or(0.);
+            let shadow = self.shadow.axes.get(&code).copied().unwrap_or(0.);
+            if (real - shadow).abs() > AXIS_TOLERANCE {
+                out.push(format!("{:?} {:.2} vs {:.2} in shadow", code, real, shadow));
+            }
+        }
+        return out.join(", ");
+    }
+
+    /// Log if the difference changed since the last call.
+    pub fn compare(&mut self, log: &loga::Log, source: DeviceRef) {
+        let diff = self.describe();
+        if diff == self.last {
+            return;
+        }
+        if diff.is_empty() {
+            log.info("Shadow output matches again", ea!(source = source.dbg_str()));
+        } else {
+            log.info("Shadow output differs", ea!(source = source.dbg_str(), diff = diff));
+        }
+        self.last = diff;
+    }
+}
@@ -0,0 +1,114 @@
+use std::{
+    process::Stdio,
+    time::Duration,
+};
+use loga::{
+    ea,
+    ResultContext,
+};
+use taskmanager::TaskManager;
+use tokio::{
+    io::{
+        AsyncBufReadExt,
+        BufReader,
+    },
+    process::{
+        Child,
+        Command,
+    },
+    sync::watch,
+};
+
+/// How long the sources get to release their devices before sleep continues.
+const RELEASE_GRACE: Duration = Duration::from_millis(200);
+
+/// Hold off sleeping until it's dropped, so there's time to release the devices
+/// after being told about it.
+fn inhibit() -> Result<Child, loga::Error> {
+    return Ok(
+        Command::new("systemd-inhibit")
+            .arg("--what=sleep")
+            .arg("--mode=delay")
+            .arg("--who=trackjoy")
+            .arg("--why=Releasing input devices")
+            .arg("sleep")
+            .arg("infinity")
+            .stdout(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .context("Error starting systemd-inhibit")?,
+    );
+}
+
+/// Watch logind for the system going to sleep and mark mapping inactive until it
+/// resumes, so the devices aren't grabbed over suspend.
+pub fn build(tm: &TaskManager, log: &loga::Log, awake: watch::Sender<bool>) -> Result<(), loga::Error> {
+    let mut monitor =
+        Command::new("gdbus")
+            .arg("monitor")
+            .arg("--system")
+            .arg("--dest")
+            .arg("org.freedesktop.login1")
+            .arg("--object-path")
+            .arg("/org/freedesktop/login1")
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .context("Error starting gdbus to watch for sleep")?;
+    let mut lines = BufReader::new(monitor.stdout.take().unwrap()).lines();
+    let mut inhibitor = match inhibit() {
+        Ok(i) => Some(i),
+        Err(e) => {
+            log.warn_e(e, "Couldn't delay sleep, devices may still be grabbed when it starts", ea!());
+            None
+        },
+    };
+    tm.critical_task::<_, loga::Error>({
+        let tm = tm.clone();
+        let log = log.clone();
+        async move {
+            let _monitor = monitor;
+            loop {
+                let line = match tm.if_alive(lines.next_line()).await {
+                    Some(x) => x,
+                    None => {
+                        break;
+                    },
+                }.context("Error reading gdbus output")?;
+                let line = match line {
+                    Some(l) => l,
+                    None => {
+                        return Err(loga::err("gdbus sleep watcher exited unexpectedly"));
+                    },
+                };
+
+                // `/org/freedesktop/login1: org.freedesktop.login1.Manager.PrepareForSleep
+                // (true,)`, `false` after resuming
+                let Some((_, args)) = line.split_once(".PrepareForSleep ") else {
+                    continue;
+                };
+                if args.starts_with("(true") {
+                    log.info("System going to sleep, releasing devices", ea!());
+                    awake.send_replace(false);
+                    if inhibitor.is_some() {
+                        tokio::time::sleep(RELEASE_GRACE).await;
+                        inhibitor = None;
+                    }
+                } else {
+                    log.info("System resumed, grabbing devices", ea!());
+                    awake.send_replace(true);
+                    if inhibitor.is_none() {
+                        match inhibit() {
+                            Ok(i) => inhibitor = Some(i),
+                            Err(e) => {
+                                log.warn_e(e, "Couldn't delay sleep", ea!());
+                            },
+                        }
+                    }
+                }
+            }
+            return Ok(());
+        }
+    });
+    return Ok(());
+}
@@ -0,0 +1,221 @@
+//! Usage statistics (`stats` in the config): where touches are on each pad and how
+//! often each button is pressed, for deciding where zones should go.
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::{
+        SystemTime,
+        UNIX_EPOCH,
+    },
+};
+use loga::{
+    ea,
+    ResultContext,
+};
+use serde::Serialize;
+use trackjoy::{
+    calibration::state_dir,
+    mapper::{
+        pad::PadSnapshot,
+        Change,
+        Changes,
+    },
+    DeviceRef,
+};
+
+/// Heatmap cells per unit space (the stick area radius).
+const HEAT_CELLS_PER_UNIT: f32 = 25.;
+
+/// Heatmap image pixels per cell.
+const HEAT_PNG_SCALE: usize = 4;
+
+fn source_name(source: DeviceRef) -> String {
+    match source {
+        DeviceRef::Pad(i) => return format!("pad{}", i),
+        DeviceRef::Keys(i) => return format!("keys{}", i),
+        DeviceRef::KeysSplit(i) => return format!("keys{}split", i),
+    }
+}
+
+#[derive(Serialize)]
+struct Heatmap {
+    /// Half the pad surface size in unit space (the stick area is the unit circle, y
+    /// down)
+    extent: [f32; 2],
+    width: usize,
+    height: usize,
+    /// Frames with a touch over each cell, rows top to bottom
+    cells: Vec<u64>,
+}
+
+#[derive(Serialize, Default)]
+struct StatsData {
+    /// Per pad
+    heatmaps: BTreeMap<String, Heatmap>,
+    /// Per source, presses per output button
+    presses: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+/// Shared by all sources, survives config reloads.
+#[derive(Clone, Default)]
+pub struct Stats(Arc<Mutex<StatsData>>);
+
+impl Stats {
+    /// Count the touches in a pad frame.
+    pub fn touches(&self, source: DeviceRef, snapshot: &PadSnapshot) {
+        if snapshot.touches.is_empty() {
+            return;
+        }
+        let mut data = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let heatmap = data.heatmaps.entry(source_name(source)).or_insert_with(|| {
+            let width = (snapshot.extent[0] * 2. * HEAT_CELLS_PER_UNIT).ceil().max(1.) as usize;
+            let height = (snapshot.extent[1] * 2. * HEAT_CELLS_PER_UNIT).ceil().max(1.) as usize;
+            Heatmap {
+                extent: snapshot.extent,
+                width: width,
+                height: height,
+                cells: vec![0; width * height],
+            }
+        });
+        for [x, y] in &snapshot.touches {
+            let col = ((x + heatmap.extent[0]) * HEAT_CELLS_PER_UNIT) as isize;
+            let row = ((y + heatmap.extent[1]) * HEAT_CELLS_PER_UNIT) as isize;
+            let col = col.clamp(0, heatmap.width as isize - 1) as usize;
+            let row = row.clamp(0, heatmap.height as isize - 1) as usize;
+            heatmap.cells[row * heatmap.width + col] += 1;
+        }
+    }
+
+    /// Count the button presses in changes from a source.
+    pub fn presses(&self, source: DeviceRef, changes: &Changes) {
+        let mut data = None;
+        for change in changes {
+            let Change::Button(code, true) = change else {
+                continue;
+            };
+            let data = data.get_or_insert_with(|| self.0.lock().unwrap_or_else(|e| e.into_inner()));
+            *data
+                .presses
+                .entry(source_name(source))
+                .or_default()
+                .entry(format!("{:?}", code))
+                .or_default() += 1;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        let data = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        return data.heatmaps.is_empty() && data.presses.is_empty();
+    }
+
+    /// Write the stats as JSON and each heatmap as a PNG to the state directory,
+    /// returning the paths.
+    pub fn write(&self) -> Result<Vec<PathBuf>, loga::Error> {
+        let dir = state_dir()?.join("stats");
+        std::fs::create_dir_all(
+            &dir,
+        ).context_with("Error creating stats directory", ea!(path = dir.to_string_lossy()))?;
+        let time = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let prefix = format!("{}-{}", time, std::process::id());
+        let data = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let mut paths = vec![];
+        let path = dir.join(format!("{}.json", prefix));
+        std::fs::write(
+            &path,
+            serde_json::to_vec_pretty(&*data).unwrap(),
+        ).context_with("Error writing stats", ea!(path = path.to_string_lossy()))?;
+        paths.push(path);
+        for (source, heatmap) in &data.heatmaps {
+            let path = dir.join(format!("{}-{}.png", prefix, source));
+            std::fs::write(
+                &path,
+                heatmap_png(heatmap),
+            ).context_with("Error writing heatmap", ea!(path = path.to_string_lossy()))?;
+            paths.push(path);
+        }
+        return Ok(paths);
+    }
+}
+
+/// Grayscale, brighter where touches were more often. Square root scaled so rarely
+/// touched areas are still visible.
+fn heatmap_png(heatmap: &Heatmap) -> Vec<u8> {
+    let max = heatmap.cells.iter().copied().max().unwrap_or(0).max(1) as f32;
+    let width = heatmap.width * HEAT_PNG_SCALE;
+    let height = heatmap.height * HEAT_PNG_SCALE;
+    let mut pixels = Vec::with_capacity(width * height);
+    for y in 0 .. height {
+        for x in 0 .. width {
+            let count = heatmap.cells[(y / HEAT_PNG_SCALE) * heatmap.width + x / HEAT_PNG_SCALE];
+            pixels.push(((count as f32 / max).sqrt() * 255.) as u8);
+        }
+    }
+    return png(width, height, &pixels);
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0 .. 8 {
+            crc = if crc & 1 != 0 {
+                0xedb88320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    return !crc;
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend((data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend(kind);
+    out.extend(data);
+    let crc = crc32(&out[start..]);
+    out.extend(crc.to_be_bytes());
+}
+
+/// An 8 bit grayscale PNG, uncompressed (heatmaps are small, this avoids a
+/// dependency).
+fn png(width: usize, height: usize, pixels: &[u8]) -> Vec<u8> {
+    // Each row starts with the filter type, none
+    let mut raw = Vec::with_capacity((width + 1) * height);
+    for row in pixels.chunks(width) {
+        raw.push(0);
+        raw.extend(row);
+    }
+
+    // Zlib stream of stored deflate blocks
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(u16::MAX as usize).peekable();
+    while let Some(block) = blocks.next() {
+        zlib.push(blocks.peek().is_none() as u8);
+        zlib.extend((block.len() as u16).to_le_bytes());
+        zlib.extend((!(block.len() as u16)).to_le_bytes());
+        zlib.extend(block);
+    }
+    let mut a = 1u32;
+    let mut b = 0u32;
+    for x in &raw {
+        a = (a + *x as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    zlib.extend(((b << 16) | a).to_be_bytes());
+    let mut header = vec![];
+    header.extend((width as u32).to_be_bytes());
+    header.extend((height as u32).to_be_bytes());
+
+    // Bit depth 8, grayscale, default compression, filter and no interlacing
+    header.extend([8, 0, 0, 0, 0]);
+    let mut out = b"\x89PNG\r\n\x1a\n".to_vec();
+    png_chunk(&mut out, b"IHDR", &header);
+    png_chunk(&mut out, b"IDAT", &zlib);
+    png_chunk(&mut out, b"IEND", &[]);
+    return out;
+}
@@ -0,0 +1,121 @@
+//! Read-only stream of the processed pad state, for overlays and visualizers.
+//! Clients connect to the socket and get a JSON object per line whenever something
+//! changes.
+use std::time::Duration;
+use futures::future::select_all;
+use loga::{
+    ea,
+    ResultContext,
+};
+use serde::Serialize;
+use taskmanager::TaskManager;
+use tokio::{
+    io::AsyncWriteExt,
+    net::{
+        UnixListener,
+        UnixStream,
+    },
+    sync::watch,
+};
+use trackjoy::mapper::pad::PadSnapshot;
+use super::control::runtime_socket_path;
+
+/// Clients that can't keep up for this long are disconnected.
+const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(Serialize)]
+struct Snapshot<'a> {
+    /// In the order of the pads on the command line
+    pads: &'a [PadSnapshot],
+}
+
+/// Listen on `trackjoy-NAME-tap.sock` in the runtime dir.
+pub fn build(
+    tm: &TaskManager,
+    log: &loga::Log,
+    name: &str,
+    mut pads: Vec<watch::Receiver<PadSnapshot>>,
+) -> Result<(), loga::Error> {
+    let path = runtime_socket_path(name, "-tap");
+
+    // Left over if a previous instance didn't stop cleanly
+    _ = std::fs::remove_file(&path);
+    let listener =
+        UnixListener::bind(&path).context_with("Error creating tap socket", ea!(path = path.to_string_lossy()))?;
+    log.info("Event tap listening", ea!(path = path.to_string_lossy()));
+    tm.critical_task::<_, loga::Error>({
+        let tm = tm.clone();
+        let log = log.clone();
+        async move {
+            let mut clients: Vec<UnixStream> = vec![];
+            loop {
+                enum Step {
+                    Connected(UnixStream),
+                    Changed,
+                    /// A pad stopped, shutting down
+                    Stopped,
+                }
+
+                let step = tm.if_alive(async {
+                    if pads.is_empty() {
+                        return listener.accept().await.map(|(c, _)| Step::Connected(c));
+                    }
+                    tokio::select!{
+                        c = listener.accept() => {
+                            return c.map(|(c, _)| Step::Connected(c));
+                        },
+                        (r, _, _) = select_all(pads.iter_mut().map(|p| Box::pin(p.changed()))) => {
+                            match r {
+                                Ok(_) => return Ok(Step::Changed),
+                                Err(_) => return Ok(Step::Stopped),
+                            }
+                        }
+                    }
+                }).await;
+                let send_to = match step {
+                    Some(Ok(Step::Connected(c))) => {
+                        // Start with the current state
+                        clients.push(c);
+                        clients.len() - 1 ..
+                    },
+                    Some(Ok(Step::Changed)) => 0 ..,
+                    Some(Ok(Step::Stopped)) => {
+                        break;
+                    },
+                    Some(Err(e)) => {
+                        log.warn_e(e.into(), "Error accepting tap connection", ea!());
+                        continue;
+                    },
+                    None => {
+                        break;
+                    },
+                };
+                let state = pads.iter_mut().map(|p| if send_to.start == 0 {
+                    p.borrow_and_update().clone()
+                } else {
+                    // Leave changes for the other clients
+                    p.borrow().clone()
+                }).collect::<Vec<_>>();
+                let mut line = serde_json::to_vec(&Snapshot { pads: &state }).unwrap();
+                line.push(b'\n');
+                let mut keep = vec![];
+                for (i, mut c) in clients.drain(..).enumerate() {
+                    if send_to.contains(&i) {
+                        match tokio::time::timeout(WRITE_TIMEOUT, c.write_all(&line)).await {
+                            Ok(Ok(_)) => { },
+                            _ => {
+                                // Disconnected or too slow
+                                continue;
+                            },
+                        }
+                    }
+                    keep.push(c);
+                }
+                clients = keep;
+            }
+            _ = std::fs::remove_file(&path);
+            return Ok(());
+        }
+    });
+    return Ok(());
+}
@@ -0,0 +1,120 @@
+//! Logging how one input is mapped, for a limited time, started with the control
+//! socket. The source logs how the input is interpreted and the changes it causes,
+//! the output what's finally sent for those changes.
+use std::{
+    collections::HashSet,
+    sync::{
+        Arc,
+        Mutex,
+        MutexGuard,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use evdev::{
+    EventType,
+    InputEvent,
+    KeyCode,
+};
+use loga::ea;
+use trackjoy::mapper::{
+    Change,
+    Changes,
+};
+use super::output_log::code_name;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TraceTarget {
+    Key(KeyCode),
+    /// Pad frames with touches
+    Touch,
+}
+
+struct TraceState {
+    target: TraceTarget,
+    until: Instant,
+    /// Output codes (type, code) changed by the traced input
+    outputs: HashSet<(u16, u16)>,
+}
+
+/// Shared by sources and the output, survives config reloads.
+#[derive(Clone, Default)]
+pub struct Tracer(Arc<Mutex<Option<TraceState>>>);
+
+impl Tracer {
+    /// The current trace, if it hasn't ended.
+    fn lock(&self) -> MutexGuard<'_, Option<TraceState>> {
+        let mut state = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if state.as_ref().map(|s| s.until <= Instant::now()).unwrap_or(false) {
+            *state = None;
+        }
+        return state;
+    }
+
+    /// Replaces any trace in progress.
+    pub fn start(&self, log: &loga::Log, target: TraceTarget, duration: Duration) {
+        log.info("Tracing input", ea!(input = format!("{:?}", target), seconds = duration.as_secs_f32()));
+        *self.0.lock().unwrap_or_else(|e| e.into_inner()) = Some(TraceState {
+            target: target,
+            until: Instant::now() + duration,
+            outputs: HashSet::new(),
+        });
+    }
+
+    /// Whether this key is being traced.
+    pub fn key(&self, key: KeyCode) -> bool {
+        return self.lock().as_ref().map(|s| s.target == TraceTarget::Key(key)).unwrap_or(false);
+    }
+
+    /// Whether pad touches are being traced.
+    pub fn touch(&self) -> bool {
+        return self.lock().as_ref().map(|s| s.target == TraceTarget::Touch).unwrap_or(false);
+    }
+
+    /// Log the changes caused by the traced input, and follow them through the
+    /// output.
+    pub fn changes(&self, log: &loga::Log, player: usize, changes: &Changes) {
+        let mut state = self.lock();
+        let Some(state) = state.as_mut() else {
+            return;
+        };
+        let mut out = vec![];
+        for change in changes {
+            match change {
+                Change::Button(k, on) => {
+                    state.outputs.insert((EventType::KEY.0, k.0));
+                    out.push(format!("{:?} {}", k, on));
+                },
+                Change::Axis(axis, v) => {
+                    state.outputs.insert((EventType::ABSOLUTE.0, axis.0));
+                    out.push(format!("{:?} {:.3}", axis, v));
+                },
+            }
+        }
+        if out.is_empty() {
+            log.info("Trace: no output changes", ea!(player = player));
+        } else {
+            log.info("Trace: output changes", ea!(player = player, changes = out.join(", ")));
+        }
+    }
+
+    /// Log what's sent to a virtual device for the traced input's changes, after
+    /// routing and output policies.
+    pub fn sent(&self, log: &loga::Log, dest: &str, events: &[InputEvent]) {
+        let state = self.lock();
+        let Some(state) = state.as_ref() else {
+            return;
+        };
+        let sent =
+            events
+                .iter()
+                .filter(|ev| state.outputs.contains(&(ev.event_type().0, ev.code())))
+                .map(|ev| format!("{} {}", code_name(ev), ev.value()))
+                .collect::<Vec<_>>();
+        if !sent.is_empty() {
+            log.info("Trace: sent", ea!(device = dest, events = sent.join(", ")));
+        }
+    }
+}
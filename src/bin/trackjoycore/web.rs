@@ -0,0 +1,232 @@
+//! Local web page for visualizing touches and editing the stick shape, enabled with
+//! the `web` feature.
+use std::{
+    io::Read,
+    path::PathBuf,
+    sync::OnceLock,
+    time::Duration,
+};
+use loga::{
+    ea,
+    ResultContext,
+};
+use taskmanager::TaskManager;
+use tokio::{
+    io::{
+        AsyncBufReadExt,
+        AsyncReadExt,
+        AsyncWriteExt,
+        BufReader,
+    },
+    net::{
+        TcpListener,
+        TcpStream,
+    },
+    sync::watch,
+};
+use trackjoy::{
+    mapper::pad::PadSnapshot,
+    parse_config,
+};
+
+const INDEX: &str = include_str!("web.html");
+
+/// Larger requests are rejected, configs are much smaller.
+const MAX_BODY: usize = 1024 * 1024;
+
+/// Drop clients that stall, since requests are handled one at a time.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Required on every request (`?token=`) so other local users and web pages can't
+/// change the config. Kept for the whole run so open pages keep working after
+/// reloads.
+static TOKEN: OnceLock<String> = OnceLock::new();
+
+struct Web {
+    config_path: PathBuf,
+    strict: bool,
+    pads: Vec<watch::Receiver<PadSnapshot>>,
+    /// `Host` headers accepted, anything else could be DNS rebinding
+    hosts: [String; 2],
+    token: &'static str,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    /// The `token` query parameter
+    token: Option<String>,
+    host: Option<String>,
+    body: Vec<u8>,
+}
+
+fn token() -> Result<&'static str, loga::Error> {
+    if let Some(t) = TOKEN.get() {
+        return Ok(t);
+    }
+    let mut bytes = [0u8; 16];
+    std::fs::File::open("/dev/urandom")
+        .and_then(|mut f| f.read_exact(&mut bytes))
+        .context("Error generating web UI token")?;
+    let token = bytes.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    return Ok(TOKEN.get_or_init(|| token));
+}
+
+fn response(status: &str, content_type: &str, body: &[u8]) -> Vec<u8> {
+    let mut out =
+        format!(
+            concat!(
+                "HTTP/1.1 {}\r\n",
+                "Content-Type: {}\r\n",
+                "Content-Length: {}\r\n",
+                "Cache-Control: no-store\r\n",
+                "Connection: close\r\n\r\n"
+            ),
+            status,
+            content_type,
+            body.len()
+        ).into_bytes();
+    out.extend_from_slice(body);
+    return out;
+}
+
+async fn read_request(conn: &mut TcpStream) -> Result<Request, loga::Error> {
+    let mut conn = BufReader::new(conn);
+    let mut line = String::new();
+    conn.read_line(&mut line).await.context("Error reading request line")?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or("").to_string();
+    let target = parts.next().unwrap_or("");
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let path = path.to_string();
+    let token = query.split('&').find_map(|p| p.strip_prefix("token=")).map(|t| t.to_string());
+    let mut host = None;
+    let mut content_length = 0usize;
+    loop {
+        let mut line = String::new();
+        if conn.read_line(&mut line).await.context("Error reading request header")? == 0 {
+            return Err(loga::err("Connection closed mid request"));
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((k, v)) = line.split_once(':') {
+            if k.eq_ignore_ascii_case("content-length") {
+                content_length = v.trim().parse().context("Invalid content-length")?;
+            } else if k.eq_ignore_ascii_case("host") {
+                host = Some(v.trim().to_string());
+            }
+        }
+    }
+    if content_length > MAX_BODY {
+        return Err(loga::err_with("Request body too large", ea!(length = content_length)));
+    }
+    let mut body = vec![0; content_length];
+    conn.read_exact(&mut body).await.context("Error reading request body")?;
+    return Ok(Request {
+        method: method,
+        path: path,
+        token: token,
+        host: host,
+        body: body,
+    });
+}
+
+/// Validate and write the config, then reload it the same as `SIGHUP`.
+fn save_config(web: &Web, body: &[u8]) -> Result<(), loga::Error> {
+    let value = serde_json::from_slice::<serde_json::Value>(body).context("Config isn't valid JSON")?;
+    parse_config(value.clone(), web.strict)?;
+    std::fs::write(&web.config_path, serde_json::to_string_pretty(&value).unwrap() + "\n").context_with(
+        "Error writing config",
+        ea!(path = web.config_path.to_string_lossy()),
+    )?;
+    unsafe {
+        libc::kill(libc::getpid(), libc::SIGHUP);
+    }
+    return Ok(());
+}
+
+async fn handle(mut conn: TcpStream, web: &Web) -> Result<(), loga::Error> {
+    let req = read_request(&mut conn).await?;
+    if !req.host.as_ref().map(|h| web.hosts.contains(h)).unwrap_or(false) {
+        conn.write_all(&response("403 Forbidden", "text/plain", b"Bad host")).await.context("Error sending response")?;
+        return Ok(());
+    }
+    if req.token.as_deref() != Some(web.token) {
+        conn
+            .write_all(&response("403 Forbidden", "text/plain", b"Missing or wrong token, use the URL from the log"))
+            .await
+            .context("Error sending response")?;
+        return Ok(());
+    }
+    let resp = match (req.method.as_str(), req.path.as_str()) {
+        ("GET", "/") => response("200 OK", "text/html; charset=utf-8", INDEX.as_bytes()),
+        ("GET", "/state") => {
+            let pads = web.pads.iter().map(|p| p.borrow().clone()).collect::<Vec<_>>();
+            response("200 OK", "application/json", &serde_json::to_vec(&pads).unwrap())
+        },
+        ("GET", "/config") => match std::fs::read(&web.config_path) {
+            Ok(c) => response("200 OK", "application/json", &c),
+            Err(e) => response("500 Internal Server Error", "text/plain", e.to_string().as_bytes()),
+        },
+        ("PUT", "/config") => match save_config(web, &req.body) {
+            Ok(_) => response("200 OK", "text/plain", b"Saved"),
+            Err(e) => response("400 Bad Request", "text/plain", e.to_string().as_bytes()),
+        },
+        _ => response("404 Not Found", "text/plain", b"Not found"),
+    };
+    conn.write_all(&resp).await.context("Error sending response")?;
+    return Ok(());
+}
+
+/// Serve the page on localhost, at a URL with a token that's logged. Saving reloads
+/// the config, which restarts this too.
+pub async fn build(
+    tm: &TaskManager,
+    log: &loga::Log,
+    port: u16,
+    config_path: PathBuf,
+    strict: bool,
+    pads: Vec<watch::Receiver<PadSnapshot>>,
+) -> Result<(), loga::Error> {
+    let listener =
+        TcpListener::bind(("127.0.0.1", port))
+            .await
+            .context_with("Error starting web UI server", ea!(port = port))?;
+    let token = token()?;
+    log.info("Web UI running", ea!(url = format!("http://127.0.0.1:{}/?token={}", port, token)));
+    let web = Web {
+        config_path: config_path,
+        strict: strict,
+        pads: pads,
+        hosts: [format!("127.0.0.1:{}", port), format!("localhost:{}", port)],
+        token: token,
+    };
+    tm.critical_task::<_, loga::Error>({
+        let tm = tm.clone();
+        let log = log.clone();
+        async move {
+            loop {
+                let conn = match tm.if_alive(listener.accept()).await {
+                    Some(Ok((c, _))) => c,
+                    Some(Err(e)) => {
+                        log.warn_e(e.into(), "Error accepting web UI connection", ea!());
+                        continue;
+                    },
+                    None => {
+                        break;
+                    },
+                };
+                match tm.if_alive(tokio::time::timeout(REQUEST_TIMEOUT, handle(conn, &web))).await {
+                    Some(Ok(Err(e))) => {
+                        log.warn_e(e, "Error handling web UI request", ea!());
+                    },
+                    _ => { },
+                }
+            }
+            return Ok(());
+        }
+    });
+    return Ok(());
+}
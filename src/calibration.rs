@@ -0,0 +1,87 @@
+//! Per-device pad calibration, kept in the state directory
+//! (`$XDG_STATE_HOME/trackjoy`, usually `~/.local/state/trackjoy`) so it doesn't
+//! need to be redone after restarting.
+use std::path::PathBuf;
+use loga::{
+    ea,
+    ResultContext,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct Calibration {
+    /// Measured horizontal position extents `[min, max]`, replacing the ones the pad
+    /// reports.
+    pub x: Option<[i32; 2]>,
+    /// Measured vertical position extents `[min, max]`.
+    pub y: Option<[i32; 2]>,
+    /// Where the stick center is relative to the middle of the pad, in millimeters
+    /// `[right, down]` as the pad is used.
+    #[serde(default)]
+    pub center_offset: [f32; 2],
+    /// Counterclockwise rotation of the pad as it's mounted, in degrees.
+    #[serde(default)]
+    pub rotation: f32,
+}
+
+/// `~/.local/state/trackjoy` or the equivalent under `XDG_STATE_HOME`.
+pub fn state_dir() -> Result<PathBuf, loga::Error> {
+    if let Some(dir) = std::env::var_os("XDG_STATE_HOME").filter(|d| !d.is_empty()) {
+        return Ok(PathBuf::from(dir).join("trackjoy"));
+    }
+    let home = std::env::var_os("HOME").ok_or_else(|| loga::err("HOME isn't set, can't find the state directory"))?;
+    return Ok(PathBuf::from(home).join(".local/state/trackjoy"));
+}
+
+/// The key for a device: the USB vendor and product id, and the unique id
+/// (serial number, bluetooth address) if it has one so identical devices are
+/// calibrated separately.
+pub fn device_key(vendor: u16, product: u16, uniq: Option<&str>) -> String {
+    match uniq.filter(|u| !u.is_empty()) {
+        Some(uniq) => return format!("{:04x}:{:04x}/{}", vendor, product, uniq),
+        None => return format!("{:04x}:{:04x}", vendor, product),
+    }
+}
+
+fn path(key: &str) -> Result<PathBuf, loga::Error> {
+    let name = key.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' {
+        c
+    } else {
+        '_'
+    }).collect::<String>();
+    return Ok(state_dir()?.join("calibration").join(format!("{}.json", name)));
+}
+
+/// Load the saved calibration for a device, if there is one.
+pub fn load(key: &str) -> Result<Option<Calibration>, loga::Error> {
+    let path = path(key)?;
+    let data = match std::fs::read(&path) {
+        Ok(d) => d,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context_with("Error reading calibration", ea!(path = path.to_string_lossy())),
+    };
+    return Ok(
+        Some(
+            serde_json::from_slice(
+                &data,
+            ).context_with("Error parsing calibration", ea!(path = path.to_string_lossy()))?,
+        ),
+    );
+}
+
+/// Save the calibration for a device, replacing any saved before.
+pub fn save(key: &str, calibration: &Calibration) -> Result<(), loga::Error> {
+    let path = path(key)?;
+    let dir = path.parent().unwrap();
+    std::fs::create_dir_all(
+        dir,
+    ).context_with("Error creating calibration directory", ea!(path = dir.to_string_lossy()))?;
+    std::fs::write(
+        &path,
+        serde_json::to_vec_pretty(calibration).unwrap(),
+    ).context_with("Error writing calibration", ea!(path = path.to_string_lossy()))?;
+    return Ok(());
+}
@@ -0,0 +1,298 @@
+//! Splitting found devices into groups, each driving one virtual gamepad (one
+//! `trackjoy` process in `trackjoy-juggler`), and assigning them to mapping slots.
+use loga::{
+    ea,
+    DebugDisplay,
+};
+use crate::Config;
+
+#[derive(PartialEq, Eq, Debug, Hash, PartialOrd, Ord, Clone, Copy)]
+pub enum DevType {
+    Keys,
+    Pad,
+}
+
+/// A usable device node.
+#[derive(PartialEq, Eq, Debug, Hash, PartialOrd, Ord, Clone)]
+pub struct FoundDevice {
+    pub type_: DevType,
+    pub path: String,
+    /// The unique id (serial number, bluetooth address) of the physical device, if
+    /// it has one.
+    pub uniq: Option<String>,
+}
+
+/// How devices are matched to mappings.
+pub struct GroupRules {
+    /// One entry per pad mapping, the unique id of the device pinned to it if any.
+    pub pads: Vec<Option<String>>,
+    /// One entry per keys mapping, like `pads`.
+    pub keys: Vec<Option<String>>,
+    /// Only use devices with one of these unique ids or paths. Empty allows all.
+    pub allow: Vec<String>,
+}
+
+impl GroupRules {
+    pub fn from_config(config: &Config) -> GroupRules {
+        return GroupRules {
+            pads: config.pad_mappings.iter().map(|m| m.uniq.clone()).collect(),
+            keys: config.keys_mappings.iter().map(|m| m.uniq.clone()).collect(),
+            allow: config.allow_devices.clone(),
+        };
+    }
+
+    fn slots(&self, type_: DevType) -> &[Option<String>] {
+        match type_ {
+            DevType::Keys => return &self.keys,
+            DevType::Pad => return &self.pads,
+        }
+    }
+}
+
+/// Devices for one virtual gamepad, by mapping slot. `None` slots have no device.
+#[derive(PartialEq, Eq, Debug, Hash, Clone)]
+pub struct Group {
+    pub pads: Vec<Option<String>>,
+    pub keys: Vec<Option<String>>,
+}
+
+impl Group {
+    fn new(rules: &GroupRules) -> Group {
+        return Group {
+            pads: vec![None; rules.pads.len()],
+            keys: vec![None; rules.keys.len()],
+        };
+    }
+
+    pub fn slots(&self, type_: DevType) -> &[Option<String>] {
+        match type_ {
+            DevType::Keys => return &self.keys,
+            DevType::Pad => return &self.pads,
+        }
+    }
+
+    fn slots_mut(&mut self, type_: DevType) -> &mut Vec<Option<String>> {
+        match type_ {
+            DevType::Keys => return &mut self.keys,
+            DevType::Pad => return &mut self.pads,
+        }
+    }
+
+    /// All slots have a device.
+    pub fn complete(&self) -> bool {
+        return self.pads.iter().chain(self.keys.iter()).all(|s| s.is_some());
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.pads.iter().chain(self.keys.iter()).all(|s| s.is_none());
+    }
+}
+
+/// Split devices into groups.
+///
+/// Devices pinned to a mapping by unique id go in that slot of the first group; if
+/// several devices have the same pinned id only the first (by path) is used. The
+/// remaining devices fill the unpinned slots in order of type then path, starting
+/// a new group when the next device doesn't fit. Devices not in the allowlist are
+/// ignored.
+///
+/// Errors if there's a device of a type with no unpinned slots to put it in.
+pub fn find_groupings(rules: &GroupRules, mut devices: Vec<FoundDevice>) -> Result<Vec<Group>, loga::Error> {
+    devices.retain(
+        |d| rules.allow.is_empty() ||
+            rules.allow.contains(&d.path) ||
+            d.uniq.as_ref().map(|u| rules.allow.contains(u)).unwrap_or(false),
+    );
+    devices.sort();
+    let mut groups = vec![Group::new(rules)];
+    let mut unpinned = vec![];
+    for device in devices {
+        let pin =
+            device
+                .uniq
+                .as_ref()
+                .and_then(|u| rules.slots(device.type_).iter().position(|p| p.as_ref() == Some(u)));
+        match pin {
+            Some(slot) => {
+                let dest = &mut groups[0].slots_mut(device.type_)[slot];
+                if dest.is_none() {
+                    *dest = Some(device.path);
+                }
+            },
+            None => {
+                unpinned.push(device);
+            },
+        }
+    }
+    let mut group_i = 0;
+    for device in unpinned {
+        if rules.slots(device.type_).iter().all(|p| p.is_some()) {
+            return Err(
+                loga::err_with(
+                    "Encountered device type with no config",
+                    ea!(type_ = device.type_.dbg_str(), device = device.path),
+                ),
+            );
+        }
+        loop {
+            if group_i == groups.len() {
+                groups.push(Group::new(rules));
+            }
+            let free =
+                groups[group_i]
+                    .slots(device.type_)
+                    .iter()
+                    .zip(rules.slots(device.type_))
+                    .position(|(s, p)| s.is_none() && p.is_none());
+            match free {
+                Some(slot) => {
+                    groups[group_i].slots_mut(device.type_)[slot] = Some(device.path);
+                    break;
+                },
+                None => {
+                    group_i += 1;
+                },
+            }
+        }
+    }
+    groups.retain(|g| !g.is_empty());
+    return Ok(groups);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use proptest::prelude::*;
+    use super::*;
+
+    fn dev_type() -> impl Strategy<Value = DevType> {
+        prop_oneof![Just(DevType::Keys), Just(DevType::Pad)]
+    }
+
+    fn devices() -> impl Strategy<Value = Vec<FoundDevice>> {
+        prop::collection::vec(
+            (dev_type(), proptest::option::of(0u8 .. 4)),
+            0 .. 12,
+        ).prop_map(
+            |v| v
+                .into_iter()
+                .enumerate()
+                .map(|(i, (type_, uniq))| FoundDevice {
+                    type_: type_,
+                    path: format!("/dev/input/event{}", i),
+                    uniq: uniq.map(|u| format!("uniq{}", u)),
+                })
+                .collect(),
+        )
+    }
+
+    fn rules() -> impl Strategy<Value = GroupRules> {
+        let slots = prop::collection::vec(proptest::option::of((0u8 .. 4).prop_map(|u| format!("uniq{}", u))), 1 .. 4);
+        (slots.clone(), slots).prop_map(|(pads, keys)| GroupRules {
+            pads: pads,
+            keys: keys,
+            allow: vec![],
+        })
+    }
+
+    fn all_paths(groups: &[Group]) -> Vec<String> {
+        return groups.iter().flat_map(|g| g.pads.iter().chain(g.keys.iter())).flatten().cloned().collect();
+    }
+
+    proptest!{
+        #[test]
+        fn devices_used_at_most_once(rules in rules(), devices in devices()) {
+            if let Ok(groups) = find_groupings(&rules, devices) {
+                let paths = all_paths(&groups);
+                let unique: HashSet<_> = paths.iter().collect();
+                prop_assert_eq!(paths.len(), unique.len());
+            }
+        }
+
+        #[test]
+        fn groups_match_mappings(rules in rules(), devices in devices()) {
+            if let Ok(groups) = find_groupings(&rules, devices) {
+                for g in &groups {
+                    prop_assert_eq!(g.pads.len(), rules.pads.len());
+                    prop_assert_eq!(g.keys.len(), rules.keys.len());
+                    prop_assert!(!g.is_empty());
+                }
+            }
+        }
+
+        #[test]
+        fn pinned_devices_in_first_group(rules in rules(), devices in devices()) {
+            if let Ok(groups) = find_groupings(&rules, devices.clone()) {
+                for d in &devices {
+                    let Some(uniq) = &d.uniq else {
+                        continue;
+                    };
+                    let Some(slot) = rules.slots(d.type_).iter().position(|p| p.as_ref() == Some(uniq)) else {
+                        continue;
+                    };
+
+                    // Some device with this id is in the slot
+                    let placed = groups[0].slots(d.type_)[slot].as_ref().unwrap();
+                    prop_assert!(devices.iter().any(|o| &o.path == placed && o.uniq.as_ref() == Some(uniq)));
+                }
+
+                // Pinned slots don't hold other devices in any group
+                for (i, g) in groups.iter().enumerate() {
+                    for type_ in [DevType::Keys, DevType::Pad] {
+                        for (slot, pin) in rules.slots(type_).iter().enumerate() {
+                            let (Some(pin), Some(path)) = (pin, &g.slots(type_)[slot]) else {
+                                continue;
+                            };
+                            prop_assert_eq!(i, 0);
+                            let d = devices.iter().find(|d| &d.path == path).unwrap();
+                            prop_assert_eq!(d.uniq.as_ref(), Some(pin));
+                        }
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn unpinned_devices_all_used(rules in rules(), devices in devices()) {
+            let unpinned: Vec<_> =
+                devices
+                    .iter()
+                    .filter(
+                        |d| d
+                            .uniq
+                            .as_ref()
+                            .map(|u| !rules.slots(d.type_).iter().any(|p| p.as_ref() == Some(u)))
+                            .unwrap_or(true),
+                    )
+                    .collect();
+            match find_groupings(&rules, devices.clone()) {
+                Ok(groups) => {
+                    let paths = all_paths(&groups);
+                    for d in unpinned {
+                        prop_assert!(paths.contains(&d.path));
+                    }
+                },
+                Err(_) => {
+                    // Only when there's nowhere to put a device
+                    prop_assert!(
+                        unpinned.iter().any(|d| rules.slots(d.type_).iter().all(|p| p.is_some()))
+                    );
+                },
+            }
+        }
+
+        #[test]
+        fn allowlist_filters(rules in rules(), devices in devices()) {
+            let rules = GroupRules {
+                allow: vec!["/dev/input/event0".to_string(), "uniq1".to_string()],
+                ..rules
+            };
+            if let Ok(groups) = find_groupings(&rules, devices.clone()) {
+                for path in all_paths(&groups) {
+                    let d = devices.iter().find(|d| d.path == path).unwrap();
+                    prop_assert!(d.path == "/dev/input/event0" || d.uniq.as_deref() == Some("uniq1"));
+                }
+            }
+        }
+    }
+}
@@ -1,30 +1,895 @@
-use std::collections::HashMap;
+pub mod calibration;
+pub mod grouping;
+pub mod mapper;
+pub mod nodes;
+mod strict;
+
+use std::{
+    collections::HashMap,
+    path::Path,
+};
 use evdev::{
     KeyCode,
     AbsoluteAxisCode,
+    EventSummary,
+    InputEvent,
+};
+use loga::{
+    ea,
+    ResultContext,
 };
 use serde::{
     Serialize,
     Deserialize,
 };
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct PadButtonConfig {
     pub axes: [AbsoluteAxisCode; 2],
     pub buttons: [KeyCode; 4],
+    /// Mirror the pad layout horizontally, so corners are assigned left to right
+    /// instead of right to left (0 is the bottom left). The stick isn't affected.
+    /// Defaults to on if `handedness` is `left`, otherwise off.
+    pub mirror_horizontal: Option<bool>,
+    /// Override the detected pad orientation. By default pads with a taller than wide
+    /// surface are treated as `portrait`, unless they're direct input devices like
+    /// touchscreens.
+    pub orientation: Option<PadOrientation>,
+    /// Ignore touch movements of this size or smaller (in the pad's position units),
+    /// to reduce jitter. Defaults to the fuzz the pad reports.
+    pub fuzz: Option<i32>,
+    /// Override the pad's reported horizontal resolution (position units per
+    /// millimeter), for pads that report none or a wrong one.
+    pub resolution_x: Option<i32>,
+    /// Override the pad's reported vertical resolution (position units per
+    /// millimeter).
+    pub resolution_y: Option<i32>,
+    /// Buttons held while a specific number of fingers are on the pad, ex: `{"3":
+    /// "BTN_MODE"}`. If the pad reports more fingers (`BTN_TOOL_*TAP`) than it tracks
+    /// touches for, the reported count is used. All touches count, even with
+    /// `multitouch` off.
+    #[serde(default)]
+    pub finger_buttons: HashMap<usize, KeyCode>,
+    /// Only track touches in multitouch slots below this. Some pad firmware reports
+    /// bogus slot numbers; touches in those slots are ignored with a warning.
+    /// Defaults to 16.
+    pub max_slots: Option<usize>,
+    /// Only touches landing in this part of the pad move the stick, ex: `{"top":
+    /// 0.66}` for the bottom third under the thumb. Touches landing elsewhere press
+    /// the corner button for the quarter of the pad they're in, even inside the stick
+    /// circle, so the other fingers of a grip don't pull the stick around.
+    pub stick_region: Option<PadRegionConfig>,
+    /// Pads that sense fingers hovering above the surface ignore them for the stick
+    /// and buttons. This maps the hovering position to a separate pair of axes
+    /// instead, centered when nothing hovers.
+    pub hover_axes: Option<[AbsoluteAxisCode; 2]>,
+    /// Buttons for physical clicks, for clickpads (pads where the whole surface clicks
+    /// down). Like libinput, where the finger is when clicking picks the button.
+    pub click_buttons: Option<PadClickConfig>,
+    /// Buttons for how hard the pad is pressed, for pads that sense force
+    /// (`ABS_PRESSURE`) like the Apple Magic Trackpad, see `PadForceConfig`.
+    pub force_buttons: Option<PadForceConfig>,
+    /// Use the pad as a radial menu instead of a stick: touches don't move the stick
+    /// or press the corner buttons, and lifting the last finger briefly presses the
+    /// button for the direction it was in.
+    pub selector: Option<PadSelectorConfig>,
+    /// Give feedback when a touch slides into a corner button zone, since the zone
+    /// edges can't be seen or felt. Touches landing in a zone don't trigger it.
+    pub zone_feedback: Option<ZoneFeedbackConfig>,
+    /// Swipe sideways with several fingers to switch profiles, see
+    /// `ProfileSwipeConfig`.
+    pub profile_swipe: Option<ProfileSwipeConfig>,
+    /// Putting this many fingers on the pad moves the pad to the next player's
+    /// gamepad, like `player_cycle_key` for keys devices, ex: `5`. It moves once
+    /// until all fingers lift. Only used with `players`.
+    pub player_cycle_fingers: Option<usize>,
+    /// For tablets, map pen tilt (`ABS_TILT_X`/`ABS_TILT_Y`) to a pair of axes, see
+    /// `PadTiltConfig`. Centered when the pen is away.
+    pub tilt: Option<PadTiltConfig>,
+    /// Drop these events from the pad before processing them, ex: `["ABS_MT_DISTANCE"]`
+    /// for a pad with unreliable hover reports.
+    #[serde(default)]
+    pub ignore_events: Vec<EventCode>,
+    /// Send these buttons to a different virtual device than the gamepad, ex:
+    /// `{"KEY_F12": "keyboard"}` with `KEY_F12` as a corner button for screenshots.
+    #[serde(default)]
+    pub outputs: HashMap<KeyCode, OutputDevice>,
+    /// Different corner buttons while modifier keys (`modifiers` in the keys mappings)
+    /// are held, ex: `[{"modifiers": ["KEY_LEFTSHIFT"], "buttons": [...]}]`. The
+    /// first entry with all of its modifiers held is used, so list combinations
+    /// before their parts.
+    #[serde(default)]
+    pub modifier_buttons: Vec<PadModifierButtons>,
+    /// Pressing this key (on a keys device) makes where the fingers on the pad are
+    /// the stick center, for drift or a preferred resting position. Pressing it with
+    /// no fingers on the pad goes back to the configured center.
+    pub recenter_key: Option<KeyCode>,
+    /// Use size and shape defaults for this pad model. By default the model is
+    /// detected by USB vendor and product id (the Perixx pad can only be selected
+    /// manually).
+    pub preset: Option<PadPreset>,
+    /// Always use this mapping for the pad with this unique id (serial number or
+    /// bluetooth address, see `/sys/class/input/eventN/device/uniq`), regardless of
+    /// the order devices are found in. Only used by `trackjoy-juggler`.
+    pub uniq: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PadClickConfig {
+    /// Clicking with a finger in the bottom left zone.
+    pub left: Option<KeyCode>,
+    /// Clicking with a finger in the bottom right zone.
+    pub right: Option<KeyCode>,
+    /// Clicking with no finger in the bottom zones.
+    pub other: Option<KeyCode>,
+    /// Height of the bottom zones in millimeters. Defaults to 10.
+    pub zone_height: Option<f32>,
+}
+
+/// Part of the pad, as fractions of its size after orientation; 0 is the top or
+/// left edge and 1 the bottom or right.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct PadRegionConfig {
+    /// Defaults to 0.
+    pub left: Option<f32>,
+    /// Defaults to 1.
+    pub right: Option<f32>,
+    /// Defaults to 0.
+    pub top: Option<f32>,
+    /// Defaults to 1.
+    pub bottom: Option<f32>,
+}
+
+/// Force levels, ex: a light press, a click, and a deep click each with their own
+/// button. Pressure is a fraction of the range the pad reports, 0 to 1.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PadForceConfig {
+    /// Ex: `[{"pressure": 0.05, "button": "BTN_TL"}, {"pressure": 0.4, "button":
+    /// "BTN_TR"}, {"pressure": 0.8, "button": "BTN_TL2"}]`. Only the button of the
+    /// highest level reached is held.
+    pub levels: Vec<PadForceLevel>,
+    /// A level is left when the pressure drops this far below it, so pressing right
+    /// at a level doesn't flicker. Defaults to 0.05.
+    pub hysteresis: Option<f32>,
+    /// Hold the buttons of all levels reached instead of only the highest.
+    #[serde(default)]
+    pub cumulative: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PadForceLevel {
+    pub pressure: f32,
+    pub button: KeyCode,
+}
+
+/// Tilt is shaped like the stick, but with its own settings since pens tilt much
+/// less than fingers move.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PadTiltConfig {
+    pub axes: [AbsoluteAxisCode; 2],
+    /// Like the stick `dead_inner`. Defaults to 0.1.
+    pub dead_inner: Option<f32>,
+    /// Like the stick `dead_outer`. Defaults to 0.
+    pub dead_outer: Option<f32>,
+    /// Like the stick `curve`. Defaults to 0.
+    pub curve: Option<f32>,
+    /// The fraction of the tilt range the pen reports that's full deflection, ex: 0.5
+    /// to reach the edge at half the pen's maximum tilt. Defaults to 1.
+    pub range: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PadModifierButtons {
+    pub modifiers: Vec<KeyCode>,
+    /// Replace `buttons` with these
+    pub buttons: [KeyCode; 4],
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PadSelectorConfig {
+    /// A button per direction, clockwise starting with up, ex: 4 buttons for up,
+    /// right, down, left.
+    pub buttons: Vec<KeyCode>,
+    /// Lifting within this distance of the center (as a fraction of the stick
+    /// radius) selects nothing, to cancel. Defaults to 0.3.
+    pub center: Option<f32>,
+}
+
+/// Switch to the next (swiping right) or previous (left) profile in `profiles`,
+/// with the top level settings first. A switch lasts until the next swipe, or until
+/// the running processes pick a different profile.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ProfileSwipeConfig {
+    /// Defaults to 4.
+    pub fingers: Option<usize>,
+    /// How far the fingers need to move sideways, in millimeters. Defaults to 20.
+    pub distance: Option<f32>,
+    /// Confirm the switch, repeated once per position in the list: once for the top
+    /// level settings, twice for the first profile, etc. Commands run once, with
+    /// `TRACKJOY_PROFILE` set to the position (0 for the top level settings). Defaults
+    /// to `zone_feedback`.
+    pub feedback: Option<ZoneFeedbackConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum ZoneFeedbackConfig {
+    /// Run a program, ex: `["paplay", "click.ogg"]`. `TRACKJOY_ZONE` is set to the
+    /// index of the corner button (0 .. 3).
+    Command(Vec<String>),
+    /// Rumble the pad, if it supports force feedback.
+    Rumble {
+        /// Defaults to 30.
+        ms: Option<u16>,
+    },
+    /// Beep the PC speaker. Needs write access to `/dev/console`.
+    Beep {
+        /// Defaults to 30.
+        ms: Option<u16>,
+        /// Defaults to 880.
+        hz: Option<u32>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum StickWeighting {
+    /// The plain average, the stick jumps to the middle when a second finger lands.
+    Mean,
+    /// Each newer touch counts twice as much as the one before it.
+    Recent,
+    /// Harder pressed touches count more (`ABS_MT_PRESSURE`). The mean if the pad
+    /// doesn't report pressure.
+    Pressure,
+    /// Larger contacts count more (`ABS_MT_TOUCH_MAJOR`). The mean if the pad doesn't
+    /// report contact size.
+    Size,
+    /// Only the first touch moves the stick, others are ignored until it lifts.
+    PrimaryOnly,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PadOrientation {
+    /// Use the pad axes as reported.
+    Landscape,
+    /// The pad reports a tall surface but is used turned a quarter turn
+    /// counterclockwise (reported top edge on the left) so its long side is
+    /// horizontal.
+    Portrait,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum PadPreset {
+    MagicTrackpad1,
+    MagicTrackpad2,
+    LogitechT650,
+    PerixxPeripad501,
+}
+
+/// Starting point values for a pad model. Set values in the config take precedence.
+pub struct PadPresetValues {
+    /// Stick oval horizontal radius in centimeters, like `width`.
+    pub width: f32,
+    /// Stick oval vertical radius in centimeters, like `height`.
+    pub height: f32,
+    pub y_smash: f32,
+}
+
+impl PadPreset {
+    pub fn values(&self) -> PadPresetValues {
+        match self {
+            // 13x11cm surface
+            PadPreset::MagicTrackpad1 => PadPresetValues {
+                width: 4.5,
+                height: 4.5,
+                y_smash: 1.,
+            },
+            // 16x11.5cm surface; wide enough for a slightly oval stick with room for the
+            // corners
+            PadPreset::MagicTrackpad2 => PadPresetValues {
+                width: 5.,
+                height: 4.5,
+                y_smash: 1.,
+            },
+            // 13x13cm surface
+            PadPreset::LogitechT650 => PadPresetValues {
+                width: 5.,
+                height: 5.,
+                y_smash: 2.,
+            },
+            // 12x9cm surface
+            PadPreset::PerixxPeripad501 => PadPresetValues {
+                width: 3.5,
+                height: 3.5,
+                y_smash: 1.,
+            },
+        }
+    }
+
+    /// Find the preset for a device by vendor and product id.
+    pub fn detect(vendor: u16, product: u16) -> Option<PadPreset> {
+        match (vendor, product) {
+            (0x05ac, 0x030e) => Some(PadPreset::MagicTrackpad1),
+            // Bluetooth devices report Apple's bluetooth vendor id
+            (0x05ac, 0x0265) | (0x004c, 0x0265) | (0x05ac, 0x0324) | (0x004c, 0x0324) => Some(
+                PadPreset::MagicTrackpad2,
+            ),
+            (0x046d, 0x4101) => Some(PadPreset::LogitechT650),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Handedness {
+    Right,
+    Left,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum KeysPreset {
+    /// Numpad as a stick on `ABS_X`/`ABS_Y`: 8/2/4/6 are up/down/left/right, 7/9/1/3
+    /// are diagonals, 5 is the stick click (`BTN_THUMBL`), 0 is `BTN_SOUTH` and Enter is
+    /// `BTN_EAST`.
+    Numpad,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct KeysStickConfig {
+    pub axes: [AbsoluteAxisCode; 2],
+    /// Which direction each key pushes the stick, as `[x, y]` with `-1` being full
+    /// left/up. The directions of all held keys are added and limited to full
+    /// deflection.
+    #[serde(default)]
+    pub keys: HashMap<KeyCode, [f32; 2]>,
+    /// Move the stick out gradually when keys are pressed, instead of jumping.
+    pub attack: Option<RampConfig>,
+    /// Move the stick back gradually when keys are released, instead of jumping.
+    pub release: Option<RampConfig>,
+}
+
+/// How a ramp progresses over its duration.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Easing {
+    /// Constant speed.
+    Linear,
+    /// Starts and ends slowly.
+    Smoothstep,
+    /// Starts quickly and slows towards the end.
+    Exponential,
+}
+
+/// A gradual change between two values.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct RampConfig {
+    /// How long the change takes.
+    pub ms: u64,
+    /// Defaults to `linear`.
+    pub easing: Option<Easing>,
+}
+
+/// An axis that stays where it's put, moved in steps by key presses (ex: volume
+/// keys or a rotary encoder as a throttle).
+#[derive(Serialize, Deserialize, Clone)]
+pub struct KeysNudgeConfig {
+    pub axis: AbsoluteAxisCode,
+    /// How far each press (or repeat) of a key moves the axis, where the full range
+    /// is `-1` to `1`. Ex: `{"KEY_VOLUMEUP": 0.1, "KEY_VOLUMEDOWN": -0.1}`.
+    #[serde(default)]
+    pub keys: HashMap<KeyCode, f32>,
+    /// Pressing this key moves the axis back to the center.
+    pub recenter_key: Option<KeyCode>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct KeysConfig {
+    /// Start with the mappings from a preset. Buttons and stick keys here override
+    /// the preset's, and `stick.axes` replaces the preset's axes.
+    pub preset: Option<KeysPreset>,
+    /// Turn keys into stick movements.
+    pub stick: Option<KeysStickConfig>,
+    /// Map keys by scan code instead of key code, for devices that report unusual
+    /// keys as `KEY_UNKNOWN`. Scan codes are hexadecimal, as shown in the `MSC_SCAN`
+    /// events in `evtest`, ex: `"70068": "BTN_SOUTH"`.
+    #[serde(default)]
+    pub scancodes: HashMap<String, KeyCode>,
+    /// Held keys auto-repeat. Normally repeats are ignored and the button stays
+    /// pressed; with this set each repeat toggles the button instead, for turbo.
+    #[serde(default)]
+    pub repeat_turbo: bool,
+    /// Axes adjusted in steps by keys.
+    #[serde(default)]
+    pub nudges: Vec<KeysNudgeConfig>,
+    /// What to do with media keys (volume, brightness, playback). Defaults to `map`.
+    pub media_keys: Option<MediaKeysPolicy>,
+    /// Drop these events from the device before processing them, ex: a broken key that
+    /// chatters.
+    #[serde(default)]
+    pub ignore_events: Vec<EventCode>,
+    /// Send these buttons to a different virtual device than the gamepad, like
+    /// `outputs` in the pad mappings.
+    #[serde(default)]
+    pub outputs: HashMap<KeyCode, OutputDevice>,
+    /// Keys that change what the pads do while held, see `modifier_buttons` in the pad
+    /// mappings. They're also mapped as usual if they're in the mappings.
+    #[serde(default)]
+    pub modifiers: Vec<KeyCode>,
+    /// Always use this mapping for the keys device with this unique id, like `uniq` in
+    /// the pad mappings. Only used by `trackjoy-juggler`.
+    pub uniq: Option<String>,
+    /// Map part of the device for another player, ex: the right half of a keyboard
+    /// for player 2 while the keys here are the left half for player 1.
+    pub split: Option<KeysSplitConfig>,
+    /// Other buttons for keys while layer keys are held, highest priority first. A
+    /// key pressed uses the first active layer, falling through to the next (and
+    /// finally the buttons here) if that layer doesn't map it and has `fallthrough`.
+    /// Stick keys and nudges aren't affected.
+    #[serde(default)]
+    pub layers: Vec<KeysLayerConfig>,
+    /// All other fields map a key to a button, ex: `"KEY_1": "BTN_SOUTH"`.
+    #[serde(flatten)]
+    pub buttons: HashMap<KeyCode, KeyCode>,
+}
+
+/// Buttons used in place of the keys mapping's, see `layers` in the keys mappings.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct KeysLayerConfig {
+    /// The layer is active while all of these keys (on the same device) are held.
+    /// They're also mapped as usual if they're in the mappings.
+    pub keys: Vec<KeyCode>,
+    /// Keys the layer doesn't map use the next active layer or the base mapping.
+    /// Otherwise they do nothing while the layer is active.
+    #[serde(default)]
+    pub fallthrough: bool,
+    /// All other fields map a key to a button, like in the keys mappings.
+    #[serde(flatten)]
+    pub buttons: HashMap<KeyCode, KeyCode>,
+}
+
+/// The part of a keys device mapped for another player, see `split` in the keys
+/// mappings. Other settings (turbo, outputs, modifiers) are shared with the main
+/// mapping, and a key should be mapped in only one of the two. It's `{"keys_split":
+/// N}` in axis mixes and its buttons don't move with `player_cycle_key`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct KeysSplitConfig {
+    /// The player this part controls, by index in `players` (starting at 0).
+    pub player: usize,
+    pub stick: Option<KeysStickConfig>,
+    #[serde(default)]
+    pub scancodes: HashMap<String, KeyCode>,
+    #[serde(default)]
+    pub nudges: Vec<KeysNudgeConfig>,
+    #[serde(flatten)]
+    pub buttons: HashMap<KeyCode, KeyCode>,
+}
+
+/// A key or absolute axis event code, ex: `"KEY_A"` or `"ABS_MT_DISTANCE"`.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(untagged)]
+pub enum EventCode {
+    Key(KeyCode),
+    Abs(AbsoluteAxisCode),
+}
+
+impl EventCode {
+    pub fn matches(&self, ev: &InputEvent) -> bool {
+        match ev.destructure() {
+            EventSummary::Key(_, code, _) => return *self == EventCode::Key(code),
+            EventSummary::AbsoluteAxis(_, code, _) => return *self == EventCode::Abs(code),
+            _ => return false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaKeysPolicy {
+    /// Treat them like other keys: mapped if they're in the mappings, otherwise
+    /// dropped while the device is grabbed.
+    Map,
+    /// Send them to a separate virtual keyboard so they keep working normally.
+    Passthrough,
+    /// Drop them, even if they're in the mappings.
+    Ignore,
+}
+
+// Defaults of unset settings, used where they're applied and by
+// `dump-effective-config`
+pub const DEFAULT_NAME: &str = "Trackpad JS";
+pub const DEFAULT_CURVE: f32 = 0.;
+pub const DEFAULT_DEAD_INNER: f32 = 0.;
+pub const DEFAULT_DEAD_OUTER: f32 = 0.4;
+pub const DEFAULT_RELEASE_MODE: ReleaseMode = ReleaseMode::Snap;
+pub const DEFAULT_FLICK_MS: u64 = 100;
+pub const DEFAULT_FLICK_BLEND_MS: u64 = 100;
+pub const DEFAULT_FLICK_SCALE: f32 = 1.;
+pub const DEFAULT_BATTERY_WARN_PERCENT: u8 = 15;
+pub const DEFAULT_CLICK_ZONE_HEIGHT: f32 = 10.;
+pub const DEFAULT_SELECTOR_CENTER: f32 = 0.3;
+pub const DEFAULT_TILT_DEAD_INNER: f32 = 0.1;
+pub const DEFAULT_TILT_DEAD_OUTER: f32 = 0.;
+pub const DEFAULT_TILT_RANGE: f32 = 1.;
+pub const DEFAULT_FORCE_HYSTERESIS: f32 = 0.05;
+pub const DEFAULT_SWIPE_FINGERS: usize = 4;
+pub const DEFAULT_SWIPE_DISTANCE: f32 = 20.;
+pub const DEFAULT_REPEAT_DELAY_MS: u64 = 400;
+pub const DEFAULT_REPEAT_INTERVAL_MS: u64 = 100;
+pub const DEFAULT_NOTCH_HYSTERESIS: f32 = 0.2;
+pub const DEFAULT_RELEASE_ORDER: ReleaseOrder = ReleaseOrder::Reverse;
+pub const DEFAULT_PRESS_ORDER_DELAY_MS: u64 = 0;
+pub const DEFAULT_RUMBLE_THRESHOLD: f32 = 0.5;
+
+impl Config {
+    /// Name of the virtual gamepad.
+    pub fn name(&self) -> &str {
+        return self.name.as_deref().unwrap_or(DEFAULT_NAME);
+    }
+
+    pub fn battery_warn_percent(&self) -> u8 {
+        return self.battery_warn_percent.unwrap_or(DEFAULT_BATTERY_WARN_PERCENT);
+    }
+
+    /// The stick shaping with this profile active (or the top level with none):
+    /// the profile's values, then the top level values, then the defaults.
+    pub fn shaping(&self, profile: Option<&ProfileConfig>) -> Shaping {
+        let curve = profile.and_then(|p| p.curve).or(self.curve).unwrap_or(DEFAULT_CURVE);
+        let flick = profile.and_then(|p| p.flick).or(self.flick);
+        return Shaping {
+            curve: curve,
+            dead_inner: profile.and_then(|p| p.dead_inner).or(self.dead_inner).unwrap_or(DEFAULT_DEAD_INNER),
+            dead_outer: profile.and_then(|p| p.dead_outer).or(self.dead_outer).unwrap_or(DEFAULT_DEAD_OUTER),
+            y_smash: profile.and_then(|p| p.y_smash).or(self.y_smash),
+            release_mode: profile
+                .and_then(|p| p.release_mode)
+                .or(self.release_mode)
+                .unwrap_or(DEFAULT_RELEASE_MODE),
+            flick: flick.map(|f| FlickConfig {
+                ms: Some(f.ms.unwrap_or(DEFAULT_FLICK_MS)),
+                blend_ms: Some(f.blend_ms.unwrap_or(DEFAULT_FLICK_BLEND_MS)),
+                curve: Some(f.curve.unwrap_or(curve)),
+                scale: Some(f.scale.unwrap_or(DEFAULT_FLICK_SCALE)),
+            }),
+        };
+    }
+}
+
+impl KeysConfig {
+    /// The mappings with the preset applied.
+    pub fn with_preset(&self) -> KeysConfig {
+        let mut out = match self.preset {
+            None => KeysConfig {
+                preset: None,
+                stick: None,
+                scancodes: HashMap::new(),
+                repeat_turbo: false,
+                nudges: vec![],
+                media_keys: None,
+                ignore_events: vec![],
+                outputs: HashMap::new(),
+                modifiers: vec![],
+                uniq: None,
+                split: None,
+                layers: vec![],
+                buttons: HashMap::new(),
+            },
+            Some(KeysPreset::Numpad) => KeysConfig {
+                preset: None,
+                stick: Some(KeysStickConfig {
+                    axes: [AbsoluteAxisCode::ABS_X, AbsoluteAxisCode::ABS_Y],
+                    keys: [
+                        (KeyCode::KEY_KP8, [0., -1.]),
+                        (KeyCode::KEY_KP2, [0., 1.]),
+                        (KeyCode::KEY_KP4, [-1., 0.]),
+                        (KeyCode::KEY_KP6, [1., 0.]),
+                        (KeyCode::KEY_KP7, [-1., -1.]),
+                        (KeyCode::KEY_KP9, [1., -1.]),
+                        (KeyCode::KEY_KP1, [-1., 1.]),
+                        (KeyCode::KEY_KP3, [1., 1.]),
+                    ].into_iter().collect(),
+                    attack: None,
+                    release: None,
+                }),
+                scancodes: HashMap::new(),
+                repeat_turbo: false,
+                nudges: vec![],
+                media_keys: None,
+                ignore_events: vec![],
+                outputs: HashMap::new(),
+                modifiers: vec![],
+                uniq: None,
+                split: None,
+                layers: vec![],
+                buttons: [
+                    (KeyCode::KEY_KP5, KeyCode::BTN_THUMBL),
+                    (KeyCode::KEY_KP0, KeyCode::BTN_SOUTH),
+                    (KeyCode::KEY_KPENTER, KeyCode::BTN_EAST),
+                ].into_iter().collect(),
+            },
+        };
+        out.buttons.extend(self.buttons.iter().map(|(k, v)| (*k, *v)));
+        out.scancodes.extend(self.scancodes.iter().map(|(k, v)| (k.clone(), *v)));
+        out.repeat_turbo = self.repeat_turbo;
+        out.media_keys = self.media_keys;
+        out.uniq = self.uniq.clone();
+        out.ignore_events = self.ignore_events.clone();
+        out.outputs = self.outputs.clone();
+        out.modifiers = self.modifiers.clone();
+        out.split = self.split.clone();
+        out.layers = self.layers.clone();
+        out.nudges.extend(self.nudges.iter().cloned());
+        if let Some(stick) = &self.stick {
+            match &mut out.stick {
+                Some(out_stick) => {
+                    out_stick.axes = stick.axes;
+                    out_stick.keys.extend(stick.keys.iter().map(|(k, v)| (*k, *v)));
+                    out_stick.attack = stick.attack;
+                    out_stick.release = stick.release;
+                },
+                None => {
+                    out.stick = Some(stick.clone());
+                },
+            }
+        }
+        return out;
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PlayerConfig {
+    /// Pad devices for this player, by index among the pad devices on the command
+    /// line (starting at 0).
+    #[serde(default)]
+    pub pads: Vec<usize>,
+    /// Keys devices for this player, by index among the keys devices on the command
+    /// line (starting at 0).
+    #[serde(default)]
+    pub keys: Vec<usize>,
+}
+
+/// A source device, by its index among the devices of that type on the command
+/// line (starting at 0), ex: `{"pad": 0}`.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceRef {
+    Pad(usize),
+    Keys(usize),
+    /// The `split` part of a keys mapping.
+    KeysSplit(usize),
+}
+
+/// A virtual device buttons can be sent to.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum OutputDevice {
+    /// The player's gamepad (default).
+    Gamepad,
+    /// A keyboard shared by all players, named like the gamepad with ` keyboard` at
+    /// the end. Created only if something is sent to it.
+    Keyboard,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MixInputConfig {
+    pub source: DeviceRef,
+    /// Multiplier for the source's value, negative to invert.
+    pub weight: f32,
+    /// Smooth the source's value before mixing, following changes with this time
+    /// constant (an exponential filter). Set per source so, ex: a pad can be smoothed
+    /// without also smoothing a keys stick that has its own ramps. Defaults to 0 (no
+    /// smoothing).
+    pub smoothing_ms: Option<u64>,
+}
+
+/// How sources are combined on an output axis.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct AxisMixConfig {
+    pub axis: AbsoluteAxisCode,
+    /// The value of the axis is the weighted sum of these sources, limited to the
+    /// axis range. Sources that aren't listed are ignored for this axis.
+    #[serde(default)]
+    pub inputs: Vec<MixInputConfig>,
+}
+
+/// How an output button is set when several sources map to it.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ButtonPolicy {
+    /// Pressed while any source holds it.
+    Any,
+    /// Follows whichever source changed it most recently.
+    LastWriter,
+}
+
+/// How an output axis is set when several sources map to it.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AxisPolicy {
+    /// The (weighted, see `axis_mixes`) sum of all sources, limited to the axis range.
+    Sum,
+    /// Follows whichever source changed it most recently.
+    LastWriter,
+}
+
+/// Auto-repeat for a held output, for menus in games that don't repeat d-pad input
+/// themselves. The output is held for `delay_ms`, then alternates released and
+/// pressed, once per `interval_ms`.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct RepeatConfig {
+    /// Defaults to 400.
+    pub delay_ms: Option<u64>,
+    /// Defaults to 100.
+    pub interval_ms: Option<u64>,
+}
+
+/// Discrete positions for an output axis, like throttle detents or a gear lever.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct NotchConfig {
+    /// Number of positions, evenly spread over the full range including both ends.
+    pub notches: usize,
+    /// How far past the middle between two notches the value has to move before the
+    /// axis changes notch, as a fraction of the distance between notches. Prevents
+    /// flickering when resting near the middle. Defaults to 0.2.
+    pub hysteresis: Option<f32>,
 }
 
-#[derive(Serialize, Deserialize)]
+/// Ordering of simultaneous button changes. Releases are sent before presses.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct PressOrderConfig {
+    /// Buttons are pressed in this order; unlisted buttons come after, in code order.
+    #[serde(default)]
+    pub order: Vec<KeyCode>,
+    /// Defaults to `reverse`.
+    pub release_order: Option<ReleaseOrder>,
+    /// Wait this long between each button change, so each is in a separate report.
+    /// Defaults to 0 (all in one report).
+    pub delay_ms: Option<u64>,
+}
+
+/// Holding all of the buttons at once releases everything on all virtual devices
+/// right away and ignores all sources for a while. What the sources held is
+/// forgotten, so keys stay released until pressed again. The buttons are output
+/// buttons (map a key or pad corner to them), from any source and player.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct EmergencyReleaseConfig {
+    /// Ex: `["BTN_START", "BTN_SELECT", "BTN_MODE"]`
+    pub buttons: Vec<KeyCode>,
+    /// Seconds to ignore the sources for afterwards, up to a day. Defaults to 5.
+    pub suspend: Option<f32>,
+}
+
+/// Default `suspend` in `EmergencyReleaseConfig`, in seconds.
+pub const DEFAULT_EMERGENCY_SUSPEND: f32 = 5.;
+
+/// Longest `suspend` in `EmergencyReleaseConfig`, in seconds.
+pub const MAX_EMERGENCY_SUSPEND: f32 = 24. * 60. * 60.;
+
+/// Store and recall output axis positions. The buttons are output buttons (map a
+/// key or pad corner to them) and are used up here, not sent to the gamepad.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct AxisSnapshotConfig {
+    /// Ex: `["ABS_X", "ABS_Y"]` for the stick.
+    pub axes: Vec<AbsoluteAxisCode>,
+    /// Pressing this stores the current positions of the axes. They're kept across
+    /// config reloads unless the virtual gamepad has to be recreated.
+    pub store: KeyCode,
+    /// While this is held the axes are at the stored positions, whatever the sources
+    /// send.
+    pub recall: KeyCode,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ReleaseOrder {
+    /// Release in the opposite of the press order.
+    Reverse,
+    /// Release in the press order.
+    Same,
+}
+
+/// What the pad stick does when the last touch controlling it lifts.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ReleaseMode {
+    /// Return to center immediately.
+    Snap,
+    /// Return to center gradually over this many milliseconds.
+    Decay(u64),
+    /// Stay where it was for this many milliseconds, then return to center.
+    Hold(u64),
+}
+
+/// Stick shaping for the start of a stick touch: these apply for `ms` after the
+/// touch lands, then blend into the usual values over `blend_ms`. Ex: a negative
+/// curve and `scale` above 1 for flicks, with a finer curve for holding afterwards.
+/// Timed from the oldest touch on the stick.
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct FlickConfig {
+    /// Defaults to 100.
+    pub ms: Option<u64>,
+    /// Defaults to 100.
+    pub blend_ms: Option<u64>,
+    /// Like `curve`, defaults to the usual curve.
+    pub curve: Option<f32>,
+    /// Multiplies the distance from the center, defaults to 1.
+    pub scale: Option<f32>,
+}
+
+/// Overrides for stick shaping, used in place of the top level values while the
+/// profile is active.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ProfileConfig {
+    pub name: String,
+    /// Activate this profile automatically while a process with one of these names is
+    /// running (the executable file name, or the kernel process name from
+    /// `/proc/PID/comm`). If several profiles match the first listed wins.
+    #[serde(default)]
+    pub processes: Vec<String>,
+    pub dead_inner: Option<f32>,
+    pub dead_outer: Option<f32>,
+    pub curve: Option<f32>,
+    pub y_smash: Option<f32>,
+    pub release_mode: Option<ReleaseMode>,
+    pub flick: Option<FlickConfig>,
+    /// Adjust output axes while this profile is active, after everything else, ex:
+    /// `{"ABS_Y": {"min": -0.8}}` to limit the stick forward to 80%.
+    #[serde(default)]
+    pub axes: HashMap<AbsoluteAxisCode, AxisAdjustConfig>,
+}
+
+/// Stick shaping with the defaults filled in, see `Config::shaping`.
+#[derive(Clone, Copy)]
+pub struct Shaping {
+    pub curve: f32,
+    pub dead_inner: f32,
+    pub dead_outer: f32,
+    /// Unset if not configured, so pads can use their preset's value.
+    pub y_smash: Option<f32>,
+    pub release_mode: ReleaseMode,
+    /// With all its values set
+    pub flick: Option<FlickConfig>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct RumbleDeadZoneConfig {
+    /// The inner dead zone (like `dead_inner`) while rumbling, if it's larger than the
+    /// usual one.
+    pub dead_inner: f32,
+    /// Only rumble at least this strong (0-1, the strongest motor) counts. Defaults to
+    /// 0.5.
+    pub threshold: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct AxisAdjustConfig {
+    /// Flip the axis.
+    #[serde(default)]
+    pub invert: bool,
+    /// Limit the axis to this range, where the full range is `-1` to `1`. Applied
+    /// after inverting.
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 pub struct Config {
+    /// Name of the virtual gamepad, defaults to `Trackpad JS`. When launched by
+    /// `trackjoy-juggler` this is a prefix, followed by the physical device name and a
+    /// number.
+    pub name: Option<String>,
     /// Which buttons to assign the 4 corners on each pad. Corners are right to left,
     /// bottom to top, with 0 being the bottom right. Each keyboard will get a
     /// subsequent mapping in this list. Codes are strings in this list (ex `"KEY_1"`):
     /// <https://docs.rs/evdev/latest/src/evdev/scancodes.rs.html>
     pub pad_mappings: Vec<PadButtonConfig>,
-    /// Which buttons to assign each key. Each pad will get a subsequent mapping in
-    /// this list. Codes are strings in this list (ex `"KEY_1"`):
+    /// Which buttons to assign each key. Each keys device will get a subsequent
+    /// mapping in this list. Codes are strings in this list (ex `"KEY_1"`):
     /// <https://docs.rs/evdev/latest/src/evdev/scancodes.rs.html>
-    pub keys_mappings: Vec<HashMap<KeyCode, KeyCode>>,
+    pub keys_mappings: Vec<KeysConfig>,
     /// Enable multitouch. On my 3rd party USB trackpad sometimes the off events for
     /// various touches would never come, leading to stuck buttons and axes. You can
     /// usually fix it by doing multitouch and releasing again (i.e. putting 2nd and
@@ -32,6 +897,9 @@ pub struct Config {
     /// off (default) only the first touch is recognized.
     #[serde(default)]
     pub multitouch: bool,
+    /// With `multitouch`, how several touches moving the stick are combined. Defaults
+    /// to `mean`.
+    pub stick_weighting: Option<StickWeighting>,
     /// Set the pad oval horizontal radius (in centimeters). Otherwise use a circle
     /// with radius of the full span of the smallest axis.
     pub width: Option<f32>,
@@ -52,4 +920,306 @@ pub struct Config {
     /// downward values, also making the top corner buttons larger. 0 = off, higher =
     /// more compression, default is 3.
     pub y_smash: Option<f32>,
+    /// What the pad stick does when the last touch on it lifts: `"snap"` back to
+    /// center (default), `{"decay": MS}` to return to center over that many
+    /// milliseconds, or `{"hold": MS}` to stay put that long before centering.
+    pub release_mode: Option<ReleaseMode>,
+    /// Different stick shaping right after a touch lands in the stick area, for quick
+    /// flicks, see `FlickConfig`.
+    pub flick: Option<FlickConfig>,
+    /// Layout preset for all pads. `left` mirrors the pads horizontally (see
+    /// `mirror_horizontal` in the pad mappings). Defaults to `right`.
+    pub handedness: Option<Handedness>,
+    /// Only map while a window with one of these X11 `WM_CLASS` names (instance or
+    /// class, see `xprop WM_CLASS`) is focused. Devices are released while other
+    /// windows are focused so they work normally on the desktop. Requires `xprop`; on
+    /// Wayland only XWayland windows are seen. Empty (default) maps all the time.
+    #[serde(default)]
+    pub focus_window_classes: Vec<String>,
+    /// Release the devices while the system is suspended, grabbing them again after
+    /// it resumes. Some pads stop sending multitouch data if they're grabbed over
+    /// suspend. Requires logind, `gdbus` and `systemd-inhibit`.
+    #[serde(default)]
+    pub release_on_sleep: bool,
+    /// Only map while one of `profiles` is active (switched to, or picked by its
+    /// running process). Devices are released with no profile active so they work
+    /// normally on the desktop.
+    #[serde(default)]
+    pub release_without_profile: bool,
+    /// Count where touches are on the pads and how often each button is pressed.
+    /// They're written to `~/.local/state/trackjoy/stats/` on exit or by request on
+    /// the control socket, as JSON and a heatmap image per pad.
+    #[serde(default)]
+    pub stats: bool,
+    /// Create a separate virtual gamepad for each player, with the listed devices.
+    /// Devices not listed go to the first player. If empty (default) there's one
+    /// gamepad with all devices.
+    #[serde(default)]
+    pub players: Vec<PlayerConfig>,
+    /// Pressing this key on a keys device moves the device to the next player's
+    /// gamepad. For pads see `player_cycle_fingers` in the pad mappings.
+    pub player_cycle_key: Option<KeyCode>,
+    /// Named profiles, see `ProfileConfig`.
+    #[serde(default)]
+    pub profiles: Vec<ProfileConfig>,
+    /// Custom combinations of sources for output axes. Axes without a mix are the sum
+    /// of everything mapped to them (limited to the axis range).
+    #[serde(default)]
+    pub axis_mixes: Vec<AxisMixConfig>,
+    /// Allow multiple devices of a player to map the same button, which is otherwise
+    /// an error. The button is pressed while any of them press it (or see
+    /// `button_policies`; buttons with a policy are always allowed to be shared).
+    #[serde(default)]
+    pub allow_shared_buttons: bool,
+    /// How to combine sources mapped to the same button, by button. Defaults to
+    /// `any`.
+    #[serde(default)]
+    pub button_policies: HashMap<KeyCode, ButtonPolicy>,
+    /// How to combine sources mapped to the same axis, by axis. Defaults to `sum`.
+    #[serde(default)]
+    pub axis_policies: HashMap<AbsoluteAxisCode, AxisPolicy>,
+    /// Auto-repeat these output buttons while held, ex: `{"BTN_DPAD_DOWN": {}}`.
+    #[serde(default)]
+    pub button_repeat: HashMap<KeyCode, RepeatConfig>,
+    /// Auto-repeat these output axes while off center, returning them to center
+    /// between pulses. For hats, ex: `{"ABS_HAT0Y": {"interval_ms": 150}}`.
+    #[serde(default)]
+    pub axis_repeat: HashMap<AbsoluteAxisCode, RepeatConfig>,
+    /// Snap these output axes to discrete positions, ex: `{"ABS_THROTTLE": {"notches":
+    /// 5}}`.
+    #[serde(default)]
+    pub axis_notches: HashMap<AbsoluteAxisCode, NotchConfig>,
+    /// Send gamepad buttons changing at the same time (ex: from a chord) in a fixed
+    /// order, optionally spaced out. By default they're sent together in no
+    /// particular order.
+    pub press_order: Option<PressOrderConfig>,
+    /// Buttons that store the current position of output axes and hold them there
+    /// later, like cruise control.
+    #[serde(default)]
+    pub axis_snapshots: Vec<AxisSnapshotConfig>,
+    /// Nothing is sent (everything is released) unless this button is held, ex: a
+    /// corner button or key, for pads mounted where they get touched by accident. The
+    /// button itself isn't sent. Applies per player, and to buttons routed to the
+    /// keyboard while no player holds it.
+    pub dead_man: Option<KeyCode>,
+    /// A panic button for stuck buttons or runaway macros, see
+    /// `EmergencyReleaseConfig`.
+    pub emergency_release: Option<EmergencyReleaseConfig>,
+    /// Widen the pad stick inner dead zone while the game rumbles, for pads near
+    /// vibration motors that shake fingers. The virtual gamepad supports rumble
+    /// (`FF_RUMBLE`) with this set, but doesn't vibrate anything.
+    pub rumble_dead_zone: Option<RumbleDeadZoneConfig>,
+    /// Devices with a battery (ex: Bluetooth trackpads) have their charge logged when
+    /// it changes, with a warning at or below this percent. Defaults to 15.
+    pub battery_warn_percent: Option<u8>,
+    /// Only use devices with one of these unique ids (see `uniq` in the mappings) or
+    /// paths. Empty (default) uses all devices. Only used by `trackjoy-juggler`.
+    #[serde(default)]
+    pub allow_devices: Vec<String>,
+    /// Names that can be used instead of codes in the pad and keys mappings, ex:
+    /// `{"A": "BTN_SOUTH", "LB": "BTN_TL", "LX": "ABS_X"}`.
+    #[serde(default)]
+    pub aliases: HashMap<String, String>,
+    /// Values reused in the pad and keys mappings, where `"$NAME"` is replaced by the
+    /// value, ex: `{"corners": ["BTN_TL", "BTN_TR", "BTN_NORTH", "BTN_WEST"]}` and
+    /// `"buttons": "$corners"`. Variables can't refer to other variables, and aren't
+    /// replaced in commands. Keys in the mappings can also contain a range (up to 768
+    /// numbers), making one entry per number with `{}` in the value replaced by the
+    /// number, ex: `"KEY_{1..4}": "BTN_TRIGGER_HAPPY{}"`.
+    #[serde(default)]
+    pub variables: HashMap<String, serde_json::Value>,
+    /// Reject unknown fields and codes, with suggestions for likely typos. Same as
+    /// `--strict`.
+    #[serde(default)]
+    pub strict: bool,
+}
+
+/// Replace aliases in mapping codes, recursively. Unique ids and command arguments
+/// aren't codes and are left alone.
+fn resolve_aliases(aliases: &HashMap<String, String>, value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::String(s) => {
+            if let Some(code) = aliases.get(s) {
+                *s = code.clone();
+            }
+        },
+        serde_json::Value::Array(a) => {
+            for v in a {
+                resolve_aliases(aliases, v);
+            }
+        },
+        serde_json::Value::Object(o) => {
+            let old = std::mem::take(o);
+            for (k, mut v) in old {
+                if k != "uniq" && k != "command" {
+                    resolve_aliases(aliases, &mut v);
+                }
+                o.insert(aliases.get(&k).cloned().unwrap_or(k), v);
+            }
+        },
+        _ => { },
+    }
+}
+
+/// Replace `"$NAME"` strings with the variable's value, recursively. Commands are
+/// free-form (ex: `$TRACKJOY_ZONE` for the shell) and are left alone.
+fn resolve_variables(
+    variables: &HashMap<String, serde_json::Value>,
+    path: &str,
+    value: &mut serde_json::Value,
+) -> Result<(), loga::Error> {
+    match value {
+        serde_json::Value::String(s) => {
+            if let Some(name) = s.strip_prefix('$') {
+                let Some(v) = variables.get(name) else {
+                    return Err(loga::err_with("Unknown variable in config", ea!(variable = name, path = path)));
+                };
+                *value = v.clone();
+            }
+        },
+        serde_json::Value::Array(a) => {
+            for (i, v) in a.iter_mut().enumerate() {
+                resolve_variables(variables, &format!("{}[{}]", path, i), v)?;
+            }
+        },
+        serde_json::Value::Object(o) => {
+            for (k, v) in o {
+                if k == "command" {
+                    continue;
+                }
+                resolve_variables(variables, &format!("{}.{}", path, k), v)?;
+            }
+        },
+        _ => { },
+    }
+    return Ok(());
+}
+
+/// Replace `{}` in strings with `n`, recursively.
+fn fill_range_value(value: &mut serde_json::Value, n: i64) {
+    match value {
+        serde_json::Value::String(s) => {
+            *s = s.replace("{}", &n.to_string());
+        },
+        serde_json::Value::Array(a) => {
+            for v in a {
+                fill_range_value(v, n);
+            }
+        },
+        serde_json::Value::Object(o) => {
+            for v in o.values_mut() {
+                fill_range_value(v, n);
+            }
+        },
+        _ => { },
+    }
+}
+
+/// Most entries a range in a config key can expand to, the size of the key code
+/// space.
+const MAX_RANGE_SIZE: i64 = 0x300;
+
+/// Expand keys with a range (`{N..M}`, inclusive) into an entry per number,
+/// recursively.
+fn expand_ranges(value: &mut serde_json::Value) -> Result<(), loga::Error> {
+    match value {
+        serde_json::Value::Array(a) => {
+            for v in a {
+                expand_ranges(v)?;
+            }
+        },
+        serde_json::Value::Object(o) => {
+            let old = std::mem::take(o);
+            for (k, mut v) in old {
+                expand_ranges(&mut v)?;
+                let range = k.split_once('{').and_then(|(prefix, rest)| {
+                    let (range, suffix) = rest.split_once('}')?;
+                    let (start, end) = range.split_once("..")?;
+                    let start = start.trim().parse::<i64>().ok()?;
+                    let end = end.trim().parse::<i64>().ok()?;
+                    return Some((prefix, start, end, suffix));
+                });
+                let Some((prefix, start, end, suffix)) = range else {
+                    o.insert(k, v);
+                    continue;
+                };
+                if end < start {
+                    return Err(loga::err_with("Range in config key ends before it starts", ea!(key = k)));
+                }
+                if end.saturating_sub(start) >= MAX_RANGE_SIZE {
+                    return Err(
+                        loga::err_with("Range in config key is too large", ea!(key = k, max = MAX_RANGE_SIZE)),
+                    );
+                }
+                for n in start ..= end {
+                    let mut v = v.clone();
+                    fill_range_value(&mut v, n);
+                    o.insert(format!("{}{}{}", prefix, n, suffix), v);
+                }
+            }
+        },
+        _ => { },
+    }
+    return Ok(());
+}
+
+/// Parse the config from json, applying `variables`, ranges and `aliases`. With
+/// `strict` (or `strict` in the config) unknown fields and codes are errors.
+pub fn parse_config(mut value: serde_json::Value, strict: bool) -> Result<Config, loga::Error> {
+    let variables: HashMap<String, serde_json::Value> = match value.get("variables") {
+        Some(v) => serde_json::from_value(v.clone()).context("Error parsing variables in config")?,
+        None => HashMap::new(),
+    };
+    let aliases: HashMap<String, String> = match value.get("aliases") {
+        Some(a) => serde_json::from_value(a.clone()).context("Error parsing aliases in config")?,
+        None => HashMap::new(),
+    };
+    if let serde_json::Value::Object(o) = &mut value {
+        for field in ["pad_mappings", "keys_mappings"] {
+            if let Some(v) = o.get_mut(field) {
+                resolve_variables(&variables, field, v)?;
+                expand_ranges(v)?;
+                resolve_aliases(&aliases, v);
+            }
+        }
+    }
+    let strict = strict || value.get("strict") == Some(&serde_json::Value::Bool(true));
+    if strict {
+        let mut problems = vec![];
+        strict::unknown_codes("", &value, &mut problems);
+        if problems.len() > 0 {
+            return Err(loga::err_with("Unknown codes in config", ea!(problems = problems.join("\n"))));
+        }
+    }
+    let config: Config = serde_json::from_value(value.clone()).context("Error parsing config")?;
+    if let Some(suspend) = config.emergency_release.as_ref().and_then(|e| e.suspend) {
+        if !(0. ..= MAX_EMERGENCY_SUSPEND).contains(&suspend) {
+            return Err(
+                loga::err_with(
+                    "Emergency release suspend must be from 0 to a day in seconds",
+                    ea!(suspend = suspend, max = MAX_EMERGENCY_SUSPEND),
+                ),
+            );
+        }
+    }
+    if strict {
+        let mut problems = vec![];
+        strict::unknown_fields(
+            "",
+            &value,
+            &serde_json::to_value(&config).context("Error serializing config for comparison")?,
+            &mut problems,
+        );
+        if problems.len() > 0 {
+            return Err(loga::err_with("Unknown fields in config", ea!(problems = problems.join("\n"))));
+        }
+    }
+    return Ok(config);
+}
+
+pub fn read_config(path: &Path, strict: bool) -> Result<Config, loga::Error> {
+    let data = std::fs::read(path).context_with("Error reading config", ea!(path = path.to_string_lossy()))?;
+    let value =
+        serde_json::from_slice(&data).context_with("Error parsing config", ea!(path = path.to_string_lossy()))?;
+    return Ok(parse_config(value, strict).context_with("Error parsing config", ea!(path = path.to_string_lossy()))?);
 }
@@ -0,0 +1,490 @@
+//! Keyboard keys to buttons, a stick and nudged axes.
+use std::{
+    collections::{
+        HashMap,
+        HashSet,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use evdev::{
+    AbsoluteAxisCode,
+    EventSummary,
+    InputEvent,
+    KeyCode,
+    MiscCode,
+    SynchronizationCode,
+};
+use glam::Vec2;
+use loga::{
+    ea,
+    ResultContext,
+};
+use crate::{
+    Easing,
+    KeysConfig,
+    KeysLayerConfig,
+    KeysNudgeConfig,
+    MediaKeysPolicy,
+    RampConfig,
+};
+use super::{
+    ease,
+    Change,
+    Changes,
+};
+
+/// How often the stick is updated while ramping.
+const RAMP_TICK: Duration = Duration::from_millis(8);
+
+/// Keys affected by the `media_keys` policy.
+pub const MEDIA_KEYS: &[KeyCode] = &[
+    KeyCode::KEY_MUTE,
+    KeyCode::KEY_VOLUMEDOWN,
+    KeyCode::KEY_VOLUMEUP,
+    KeyCode::KEY_MICMUTE,
+    KeyCode::KEY_BRIGHTNESSDOWN,
+    KeyCode::KEY_BRIGHTNESSUP,
+    KeyCode::KEY_KBDILLUMTOGGLE,
+    KeyCode::KEY_KBDILLUMDOWN,
+    KeyCode::KEY_KBDILLUMUP,
+    KeyCode::KEY_PLAYPAUSE,
+    KeyCode::KEY_PLAYCD,
+    KeyCode::KEY_PAUSECD,
+    KeyCode::KEY_STOPCD,
+    KeyCode::KEY_NEXTSONG,
+    KeyCode::KEY_PREVIOUSSONG,
+    KeyCode::KEY_FASTFORWARD,
+    KeyCode::KEY_REWIND,
+];
+
+/// What the caller needs to do after an event.
+#[derive(PartialEq, Debug)]
+pub enum KeysStep {
+    None,
+    /// Send this media key event to the auxiliary keyboard (`media_keys` is
+    /// `passthrough`).
+    Passthrough(KeyCode, i32),
+    /// A frame ended, send the changes to the (possibly new) player. May be empty if
+    /// only the player changed.
+    Send,
+}
+
+/// The stick moving gradually to where the held keys put it.
+struct StickRamp {
+    start: Instant,
+    from: Vec2,
+    to: Vec2,
+    config: RampConfig,
+}
+
+/// The keys state machine: feed it the keyboard's events, it produces output
+/// changes at the end of each frame.
+pub struct KeysMapper {
+    button_codes: HashMap<KeyCode, KeyCode>,
+    layers: Vec<KeysLayerConfig>,
+    /// Keys currently held, for layers
+    held_keys: HashSet<KeyCode>,
+    /// The button each held key pressed, chosen by the layers active when it was
+    /// pressed
+    key_buttons: HashMap<KeyCode, Option<KeyCode>>,
+    scan_codes: HashMap<u32, KeyCode>,
+    repeat_turbo: bool,
+    stick_axes: Option<[AbsoluteAxisCode; 2]>,
+    stick_keys: HashMap<KeyCode, Vec2>,
+    nudge_configs: Vec<KeysNudgeConfig>,
+    media_keys: MediaKeysPolicy,
+    player: usize,
+    player_count: usize,
+    player_cycle_key: Option<KeyCode>,
+    cycle_player: bool,
+    /// The scan code comes right before the key event it's for
+    pending_scan: Option<u32>,
+    buttons: HashMap<KeyCode, bool>,
+    last_buttons: HashMap<KeyCode, bool>,
+    /// Buttons that may differ from `last_buttons`
+    dirty: HashSet<KeyCode>,
+    stick_held: HashSet<KeyCode>,
+    stick_attack: Option<RampConfig>,
+    stick_release: Option<RampConfig>,
+    /// Where the held keys put the stick
+    stick_target: Vec2,
+    stick_ramp: Option<StickRamp>,
+    last_stick: Vec2,
+    /// Unit space, persists until moved or recentered
+    nudges: Vec<f32>,
+    last_nudges: Vec<f32>,
+}
+
+/// New button state for a key event value (0 release, 1 press, 2 repeat).
+fn button_state(last: bool, value: i32, repeat_turbo: bool) -> bool {
+    match value {
+        0 => return false,
+        2 => {
+            if repeat_turbo {
+                return !last;
+            } else {
+                return last;
+            }
+        },
+        _ => return true,
+    }
+}
+
+impl KeysMapper {
+    /// `held` are the keys already held at startup.
+    pub fn new(
+        config: KeysConfig,
+        held: impl IntoIterator<Item = KeyCode>,
+        player: usize,
+        player_count: usize,
+        player_cycle_key: Option<KeyCode>,
+    ) -> Result<KeysMapper, loga::Error> {
+        let mut scan_codes = HashMap::new();
+        for (scan, dest_code) in config.scancodes {
+            scan_codes.insert(
+                u32::from_str_radix(scan.trim_start_matches("0x"), 16).context_with(
+                    "Invalid hexadecimal scan code in keys mapping",
+                    ea!(scancode = scan),
+                )?,
+                dest_code,
+            );
+        }
+        let mut out = KeysMapper {
+            button_codes: config.buttons,
+            layers: config.layers,
+            held_keys: HashSet::new(),
+            key_buttons: HashMap::new(),
+            scan_codes: scan_codes,
+            repeat_turbo: config.repeat_turbo,
+            stick_axes: config.stick.as_ref().map(|s| s.axes),
+            stick_attack: config.stick.as_ref().and_then(|s| s.attack),
+            stick_release: config.stick.as_ref().and_then(|s| s.release),
+            stick_keys: match config.stick {
+                Some(s) => s.keys.into_iter().map(|(k, v)| (k, Vec2::from(v))).collect(),
+                None => HashMap::new(),
+            },
+            nudges: vec![0.; config.nudges.len()],
+            last_nudges: vec![0.; config.nudges.len()],
+            nudge_configs: config.nudges,
+            media_keys: config.media_keys.unwrap_or(MediaKeysPolicy::Map),
+            player: player,
+            player_count: player_count,
+            player_cycle_key: player_cycle_key,
+            cycle_player: false,
+            pending_scan: None,
+            buttons: HashMap::new(),
+            last_buttons: HashMap::new(),
+            dirty: HashSet::new(),
+            stick_held: HashSet::new(),
+            stick_target: Vec2::ZERO,
+            stick_ramp: None,
+            last_stick: Vec2::ZERO,
+        };
+        for dest_code in out
+            .button_codes
+            .values()
+            .chain(out.scan_codes.values())
+            .chain(out.layers.iter().flat_map(|l| l.buttons.values())) {
+            out.buttons.insert(*dest_code, false);
+            out.last_buttons.insert(*dest_code, false);
+        }
+        for k in held {
+            out.held_keys.insert(k);
+            out.key_buttons.insert(k, out.button_codes.get(&k).copied());
+            if let Some(c) = out.button_codes.get(&k) {
+                out.buttons.insert(*c, true);
+                out.dirty.insert(*c);
+            }
+            if out.stick_keys.contains_key(&k) {
+                out.stick_held.insert(k);
+            }
+        }
+        return Ok(out);
+    }
+
+    /// The button a key press maps to with the layers currently active.
+    fn layer_button(&self, key: KeyCode) -> Option<KeyCode> {
+        for layer in &self.layers {
+            if !layer.keys.iter().all(|k| self.held_keys.contains(k)) {
+                continue;
+            }
+            if let Some(c) = layer.buttons.get(&key) {
+                return Some(*c);
+            }
+            if !layer.fallthrough {
+                return None;
+            }
+        }
+        return self.button_codes.get(&key).copied();
+    }
+
+    /// The player currently controlled.
+    pub fn player(&self) -> usize {
+        return self.player;
+    }
+
+    /// How a key is currently mapped, for tracing: the media key policy, each layer
+    /// and whether it's active, the button held or that a press would press, and the
+    /// stick and nudges it moves.
+    pub fn explain(&self, key: KeyCode) -> String {
+        let mut out = vec![];
+        if MEDIA_KEYS.contains(&key) {
+            out.push(format!("media key, policy {}", match self.media_keys {
+                MediaKeysPolicy::Map => "map",
+                MediaKeysPolicy::Passthrough => "passthrough",
+                MediaKeysPolicy::Ignore => "ignore",
+            }));
+        }
+        for (i, layer) in self.layers.iter().enumerate() {
+            let active = layer.keys.iter().all(|k| self.held_keys.contains(k));
+            let mapping = match layer.buttons.get(&key) {
+                Some(c) => format!("{:?}", c),
+                None if layer.fallthrough => "falls through".to_string(),
+                None => "unmapped".to_string(),
+            };
+            out.push(
+                format!(
+                    "layer {} ({:?}) {}: {}",
+                    i,
+                    layer.keys,
+                    if active {
+                        "active"
+                    } else {
+                        "inactive"
+                    },
+                    mapping
+                ),
+            );
+        }
+        match self.button_codes.get(&key) {
+            Some(c) => out.push(format!("base {:?}", c)),
+            None => out.push("base unmapped".to_string()),
+        }
+        match self.key_buttons.get(&key) {
+            Some(Some(c)) => out.push(format!("held, pressed {:?}", c)),
+            Some(None) => out.push("held, pressed nothing".to_string()),
+            None => match self.layer_button(key) {
+                Some(c) => out.push(format!("press maps to {:?}", c)),
+                None => out.push("press maps to nothing".to_string()),
+            },
+        }
+        if let Some(v) = self.stick_keys.get(&key) {
+            out.push(format!("stick {:.2},{:.2}", v.x, v.y));
+        }
+        for nudge in &self.nudge_configs {
+            if let Some(step) = nudge.keys.get(&key) {
+                out.push(format!("nudge {:?} by {}", nudge.axis, step));
+            }
+            if nudge.recenter_key == Some(key) {
+                out.push(format!("recenters nudge {:?}", nudge.axis));
+            }
+        }
+        if self.player_cycle_key == Some(key) {
+            out.push(format!("cycles player, now {}", self.player));
+        }
+        return out.join("; ");
+    }
+
+    /// Release all pressed buttons and center the stick. Keys keep being tracked.
+    pub fn release(&mut self, changes: &mut Changes) {
+        for (k, on) in &mut self.last_buttons {
+            if *on {
+                changes.push(Change::Button(*k, false));
+                *on = false;
+
+                // Pressed again when reactivated if it's still held
+                self.dirty.insert(*k);
+            }
+        }
+        if let Some(axes) = self.stick_axes {
+            if self.last_stick != Vec2::ZERO {
+                changes.push(Change::Axis(axes[0], 0.));
+                changes.push(Change::Axis(axes[1], 0.));
+                self.last_stick = Vec2::ZERO;
+            }
+
+            // Ramps again from center when reactivated
+            self.stick_target = Vec2::ZERO;
+            self.stick_ramp = None;
+        }
+    }
+
+    /// When `tick` should be called next, if the stick is ramping.
+    pub fn deadline(&self, now: Instant) -> Option<Instant> {
+        return self.stick_ramp.as_ref().map(|_| now + RAMP_TICK);
+    }
+
+    /// Update the ramping stick, see `deadline`.
+    pub fn tick(&mut self, active: bool, now: Instant, changes: &mut Changes) {
+        if self.stick_ramp.is_none() || !active {
+            return;
+        }
+        let stick = self.stick_value(now);
+        self.push_stick(stick, changes);
+    }
+
+    /// The stick position, following the ramp if there is one.
+    fn stick_value(&mut self, now: Instant) -> Vec2 {
+        let Some(ramp) = &self.stick_ramp else {
+            return self.stick_target;
+        };
+        let elapsed = now.saturating_duration_since(ramp.start);
+        if ramp.config.ms == 0 || elapsed >= Duration::from_millis(ramp.config.ms) {
+            let to = ramp.to;
+            self.stick_ramp = None;
+            return to;
+        }
+        let p = elapsed.as_secs_f32() * 1000. / ramp.config.ms as f32;
+        return ramp.from + (ramp.to - ramp.from) * ease(ramp.config.easing.unwrap_or(Easing::Linear), p);
+    }
+
+    fn push_stick(&mut self, stick: Vec2, changes: &mut Changes) {
+        let Some(axes) = self.stick_axes else {
+            return;
+        };
+        if stick != self.last_stick {
+            changes.push(Change::Axis(axes[0], stick.x));
+            changes.push(Change::Axis(axes[1], stick.y));
+            self.last_stick = stick;
+        }
+    }
+
+    /// Process an event. At the end of a frame (`SYN_REPORT`) changes to the outputs
+    /// are added to `changes`, unless `active` is off.
+    pub fn event(&mut self, ev: InputEvent, active: bool, now: Instant, changes: &mut Changes) -> KeysStep {
+        match ev.destructure() {
+            EventSummary::Synchronization(_, t, _) => {
+                if t != SynchronizationCode::SYN_REPORT {
+                    return KeysStep::None;
+                }
+                self.pending_scan = None;
+                if !active {
+                    return KeysStep::None;
+                }
+                let cycled = self.cycle_player;
+                if self.cycle_player {
+                    // Everything held moves to the new player along with the device
+                    self.cycle_player = false;
+                    self.player = (self.player + 1) % self.player_count;
+                }
+                for k in self.dirty.drain() {
+                    let on = self.buttons[&k];
+                    let last = self.last_buttons.get_mut(&k).unwrap();
+                    if on != *last {
+                        changes.push(Change::Button(k, on));
+                        *last = on;
+                    }
+                }
+                if self.stick_axes.is_some() {
+                    let mut unitspace_vec = Vec2::ZERO;
+                    for k in &self.stick_held {
+                        unitspace_vec += self.stick_keys[k];
+                    }
+                    if unitspace_vec.length() > 1. {
+                        unitspace_vec = unitspace_vec.normalize();
+                    }
+                    if unitspace_vec != self.stick_target {
+                        // Moving out uses the attack ramp, moving in the release ramp
+                        self.stick_target = unitspace_vec;
+                        let ramp = if unitspace_vec.length() >= self.last_stick.length() {
+                            self.stick_attack
+                        } else {
+                            self.stick_release
+                        };
+                        self.stick_ramp = ramp.map(|config| StickRamp {
+                            start: now,
+                            from: self.last_stick,
+                            to: unitspace_vec,
+                            config: config,
+                        });
+                    }
+                    let stick = self.stick_value(now);
+                    self.push_stick(stick, changes);
+                }
+                for (i, nudge) in self.nudge_configs.iter().enumerate() {
+                    if self.nudges[i] != self.last_nudges[i] {
+                        changes.push(Change::Axis(nudge.axis, self.nudges[i]));
+                        self.last_nudges[i] = self.nudges[i];
+                    }
+                }
+                if changes.len() > 0 || cycled {
+                    return KeysStep::Send;
+                }
+                return KeysStep::None;
+            },
+            EventSummary::Key(_, t, v) => {
+                if MEDIA_KEYS.contains(&t) {
+                    match self.media_keys {
+                        MediaKeysPolicy::Map => { },
+                        MediaKeysPolicy::Passthrough => {
+                            self.pending_scan = None;
+                            return KeysStep::Passthrough(t, v);
+                        },
+                        MediaKeysPolicy::Ignore => {
+                            self.pending_scan = None;
+                            return KeysStep::None;
+                        },
+                    }
+                }
+                if Some(t) == self.player_cycle_key && v == 1 {
+                    self.cycle_player = true;
+                }
+                // The layers are picked on press, so releasing a layer key doesn't leave
+                // buttons stuck
+                let button = match v {
+                    1 => {
+                        let c = self.layer_button(t);
+                        self.key_buttons.insert(t, c);
+                        c
+                    },
+                    _ => self.key_buttons.get(&t).copied().unwrap_or_else(|| self.button_codes.get(&t).copied()),
+                };
+                if v == 0 {
+                    self.held_keys.remove(&t);
+                    self.key_buttons.remove(&t);
+                } else {
+                    self.held_keys.insert(t);
+                }
+                match button {
+                    Some(c) => {
+                        self.buttons.insert(c, button_state(self.buttons[&c], v, self.repeat_turbo));
+                        self.dirty.insert(c);
+                    },
+                    None => (),
+                }
+                if let Some(c) = self.pending_scan.take().and_then(|scan| self.scan_codes.get(&scan)) {
+                    self.buttons.insert(*c, button_state(self.buttons[c], v, self.repeat_turbo));
+                    self.dirty.insert(*c);
+                }
+                if v != 0 {
+                    for (i, nudge) in self.nudge_configs.iter().enumerate() {
+                        if Some(t) == nudge.recenter_key && v == 1 {
+                            self.nudges[i] = 0.;
+                        }
+                        if let Some(step) = nudge.keys.get(&t) {
+                            self.nudges[i] = (self.nudges[i] + step).clamp(-1., 1.);
+                        }
+                    }
+                }
+                if self.stick_keys.contains_key(&t) {
+                    if v != 0 {
+                        self.stick_held.insert(t);
+                    } else {
+                        self.stick_held.remove(&t);
+                    }
+                }
+                return KeysStep::None;
+            },
+            EventSummary::Misc(_, MiscCode::MSC_SCAN, v) => {
+                self.pending_scan = Some(v as u32);
+                return KeysStep::None;
+            },
+            _ => {
+                return KeysStep::None;
+            },
+        }
+    }
+}
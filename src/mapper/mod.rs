@@ -0,0 +1,134 @@
+//! Turning source device events into output changes, independent of the devices
+//! themselves.
+use std::time::Duration;
+use loga::ea;
+use smallvec::SmallVec;
+use evdev::{
+    AbsoluteAxisCode,
+    KeyCode,
+};
+use crate::{
+    Config,
+    Easing,
+    ProfileConfig,
+    ReleaseMode,
+    DEFAULT_CURVE,
+    DEFAULT_DEAD_INNER,
+    DEFAULT_DEAD_OUTER,
+    DEFAULT_FLICK_BLEND_MS,
+    DEFAULT_FLICK_MS,
+    DEFAULT_FLICK_SCALE,
+    DEFAULT_RELEASE_MODE,
+};
+
+pub mod keys;
+pub mod pad;
+
+/// A new value a source wants for an output. Axes are in unit space, -1 .. 1.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Change {
+    Button(KeyCode, bool),
+    Axis(AbsoluteAxisCode, f32),
+}
+
+/// Changes in one frame. Frames rarely have more than a few changes, so these are
+/// kept inline to avoid allocating for each report.
+pub type Changes = SmallVec<[Change; 8]>;
+
+/// Stick shaping parameters, derived from the config with the active profile's
+/// overrides applied.
+#[derive(Clone, Copy, PartialEq)]
+pub struct Tuning {
+    pub active_low: f32,
+    pub active_high: f32,
+    pub curve: f32,
+    /// Unset if not configured, so pads can use their preset's value.
+    pub y_smash: Option<f32>,
+    pub release_mode: ReleaseMode,
+    pub flick: Option<Flick>,
+}
+
+/// Shaping for the start of a stick touch, see `FlickConfig`.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Flick {
+    pub duration: Duration,
+    pub blend: Duration,
+    pub curve: f32,
+    pub scale: f32,
+}
+
+/// Progress of a ramp (0 .. 1) shaped by the easing.
+pub fn ease(easing: Easing, p: f32) -> f32 {
+    let p = p.clamp(0., 1.);
+    match easing {
+        Easing::Linear => return p,
+        Easing::Smoothstep => return p * p * (3. - 2. * p),
+        Easing::Exponential => {
+            // Scaled so it reaches 1 exactly
+            return (1. - 2f32.powf(-10. * p)) / (1. - 2f32.powf(-10.));
+        },
+    }
+}
+
+/// Turn into always positive, at 0 result is 1
+pub fn exp_param(v: f32) -> f32 {
+    return 1.37f32.powf(v);
+}
+
+impl Default for Tuning {
+    /// The shaping with nothing configured.
+    fn default() -> Tuning {
+        return Tuning {
+            active_low: DEFAULT_DEAD_INNER,
+            active_high: 1. - DEFAULT_DEAD_OUTER,
+            curve: exp_param(DEFAULT_CURVE),
+            y_smash: None,
+            release_mode: DEFAULT_RELEASE_MODE,
+            flick: None,
+        };
+    }
+}
+
+impl Tuning {
+    pub fn new(config: &Config, profile: Option<&ProfileConfig>) -> Result<Tuning, loga::Error> {
+        let shaping = config.shaping(profile);
+        let out = Tuning {
+            curve: exp_param(shaping.curve),
+            y_smash: shaping.y_smash.map(exp_param),
+            active_low: shaping.dead_inner,
+            active_high: 1.0 - shaping.dead_outer,
+            release_mode: shaping.release_mode,
+            flick: shaping.flick.map(|f| Flick {
+                duration: Duration::from_millis(f.ms.unwrap_or(DEFAULT_FLICK_MS)),
+                blend: Duration::from_millis(f.blend_ms.unwrap_or(DEFAULT_FLICK_BLEND_MS)),
+                curve: exp_param(f.curve.unwrap_or(shaping.curve)),
+                scale: f.scale.unwrap_or(DEFAULT_FLICK_SCALE),
+            }),
+        };
+        if out.active_high - out.active_low < 0. {
+            return Err(
+                loga::err_with("Dead zones overlap", ea!(profile = profile.map(|p| p.name.as_str()).unwrap_or("-"))),
+            );
+        }
+        return Ok(out);
+    }
+
+    /// The curve and distance scale for a stick touch of this age.
+    pub fn shape_at(&self, age: Duration) -> (f32, f32) {
+        let Some(flick) = self.flick else {
+            return (self.curve, 1.);
+        };
+        let weight = if age <= flick.duration {
+            1.
+        } else if flick.blend.is_zero() {
+            0.
+        } else {
+            (1. - (age - flick.duration).as_secs_f32() / flick.blend.as_secs_f32()).max(0.)
+        };
+        return (
+            self.curve + (flick.curve - self.curve) * weight,
+            1. + (flick.scale - 1.) * weight,
+        );
+    }
+}
+
@@ -0,0 +1,1343 @@
+//! Trackpad touches to a stick, corner buttons and finger count buttons.
+use std::{
+    collections::HashMap,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use evdev::{
+    AbsoluteAxisCode,
+    EventSummary,
+    InputEvent,
+    KeyCode,
+    SynchronizationCode,
+};
+use glam::Vec2;
+use loga::ea;
+use serde::Serialize;
+use smallvec::SmallVec;
+use crate::{
+    PadOrientation,
+    ReleaseMode,
+    StickWeighting,
+};
+use super::{
+    Change,
+    Changes,
+    Tuning,
+};
+
+pub const BUTTON_COUNT: usize = 4;
+
+/// `MT_TOOL_PALM` from `linux/input.h`, value of `ABS_MT_TOOL_TYPE`
+pub const MT_TOOL_PALM: i32 = 2;
+
+/// Multitouch slots tracked if not configured. Pads rarely track more than 10
+/// touches.
+pub const DEFAULT_MAX_SLOTS: usize = 16;
+
+/// How often the stick is updated while decaying after release.
+const RELEASE_TICK: Duration = Duration::from_millis(8);
+
+/// How long a selector button is held after the touch lifts.
+const SELECT_PULSE: Duration = Duration::from_millis(50);
+
+/// The corner button zone for a unit space position outside the stick area.
+fn corner(unitspace_vec: Vec2, mirror_horizontal: bool) -> usize {
+    let zone_x = if mirror_horizontal {
+        -unitspace_vec.x
+    } else {
+        unitspace_vec.x
+    };
+    match (zone_x >= 0., unitspace_vec.y >= 0.) {
+        (true, true) => return 0,
+        (false, true) => return 1,
+        (true, false) => return 2,
+        (false, false) => return 3,
+    }
+}
+
+/// Dead zones and curve applied to a unit space vector, the result is limited to
+/// the unit circle.
+fn shape(mut unitspace_vec: Vec2, active_low: f32, active_high: f32, curve: f32) -> Vec2 {
+    let dist = unitspace_vec.length();
+    if dist < active_low {
+        // Center dead space
+        unitspace_vec = Vec2::ZERO;
+    } else {
+        if dist >= active_high {
+            // Outer dead space (set length to 1)
+            unitspace_vec /= dist;
+        } else {
+            // Scale linearly between dead spaces
+            let activespace_dist = (dist - active_low) / (active_high - active_low);
+            unitspace_vec *= activespace_dist / dist;
+
+            // Apply a curve
+            unitspace_vec = unitspace_vec * (activespace_dist.powf(curve) / activespace_dist);
+        }
+    }
+    return unitspace_vec;
+}
+
+/// Range information for a pad position axis, as reported by the device.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct AxisRange {
+    pub minimum: i32,
+    pub maximum: i32,
+    /// Units per millimeter
+    pub resolution: i32,
+    pub fuzz: i32,
+}
+
+/// How the pad surface maps to unit space.
+#[derive(Clone, Copy)]
+pub struct PadGeometry {
+    pub middle: Vec2,
+    /// Turned a quarter turn counterclockwise (reported top edge on the left)
+    pub portrait: bool,
+    /// Position offsets from the middle (oriented) are divided by this to get unit
+    /// space
+    pub unit_divisor: Vec2,
+    /// Half the surface size (oriented), in position units
+    pub half_size: Vec2,
+    /// Position units per millimeter (oriented)
+    pub resolution: Vec2,
+    pub fuzz: f32,
+    /// From calibration, as `Vec2::from_angle`
+    pub rotation: Vec2,
+    /// From calibration or recentering, oriented position units
+    pub center_offset: Vec2,
+}
+
+impl PadGeometry {
+    /// `direct` is whether the device is a direct input device (touchscreen, tablet),
+    /// which already reports in the orientation it's used in.
+    pub fn new(
+        x: AxisRange,
+        y: AxisRange,
+        direct: bool,
+        orientation: Option<PadOrientation>,
+        fuzz: Option<i32>,
+        resolution_x: Option<i32>,
+        resolution_y: Option<i32>,
+        cm_x_radius: Option<f32>,
+        cm_y_radius: Option<f32>,
+    ) -> Result<PadGeometry, loga::Error> {
+        let source_max = Vec2::new(x.maximum as f32, y.maximum as f32);
+        let source_min = Vec2::new(x.minimum as f32, y.minimum as f32);
+        let source_range_half = (source_max - source_min) / 2.;
+        let source_middle = source_min + source_range_half;
+        let resolution =
+            Vec2::new(resolution_x.unwrap_or(x.resolution) as f32, resolution_y.unwrap_or(y.resolution) as f32);
+        {
+            // Everything below is in physical units, so the resolution has to make sense
+            let phys_size = (source_max - source_min) / resolution / 10.;
+            if !(resolution.x > 0. && resolution.y > 0.) || phys_size.min_element() < 1. ||
+                phys_size.max_element() > 100. {
+                return Err(
+                    loga::err_with(
+                        "Pad resolution is missing or unrealistic, set resolution_x and resolution_y (units per mm) in the pad mapping",
+                        ea!(
+                            resolution_x = resolution.x,
+                            resolution_y = resolution.y,
+                            width_cm = phys_size.x,
+                            height_cm = phys_size.y
+                        ),
+                    ),
+                );
+            }
+        }
+        let fuzz = fuzz.unwrap_or(x.fuzz.max(y.fuzz)) as f32;
+        let portrait = match orientation {
+            Some(o) => o == PadOrientation::Portrait,
+            None => {
+                let tall = (source_max.y - source_min.y) / resolution.y > (source_max.x - source_min.x) / resolution.x;
+                tall && !direct
+            },
+        };
+
+        // From here on everything is in oriented space
+        let (source_range_half, resolution) = if portrait {
+            (Vec2::new(source_range_half.y, source_range_half.x), Vec2::new(resolution.y, resolution.x))
+        } else {
+            (source_range_half, resolution)
+        };
+        let phys_size = source_range_half * 2. / resolution / 10.;
+        let mut unit_divisor;
+        if phys_size.x > phys_size.y {
+            unit_divisor = Vec2::new(source_range_half.y * resolution.x / resolution.y, source_range_half.y);
+        } else {
+            unit_divisor = Vec2::new(source_range_half.x, source_range_half.x * resolution.y / resolution.x);
+        }
+        if let Some(x_radius) = cm_x_radius {
+            unit_divisor.x = x_radius * 10. * resolution.x;
+        }
+        if let Some(y_radius) = cm_y_radius {
+            unit_divisor.y = y_radius * 10. * resolution.x;
+        }
+        return Ok(PadGeometry {
+            middle: source_middle,
+            portrait: portrait,
+            unit_divisor: unit_divisor,
+            half_size: source_range_half,
+            resolution: resolution,
+            fuzz: fuzz,
+            rotation: Vec2::X,
+            center_offset: Vec2::ZERO,
+        });
+    }
+
+    /// Apply a saved calibration's stick center offset (millimeters, oriented) and
+    /// counterclockwise mounting rotation (degrees). Measured extents go in the axis
+    /// ranges instead.
+    pub fn calibrate(&mut self, center_offset: Vec2, rotation: f32) {
+        self.center_offset = center_offset * self.resolution;
+
+        // Positions are y-down, so undoing a counterclockwise turn is a negative angle
+        self.rotation = Vec2::from_angle(-rotation.to_radians());
+    }
+
+    /// Where a position is on the surface after orientation, 0 to 1 left to right and
+    /// top to bottom. Calibration and recentering don't affect it.
+    fn surface_fraction(&self, pos: Vec2) -> Vec2 {
+        let v = pos - self.middle;
+        let v = if self.portrait {
+            Vec2::new(v.y, -v.x)
+        } else {
+            v
+        };
+        return (v / self.half_size + 1.) / 2.;
+    }
+
+    fn orient(&self, v: Vec2) -> Vec2 {
+        let v = if self.portrait {
+            Vec2::new(v.y, -v.x)
+        } else {
+            v
+        };
+        return self.rotation.rotate(v) - self.center_offset;
+    }
+}
+
+/// A touch already on the pad at startup.
+#[derive(Clone, Copy)]
+pub struct InitialTouch {
+    /// `ABS_MT_TRACKING_ID`, -1 if there's no touch in the slot
+    pub tracking_id: i32,
+    pub pos: Vec2,
+    pub palm: bool,
+    /// Hovering over the pad, not touching (`ABS_MT_DISTANCE` above 0)
+    pub hover: bool,
+}
+
+/// What the pad maps to.
+pub struct PadOutputs {
+    pub axis_codes: [AbsoluteAxisCode; 2],
+    pub button_codes: [KeyCode; BUTTON_COUNT],
+    pub finger_buttons: HashMap<usize, KeyCode>,
+    pub multitouch: bool,
+    /// How several stick touches are combined
+    pub stick_weighting: StickWeighting,
+    pub mirror_horizontal: bool,
+    /// Used if the tuning doesn't have one.
+    pub default_y_smash: f32,
+    /// The position of a hovering contact, in unit space without shaping. Centered
+    /// when nothing hovers.
+    pub hover_axes: Option<[AbsoluteAxisCode; 2]>,
+    /// Where touches have to land to move the stick, anywhere if not set.
+    pub stick_region: Option<Region>,
+    /// Only set for clickpads.
+    pub click_zones: Option<ClickZones>,
+    /// Only set for pads that report pressure.
+    pub force: Option<ForceLevels>,
+    /// Replaces the stick and corner buttons.
+    pub selector: Option<Selector>,
+    pub profile_swipe: Option<ProfileSwipe>,
+    /// Fingers to move the pad to the next player, see `take_player_cycle`
+    pub player_cycle_fingers: Option<usize>,
+    pub tilt: Option<Tilt>,
+}
+
+impl PadOutputs {
+    /// Just the stick and corner buttons, with everything optional off.
+    pub fn new(axis_codes: [AbsoluteAxisCode; 2], button_codes: [KeyCode; BUTTON_COUNT]) -> PadOutputs {
+        return PadOutputs {
+            axis_codes: axis_codes,
+            button_codes: button_codes,
+            finger_buttons: HashMap::new(),
+            multitouch: false,
+            stick_weighting: StickWeighting::Mean,
+            mirror_horizontal: false,
+            default_y_smash: 1.,
+            hover_axes: None,
+            stick_region: None,
+            click_zones: None,
+            force: None,
+            selector: None,
+            profile_swipe: None,
+            player_cycle_fingers: None,
+            tilt: None,
+        };
+    }
+}
+
+/// Pen tilt (`ABS_TILT_X`/`ABS_TILT_Y`) to a pair of axes, shaped separately from
+/// the stick.
+#[derive(Clone, Copy)]
+pub struct Tilt {
+    pub axis_codes: [AbsoluteAxisCode; 2],
+    /// Tilt values for full deflection
+    pub full: Vec2,
+    pub active_low: f32,
+    pub active_high: f32,
+    pub curve: f32,
+}
+
+/// Several fingers moving sideways together, for switching profiles.
+#[derive(Clone, Copy)]
+pub struct ProfileSwipe {
+    pub fingers: usize,
+    /// Millimeters
+    pub distance: f32,
+}
+
+/// Selector mode: touches don't move the stick, instead the sector the touch lifts
+/// in presses a button briefly, like a radial menu.
+#[derive(Clone)]
+pub struct Selector {
+    /// One per sector, clockwise starting with the sector centered at the top
+    pub buttons: Vec<KeyCode>,
+    /// Lifting within this radius (unit space) selects nothing
+    pub center: f32,
+}
+
+/// Buttons for physical clicks on clickpads (`INPUT_PROP_BUTTONPAD`), which report
+/// every click as `BTN_LEFT`. Like libinput, the position of the touch on the pad
+/// when clicking picks the button.
+#[derive(Clone, Copy)]
+pub struct ClickZones {
+    /// Clicking with a touch in the bottom left zone
+    pub left: Option<KeyCode>,
+    /// Clicking with a touch in the bottom right zone
+    pub right: Option<KeyCode>,
+    /// Clicking anywhere else
+    pub other: Option<KeyCode>,
+    /// Height of the bottom zones, in millimeters
+    pub zone_height: f32,
+}
+
+/// Part of the pad surface, as fractions of its oriented size (0, 0 is the top
+/// left).
+#[derive(Clone, Copy, Debug)]
+pub struct Region {
+    pub min: Vec2,
+    pub max: Vec2,
+}
+
+impl Region {
+    pub fn contains(&self, fraction: Vec2) -> bool {
+        return fraction.cmpge(self.min).all() && fraction.cmple(self.max).all();
+    }
+}
+
+/// Buttons for pressure levels (`ABS_PRESSURE`), for pads that sense force.
+#[derive(Clone, Debug)]
+pub struct ForceLevels {
+    /// Pressure in the pad's units and the button, lowest first
+    pub levels: Vec<(i32, KeyCode)>,
+    /// A level is left when the pressure drops this far below it
+    pub hysteresis: i32,
+    /// Hold the buttons of all levels reached, not just the highest
+    pub cumulative: bool,
+}
+
+impl ForceLevels {
+    /// The buttons held at a level, 0 being below the first.
+    fn buttons(&self, level: usize) -> Vec<KeyCode> {
+        if level == 0 {
+            return vec![];
+        }
+        if self.cumulative {
+            return self.levels[.. level].iter().map(|l| l.1).collect();
+        }
+        return vec![self.levels[level - 1].1];
+    }
+}
+
+/// Something wrong with the events from the pad, to be logged.
+#[derive(PartialEq, Debug)]
+pub enum PadWarning {
+    /// The pad selected a multitouch slot outside `0 .. max_slots`, its touches are
+    /// ignored. Only reported the first time.
+    SlotOutOfRange(i32),
+}
+
+/// The pad's current state for visualizing. Positions are in unit space: the stick
+/// area is the unit circle, y down.
+#[derive(Serialize, Clone, PartialEq, Debug, Default)]
+pub struct PadSnapshot {
+    /// Half the pad surface size
+    pub extent: [f32; 2],
+    /// Touches on the surface, excluding palms and hovering touches
+    pub touches: Vec<[f32; 2]>,
+    /// The stick output, after dead zones and curve
+    pub stick: [f32; 2],
+    /// Output buttons currently pressed
+    pub buttons: Vec<KeyCode>,
+}
+
+/// The stick returning to center after the last axis touch lifted.
+struct Release {
+    start: Instant,
+    from: Vec2,
+}
+
+#[derive(PartialEq, Debug)]
+enum TouchBake {
+    Indeterminate,
+    Axis,
+    Button(usize),
+}
+
+#[derive(Debug)]
+struct TouchState {
+    enabled: bool,
+    tracking_id: i32,
+    /// Started this frame, position isn't filtered
+    new: bool,
+    palm: bool,
+    hover: bool,
+    pos: Vec2,
+    /// `ABS_MT_PRESSURE`, 0 if not reported
+    pressure: f32,
+    /// `ABS_MT_TOUCH_MAJOR`, 0 if not reported
+    size: f32,
+    baked: TouchBake,
+    /// The corner zone the touch is over, `None` for the stick area. Unlike `baked`
+    /// this follows the touch as it moves.
+    zone: Option<usize>,
+    /// When the touch started, `None` if it was already down at startup
+    landed: Option<Instant>,
+}
+
+/// A touch moving the stick this frame, for weighting.
+struct AxisTouch {
+    tracking_id: i32,
+    /// Unit space
+    pos: Vec2,
+    landed: Option<Instant>,
+    pressure: f32,
+    size: f32,
+}
+
+impl AxisTouch {
+    fn new(state: &TouchState, pos: Vec2) -> AxisTouch {
+        return AxisTouch {
+            tracking_id: state.tracking_id,
+            pos: pos,
+            landed: state.landed,
+            pressure: state.pressure,
+            size: state.size,
+        };
+    }
+}
+
+/// The pad state machine: feed it the pad's events, it produces output changes at
+/// the end of each frame.
+pub struct PadMapper {
+    outputs: PadOutputs,
+    geometry: PadGeometry,
+    /// Something changed since the last frame was processed
+    dirty: bool,
+    /// None if the pad selected a slot out of range
+    slot: Option<usize>,
+    max_slots: usize,
+    slot_warned: bool,
+    tool_fingers: usize,
+    last_axis: Vec2,
+    /// There were axis touches in the last frame
+    axis_touched: bool,
+    /// When the oldest axis touch landed, for flick shaping
+    axis_landed: Option<Instant>,
+    /// The tracking id of the touch moving the stick, for `PrimaryOnly` weighting
+    axis_primary: Option<i32>,
+    release: Option<Release>,
+    last_buttons: [bool; BUTTON_COUNT],
+    last_finger_button: Option<KeyCode>,
+    last_hover: Vec2,
+    /// Pen tilt as reported
+    tilt: Vec2,
+    last_tilt: Vec2,
+    /// The physical button is down
+    click_down: bool,
+    last_click: Option<KeyCode>,
+    /// Pressure as reported
+    pressure: i32,
+    /// Number of force levels reached
+    last_force_level: usize,
+    /// Selector mode: where the selecting touch is while it's down
+    select_pos: Option<Vec2>,
+    /// Selector mode: the selected button and when to release it
+    select_pulse: Option<(KeyCode, Instant)>,
+    /// A touch moved into a corner zone, see `take_zone_entered`
+    zone_entered: Option<usize>,
+    /// Profile swipe: the touch count and their average position (oriented position
+    /// units) when enough fingers landed
+    swipe_start: Option<(usize, Vec2)>,
+    /// Profile swipe: already swiped, wait for all fingers to lift
+    swipe_done: bool,
+    /// See `take_swipe`
+    swiped: Option<i32>,
+    /// Player cycle: already moved, wait for all fingers to lift
+    player_cycle_done: bool,
+    /// See `take_player_cycle`
+    player_cycled: bool,
+    /// The center offset before any recentering
+    base_center_offset: Vec2,
+    touch_states: Vec<TouchState>,
+}
+
+impl PadMapper {
+    /// `initial_touches` has one entry per slot, empty if the pad doesn't do
+    /// multitouch. Touches in slots at or above `max_slots` are ignored.
+    pub fn new(
+        outputs: PadOutputs,
+        geometry: PadGeometry,
+        max_slots: usize,
+        initial_slot: usize,
+        initial_touches: Vec<InitialTouch>,
+    ) -> PadMapper {
+        let max_slots = max_slots.max(1);
+        let mut touch_states = initial_touches.into_iter().take(max_slots).map(|t| TouchState {
+            enabled: t.tracking_id != -1,
+            tracking_id: t.tracking_id,
+            new: t.tracking_id != -1,
+            palm: t.palm,
+            hover: t.hover,
+            pos: t.pos,
+            pressure: 0.,
+            size: 0.,
+            baked: TouchBake::Indeterminate,
+            zone: None,
+            landed: None,
+        }).collect::<Vec<_>>();
+        if touch_states.is_empty() {
+            touch_states.push(TouchState {
+                enabled: false,
+                tracking_id: -1,
+                new: false,
+                palm: false,
+                hover: false,
+                pos: geometry.middle,
+                pressure: 0.,
+                size: 0.,
+                baked: TouchBake::Indeterminate,
+                zone: None,
+                landed: None,
+            });
+        }
+        return PadMapper {
+            outputs: outputs,
+            base_center_offset: geometry.center_offset,
+            geometry: geometry,
+            dirty: true,
+            slot: Some(initial_slot.min(touch_states.len() - 1)),
+            max_slots: max_slots,
+            slot_warned: false,
+            tool_fingers: 0,
+            last_axis: Vec2::ZERO,
+            axis_touched: false,
+            axis_landed: None,
+            axis_primary: None,
+            release: None,
+            last_buttons: [false; BUTTON_COUNT],
+            last_finger_button: None,
+            last_hover: Vec2::ZERO,
+            tilt: Vec2::ZERO,
+            last_tilt: Vec2::ZERO,
+            click_down: false,
+            last_click: None,
+            pressure: 0,
+            last_force_level: 0,
+            select_pos: None,
+            select_pulse: None,
+            zone_entered: None,
+            swipe_start: None,
+            swipe_done: false,
+            swiped: None,
+            player_cycle_done: false,
+            player_cycled: false,
+            touch_states: touch_states,
+        };
+    }
+
+    /// Release everything that's held. Touches keep being tracked.
+    pub fn release(&mut self, changes: &mut Changes) {
+        self.release = None;
+        if self.last_axis != Vec2::ZERO {
+            changes.push(Change::Axis(self.outputs.axis_codes[0], 0.));
+            changes.push(Change::Axis(self.outputs.axis_codes[1], 0.));
+            self.last_axis = Vec2::ZERO;
+        }
+        for i in 0 .. BUTTON_COUNT {
+            if self.last_buttons[i] {
+                changes.push(Change::Button(self.outputs.button_codes[i], false));
+                self.last_buttons[i] = false;
+            }
+        }
+        if let Some(c) = self.last_finger_button.take() {
+            changes.push(Change::Button(c, false));
+        }
+        if let Some(c) = self.last_click.take() {
+            changes.push(Change::Button(c, false));
+        }
+        if let Some(force) = &self.outputs.force {
+            for c in force.buttons(self.last_force_level) {
+                changes.push(Change::Button(c, false));
+            }
+            self.last_force_level = 0;
+        }
+        if let Some((c, _)) = self.select_pulse.take() {
+            changes.push(Change::Button(c, false));
+        }
+        if let Some(axes) = self.outputs.hover_axes {
+            if self.last_hover != Vec2::ZERO {
+                changes.push(Change::Axis(axes[0], 0.));
+                changes.push(Change::Axis(axes[1], 0.));
+                self.last_hover = Vec2::ZERO;
+            }
+        }
+        if let Some(tilt) = self.outputs.tilt {
+            if self.last_tilt != Vec2::ZERO {
+                changes.push(Change::Axis(tilt.axis_codes[0], 0.));
+                changes.push(Change::Axis(tilt.axis_codes[1], 0.));
+                self.last_tilt = Vec2::ZERO;
+            }
+        }
+    }
+
+    /// The current state, for visualizing.
+    pub fn snapshot(&self) -> PadSnapshot {
+        let mut touches = vec![];
+        for state in &self.touch_states {
+            if !state.enabled || state.palm || state.hover {
+                continue;
+            }
+            touches.push((self.geometry.orient(state.pos - self.geometry.middle) / self.geometry.unit_divisor).into());
+        }
+        let mut buttons = vec![];
+        for i in 0 .. BUTTON_COUNT {
+            if self.last_buttons[i] {
+                buttons.push(self.outputs.button_codes[i]);
+            }
+        }
+        buttons.extend(self.last_finger_button);
+        buttons.extend(self.last_click);
+        if let Some(force) = &self.outputs.force {
+            buttons.extend(force.buttons(self.last_force_level));
+        }
+        return PadSnapshot {
+            extent: (self.geometry.half_size / self.geometry.unit_divisor).into(),
+            touches: touches,
+            stick: self.last_axis.into(),
+            buttons: buttons,
+        };
+    }
+
+    /// When `tick` should be called next, if the outputs are changing without events
+    /// (the stick returning to center after release, a selector button being
+    /// released, flick shaping blending out).
+    pub fn deadline(&self, tuning: &Tuning, now: Instant) -> Option<Instant> {
+        let pulse = self.select_pulse.map(|(_, at)| at);
+        let release = self.release.as_ref().map(|release| match tuning.release_mode {
+            ReleaseMode::Snap => now,
+            ReleaseMode::Decay(_) => now + RELEASE_TICK,
+            ReleaseMode::Hold(ms) => release.start + Duration::from_millis(ms),
+        });
+        let flick = self.flick_deadline(tuning, now);
+        return [pulse, release, flick].into_iter().flatten().min();
+    }
+
+    /// When the stick shaping changes next while a flick blends out.
+    fn flick_deadline(&self, tuning: &Tuning, now: Instant) -> Option<Instant> {
+        let (Some(flick), Some(landed)) = (tuning.flick, self.axis_landed) else {
+            return None;
+        };
+        let blend_start = landed + flick.duration;
+        if now < blend_start {
+            return Some(blend_start);
+        } else if now < blend_start + flick.blend {
+            return Some(now + RELEASE_TICK);
+        } else {
+            return None;
+        }
+    }
+
+    /// Update outputs that change over time, see `deadline`.
+    pub fn tick(&mut self, tuning: &Tuning, active: bool, now: Instant, changes: &mut Changes) {
+        if !active {
+            return;
+        }
+        if let Some((c, at)) = self.select_pulse {
+            if now >= at {
+                changes.push(Change::Button(c, false));
+                self.select_pulse = None;
+            }
+        }
+        if self.axis_landed.is_some() && tuning.flick.is_some() {
+            // Reshape the stick for the touch's age
+            self.dirty = true;
+            self.frame(tuning, active, now, changes);
+            return;
+        }
+        if self.release.is_none() {
+            return;
+        }
+        let axis = self.release_axis(tuning, now);
+        self.push_axis(axis, changes);
+    }
+
+    /// Change the corner button codes, ex: for modifier keys. Held buttons switch to
+    /// the new codes.
+    pub fn set_button_codes(&mut self, codes: [KeyCode; BUTTON_COUNT], changes: &mut Changes) {
+        for i in 0 .. BUTTON_COUNT {
+            let old = self.outputs.button_codes[i];
+            if codes[i] == old {
+                continue;
+            }
+            if self.last_buttons[i] {
+                changes.push(Change::Button(old, false));
+                changes.push(Change::Button(codes[i], true));
+            }
+        }
+        self.outputs.button_codes = codes;
+    }
+
+    /// Replace the geometry, ex: if the pad reports different ranges after resuming.
+    /// Undoes recentering.
+    pub fn set_geometry(&mut self, geometry: PadGeometry) {
+        self.base_center_offset = geometry.center_offset;
+        self.geometry = geometry;
+        self.dirty = true;
+    }
+
+    /// Move the stick center to the average of the touches, or back to where it
+    /// started if there are none. Returns the new center offset in millimeters.
+    pub fn recenter(&mut self) -> Vec2 {
+        let mut sum = Vec2::ZERO;
+        let mut count = 0usize;
+        for state in &self.touch_states {
+            if !state.enabled || state.palm || state.hover {
+                continue;
+            }
+            sum += self.geometry.orient(state.pos - self.geometry.middle);
+            count += 1;
+        }
+        if count == 0 {
+            self.geometry.center_offset = self.base_center_offset;
+        } else {
+            self.geometry.center_offset += sum / count as f32;
+        }
+        self.dirty = true;
+        return self.geometry.center_offset / self.geometry.resolution;
+    }
+
+    /// The internal state, for crash reports.
+    pub fn dump(&self) -> String {
+        let mut out = String::new();
+        out.push_str(&format!("slot: {:?} (max {})\n", self.slot, self.max_slots));
+        out.push_str(&format!("dirty: {}\n", self.dirty));
+        out.push_str(&format!("tool_fingers: {}\n", self.tool_fingers));
+        out.push_str(&format!("last_axis: {:?}\n", self.last_axis));
+        out.push_str(&format!("axis_touched: {}\n", self.axis_touched));
+        out.push_str(&format!("axis_landed: {:?}\n", self.axis_landed));
+        out.push_str(&format!("releasing: {}\n", self.release.is_some()));
+        out.push_str(&format!("last_buttons: {:?}\n", self.last_buttons));
+        out.push_str(&format!("last_finger_button: {:?}\n", self.last_finger_button));
+        out.push_str(&format!("click_down: {}, last_click: {:?}\n", self.click_down, self.last_click));
+        out.push_str(&format!("pressure: {}, last_force_level: {}\n", self.pressure, self.last_force_level));
+        out.push_str(&format!("tilt: {:?}, last_tilt: {:?}\n", self.tilt, self.last_tilt));
+        for (i, state) in self.touch_states.iter().enumerate() {
+            out.push_str(&format!("touch {}: {:?}\n", i, state));
+        }
+        return out;
+    }
+
+    /// The corner zone (index into the button codes) a touch slid into since the last
+    /// call, for feedback. Touches landing in a zone don't count.
+    pub fn take_zone_entered(&mut self) -> Option<usize> {
+        return self.zone_entered.take();
+    }
+
+    /// The direction of a profile swipe since the last call, 1 for right and -1 for
+    /// left.
+    pub fn take_swipe(&mut self) -> Option<i32> {
+        return self.swiped.take();
+    }
+
+    /// Whether enough fingers landed to move the pad to the next player since the
+    /// last call.
+    pub fn take_player_cycle(&mut self) -> bool {
+        return std::mem::take(&mut self.player_cycled);
+    }
+
+    /// Track the touches for a profile swipe.
+    fn swipe(&mut self, swipe: ProfileSwipe, touch_count: usize, touch_sum: Vec2) {
+        // Some pads track fewer slots than the fingers they detect, use the higher count
+        let fingers = touch_count.max(self.tool_fingers);
+        if fingers == 0 {
+            self.swipe_start = None;
+            self.swipe_done = false;
+            return;
+        }
+        if self.swipe_done || fingers < swipe.fingers || touch_count == 0 {
+            self.swipe_start = None;
+            return;
+        }
+        let pos = touch_sum / touch_count as f32;
+        match self.swipe_start {
+            Some((count, start)) if count == touch_count => {
+                let moved = (pos.x - start.x) / self.geometry.resolution.x;
+                if moved.abs() >= swipe.distance {
+                    self.swiped = Some(if moved > 0. {
+                        1
+                    } else {
+                        -1
+                    });
+                    self.swipe_done = true;
+                }
+            },
+            _ => {
+                // Start over when a finger lands or lifts, since the average jumps
+                self.swipe_start = Some((touch_count, pos));
+            },
+        }
+    }
+
+    /// The selector button for a touch lifting at this position.
+    fn select(&self, pos: Vec2) -> Option<KeyCode> {
+        let selector = self.outputs.selector.as_ref()?;
+        if selector.buttons.is_empty() || pos.length() < selector.center {
+            return None;
+        }
+
+        // Clockwise from the top, y is down
+        let sector = std::f32::consts::TAU / selector.buttons.len() as f32;
+        let angle = pos.x.atan2(-pos.y).rem_euclid(std::f32::consts::TAU);
+        let i = ((angle + sector / 2.) / sector) as usize % selector.buttons.len();
+        return Some(selector.buttons[i]);
+    }
+
+    /// Process an event. At the end of a frame (`SYN_REPORT`) changes to the outputs
+    /// are added to `changes`, unless `active` is off.
+    pub fn event(
+        &mut self,
+        ev: InputEvent,
+        tuning: &Tuning,
+        active: bool,
+        now: Instant,
+        changes: &mut Changes,
+    ) -> Option<PadWarning> {
+        match ev.destructure() {
+            EventSummary::Synchronization(_, t, _) => {
+                if t == SynchronizationCode::SYN_REPORT {
+                    self.frame(tuning, active, now, changes);
+                }
+            },
+            EventSummary::AbsoluteAxis(_, type_, value) => {
+                if type_ == AbsoluteAxisCode::ABS_TILT_X || type_ == AbsoluteAxisCode::ABS_TILT_Y {
+                    if self.outputs.tilt.is_some() {
+                        self.tilt[(type_ == AbsoluteAxisCode::ABS_TILT_Y) as usize] = value as f32;
+                        self.dirty = true;
+                    }
+                    return None;
+                }
+                if type_ == AbsoluteAxisCode::ABS_PRESSURE {
+                    if self.outputs.force.is_some() {
+                        self.pressure = value;
+                        self.dirty = true;
+                    }
+                    return None;
+                }
+                if type_ == AbsoluteAxisCode::ABS_MT_SLOT {
+                    if value < 0 || value as usize >= self.max_slots {
+                        // Buggy firmware, ignore events until a valid slot is selected
+                        self.slot = None;
+                        if !self.slot_warned {
+                            self.slot_warned = true;
+                            return Some(PadWarning::SlotOutOfRange(value));
+                        }
+                        return None;
+                    }
+                    let slot = value as usize;
+                    self.slot = Some(slot);
+                    while self.touch_states.len() < slot + 1 {
+                        self.touch_states.push(TouchState {
+                            enabled: false,
+                            tracking_id: -1,
+                            new: false,
+                            palm: false,
+                            hover: false,
+                            pos: self.geometry.middle,
+                            pressure: 0.,
+                            size: 0.,
+                            baked: TouchBake::Indeterminate,
+                            zone: None,
+                            landed: None,
+                        });
+                    }
+                    self.dirty = true;
+                    return None;
+                }
+                let Some(slot) = self.slot else {
+                    return None;
+                };
+                match type_ {
+                    AbsoluteAxisCode::ABS_MT_POSITION_X => {
+                        let touch = &mut self.touch_states[slot];
+                        if !touch.new && (value as f32 - touch.pos.x).abs() <= self.geometry.fuzz {
+                            return None;
+                        }
+                        touch.pos.x = value as f32;
+                    },
+                    AbsoluteAxisCode::ABS_MT_POSITION_Y => {
+                        let touch = &mut self.touch_states[slot];
+                        if !touch.new && (value as f32 - touch.pos.y).abs() <= self.geometry.fuzz {
+                            return None;
+                        }
+                        touch.pos.y = value as f32;
+                    },
+                    AbsoluteAxisCode::ABS_MT_PRESSURE => {
+                        self.touch_states[slot].pressure = value as f32;
+                    },
+                    AbsoluteAxisCode::ABS_MT_TOUCH_MAJOR => {
+                        self.touch_states[slot].size = value as f32;
+                    },
+                    AbsoluteAxisCode::ABS_MT_TOOL_TYPE => {
+                        self.touch_states[slot].palm = value == MT_TOOL_PALM;
+                    },
+                    AbsoluteAxisCode::ABS_MT_DISTANCE => {
+                        self.touch_states[slot].hover = value > 0;
+                    },
+                    AbsoluteAxisCode::ABS_MT_TRACKING_ID => {
+                        let touch = &self.touch_states[slot];
+                        if value == -1 || (touch.enabled && value != touch.tracking_id) {
+                            // Lifted, or some firmware reuses a slot for a different touch without
+                            // lifting first - treat that as a lift and a new touch so the old touch's
+                            // zone doesn't carry over
+                            self.lift(slot);
+                        }
+                        let touch = &mut self.touch_states[slot];
+                        if value != -1 && !touch.enabled {
+                            touch.enabled = true;
+                            touch.tracking_id = value;
+                            touch.new = true;
+                            touch.landed = Some(now);
+                        }
+                    },
+                    _ => (),
+                }
+                self.dirty = true;
+            },
+            EventSummary::Key(_, KeyCode::BTN_TOOL_PEN, 0) if self.outputs.tilt.is_some() => {
+                // Pen left, tablets don't always reset the tilt
+                self.tilt = Vec2::ZERO;
+                self.dirty = true;
+            },
+            EventSummary::Key(_, KeyCode::BTN_LEFT, value) if self.outputs.click_zones.is_some() => {
+                self.click_down = value != 0;
+                self.dirty = true;
+            },
+            EventSummary::Key(_, code, value) => {
+                let fingers = match code {
+                    KeyCode::BTN_TOOL_FINGER => 1,
+                    KeyCode::BTN_TOOL_DOUBLETAP => 2,
+                    KeyCode::BTN_TOOL_TRIPLETAP => 3,
+                    KeyCode::BTN_TOOL_QUADTAP => 4,
+                    KeyCode::BTN_TOOL_QUINTTAP => 5,
+                    _ => return None,
+                };
+                if value != 0 {
+                    self.tool_fingers = fingers;
+                } else if self.tool_fingers == fingers {
+                    self.tool_fingers = 0;
+                }
+                self.dirty = true;
+            },
+            _ => { },
+        }
+        return None;
+    }
+
+    /// The touch in the slot ended.
+    fn lift(&mut self, slot: usize) {
+        self.touch_states[slot].enabled = false;
+        self.touch_states[slot].tracking_id = -1;
+        self.touch_states[slot].new = false;
+        if let TouchBake::Button(i) = self.touch_states[slot].baked {
+            // Sometimes evdev doesn't send release events for slots so they get stuck. Make
+            // another press + release reset the button as an intuitive workaround/fix...
+            for s in &mut self.touch_states {
+                if s.enabled && match s.baked {
+                    TouchBake::Button(j) if i == j => true,
+                    _ => false,
+                } {
+                    s.enabled = false;
+                    s.tracking_id = -1;
+                    s.baked = TouchBake::Indeterminate;
+                }
+            }
+        }
+        self.touch_states[slot].baked = TouchBake::Indeterminate;
+        self.touch_states[slot].palm = false;
+        self.touch_states[slot].hover = false;
+        self.touch_states[slot].pressure = 0.;
+        self.touch_states[slot].size = 0.;
+    }
+
+    /// Combine the touches moving the stick (unit space), `None` if there are none.
+    fn combine_axis(&mut self, touches: &[AxisTouch]) -> Option<Vec2> {
+        if touches.is_empty() {
+            self.axis_primary = None;
+            return None;
+        }
+        let weights: SmallVec<[f32; 4]> = match self.outputs.stick_weighting {
+            StickWeighting::Mean => touches.iter().map(|_| 1.).collect(),
+            StickWeighting::Recent => touches
+                .iter()
+                .map(|t| 2f32.powi(touches.iter().filter(|o| o.landed < t.landed).count() as i32))
+                .collect(),
+            StickWeighting::Pressure => touches.iter().map(|t| t.pressure).collect(),
+            StickWeighting::Size => touches.iter().map(|t| t.size).collect(),
+            StickWeighting::PrimaryOnly => {
+                // Touches from before startup count as the oldest
+                let primary = match touches.iter().find(|t| Some(t.tracking_id) == self.axis_primary) {
+                    Some(t) => t,
+                    None => touches.iter().min_by_key(|t| t.landed).unwrap(),
+                };
+                self.axis_primary = Some(primary.tracking_id);
+                return Some(primary.pos);
+            },
+        };
+        let total = weights.iter().sum::<f32>();
+        if !(total > 0.) {
+            // The pad doesn't report what's weighted by
+            return Some(touches.iter().map(|t| t.pos).sum::<Vec2>() / touches.len() as f32);
+        }
+        return Some(touches.iter().zip(&weights).map(|(t, w)| t.pos * *w).sum::<Vec2>() / total);
+    }
+
+    /// The stick position while returning to center after release.
+    fn release_axis(&mut self, tuning: &Tuning, now: Instant) -> Vec2 {
+        let Some(release) = &self.release else {
+            return Vec2::ZERO;
+        };
+        let elapsed = now.saturating_duration_since(release.start);
+        let axis = match tuning.release_mode {
+            ReleaseMode::Snap => Vec2::ZERO,
+            ReleaseMode::Decay(ms) => {
+                if elapsed >= Duration::from_millis(ms) {
+                    Vec2::ZERO
+                } else {
+                    release.from * (1. - elapsed.as_secs_f32() * 1000. / ms as f32)
+                }
+            },
+            ReleaseMode::Hold(ms) => {
+                if elapsed >= Duration::from_millis(ms) {
+                    Vec2::ZERO
+                } else {
+                    release.from
+                }
+            },
+        };
+        if axis == Vec2::ZERO {
+            self.release = None;
+        }
+        return axis;
+    }
+
+    fn push_axis(&mut self, axis: Vec2, changes: &mut Changes) {
+        if axis != self.last_axis {
+            changes.push(Change::Axis(self.outputs.axis_codes[0], axis.x));
+            changes.push(Change::Axis(self.outputs.axis_codes[1], axis.y));
+        }
+        self.last_axis = axis;
+    }
+
+    fn frame(&mut self, tuning: &Tuning, active: bool, now: Instant, changes: &mut Changes) {
+        if !self.dirty {
+            // Nothing changed, ex: all movement was under the fuzz
+            return;
+        }
+        self.dirty = false;
+        let Tuning { active_low, active_high, curve, y_smash, .. } = *tuning;
+        let y_smash = y_smash.unwrap_or(self.outputs.default_y_smash);
+        let mut axis_touches: SmallVec<[AxisTouch; 4]> = SmallVec::new();
+        let mut buttons = [false; BUTTON_COUNT];
+        let mut touch_count = 0usize;
+        let mut touch_sum = Vec2::ZERO;
+        let mut hover = None;
+        let mut select_touch = None;
+        for (state_i, state) in self.touch_states.iter_mut().enumerate() {
+            state.new = false;
+            if !state.enabled || state.palm {
+                continue;
+            }
+            if state.hover {
+                // Not touching yet, don't pick a zone until it lands
+                if hover.is_none() {
+                    hover = Some(self.geometry.orient(state.pos - self.geometry.middle) / self.geometry.unit_divisor);
+                }
+                continue;
+            }
+            touch_count += 1;
+            touch_sum += self.geometry.orient(state.pos - self.geometry.middle);
+            if state_i > 0 && !self.outputs.multitouch {
+                continue;
+            }
+
+            // narrowest axis is -1 .. 1 for full span of trackpad; -1 is up; trans axis may
+            // be over or under 1 depending on resolution ratio ratio
+            let mut unitspace_vec = self.geometry.orient(state.pos - self.geometry.middle) / self.geometry.unit_divisor;
+            if self.outputs.selector.is_some() {
+                // Only the selection matters, no stick or corner buttons
+                if select_touch.is_none() {
+                    select_touch = Some(unitspace_vec);
+                }
+                continue;
+            }
+
+            // y-space compressed downward (towards 1) with low numbers of y_smash
+            unitspace_vec.y = ((unitspace_vec.y / 2. + 0.52).clamp(0., 1.1).powf(y_smash) - 0.52) * 2.;
+            let zone = if unitspace_vec.length() <= 1. {
+                None
+            } else {
+                Some(corner(unitspace_vec, self.outputs.mirror_horizontal))
+            };
+            if state.baked != TouchBake::Indeterminate && zone != state.zone && zone.is_some() {
+                self.zone_entered = zone;
+            }
+            state.zone = zone;
+            match state.baked {
+                TouchBake::Indeterminate => {
+                    // The role is picked where the touch lands and kept until it lifts
+                    let stick_region = match self.outputs.stick_region {
+                        Some(r) => r.contains(self.geometry.surface_fraction(state.pos)),
+                        None => true,
+                    };
+                    match zone {
+                        None if stick_region => {
+                            state.baked = TouchBake::Axis;
+                            axis_touches.push(AxisTouch::new(state, unitspace_vec));
+                        },
+                        None => {
+                            // Buttons only, by quarter
+                            let button_i = corner(unitspace_vec, self.outputs.mirror_horizontal);
+                            buttons[button_i] = true;
+                            state.baked = TouchBake::Button(button_i);
+                        },
+                        Some(button_i) => {
+                            buttons[button_i] = true;
+                            state.baked = TouchBake::Button(button_i);
+                        },
+                    }
+                },
+                TouchBake::Axis => {
+                    axis_touches.push(AxisTouch::new(state, unitspace_vec));
+                },
+                TouchBake::Button(button_i) => {
+                    buttons[button_i] = true;
+                },
+            }
+        }
+        if let Some(swipe) = self.outputs.profile_swipe {
+            self.swipe(swipe, touch_count, touch_sum);
+        }
+        if let Some(cycle_fingers) = self.outputs.player_cycle_fingers {
+            // Some pads track fewer slots than the fingers they detect, use the higher count
+            let fingers = touch_count.max(self.tool_fingers);
+            if fingers == 0 {
+                self.player_cycle_done = false;
+            } else if fingers >= cycle_fingers && !self.player_cycle_done {
+                self.player_cycled = true;
+                self.player_cycle_done = true;
+            }
+        }
+
+        // Flick shaping is timed from the oldest axis touch, touches from before startup
+        // count as old
+        self.axis_landed =
+            self
+                .touch_states
+                .iter()
+                .filter(|s| s.enabled && !s.palm && !s.hover && s.baked == TouchBake::Axis)
+                .map(|s| s.landed)
+                .min()
+                .flatten();
+        let axis_combined = self.combine_axis(&axis_touches);
+        let mut selected = None;
+        if self.outputs.selector.is_some() {
+            match select_touch {
+                Some(pos) => {
+                    self.select_pos = Some(pos);
+                },
+                None => {
+                    // Last touch lifted, select where it was
+                    if let Some(pos) = self.select_pos.take() {
+                        selected = self.select(pos);
+                    }
+                },
+            }
+        }
+        if !active {
+            return;
+        }
+
+        // Prepare selector change
+        if let Some(c) = selected {
+            if let Some((last, _)) = self.select_pulse.take() {
+                changes.push(Change::Button(last, false));
+            }
+            changes.push(Change::Button(c, true));
+            self.select_pulse = Some((c, now + SELECT_PULSE));
+        }
+
+        // Prepare axis change
+        let axis = if let Some(axis_combined) = axis_combined {
+            self.axis_touched = true;
+            self.release = None;
+
+            // Combined axis touches, unit vec (-1 .. 1 both axes)
+            let (curve, scale) = match self.axis_landed {
+                Some(landed) => tuning.shape_at(now.saturating_duration_since(landed)),
+                None => (curve, 1.),
+            };
+            shape(axis_combined * scale, active_low, active_high, curve)
+        } else {
+            if self.axis_touched {
+                // Last axis touch lifted
+                self.axis_touched = false;
+                if self.last_axis != Vec2::ZERO {
+                    self.release = Some(Release {
+                        start: now,
+                        from: self.last_axis,
+                    });
+                }
+            }
+            self.release_axis(tuning, now)
+        };
+        self.push_axis(axis, changes);
+
+        // Prepare button changes
+        for i in 0 .. BUTTON_COUNT {
+            let on = buttons[i];
+            if on != self.last_buttons[i] {
+                changes.push(Change::Button(self.outputs.button_codes[i], on));
+            }
+            self.last_buttons[i] = on;
+        }
+
+        // Some pads track fewer slots than the fingers they detect, use the higher count
+        let finger_button = self.outputs.finger_buttons.get(&touch_count.max(self.tool_fingers)).cloned();
+        if finger_button != self.last_finger_button {
+            if let Some(c) = self.last_finger_button {
+                changes.push(Change::Button(c, false));
+            }
+            if let Some(c) = finger_button {
+                changes.push(Change::Button(c, true));
+            }
+            self.last_finger_button = finger_button;
+        }
+
+        // Prepare click change, the button stays the same until the click is released
+        if let Some(zones) = self.outputs.click_zones {
+            let click = if !self.click_down {
+                None
+            } else if self.last_click.is_some() {
+                self.last_click
+            } else {
+                // Zones are on the physical surface, regardless of where the stick center is
+                let zone_top = 1. - zones.zone_height * self.geometry.resolution.y / (self.geometry.half_size.y * 2.);
+                let mut click = zones.other;
+                for state in &self.touch_states {
+                    if !state.enabled || state.palm || state.hover {
+                        continue;
+                    }
+                    let pos = self.geometry.surface_fraction(state.pos);
+                    if pos.y >= zone_top {
+                        click = if pos.x < 0.5 {
+                            zones.left
+                        } else {
+                            zones.right
+                        };
+                        break;
+                    }
+                }
+                click
+            };
+            if click != self.last_click {
+                if let Some(c) = self.last_click {
+                    changes.push(Change::Button(c, false));
+                }
+                if let Some(c) = click {
+                    changes.push(Change::Button(c, true));
+                }
+                self.last_click = click;
+            }
+        }
+
+        // Prepare force change. Levels are only left below the hysteresis so pressing
+        // right at a threshold doesn't flicker.
+        if let Some(force) = &self.outputs.force {
+            let pressure = if touch_count == 0 {
+                i32::MIN
+            } else {
+                self.pressure
+            };
+            let mut level = self.last_force_level;
+            while level < force.levels.len() && pressure >= force.levels[level].0 {
+                level += 1;
+            }
+            while level > 0 && pressure < force.levels[level - 1].0.saturating_sub(force.hysteresis) {
+                level -= 1;
+            }
+            if level != self.last_force_level {
+                let old = force.buttons(self.last_force_level);
+                let new = force.buttons(level);
+                for c in &old {
+                    if !new.contains(c) {
+                        changes.push(Change::Button(*c, false));
+                    }
+                }
+                for c in &new {
+                    if !old.contains(c) {
+                        changes.push(Change::Button(*c, true));
+                    }
+                }
+                self.last_force_level = level;
+            }
+        }
+
+        // Prepare hover change
+        if let Some(axes) = self.outputs.hover_axes {
+            let hover = hover.unwrap_or(Vec2::ZERO).clamp(Vec2::splat(-1.), Vec2::splat(1.));
+            if hover != self.last_hover {
+                changes.push(Change::Axis(axes[0], hover.x));
+                changes.push(Change::Axis(axes[1], hover.y));
+            }
+            self.last_hover = hover;
+        }
+
+        // Prepare tilt change
+        if let Some(tilt) = self.outputs.tilt {
+            let v = shape(self.tilt / tilt.full, tilt.active_low, tilt.active_high, tilt.curve);
+            if v != self.last_tilt {
+                changes.push(Change::Axis(tilt.axis_codes[0], v.x));
+                changes.push(Change::Axis(tilt.axis_codes[1], v.y));
+            }
+            self.last_tilt = v;
+        }
+    }
+}
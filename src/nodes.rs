@@ -0,0 +1,133 @@
+//! Picking the event node to use for a device. Physical devices usually have
+//! several nodes (mouse emulation, multitouch, keyboard, consumer keys) and only
+//! one of them is useful for each use.
+use std::{
+    collections::HashMap,
+    fs::read_dir,
+    os::unix::fs::FileTypeExt,
+    path::{
+        Path,
+        PathBuf,
+    },
+};
+use loga::{
+    ea,
+    ResultContext,
+};
+use crate::grouping::DevType;
+
+const INPUT_DIR: &'static str = "/dev/input";
+const BY_ID_DIR: &'static str = "/dev/input/by-id";
+
+/// Udev properties of a device node (`ID_BUS`, `ID_INPUT_TOUCHPAD`, etc).
+pub fn udev_properties(path: &Path) -> Result<HashMap<String, String>, loga::Error> {
+    let out =
+        std::process::Command::new("udevadm")
+            .arg("info")
+            .arg("--query=property")
+            .arg(path)
+            .output()
+            .context_with("Error getting udev properties of device", ea!(device = path.to_string_lossy()))?;
+    return Ok(String::from_utf8_lossy(&out.stdout).lines().filter_map(|l| l.split_once('=')).map(|(k, v)| {
+        (k.to_string(), v.to_string())
+    }).collect());
+}
+
+/// What a node is usable as, going by its udev properties.
+pub fn node_type(props: &HashMap<String, String>) -> Option<DevType> {
+    if props.get("ID_INPUT_TOUCHPAD").map(|v| v.as_str()) == Some("1") {
+        return Some(DevType::Pad);
+    } else if props.get("ID_INPUT_KEYBOARD").map(|v| v.as_str()) == Some("1") {
+        return Some(DevType::Keys);
+    } else {
+        return None;
+    }
+}
+
+/// The number of an `eventN` node, for ordering.
+fn event_number(path: &Path) -> Option<usize> {
+    return path.file_name()?.to_str()?.strip_prefix("event")?.parse().ok();
+}
+
+/// Event nodes of the input devices under a sysfs directory. Symlinks aren't
+/// followed, sysfs has loops.
+fn sysfs_event_nodes(dir: &Path, depth: usize, out: &mut Vec<PathBuf>) {
+    let Ok(entries) = read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let Ok(file_type) = entry.file_type() else {
+            continue;
+        };
+        if !file_type.is_dir() {
+            continue;
+        }
+        let name = PathBuf::from(entry.file_name());
+        if event_number(&name).is_some() {
+            out.push(Path::new(INPUT_DIR).join(name));
+        } else if depth > 0 {
+            sysfs_event_nodes(&entry.path(), depth - 1, out);
+        }
+    }
+}
+
+/// Find the event node to use as `type_` given a node, a sysfs device directory
+/// (ex: `/sys/class/input/input5` or the HID device above it), or the start of a
+/// name in `/dev/input/by-id` (ex: `usb-Apple_Inc._Magic_Trackpad_2`). Nodes are
+/// returned as is. Otherwise the node is picked like `trackjoy-juggler` does: the
+/// touchpad node for pads, the highest numbered keyboard node for keys.
+pub fn find_node(path: &Path, type_: DevType) -> Result<PathBuf, loga::Error> {
+    let ctx = ea!(device = path.to_string_lossy());
+    let mut candidates = vec![];
+    match std::fs::metadata(path) {
+        Ok(m) if m.file_type().is_char_device() => {
+            return Ok(path.to_path_buf());
+        },
+        Ok(m) if m.is_dir() => {
+            sysfs_event_nodes(path, 4, &mut candidates);
+        },
+        Ok(_) => {
+            return Err(loga::err_with("Device isn't an event node or a sysfs device directory", ctx));
+        },
+        Err(_) => {
+            let Some(prefix) = path.file_name().and_then(|n| n.to_str()) else {
+                return Err(loga::err_with("Device doesn't exist", ctx));
+            };
+            let entries =
+                read_dir(BY_ID_DIR).context_with("Device doesn't exist, and error listing devices", ctx.clone())?;
+            for entry in entries.flatten() {
+                if !entry.file_name().to_string_lossy().starts_with(prefix) {
+                    continue;
+                }
+                match entry.path().canonicalize() {
+                    Ok(p) => candidates.push(p),
+                    Err(_) => { },
+                }
+            }
+        },
+    }
+    candidates.sort_by_key(|p| event_number(p));
+    candidates.dedup();
+    if candidates.is_empty() {
+        return Err(loga::err_with("Device doesn't exist or has no event nodes", ctx));
+    }
+    let mut matching = vec![];
+    for node in &candidates {
+        if node_type(&udev_properties(node)?) == Some(type_) {
+            matching.push(node.clone());
+        }
+    }
+    let found = match type_ {
+        DevType::Pad => matching.into_iter().next(),
+        DevType::Keys => matching.into_iter().last(),
+    };
+    return found.ok_or_else(
+        || loga::err_with(
+            "None of the device's event nodes are usable for this type",
+            ea!(
+                device = path.to_string_lossy(),
+                nodes = candidates.iter().map(|p| p.to_string_lossy()).collect::<Vec<_>>().join(", ")
+            ),
+        ),
+    );
+}
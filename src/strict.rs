@@ -0,0 +1,122 @@
+//! Checks for `strict` config parsing: fields that would otherwise be silently
+//! ignored and misspelled codes, with suggestions.
+use evdev::{
+    AbsoluteAxisCode,
+    KeyCode,
+};
+
+/// Number of single character edits to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0 ..= b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev_diag = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let next = (row[j + 1] + 1).min(row[j] + 1).min(prev_diag + (ca != *cb) as usize);
+            prev_diag = row[j + 1];
+            row[j + 1] = next;
+        }
+    }
+    return row[b.len()];
+}
+
+/// The closest option, if any is close enough to be a likely typo.
+fn suggest<'a>(name: &str, options: impl Iterator<Item = &'a str>) -> Option<&'a str> {
+    let max = (name.len() / 3).max(2);
+    return options
+        .map(|o| (edit_distance(&name.to_uppercase(), &o.to_uppercase()), o))
+        .filter(|(d, _)| *d <= max)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, o)| o);
+}
+
+fn describe(problem: String, suggestion: Option<&str>) -> String {
+    match suggestion {
+        Some(s) => return format!("{} (did you mean `{}`?)", problem, s),
+        None => return problem,
+    }
+}
+
+/// Find fields in the input that weren't used, by comparing it with the parsed
+/// config serialized again.
+pub(crate) fn unknown_fields(
+    path: &str,
+    input: &serde_json::Value,
+    parsed: &serde_json::Value,
+    out: &mut Vec<String>,
+) {
+    match (input, parsed) {
+        (serde_json::Value::Object(input), serde_json::Value::Object(parsed)) => {
+            for (k, v) in input {
+                let field_path = format!("{}.{}", path, k);
+                match parsed.get(k) {
+                    Some(parsed_v) => unknown_fields(&field_path, v, parsed_v, out),
+                    None => {
+                        out.push(
+                            describe(
+                                format!("Unknown field `{}`", field_path),
+                                suggest(k, parsed.keys().filter(|pk| !input.contains_key(*pk)).map(|pk| pk.as_str())),
+                            ),
+                        );
+                    },
+                }
+            }
+        },
+        (serde_json::Value::Array(input), serde_json::Value::Array(parsed)) => {
+            for (i, (v, parsed_v)) in input.iter().zip(parsed.iter()).enumerate() {
+                unknown_fields(&format!("{}[{}]", path, i), v, parsed_v, out);
+            }
+        },
+        _ => { },
+    }
+}
+
+fn code_names() -> Vec<String> {
+    let mut out = vec![];
+    for c in 0 .. 0x300 {
+        out.push(format!("{:?}", KeyCode(c)));
+    }
+    for c in 0 .. 0x40 {
+        out.push(format!("{:?}", AbsoluteAxisCode(c)));
+    }
+    out.retain(|n| n.starts_with("KEY_") || n.starts_with("BTN_") || n.starts_with("ABS_"));
+    return out;
+}
+
+/// Find strings and object keys in the input that look like codes but aren't.
+pub(crate) fn unknown_codes(path: &str, input: &serde_json::Value, out: &mut Vec<String>) {
+    fn check(path: &str, s: &str, names: &mut Option<Vec<String>>, out: &mut Vec<String>) {
+        if !(s.starts_with("KEY_") || s.starts_with("BTN_") || s.starts_with("ABS_")) {
+            return;
+        }
+        let names = names.get_or_insert_with(code_names);
+        if names.iter().any(|n| n == s) {
+            return;
+        }
+        out.push(
+            describe(format!("Unknown code `{}` at `{}`", s, path), suggest(s, names.iter().map(|n| n.as_str()))),
+        );
+    }
+
+    fn walk(path: &str, input: &serde_json::Value, names: &mut Option<Vec<String>>, out: &mut Vec<String>) {
+        match input {
+            serde_json::Value::String(s) => check(path, s, names, out),
+            serde_json::Value::Array(a) => {
+                for (i, v) in a.iter().enumerate() {
+                    walk(&format!("{}[{}]", path, i), v, names, out);
+                }
+            },
+            serde_json::Value::Object(o) => {
+                for (k, v) in o {
+                    let field_path = format!("{}.{}", path, k);
+                    check(&field_path, k, names, out);
+                    walk(&field_path, v, names, out);
+                }
+            },
+            _ => { },
+        }
+    }
+
+    walk(path, input, &mut None, out);
+}
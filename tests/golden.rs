@@ -0,0 +1,249 @@
+//! Feeds recorded source events through the mappers and compares the output
+//! changes against the expected files. Each directory in `tests/golden` is a case:
+//!
+//! * `case.json` - `{"pad": {...}}` with the pad's axis info and settings, or
+//!   `{"keys": {...}}` with a keys mapping
+//!
+//! * `events` - one event per line like `evtest` shows them, `EV_ABS
+//!   ABS_MT_POSITION_X 1234`, with `#` comments. `RECENTER` recenters the pad
+//!   stick like `recenter_key`.
+//!
+//! * `expected` - the changes at the end of each frame (`SYN_REPORT`), one line per
+//!   frame, `-` if nothing changed
+//!
+//! Set `TRACKJOY_BLESS=1` to overwrite the expected files with the current output
+//! after an intentional change.
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    path::Path,
+    time::Instant,
+};
+use evdev::{
+    AbsoluteAxisCode,
+    EventType,
+    InputEvent,
+    KeyCode,
+    MiscCode,
+    SynchronizationCode,
+};
+use serde::Deserialize;
+use trackjoy::{
+    mapper::{
+        exp_param,
+        keys::KeysMapper,
+        pad::{
+            AxisRange,
+            ClickZones,
+            PadGeometry,
+            PadMapper,
+            DEFAULT_MAX_SLOTS,
+            PadOutputs,
+        },
+        Change,
+        Changes,
+        Tuning,
+    },
+    KeysConfig,
+};
+
+#[derive(Deserialize)]
+struct CaseAxis {
+    minimum: i32,
+    maximum: i32,
+    resolution: i32,
+    fuzz: i32,
+}
+
+#[derive(Deserialize)]
+struct CaseClickZones {
+    left: Option<KeyCode>,
+    right: Option<KeyCode>,
+    other: Option<KeyCode>,
+    zone_height: f32,
+}
+
+#[derive(Deserialize)]
+struct CasePad {
+    x: CaseAxis,
+    y: CaseAxis,
+    #[serde(default)]
+    multitouch: bool,
+    #[serde(default)]
+    mirror_horizontal: bool,
+    #[serde(default)]
+    finger_buttons: HashMap<usize, KeyCode>,
+    hover_axes: Option<[AbsoluteAxisCode; 2]>,
+    click_zones: Option<CaseClickZones>,
+    dead_inner: f32,
+    dead_outer: f32,
+    curve: f32,
+    y_smash: f32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Case {
+    Pad(CasePad),
+    Keys(KeysConfig),
+}
+
+fn axis_range(a: CaseAxis) -> AxisRange {
+    return AxisRange {
+        minimum: a.minimum,
+        maximum: a.maximum,
+        resolution: a.resolution,
+        fuzz: a.fuzz,
+    };
+}
+
+enum Step {
+    Event(InputEvent),
+    Recenter,
+}
+
+fn parse_events(text: &str) -> Vec<Step> {
+    let mut out = vec![];
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("#") {
+            continue;
+        }
+        if line == "RECENTER" {
+            out.push(Step::Recenter);
+            continue;
+        }
+        let parts = line.split_whitespace().collect::<Vec<_>>();
+        let [type_, code, value] = parts.as_slice() else {
+            panic!("Line {}: expected type, code and value: {}", i + 1, line);
+        };
+        let value = value.parse::<i32>().unwrap_or_else(|e| panic!("Line {}: bad value: {}", i + 1, e));
+        let code_json = serde_json::Value::String(code.to_string());
+        let (type_, code) = match *type_ {
+            "EV_SYN" => {
+                assert_eq!(*code, "SYN_REPORT", "Line {}: only SYN_REPORT is supported", i + 1);
+                (EventType::SYNCHRONIZATION, SynchronizationCode::SYN_REPORT.0)
+            },
+            "EV_KEY" => (EventType::KEY, serde_json::from_value::<KeyCode>(code_json).unwrap().0),
+            "EV_ABS" => (EventType::ABSOLUTE, serde_json::from_value::<AbsoluteAxisCode>(code_json).unwrap().0),
+            "EV_MSC" => {
+                assert_eq!(*code, "MSC_SCAN", "Line {}: only MSC_SCAN is supported", i + 1);
+                (EventType::MISC, MiscCode::MSC_SCAN.0)
+            },
+            t => panic!("Line {}: unsupported event type {}", i + 1, t),
+        };
+        out.push(Step::Event(InputEvent::new(type_.0, code, value)));
+    }
+    return out;
+}
+
+fn format_frame(out: &mut String, changes: &Changes) {
+    if changes.is_empty() {
+        out.push_str("-");
+    }
+    for (i, change) in changes.iter().enumerate() {
+        if i > 0 {
+            out.push_str(" ");
+        }
+        match change {
+            Change::Button(k, on) => write!(out, "{:?}={}", k, *on as i32).unwrap(),
+            Change::Axis(axis, v) => write!(out, "{:?}={:.4}", axis, v).unwrap(),
+        }
+    }
+    out.push_str("\n");
+}
+
+fn is_report(ev: &InputEvent) -> bool {
+    return ev.event_type() == EventType::SYNCHRONIZATION && ev.code() == SynchronizationCode::SYN_REPORT.0;
+}
+
+fn run_case(dir: &Path) -> String {
+    let case =
+        serde_json::from_slice::<Case>(&std::fs::read(dir.join("case.json")).unwrap())
+            .unwrap_or_else(|e| panic!("Bad case.json in {}: {}", dir.display(), e));
+    let events = parse_events(&std::fs::read_to_string(dir.join("events")).unwrap());
+    let mut out = String::new();
+    let mut changes = Changes::new();
+    match case {
+        Case::Pad(c) => {
+            let geometry =
+                PadGeometry::new(axis_range(c.x), axis_range(c.y), false, None, None, None, None, None, None).unwrap();
+            let tuning = Tuning {
+                active_low: c.dead_inner,
+                active_high: 1. - c.dead_outer,
+                curve: exp_param(c.curve),
+                y_smash: Some(exp_param(c.y_smash)),
+                ..Tuning::default()
+            };
+            let mut mapper = PadMapper::new(PadOutputs {
+                finger_buttons: c.finger_buttons,
+                multitouch: c.multitouch,
+                mirror_horizontal: c.mirror_horizontal,
+                hover_axes: c.hover_axes,
+                click_zones: c.click_zones.map(|z| ClickZones {
+                    left: z.left,
+                    right: z.right,
+                    other: z.other,
+                    zone_height: z.zone_height,
+                }),
+                ..PadOutputs::new(
+                    [AbsoluteAxisCode::ABS_X, AbsoluteAxisCode::ABS_Y],
+                    [KeyCode::BTN_NORTH, KeyCode::BTN_WEST, KeyCode::BTN_EAST, KeyCode::BTN_SOUTH],
+                )
+            }, geometry, DEFAULT_MAX_SLOTS, 0, vec![]);
+            let now = Instant::now();
+            for step in events {
+                let ev = match step {
+                    Step::Event(ev) => ev,
+                    Step::Recenter => {
+                        mapper.recenter();
+                        continue;
+                    },
+                };
+                mapper.event(ev, &tuning, true, now, &mut changes);
+                if is_report(&ev) {
+                    format_frame(&mut out, &changes);
+                    changes.clear();
+                }
+            }
+        },
+        Case::Keys(c) => {
+            let mut mapper = KeysMapper::new(c, [], 0, 1, None).unwrap();
+            let now = Instant::now();
+            for step in events {
+                let Step::Event(ev) = step else {
+                    panic!("RECENTER is only for pad cases in {}", dir.display());
+                };
+                mapper.event(ev, true, now, &mut changes);
+                if is_report(&ev) {
+                    format_frame(&mut out, &changes);
+                    changes.clear();
+                }
+            }
+        },
+    }
+    return out;
+}
+
+#[test]
+fn golden() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let bless = std::env::var_os("TRACKJOY_BLESS").is_some();
+    let mut failed = vec![];
+    let mut dirs = std::fs::read_dir(&root).unwrap().map(|e| e.unwrap().path()).collect::<Vec<_>>();
+    dirs.sort();
+    for dir in dirs {
+        let got = run_case(&dir);
+        let expected_path = dir.join("expected");
+        if bless {
+            std::fs::write(&expected_path, &got).unwrap();
+            continue;
+        }
+        let expected = std::fs::read_to_string(&expected_path).unwrap_or_default();
+        if got != expected {
+            eprintln!("Case {} differs\n--- expected\n{}--- got\n{}", dir.display(), expected, got);
+            failed.push(dir);
+        }
+    }
+    assert!(failed.is_empty(), "Golden cases failed: {:?}", failed);
+}
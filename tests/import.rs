@@ -0,0 +1,52 @@
+//! Runs `trackjoy import` on the layouts in `tests/import` and compares the config
+//! against the `.json` file with the same name. Both are parsed as configs first,
+//! so only the mappings matter, not field order or defaults.
+use std::{
+    path::Path,
+    process::Command,
+};
+use trackjoy::parse_config;
+
+fn normalize(text: &[u8], path: &Path) -> serde_json::Value {
+    let value =
+        serde_json::from_slice::<serde_json::Value>(
+            text,
+        ).unwrap_or_else(|e| panic!("Bad JSON for {}: {}", path.display(), e));
+    let config = parse_config(value, true).unwrap_or_else(|e| panic!("Bad config for {}: {}", path.display(), e));
+    return serde_json::to_value(&config).unwrap();
+}
+
+#[test]
+fn import() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/import");
+    let mut failed = vec![];
+    let mut layouts =
+        std::fs::read_dir(&root)
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .filter(|p| p.extension().map(|e| e != "json").unwrap_or(false))
+            .collect::<Vec<_>>();
+    layouts.sort();
+    for layout in layouts {
+        let output = Command::new(env!("CARGO_BIN_EXE_trackjoy")).arg("import").arg(&layout).output().unwrap();
+        if !output.status.success() {
+            eprintln!("Importing {} failed:\n{}", layout.display(), String::from_utf8_lossy(&output.stderr));
+            failed.push(layout);
+            continue;
+        }
+        let expected_path = layout.with_extension("json");
+        let got = normalize(&output.stdout, &layout);
+        let expected = normalize(&std::fs::read(&expected_path).unwrap(), &expected_path);
+        if got != expected {
+            eprintln!(
+                "Import of {} differs\n--- expected\n{:#}\n--- got\n{:#}\n--- log\n{}",
+                layout.display(),
+                expected,
+                got,
+                String::from_utf8_lossy(&output.stderr)
+            );
+            failed.push(layout);
+        }
+    }
+    assert!(failed.is_empty(), "Imports failed: {:?}", failed);
+}